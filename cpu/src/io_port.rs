@@ -0,0 +1,121 @@
+/**
+ * The 6510's on-chip I/O port, at $0000 (data direction) and $0001
+ * (data). On the Commodore 64 its low bits bank the ROMs in and out.
+ *
+ * Pins set as outputs carry the data register. Inputs read what the
+ * board does with them: pulled high, or nothing at all. A pin nothing
+ * drives keeps the charge it had as an output for a while and then
+ * reads as 0, which some copy protection checks for.
+ */
+pub mod io_port {
+    use std::sync::{Arc, Mutex};
+
+    // How long a pin left floating keeps reading as 1, in cycles
+    pub const FALL_OFF_CYCLES: u64 = 350_000;
+
+    // Shared between the CPU and whatever decodes addresses from the pins
+    pub type SharedIoPort = Arc<Mutex<IoPort>>;
+
+    pub struct IoPort {
+        pub ddr: u8,    // Data direction: 1 = output
+        pub output: u8, // Data register
+
+        pull_ups: u8, // Input pins the board pulls high; the rest float
+
+        // Floating pins still charged high, and until when
+        charged: u8,
+        charged_until: [u64; 8],
+    }
+
+    impl Default for IoPort {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl IoPort {
+        // A port with every pin floating when it isn't an output
+        pub fn new() -> Self {
+            Self {
+                ddr: 0x00,
+                output: 0x00,
+                pull_ups: 0x00,
+                charged: 0x00,
+                charged_until: [0; 8],
+            }
+        }
+
+        pub fn shared() -> SharedIoPort {
+            Arc::new(Mutex::new(Self::new()))
+        }
+
+        // Which pins the board pulls high when they're inputs
+        pub fn set_pull_ups(&mut self, pull_ups: u8) {
+            self.pull_ups = pull_ups;
+        }
+
+        // The levels on the pins at cycle `now`
+        pub fn pins(&self, now: u64) -> u8 {
+            let mut floating = 0x00;
+            for bit in 0..8 {
+                if self.charged & 1 << bit != 0 && now < self.charged_until[bit] {
+                    floating |= 1 << bit;
+                }
+            }
+            let inputs = self.pull_ups | (floating & !self.pull_ups);
+            (self.output & self.ddr) | (inputs & !self.ddr)
+        }
+
+        pub fn read(&self, address: u16, now: u64) -> u8 {
+            if address == 0x0000 {
+                self.ddr
+            } else {
+                self.pins(now)
+            }
+        }
+
+        pub fn write(&mut self, address: u16, data: u8, now: u64) {
+            if address == 0x0000 {
+                // Outputs turned into inputs hold what they were driving
+                let released = self.ddr & !data;
+                for bit in 0..8 {
+                    if released & 1 << bit != 0 {
+                        self.charged_until[bit] = now + FALL_OFF_CYCLES;
+                    }
+                }
+                self.charged = (self.charged & !released) | (self.output & released);
+                self.ddr = data;
+            } else {
+                self.output = data;
+            }
+        }
+
+        pub fn reset(&mut self) {
+            // Every pin becomes an input
+            self.ddr = 0x00;
+            self.output = 0x00;
+            self.charged = 0x00;
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_floating_pins() {
+            let mut port = IoPort::new();
+            port.set_pull_ups(0x3F);
+            assert_eq!(port.pins(0), 0x3F);
+
+            // Drive bits 6 and 7, then let go of them
+            port.write(0x0000, 0xC7, 0);
+            port.write(0x0001, 0xC5, 0);
+            assert_eq!(port.read(0x0001, 10), 0xFD);
+            port.write(0x0000, 0x07, 100);
+            assert_eq!(port.read(0x0000, 100), 0x07);
+            assert_eq!(port.read(0x0001, 100 + FALL_OFF_CYCLES - 1), 0xFD);
+            assert_eq!(port.read(0x0001, 100 + FALL_OFF_CYCLES), 0x3D);
+        }
+    }
+}
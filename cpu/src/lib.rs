@@ -1,7 +1,18 @@
+// Each module keeps its contents in an inner public module of the same name,
+// which is what gets imported (`addresses::addresses` and so on)
+#[allow(clippy::module_inception, reason = "the crate's module layout")]
 mod addresses;
+#[allow(clippy::module_inception, reason = "the crate's module layout")]
 mod instructions;
+#[allow(clippy::module_inception, reason = "the crate's module layout")]
+pub mod io_port;
+#[allow(clippy::module_inception, reason = "the crate's module layout")]
 mod registers;
 
+#[allow(
+    clippy::needless_return,
+    reason = "the CPU core spells out its returns, like the instruction tables"
+)]
 pub mod cpu {
     use std::sync::{Arc, Mutex};
 
@@ -11,6 +22,7 @@ pub mod cpu {
             self,
             instructions::{execute_instruction, AddressingMode},
         },
+        io_port::io_port::{IoPort, SharedIoPort},
         registers::{self, registers::Registers},
     };
 
@@ -23,7 +35,16 @@ pub mod cpu {
         pub state: State,         // CPU state
         pub registers: Registers, // Registers
         // Bus read function pointer
+        #[allow(
+            clippy::type_complexity,
+            reason = "the closure type is spelled out so the bus side can see what to pass"
+        )]
         pub read_byte: Option<Arc<Mutex<dyn FnMut(u16) -> u8>>>,
+        #[allow(
+            clippy::type_complexity,
+            clippy::unused_unit,
+            reason = "written like the read function's type beside it"
+        )]
         pub write_byte: Option<Arc<Mutex<dyn FnMut(u16, u8) -> ()>>>,
 
         pub cycles: u8,    // Number of cycles remaining for current instruction
@@ -35,6 +56,9 @@ pub mod cpu {
         pub fetched: u8,   // Fetched data
 
         pub enable_illegal_opcodes: bool, // Enable illegal opcodes
+
+        pub io_port: SharedIoPort, // The 6510's port at $0000/$0001
+        pub cycle: u64,            // Cycles run since power on
     }
 
     #[derive(Clone, Copy, PartialEq)]
@@ -42,27 +66,54 @@ pub mod cpu {
         NMOS, // Original 6502 (with ROR bug)
         CMOS, // Modified 65C02 (no ROR bug)
         NES,  // Modified 2A03 (no decimal mode)
+        MOS6510, // NMOS 6502 with an I/O port at $0000/$0001 (Commodore 64)
     }
 
     impl Variant {
-        pub fn from_string(variant: String) -> Self {
+        pub fn from_string(variant: String) -> Result<Self, Error> {
             match variant.as_str() {
-                "NMOS" => return Self::NMOS,
-                "CMOS" => return Self::CMOS,
-                "NES" => return Self::NES,
-                _ => panic!("Invalid CPU variant"),
+                "NMOS" => return Ok(Self::NMOS),
+                "CMOS" => return Ok(Self::CMOS),
+                "NES" => return Ok(Self::NES),
+                "6510" => return Ok(Self::MOS6510),
+                _ => return Err(Error::UnknownVariant(variant)),
             }
         }
 
+        #[allow(
+            clippy::inherent_to_string,
+            reason = "variant names are only ever wanted as strings"
+        )]
         pub fn to_string(&self) -> String {
             match self {
                 Self::NMOS => return String::from("NMOS"),
                 Self::CMOS => return String::from("CMOS"),
                 Self::NES => return String::from("NES"),
+                Self::MOS6510 => return String::from("6510"),
+            }
+        }
+    }
+
+    // Errors from setting up the CPU
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub enum Error {
+        UnknownVariant(String), // Not one of the names `Variant::from_string` knows
+    }
+
+    impl std::fmt::Display for Error {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Self::UnknownVariant(name) => write!(
+                    f,
+                    "unknown CPU variant \"{}\" (expected NMOS, CMOS, NES or 6510)",
+                    name
+                ),
             }
         }
     }
 
+    impl std::error::Error for Error {}
+
     #[derive(Clone, Copy, PartialEq)]
     pub enum State {
         Stopped,       // CPU is stopped
@@ -73,6 +124,10 @@ pub mod cpu {
     }
 
     impl Cpu {
+        #[allow(
+            clippy::new_without_default,
+            reason = "a CPU isn't usable until it's been connected to a bus"
+        )]
         pub fn new() -> Self {
             Self {
                 registers: Registers::new(),
@@ -89,20 +144,33 @@ pub mod cpu {
 
                 enable_illegal_opcodes: false,
 
+                io_port: IoPort::shared(),
+                cycle: 0,
+
                 read_byte: None,
                 write_byte: None,
             }
         }
 
+        #[allow(clippy::type_complexity, reason = "matches the `read_byte` field")]
         pub fn connect_read_byte(&mut self, read_fn: Arc<Mutex<dyn FnMut(u16) -> u8>>) {
             self.read_byte = Some(read_fn);
         }
 
+        #[allow(
+            clippy::type_complexity,
+            clippy::unused_unit,
+            reason = "matches the `write_byte` field"
+        )]
         pub fn connect_write_byte(&mut self, write_fn: Arc<Mutex<dyn FnMut(u16, u8) -> ()>>) {
             self.write_byte = Some(write_fn);
         }
 
         pub fn read_byte(&mut self, address: u16) -> u8 {
+            if self.has_io_port(address) {
+                // The port answers from inside the chip
+                return self.io_port.lock().unwrap().read(address, self.cycle);
+            }
             if let Some(read_byte_fn) = &mut self.read_byte {
                 // Read from the bus
                 return read_byte_fn.lock().unwrap()(address);
@@ -113,12 +181,21 @@ pub mod cpu {
         }
 
         pub fn write_byte(&mut self, address: u16, value: u8) {
+            if self.has_io_port(address) {
+                self.io_port.lock().unwrap().write(address, value, self.cycle);
+                return;
+            }
             if let Some(write_byte_fn) = &mut self.write_byte {
                 // Write to the bus
                 write_byte_fn.lock().unwrap()(address, value);
             }
         }
 
+        // Whether `address` is the 6510's port rather than the bus
+        fn has_io_port(&self, address: u16) -> bool {
+            return self.variant == Variant::MOS6510 && address < 0x0002;
+        }
+
         pub fn dump_cycles(&self) {
             println!("Cycles remaining: {}", self.cycles);
         }
@@ -132,6 +209,10 @@ pub mod cpu {
         }
 
         pub fn reset(&mut self) {
+            // The 6510's port goes back to inputs first, which on the C64
+            // banks in the KERNAL holding the reset vector
+            self.io_port.lock().unwrap().reset();
+
             // Reset registers to initial state
             self.registers.a = 0x00;
             self.registers.x = 0x00;
@@ -205,7 +286,6 @@ pub mod cpu {
                 // Set state to fetching
                 self.state = State::Fetching;
                 self.opcode = self.read(self.registers.pc);
-                println!("Opcode: {:02X}", self.opcode);
                 self.registers.pc += 1;
 
                 // Get the number of cycles for this opcode
@@ -229,6 +309,7 @@ pub mod cpu {
 
             // Decrement the number of cycles remaining
             self.cycles -= 1;
+            self.cycle += 1;
         }
 
         pub fn execute_addr_mode(&mut self, mode: AddressingMode) -> u8 {
@@ -256,6 +337,10 @@ pub mod cpu {
             return instructions::instructions::get_cycles(opcode);
         }
 
+        #[allow(
+            clippy::bool_comparison,
+            reason = "reads as the datasheet's condition, I = 0"
+        )]
         pub fn irq(&mut self) {
             // If interrupts are enabled, push the program counter and flags to the stack
             if self
@@ -392,6 +477,10 @@ pub mod cpu {
             }
             return 0;
         }
+        #[allow(
+            clippy::identity_op,
+            reason = "`ptr + 0` and `ptr + 1` name the low and high bytes of the pointer"
+        )]
         pub fn addr_indirect(&mut self) -> u8 {
             let ptr_lo = self.read(self.registers.pc) as u16;
             let ptr_hi = self.read(self.registers.pc + 1) as u16;
@@ -55,6 +55,10 @@ pub mod registers {
     }
 
     #[cfg(test)]
+    #[allow(
+        clippy::bool_assert_comparison,
+        reason = "flags are checked against the value they were set to"
+    )]
     mod tests {
         use super::*;
 
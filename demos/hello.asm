@@ -0,0 +1,24 @@
+  .org $C000
+
+; Host interface registers
+putchar = $7F00
+exit = $7F01
+
+reset:
+  lda #"O"
+  sta putchar
+  lda #"K"
+  sta putchar
+  lda #$0A
+  sta putchar
+
+  ; Report success
+  lda #0
+  sta exit
+
+loop:
+  jmp loop
+
+  .org $fffc
+  .word reset
+  .word reset
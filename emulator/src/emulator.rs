@@ -1,92 +1,307 @@
-use std::sync::{Arc, Mutex, RwLock};
-use std::thread;
+use std::io::BufRead;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
 use cpu::{self, cpu::Cpu};
 
-use self::bus::Bus;
+use self::bus::{Bus, IrqWiring};
+use self::error::{Error, Result};
+use self::loaders::Image;
+use self::throttle::Throttle;
+use self::devices::{
+    host_interface::HostInterface, led_bar::LedBar, port::Port, via::Via, SharedDevice,
+};
 
 pub mod bus;
 pub mod devices;
+pub mod error;
+pub mod framebuffer;
+pub mod loaders;
+pub mod machines;
+pub mod scheduler;
+pub mod throttle;
+pub mod wav;
+
+// Where `init` maps the host interface used by test programs
+pub const HOST_INTERFACE_ADDRESS: u16 = 0x7F00;
+
+// Where the CLI maps the file I/O device when given a directory to share
+pub const FILE_IO_ADDRESS: u16 = 0x7F20;
 
 pub struct Emulator {
     pub cpu: Cpu,
-    cpu_speed_hz: f64,
-    pub bus: bus::Bus,
+    pub bus: Arc<Mutex<Bus>>,
+    pub cycles: u64,        // Total number of cycles run since reset
+    pub halted_cycles: u64, // Cycles the CPU spent held off the bus since reset
+    nmi_line: bool,         // Last seen level of the NMI line (NMI is edge triggered)
+
+    // Whether `run` keeps to the wall clock, and how fast it managed to go
+    throttled: bool,
+    effective_speed_hz: Option<f64>,
+
+    // Set from another thread (a Ctrl-C handler) to make `run` return
+    interrupt: Arc<AtomicBool>,
+
+    // Set when a device asks the emulator to stop
+    exit_status: Option<i32>,
+}
+
+impl Default for Emulator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
+
 impl Emulator {
     pub fn new() -> Self {
         Self {
             cpu: Cpu::new(),
-            cpu_speed_hz: 0_000_000.0,
-            bus: Bus::new(),
+            bus: Arc::new(Mutex::new(Bus::new())),
+            cycles: 0,
+            halted_cycles: 0,
+            nmi_line: false,
+            throttled: true,
+            effective_speed_hz: None,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            exit_status: None,
         }
     }
 
     pub fn init(&mut self) {
-        self.bus = Bus::new();
-        self.cpu_speed_hz = 0_000_000.0;
+        self.init_empty();
+
+        // The blink demo drives eight LEDs on port B of a VIA at $6000
+        let mut via = Via::new();
+        via.attach(Arc::new(Mutex::new(LedBar::on_port(Port::B, 8))));
+        self.add_device(0x6000, 0x600F, Arc::new(Mutex::new(via)));
+
+        // Test programs print and report their result through the host interface
+        self.add_device(
+            HOST_INTERFACE_ADDRESS,
+            HOST_INTERFACE_ADDRESS + 0x1F,
+            Arc::new(Mutex::new(HostInterface::new())),
+        );
+    }
+
+    // A fresh CPU on a bus with nothing but memory, for machine profiles to fill in
+    pub fn init_empty(&mut self) {
+        self.bus = Arc::new(Mutex::new(Bus::new()));
         self.cpu = Cpu::new();
+        self.cycles = 0;
+        self.halted_cycles = 0;
+        self.nmi_line = false;
+        self.exit_status = None;
         self.init_bus();
     }
 
     pub fn init_bus(&mut self) {
+        // The CPU and the emulator share the same bus
         let read_byte_fn = Arc::new(Mutex::new({
-            let mut bus = self.bus.clone();
-            move |address: u16| -> u8 { bus.read_byte(address) }
+            let bus = self.bus.clone();
+            move |address: u16| -> u8 { bus.lock().unwrap().read_byte(address) }
         }));
 
         let write_byte_fn = Arc::new(Mutex::new({
-            let mut bus = self.bus.clone();
-            move |address: u16, value: u8| bus.write_byte(address, value)
+            let bus = self.bus.clone();
+            move |address: u16, value: u8| bus.lock().unwrap().write_byte(address, value)
         }));
 
         self.cpu.connect_read_byte(read_byte_fn);
         self.cpu.connect_write_byte(write_byte_fn);
     }
 
-    #[allow(dead_code)]
-    pub fn change_speed(&mut self, speed: f64) {
-        self.cpu_speed_hz = speed;
+    // Run as fast as the host can instead of at the clock rate given to
+    // `run`, which devices still keep time by
+    pub fn set_throttled(&mut self, throttled: bool) {
+        self.throttled = throttled;
+    }
+
+    // The speed the last `run` actually reached, in Hz
+    pub fn effective_speed_hz(&self) -> Option<f64> {
+        self.effective_speed_hz
+    }
+
+    // A flag that stops `run` at the end of the slice it's in when set
+    pub fn interrupt_flag(&self) -> Arc<AtomicBool> {
+        self.interrupt.clone()
+    }
+
+    pub fn interrupted(&self) -> bool {
+        self.interrupt.load(Ordering::SeqCst)
+    }
+
+    pub fn add_device(&mut self, start: u16, end: u16, device: SharedDevice) {
+        self.bus.lock().unwrap().add_device(start, end, device);
     }
 
-    #[allow(dead_code)]
-    pub fn change_speed_mhz(&mut self, speed: f64) {
-        self.cpu_speed_hz = speed * 1_000_000.0;
+    pub fn add_device_wired(
+        &mut self,
+        start: u16,
+        end: u16,
+        device: SharedDevice,
+        irq_wiring: IrqWiring,
+    ) {
+        self.bus
+            .lock()
+            .unwrap()
+            .add_device_wired(start, end, device, irq_wiring);
     }
 
-    pub fn load_rom_from_path(&mut self, path: &str, address: u16) {
+    pub fn load_rom_from_path(&mut self, path: &str, address: u16) -> Result<()> {
         // Load the rom file into a vector
-        let rom = std::fs::read(path).unwrap();
+        let rom = std::fs::read(path).map_err(|error| Error::io(path, error))?;
 
         // Load the rom file into memory
-        self.bus.load_rom_at(&rom, address);
+        self.bus.lock().unwrap().load_rom_at(&rom, address)
     }
 
-    pub fn change_variant(&mut self, variant: String) {
-        // Change the variant of the CPU
-        self.cpu
-            .change_variant(cpu::cpu::Variant::from_string(variant));
+    // Place every segment of a program image, and if asked start the
+    // program at the image's entry address rather than the reset vector's
+    pub fn load_image(&mut self, image: &Image, set_reset_vector: bool) -> Result<()> {
+        image.load_into(&mut self.bus.lock().unwrap(), set_reset_vector)
     }
 
-    // Run the emulator for a certain number of cycles (optional)
-    pub fn run(&mut self, speed_mhz: f64, num_cycles: Option<u64>, benchmark_mode: bool) {
-        let mut cycles_left = num_cycles.unwrap_or(u64::MAX);
+    // Reset the CPU and every attached device
+    pub fn reset(&mut self) {
+        self.bus.lock().unwrap().reset_devices();
+        self.cpu.reset();
+        self.cycles = 0;
+        self.halted_cycles = 0;
+        self.nmi_line = false;
+        self.exit_status = None;
+    }
 
-        // Change the variant to CMOS
-        self.change_variant(String::from("CMOS"));
+    // The exit status a device asked for, once the emulator has been told to stop
+    pub fn exit_status(&self) -> Option<i32> {
+        self.exit_status
+    }
 
-        self.cpu.reset();
+    // Run a single CPU cycle, servicing interrupts and due device events
+    pub fn clock(&mut self) {
+        // Devices and interrupts are only looked at between instructions,
+        // which is also where the CPU waits while a device holds RDY low
+        if self.cpu.cycles == 0 && !self.sync_devices() {
+            self.cycles += 1;
+            self.halted_cycles += 1;
+            return;
+        }
+
+        self.cpu.clock();
+        self.cycles += 1;
+    }
+
+    // Run the rest of the current instruction and all of the next one
+    pub fn step(&mut self) {
+        while self.cpu.cycles > 0 {
+            self.clock();
+        }
+        self.clock();
+        while self.cpu.cycles > 0 {
+            self.clock();
+        }
+    }
+
+    // Run one instruction for every line read from `input` (like pressing the
+    // button on a single-step clock), printing the registers after each,
+    // until "q" or the end of the input
+    pub fn run_single_step<R: BufRead>(&mut self, input: R) -> Option<i32> {
+        self.reset();
+        self.bus.lock().unwrap().set_clock_rate(1.0);
+        while self.cpu.cycles > 0 {
+            self.clock();
+        }
+
+        for line in input.lines() {
+            match line {
+                Ok(line) if line.trim() != "q" => {}
+                _ => break,
+            }
+            self.step();
+
+            let registers = &self.cpu.registers;
+            println!(
+                "PC=${:04X} op=${:02X} A=${:02X} X=${:02X} Y=${:02X} SP=${:02X} P=${:02X} cycles={}",
+                registers.pc,
+                self.cpu.opcode,
+                registers.a,
+                registers.x,
+                registers.y,
+                registers.sp,
+                registers.flags,
+                self.cycles
+            );
+            if self.exit_status.is_some() {
+                break;
+            }
+        }
+        self.exit_status
+    }
+
+    // Bring the devices up to date and take interrupts. Returns false if
+    // the CPU is being held off the bus.
+    fn sync_devices(&mut self) -> bool {
+        let (irq, nmi, halted) = {
+            let mut bus = self.bus.lock().unwrap();
+            bus.sync(self.cycles);
+            if self.exit_status.is_none() {
+                self.exit_status = bus.exit_status();
+            }
+            (bus.irq(), bus.nmi(), bus.halted())
+        };
+
+        if halted {
+            return false;
+        }
+
+        // NMI fires on the falling edge of the (active low) line
+        let nmi_edge = nmi && !self.nmi_line;
+        self.nmi_line = nmi;
+
+        if nmi_edge {
+            self.cpu.nmi();
+        } else if irq {
+            self.cpu.irq();
+        }
+        true
+    }
+
+    // Change the variant of the CPU, by name
+    pub fn change_variant(&mut self, variant: String) -> Result<()> {
+        self.set_variant(cpu::cpu::Variant::from_string(variant)?);
+        Ok(())
+    }
+
+    pub fn set_variant(&mut self, variant: cpu::cpu::Variant) {
+        self.cpu.change_variant(variant);
+    }
+
+    // Run the emulator for a certain number of cycles (optional), until a
+    // device asks it to stop, or until interrupted (see `interrupt_flag`).
+    // Returns the exit status the device asked for.
+    pub fn run(
+        &mut self,
+        speed_mhz: f64,
+        num_cycles: Option<u64>,
+        benchmark_mode: bool,
+    ) -> Option<i32> {
+        let mut cycles_left = num_cycles.unwrap_or(u64::MAX);
+
+        self.reset();
 
         // Calculate the number of cycles to run per second
         let cycles_per_second = speed_mhz * 1_000_000.0;
+        self.bus.lock().unwrap().set_clock_rate(cycles_per_second);
 
-        if (benchmark_mode) {
+        if benchmark_mode {
             // Start a timer
             let start = std::time::Instant::now();
 
             // Run the CPU for the specified number of cycles
             for _ in 0..cycles_left {
-                self.cpu.clock();
+                self.clock();
+                if self.exit_status.is_some() {
+                    break;
+                }
             }
 
             // Stop the timer
@@ -95,8 +310,9 @@ impl Emulator {
             // Calculate the time elapsed
             let time_elapsed = end.duration_since(start);
 
-            // Calculate the number of cycles per second
-            let cycles_per_second = num_cycles.unwrap() as f64 / start.elapsed().as_secs_f64();
+            // Calculate the number of cycles per second, from the cycles
+            // actually run (the program may have stopped early)
+            let cycles_per_second = self.cycles as f64 / time_elapsed.as_secs_f64();
 
             // Calculate the number of instructions per second
             let instructions_per_second = cycles_per_second / 6.0;
@@ -112,35 +328,32 @@ impl Emulator {
             );
             println!("Time elapsed: {:?}", time_elapsed);
             println!("MHz: {}", mhz);
-            println!("");
+            println!();
             println!("* This is the average number of instructions per second, as not all instructions take the same number of cycles.");
         } else {
-            // Run the CPU in this thread
-            while cycles_left > 0 {
-                println!("cycles_left: {}", cycles_left);
-                self.cpu.clock();
-                cycles_left -= 1;
-                std::thread::sleep(std::time::Duration::from_secs_f64(1.0 / cycles_per_second));
-            }
-        }
-        /*
-        let cpu_thread = thread::spawn({
-            let shared_cpu = shared_cpu.clone();
-            move || {
-                while cycles_left > 0 {
-                    shared_cpu.lock().unwrap().clock();
-                    cycles_left -= 1;
-                    std::thread::sleep(std::time::Duration::from_secs_f64(1.0 / cycles_per_second));
+            // Run the CPU in this thread, a slice at a time, resting
+            // between slices so it keeps to the clock rate
+            let mut throttle = Throttle::new(cycles_per_second, self.throttled);
+            while cycles_left > 0 && self.exit_status.is_none() && !self.interrupted() {
+                let slice = throttle.slice_cycles().min(cycles_left);
+                let mut ran = 0;
+                while ran < slice && self.exit_status.is_none() {
+                    self.clock();
+                    ran += 1;
                 }
+                cycles_left -= ran;
+                throttle.pace(ran);
             }
-        });
-        */
+            self.effective_speed_hz = Some(throttle.effective_hz());
+        }
+
+        self.exit_status
     }
 
-    pub fn benchmark(&mut self) {
+    pub fn benchmark(&mut self) -> Result<()> {
         println!("Running benchmark...");
-        self.load_rom_from_path("demos/blink.bin", 0xC000);
-        self.change_variant(String::from("CMOS"));
+        self.load_rom_from_path("demos/blink.bin", 0xC000)?;
+        self.set_variant(cpu::cpu::Variant::CMOS);
         self.cpu.reset();
 
         let num_cycles = 100;
@@ -149,5 +362,6 @@ impl Emulator {
 
         // Run the CPU
         self.run(1.0, Some(num_cycles), true);
+        Ok(())
     }
 }
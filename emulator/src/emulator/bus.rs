@@ -3,13 +3,54 @@
  *
  * All of the components of the system are connected to this bus,
  * and they use a hook system (implemented here) to interact with the bus.
+ * Peripherals implementing the `Device` trait are mapped into address
+ * ranges and take priority over hooks and plain memory.
+ *
+ * The bus also owns the event scheduler: devices are brought up to date
+ * ("caught up") right before the CPU touches one of their registers, and
+ * otherwise only when their next scheduled event is due.
  */
 use std::{collections::HashMap, sync::{Mutex, Arc}};
 
+use super::devices::{Dma, SharedDevice};
+use super::error::{Error, Result};
+use super::scheduler::Scheduler;
+
+pub type ReadHookFn = Arc<Mutex<dyn FnMut(u16) -> u8 + Send>>;
+pub type WriteHookFn = Arc<Mutex<dyn FnMut(u16, u8) + Send>>;
+
 #[derive(Clone)]
 pub struct Hook {
-    pub read: Option<Arc<Mutex<dyn FnMut(u16) -> u8 + Send>>>,
-    pub write: Option<Arc<Mutex<dyn FnMut(u16, u8) + Send>>>,
+    pub read: Option<ReadHookFn>,
+    pub write: Option<WriteHookFn>,
+}
+
+// Where a device's IRQ output is wired to: the CPU's IRQ input (as on
+// most boards), its NMI input, or nowhere
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IrqWiring {
+    #[default]
+    Irq,
+    Nmi,
+    None,
+}
+
+// A device mapped into the address range start..=end
+#[derive(Clone)]
+pub struct MappedDevice {
+    pub start: u16,
+    pub end: u16,
+    pub device: SharedDevice,
+    pub irq_wiring: IrqWiring,
+}
+
+// Output lines of a device, refreshed whenever it runs
+#[derive(Clone, Copy, Default)]
+struct Lines {
+    irq: bool,
+    nmi: bool,
+    halt: bool,
+    exit_status: Option<i32>,
 }
 
 #[derive(Clone)]
@@ -19,6 +60,28 @@ pub struct Bus {
 
     // The hooks for the system, must be able to implement Copy
     hooks: HashMap<u16, Hook>,
+
+    // Memory-mapped devices
+    devices: Vec<MappedDevice>,
+
+    // Interrupt, halt and exit outputs of each device
+    lines: Vec<Lines>,
+
+    // The current CPU cycle, as of the start of the current instruction
+    cycle: u64,
+
+    // Device events, and the cycle of the earliest one
+    scheduler: Scheduler,
+    next_deadline: u64,
+
+    // The CPU is held off the bus until this cycle by a DMA transfer
+    stall_until: u64,
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Bus {
@@ -26,6 +89,12 @@ impl Bus {
         Self {
             memory: [0; 0xFFFF + 1],
             hooks: HashMap::new(),
+            devices: Vec::new(),
+            lines: Vec::new(),
+            cycle: 0,
+            scheduler: Scheduler::new(),
+            next_deadline: u64::MAX,
+            stall_until: 0,
         }
     }
 
@@ -39,14 +108,117 @@ impl Bus {
         }
     }
 
+    pub fn add_device(&mut self, start: u16, end: u16, device: SharedDevice) {
+        self.add_device_wired(start, end, device, IrqWiring::Irq);
+    }
+
+    // Map a device whose IRQ output goes somewhere other than the CPU's IRQ
+    pub fn add_device_wired(
+        &mut self,
+        start: u16,
+        end: u16,
+        device: SharedDevice,
+        irq_wiring: IrqWiring,
+    ) {
+        self.devices.push(MappedDevice {
+            start,
+            end,
+            device,
+            irq_wiring,
+        });
+        self.lines.push(Lines::default());
+        self.refresh_device(self.devices.len() - 1);
+    }
+
+    pub fn devices(&self) -> &[MappedDevice] {
+        &self.devices
+    }
+
+    fn find_device(&self, address: u16) -> Option<usize> {
+        self.devices
+            .iter()
+            .position(|mapped| address >= mapped.start && address <= mapped.end)
+    }
+
+    pub fn cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    // Cycle of the earliest pending device event (u64::MAX if there is none)
+    pub fn next_deadline(&self) -> u64 {
+        self.next_deadline
+    }
+
+    // Advance the bus clock to `now`, running every device event that is due
+    pub fn sync(&mut self, now: u64) {
+        self.cycle = now;
+        if now < self.next_deadline {
+            return;
+        }
+
+        while let Some(index) = self.scheduler.pop_due(now) {
+            self.devices[index].device.lock().unwrap().tick(now);
+            self.refresh_device(index);
+        }
+        self.next_deadline = self.scheduler.next_deadline();
+    }
+
+    // Re-read a device's output lines and next event after it has run
+    fn refresh_device(&mut self, index: usize) {
+        let dma_pending = self.devices[index].device.lock().unwrap().dma_pending();
+        if dma_pending {
+            self.run_dma(index);
+        }
+
+        let (lines, next_event) = {
+            let wiring = self.devices[index].irq_wiring;
+            let device = self.devices[index].device.lock().unwrap();
+            let irq = device.irq();
+            let lines = Lines {
+                irq: irq && wiring == IrqWiring::Irq,
+                nmi: device.nmi() || (irq && wiring == IrqWiring::Nmi),
+                halt: device.halt(),
+                exit_status: device.exit_status(),
+            };
+            (lines, device.next_event())
+        };
+
+        self.lines[index] = lines;
+        self.scheduler.schedule(index, next_event);
+        self.next_deadline = self.scheduler.next_deadline();
+    }
+
+    // Let a device take over the bus for its transfer
+    fn run_dma(&mut self, index: usize) {
+        let device = self.devices[index].device.clone();
+        let stall = device.lock().unwrap().dma(&mut DmaAccess {
+            bus: self,
+            owner: index,
+        });
+        self.stall_until = self.stall_until.max(self.cycle) + stall;
+    }
+
     pub fn read_byte(&mut self, address: u16) -> u8 {
+        // Check if there is a device mapped at this address
+        if let Some(index) = self.find_device(address) {
+            let data = {
+                let mapped = &self.devices[index];
+                let mut device = mapped.device.lock().unwrap();
+
+                // Catch the device up before it sees the access
+                device.tick(self.cycle);
+                device.read(address - mapped.start)
+            };
+            self.refresh_device(index);
+            return data;
+        }
+
         // Check if there is a hook for this address
         if let Some(hook) = self.hooks.get_mut(&address) {
             // Check if the hook has a read function
             if let Some(read) = &mut hook.read {
                 // Call the read function
                 let data = read.lock().unwrap()(address);
-                println!("Read byte from address 0x{:04X}: 0x{:02X}", address, data);
                 return data;
             }
         }
@@ -55,7 +227,31 @@ impl Bus {
         self.memory[address as usize]
     }
 
+    // Read a byte without triggering any device side effects (for debuggers and tests)
+    pub fn peek_byte(&self, address: u16) -> u8 {
+        if let Some(index) = self.find_device(address) {
+            let mapped = &self.devices[index];
+            return mapped.device.lock().unwrap().peek(address - mapped.start);
+        }
+
+        self.memory[address as usize]
+    }
+
     pub fn write_byte(&mut self, address: u16, value: u8) {
+        // Check if there is a device mapped at this address
+        if let Some(index) = self.find_device(address) {
+            {
+                let mapped = &self.devices[index];
+                let mut device = mapped.device.lock().unwrap();
+
+                // Catch the device up before it sees the access
+                device.tick(self.cycle);
+                device.write(address - mapped.start, value);
+            }
+            self.refresh_device(index);
+            return;
+        }
+
         // Check if there is a hook for this address
         if let Some(hook) = self.hooks.get_mut(&address) {
             // Check if the hook has a write function
@@ -70,10 +266,171 @@ impl Bus {
         self.memory[address as usize] = value;
     }
 
-    pub fn load_rom_at(&mut self, rom: &[u8], address: u16) {
+    pub fn load_rom_at(&mut self, rom: &[u8], address: u16) -> Result<()> {
+        let start = address as usize;
+        let end = start + rom.len();
+        if end > self.memory.len() {
+            return Err(Error::ImageTooLarge {
+                address: address as u32,
+                size: rom.len(),
+                end: self.memory.len() as u32,
+            });
+        }
+
         // Load the rom into memory
-        for (i, byte) in rom.iter().enumerate() {
-            self.memory[address as usize + i] = *byte;
+        self.memory[start..end].copy_from_slice(rom);
+        Ok(())
+    }
+
+    // Advance every device up to the given CPU cycle, due or not
+    pub fn tick_devices(&mut self, now: u64) {
+        self.cycle = now;
+        for index in 0..self.devices.len() {
+            self.devices[index].device.lock().unwrap().tick(now);
+            self.refresh_device(index);
+        }
+    }
+
+    // Let every device know how fast the CPU is clocked
+    pub fn set_clock_rate(&mut self, hz: f64) {
+        for index in 0..self.devices.len() {
+            self.devices[index].device.lock().unwrap().clock_rate_changed(hz);
+            self.refresh_device(index);
+        }
+    }
+
+    pub fn reset_devices(&mut self) {
+        self.cycle = 0;
+        self.stall_until = 0;
+        for index in 0..self.devices.len() {
+            self.devices[index].device.lock().unwrap().reset();
+            self.refresh_device(index);
+        }
+    }
+
+    // The IRQ line is wired-OR: asserted if any device asserts it
+    pub fn irq(&self) -> bool {
+        self.lines.iter().any(|lines| lines.irq)
+    }
+
+    pub fn nmi(&self) -> bool {
+        self.lines.iter().any(|lines| lines.nmi)
+    }
+
+    // RDY is wired-AND: any device can hold the CPU, as can a DMA transfer
+    pub fn halted(&self) -> bool {
+        self.cycle < self.stall_until || self.lines.iter().any(|lines| lines.halt)
+    }
+
+    // The exit status of the first device that asked the emulator to stop
+    pub fn exit_status(&self) -> Option<i32> {
+        self.lines.iter().find_map(|lines| lines.exit_status)
+    }
+
+    // Snapshot every device, in mapping order
+    pub fn snapshot_devices(&self) -> Vec<Vec<u8>> {
+        self.devices
+            .iter()
+            .map(|mapped| mapped.device.lock().unwrap().snapshot())
+            .collect()
+    }
+
+    pub fn restore_devices(&mut self, states: &[Vec<u8>]) {
+        for (index, state) in states.iter().enumerate().take(self.devices.len()) {
+            self.devices[index].device.lock().unwrap().restore(state);
+            self.refresh_device(index);
         }
     }
 }
+
+// The bus as seen by a device doing DMA. The device is locked while it
+// runs, so it can't reach its own registers this way.
+struct DmaAccess<'a> {
+    bus: &'a mut Bus,
+    owner: usize,
+}
+
+impl Dma for DmaAccess<'_> {
+    fn read(&mut self, address: u16) -> u8 {
+        if self.bus.find_device(address) == Some(self.owner) {
+            return 0xFF;
+        }
+        self.bus.read_byte(address)
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        if self.bus.find_device(address) != Some(self.owner) {
+            self.bus.write_byte(address, data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::devices::led_bar::LedBar;
+    use crate::emulator::devices::port::Port;
+    use crate::emulator::devices::via::Via;
+
+    #[test]
+    fn test_device_mapping() {
+        let mut bus = Bus::new();
+        let via = Arc::new(Mutex::new(Via::new()));
+        let leds = Arc::new(Mutex::new(LedBar::on_port(Port::B, 8)));
+        leds.lock().unwrap().set_render(false);
+        via.lock().unwrap().attach(leds.clone());
+        bus.add_device(0x6000, 0x600F, via);
+
+        // Writes inside the range go to the device, relative to its base
+        bus.write_byte(0x6002, 0xFF);
+        bus.write_byte(0x6000, 0x81);
+        assert_eq!(bus.peek_byte(0x6000), 0x81);
+        assert_eq!(bus.peek_byte(0x6002), 0xFF);
+        assert_eq!(bus.memory[0x6000], 0x00);
+        assert!(leds.lock().unwrap().is_lit(0));
+        assert!(leds.lock().unwrap().is_lit(7));
+
+        // Writes outside the range go to memory
+        bus.write_byte(0x6010, 0x42);
+        assert_eq!(bus.read_byte(0x6010), 0x42);
+    }
+
+    #[test]
+    fn test_scheduled_device_event() {
+        let mut bus = Bus::new();
+        bus.add_device(0x6000, 0x600F, Arc::new(Mutex::new(Via::new())));
+
+        // Enable the T1 interrupt and start a 100 cycle one-shot at cycle 1000
+        bus.sync(1000);
+        bus.write_byte(0x600E, 0xC0);
+        bus.write_byte(0x6004, 100);
+        bus.write_byte(0x6005, 0);
+        assert_eq!(bus.next_deadline(), 1101);
+
+        // Nothing happens until the event is due
+        bus.sync(1100);
+        assert!(!bus.irq());
+        bus.sync(1101);
+        assert!(bus.irq());
+        assert_eq!(bus.next_deadline(), u64::MAX);
+
+        // Reading T1C-L acknowledges the interrupt
+        bus.read_byte(0x6004);
+        assert!(!bus.irq());
+    }
+
+    #[test]
+    fn test_irq_wiring() {
+        let mut bus = Bus::new();
+        let via = Arc::new(Mutex::new(Via::new()));
+        bus.add_device_wired(0x6000, 0x600F, via, IrqWiring::Nmi);
+
+        // The VIA's T1 interrupt comes out on NMI instead of IRQ
+        bus.write_byte(0x600E, 0xC0);
+        bus.write_byte(0x6004, 10);
+        bus.write_byte(0x6005, 0);
+        bus.sync(100);
+        assert!(bus.nmi());
+        assert!(!bus.irq());
+    }
+}
@@ -0,0 +1,303 @@
+/**
+ * A 6551 ACIA (asynchronous serial port), as on Ben Eater's 6502 computer
+ * where it talks to a terminal over RS-232.
+ *
+ * Transmitted bytes go to the host's stdout and received bytes come from
+ * the host's keyboard, each taking as long as a character does at the
+ * programmed baud rate. A received byte is only taken from the host once
+ * the program has read the last one, so nothing is lost to overruns.
+ *
+ * Registers:
+ *
+ *   $0  (R) receive data, (W) transmit data
+ *   $1  (R) status, (W) programmed reset
+ *   $2  command: parity, echo, transmit and receive interrupts, DTR
+ *   $3  control: stop bits, word length, baud rate
+ *
+ * The transmitter works as the datasheet says rather than like the
+ * W65C51N's (whose TDRE bit is stuck at 1), so programs written for either
+ * chip run; the WDC delay loop just waits longer than it has to.
+ */
+use std::io::{self, Write};
+
+use super::host_input::HostInput;
+use super::Device;
+
+pub const DATA: u16 = 0x0;
+pub const STATUS: u16 = 0x1;
+pub const COMMAND: u16 = 0x2;
+pub const CONTROL: u16 = 0x3;
+
+// Status register bits
+pub const STATUS_IRQ: u8 = 0x80;
+pub const STATUS_TDRE: u8 = 0x10;
+pub const STATUS_RDRF: u8 = 0x08;
+
+// Command register bits
+const COMMAND_DTR: u8 = 0x01;
+const COMMAND_IRQ_DISABLE: u8 = 0x02; // Receiver interrupts off
+const COMMAND_TIC: u8 = 0x0C; // Transmitter interrupt control
+const COMMAND_TX_IRQ: u8 = 0x04;
+const COMMAND_ECHO: u8 = 0x10;
+
+// Baud rates for the control register's low nibble. 0 selects the 16x
+// external clock, which Ben Eater's board doesn't use; treat it as 115200.
+const BAUD_RATES: [f64; 16] = [
+    115200.0, 50.0, 75.0, 109.92, 134.58, 150.0, 300.0, 600.0, 1200.0, 1800.0, 2400.0, 3600.0,
+    4800.0, 7200.0, 9600.0, 19200.0,
+];
+
+enum Output {
+    Stdout,
+    Buffer(Vec<u8>),
+}
+
+pub struct Acia {
+    output: Output,
+    input: Option<HostInput>,
+
+    command: u8,
+    control: u8,
+    received: u8,
+    rdrf: bool,
+    irq: bool,
+
+    hz: f64,
+    transmit_done: Option<u64>, // Cycle the byte being sent finishes at
+    next_receive: u64,          // Earliest cycle the next byte can arrive
+    last_cycle: u64,
+}
+
+impl Default for Acia {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Acia {
+    // Transmitted bytes go to stdout
+    pub fn new() -> Self {
+        Self {
+            output: Output::Stdout,
+            input: None,
+            command: 0,
+            control: 0,
+            received: 0,
+            rdrf: false,
+            irq: false,
+            hz: 1_000_000.0,
+            transmit_done: None,
+            next_receive: 0,
+            last_cycle: 0,
+        }
+    }
+
+    // Transmitted bytes are kept in memory, for tests
+    pub fn captured() -> Self {
+        Self {
+            output: Output::Buffer(Vec::new()),
+            ..Self::new()
+        }
+    }
+
+    // Receive from the host terminal or a script
+    pub fn set_input(&mut self, input: HostInput) {
+        self.input = Some(input);
+    }
+
+    // Everything transmitted so far, if the output is captured
+    pub fn output(&self) -> Option<&[u8]> {
+        match &self.output {
+            Output::Stdout => None,
+            Output::Buffer(buffer) => Some(buffer),
+        }
+    }
+
+    // How long one character takes on the line, in cycles
+    pub fn character_cycles(&self) -> u64 {
+        let baud = BAUD_RATES[(self.control & 0x0F) as usize];
+        let word_length = 8 - (self.control >> 5 & 0x03) as u32;
+        let parity = (self.command & 0x20 != 0) as u32;
+        let stop_bits = if self.control & 0x80 != 0 { 2 } else { 1 };
+        let bits = 1 + word_length + parity + stop_bits;
+        ((self.hz * bits as f64 / baud) as u64).max(1)
+    }
+
+    fn status(&self) -> u8 {
+        let mut status = 0;
+        if self.irq {
+            status |= STATUS_IRQ;
+        }
+        if self.transmit_done.is_none() {
+            status |= STATUS_TDRE;
+        }
+        if self.rdrf {
+            status |= STATUS_RDRF;
+        }
+        status
+    }
+
+    fn send(&mut self, data: u8) {
+        match &mut self.output {
+            Output::Stdout => {
+                let mut stdout = io::stdout();
+                let _ = stdout.write_all(&[data]);
+                let _ = stdout.flush();
+            }
+            Output::Buffer(buffer) => buffer.push(data),
+        }
+    }
+
+    fn receive_interrupts(&self) -> bool {
+        self.command & (COMMAND_DTR | COMMAND_IRQ_DISABLE) == COMMAND_DTR
+    }
+
+    // Finish sending, and take the next byte from the host if there's room
+    fn advance(&mut self, now: u64) {
+        self.last_cycle = self.last_cycle.max(now);
+
+        if let Some(done) = self.transmit_done {
+            if now >= done {
+                self.transmit_done = None;
+                if self.command & COMMAND_TIC == COMMAND_TX_IRQ {
+                    self.irq = true;
+                }
+            }
+        }
+
+        if self.rdrf || now < self.next_receive || self.command & COMMAND_DTR == 0 {
+            return;
+        }
+        let Some(byte) = self.input.as_mut().and_then(|input| input.try_read()) else {
+            return;
+        };
+
+        // Terminals send CR for Enter
+        self.received = if byte == b'\n' { b'\r' } else { byte };
+        self.rdrf = true;
+        self.next_receive = now + self.character_cycles();
+        if self.receive_interrupts() {
+            self.irq = true;
+        }
+        if self.command & (COMMAND_ECHO | COMMAND_TIC) == COMMAND_ECHO {
+            self.send(self.received);
+        }
+    }
+}
+
+impl Device for Acia {
+    fn name(&self) -> &str {
+        "acia"
+    }
+
+    fn read(&mut self, offset: u16) -> u8 {
+        match offset & 0x03 {
+            DATA => {
+                self.rdrf = false;
+                self.received
+            }
+            STATUS => {
+                let status = self.status();
+                self.irq = false;
+                status
+            }
+            _ => self.peek(offset),
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        match offset & 0x03 {
+            DATA => self.received,
+            STATUS => self.status(),
+            COMMAND => self.command,
+            _ => self.control,
+        }
+    }
+
+    fn write(&mut self, offset: u16, data: u8) {
+        match offset & 0x03 {
+            DATA => {
+                // A byte written while one is still going out replaces it
+                self.send(data);
+                self.transmit_done = Some(self.last_cycle + self.character_cycles());
+            }
+            STATUS => {
+                // Programmed reset: the low command bits clear
+                self.command &= 0xE0;
+            }
+            COMMAND => self.command = data,
+            _ => self.control = data,
+        }
+    }
+
+    fn tick(&mut self, now: u64) {
+        self.advance(now);
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        let receive = match &self.input {
+            Some(input) if !input.is_exhausted() && !self.rdrf => {
+                // Keep an eye on the host for more keys
+                Some(
+                    self.next_receive
+                        .max(self.last_cycle + self.character_cycles()),
+                )
+            }
+            _ => None,
+        };
+        match (self.transmit_done, receive) {
+            (Some(done), Some(receive)) => Some(done.min(receive)),
+            (done, receive) => done.or(receive),
+        }
+    }
+
+    fn clock_rate_changed(&mut self, hz: f64) {
+        self.hz = hz;
+    }
+
+    fn reset(&mut self) {
+        self.command = 0;
+        self.control = 0;
+        self.rdrf = false;
+        self.irq = false;
+        self.transmit_done = None;
+        self.next_receive = 0;
+        self.last_cycle = 0;
+    }
+
+    fn irq(&self) -> bool {
+        self.irq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serial() {
+        // 19200 baud 8N1, receiver interrupts on, as in Ben Eater's videos
+        let mut acia = Acia::captured();
+        acia.set_input(HostInput::scripted(b"hi"));
+        acia.write(CONTROL, 0x1F);
+        acia.write(COMMAND, 0x09);
+        assert_eq!(acia.character_cycles(), 520);
+
+        acia.write(DATA, b'A');
+        assert_eq!(acia.peek(STATUS) & STATUS_TDRE, 0);
+        acia.tick(520);
+        assert_ne!(acia.peek(STATUS) & STATUS_TDRE, 0);
+        assert_eq!(acia.output(), Some(&b"A"[..]));
+
+        // The second key waits until the first has been read
+        assert!(acia.irq());
+        assert_eq!(acia.read(STATUS) & (STATUS_IRQ | STATUS_RDRF), 0x88);
+        assert!(!acia.irq());
+        acia.tick(2000);
+        assert_eq!(acia.read(DATA), b'h');
+        assert_eq!(acia.next_event(), Some(2520));
+        acia.tick(2520);
+        assert_eq!(acia.read(DATA), b'i');
+        assert_eq!(acia.next_event(), None);
+    }
+}
@@ -0,0 +1,275 @@
+/**
+ * The Apple-1's keyboard and video terminal, on the ports of its 6821 PIA.
+ *
+ * Keyboard: the ASCII code goes to PA0-PA6 with PA7 tied high, and each
+ * key pulses CA1 (the strobe). Host keys are upper-cased, Enter becomes
+ * CR and backspace becomes the underscore WozMon uses for rubout.
+ *
+ * Display: PB0-PB6 carry the character, the CB2 strobe from a write to
+ * port B tells the terminal to take it, and PB7 (and CB1) report whether
+ * it's still busy. The terminal's shift-register memory only moves one
+ * character into place per 60 Hz frame, so output crawls along at about
+ * 60 characters a second, as on the real machine. Lines are 40 columns
+ * wide; control characters other than CR are ignored, and the character
+ * ROM has no lowercase so $60-$7F show as $40-$5F.
+ */
+use std::io::{self, Write};
+
+use super::host_input::HostInput;
+use super::port::{Port, PortDevice};
+
+pub const COLUMNS: u8 = 40;
+
+// Characters the display accepts per second (one per video frame)
+const CHARACTERS_PER_SECOND: f64 = 60.0;
+
+// How often the keyboard is polled for a key, per second
+const KEYBOARD_POLLS_PER_SECOND: f64 = 1000.0;
+
+enum Output {
+    Stdout,
+    Buffer(Vec<u8>),
+}
+
+pub struct Apple1Terminal {
+    input: Option<HostInput>,
+    key: u8,
+    strobe: bool,   // CA1: a key is waiting to be read
+    next_poll: u64, // Earliest cycle the next key can arrive
+
+    output: Output,
+    column: u8,
+    data: u8, // Levels on port B
+    busy_until: u64,
+
+    hz: f64,
+    last_cycle: u64,
+}
+
+impl Default for Apple1Terminal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Apple1Terminal {
+    // Characters are printed on stdout
+    pub fn new() -> Self {
+        Self {
+            input: None,
+            key: 0,
+            strobe: false,
+            next_poll: 0,
+            output: Output::Stdout,
+            column: 0,
+            data: 0xFF,
+            busy_until: 0,
+            hz: 1_000_000.0,
+            last_cycle: 0,
+        }
+    }
+
+    // Characters are kept in memory, for tests
+    pub fn captured() -> Self {
+        Self {
+            output: Output::Buffer(Vec::new()),
+            ..Self::new()
+        }
+    }
+
+    // Type on the host terminal or from a script
+    pub fn set_input(&mut self, input: HostInput) {
+        self.input = Some(input);
+    }
+
+    // Everything displayed so far, if the output is captured
+    pub fn output(&self) -> Option<&[u8]> {
+        match &self.output {
+            Output::Stdout => None,
+            Output::Buffer(buffer) => Some(buffer),
+        }
+    }
+
+    fn busy(&self, now: u64) -> bool {
+        now < self.busy_until
+    }
+
+    fn frame_cycles(&self) -> u64 {
+        ((self.hz / CHARACTERS_PER_SECOND) as u64).max(1)
+    }
+
+    fn poll_cycles(&self) -> u64 {
+        ((self.hz / KEYBOARD_POLLS_PER_SECOND) as u64).max(1)
+    }
+
+    fn print(&mut self, bytes: &[u8]) {
+        match &mut self.output {
+            Output::Stdout => {
+                let mut stdout = io::stdout();
+                let _ = stdout.write_all(bytes);
+                let _ = stdout.flush();
+            }
+            Output::Buffer(buffer) => buffer.extend_from_slice(bytes),
+        }
+    }
+
+    // Put a character from port B on the screen
+    fn display(&mut self, character: u8) {
+        match character & 0x7F {
+            b'\r' => {
+                self.print(b"\n");
+                self.column = 0;
+            }
+            0x00..=0x1F => {}
+            character => {
+                let glyph = if character >= 0x60 {
+                    character - 0x20
+                } else {
+                    character
+                };
+                self.print(&[glyph]);
+                self.column += 1;
+                if self.column == COLUMNS {
+                    self.print(b"\n");
+                    self.column = 0;
+                }
+            }
+        }
+    }
+}
+
+// The code the Apple-1 keyboard sends for a host key
+fn key_code(key: u8) -> u8 {
+    let code = match key {
+        b'\n' => b'\r',
+        0x08 | 0x7F => b'_',
+        key => key.to_ascii_uppercase(),
+    };
+    code & 0x7F
+}
+
+impl PortDevice for Apple1Terminal {
+    fn name(&self) -> &str {
+        "apple1_terminal"
+    }
+
+    fn pins_changed(&mut self, port: Port, pins: u8, _ddr: u8, _now: u64) {
+        if port == Port::B {
+            self.data = pins;
+        }
+    }
+
+    fn strobe(&mut self, port: Port, now: u64) {
+        // A character sent while the terminal is busy is lost
+        if port == Port::B && !self.busy(now) {
+            self.display(self.data);
+            self.busy_until = now + self.frame_cycles();
+        }
+    }
+
+    fn drive(&mut self, port: Port, now: u64) -> u8 {
+        match port {
+            Port::A => {
+                // Reading the key clears the strobe
+                self.strobe = false;
+                self.next_poll = self.next_poll.max(now + 1);
+                0x80 | self.key
+            }
+            Port::B if self.busy(now) => 0xFF,
+            Port::B => 0x7F,
+        }
+    }
+
+    fn control(&mut self, port: Port, now: u64) -> Option<bool> {
+        match port {
+            Port::A => Some(self.strobe),
+            Port::B => Some(!self.busy(now)),
+        }
+    }
+
+    fn tick(&mut self, now: u64) {
+        self.last_cycle = self.last_cycle.max(now);
+        if self.strobe || now < self.next_poll {
+            return;
+        }
+        if let Some(key) = self.input.as_mut().and_then(|input| input.try_read()) {
+            self.key = key_code(key);
+            self.strobe = true;
+        }
+        self.next_poll = now + self.poll_cycles();
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        let keyboard = match &self.input {
+            Some(input) if !input.is_exhausted() && !self.strobe => {
+                Some(self.next_poll.max(self.last_cycle + 1))
+            }
+            _ => None,
+        };
+        let display = Some(self.busy_until).filter(|&done| done > self.last_cycle);
+        match (keyboard, display) {
+            (Some(keyboard), Some(display)) => Some(keyboard.min(display)),
+            (keyboard, display) => keyboard.or(display),
+        }
+    }
+
+    fn clock_rate_changed(&mut self, hz: f64) {
+        self.hz = hz;
+    }
+
+    fn reset(&mut self) {
+        // RESET only reaches the 6502 and the PIA
+        self.strobe = false;
+        self.next_poll = 0;
+        self.busy_until = 0;
+        self.last_cycle = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::devices::pia::{Pia, CR_IRQ1};
+    use crate::emulator::devices::Device;
+    use std::sync::{Arc, Mutex};
+
+    const KBD: u16 = 0x0;
+    const KBDCR: u16 = 0x1;
+    const DSP: u16 = 0x2;
+    const DSPCR: u16 = 0x3;
+
+    #[test]
+    fn test_wozmon_echo() {
+        let mut terminal = Apple1Terminal::captured();
+        terminal.set_input(HostInput::scripted(b"a\n"));
+        let terminal = Arc::new(Mutex::new(terminal));
+        let mut pia = Pia::new();
+        pia.attach(terminal.clone());
+
+        // WozMon's setup: PB0-PB6 outputs, then $A7 to both control registers
+        pia.reset();
+        pia.write(DSP, 0x7F);
+        pia.write(KBDCR, 0xA7);
+        pia.write(DSPCR, 0xA7);
+
+        // The key arrives with bit 7 set, upper-cased, and clears the flag
+        pia.tick(1);
+        assert_ne!(pia.read(KBDCR) & CR_IRQ1, 0);
+        assert_eq!(pia.read(KBD), 0xC1);
+        assert_eq!(pia.read(KBDCR) & CR_IRQ1, 0);
+
+        // Echo it: the display stays busy for a frame (1/60 s at 1 MHz)
+        assert_eq!(pia.read(DSP) & 0x80, 0);
+        pia.write(DSP, 0xC1);
+        assert_ne!(pia.read(DSP) & 0x80, 0);
+        pia.write(DSP, 0xC2);
+        pia.tick(16_667);
+        assert_eq!(pia.read(DSP) & 0x80, 0);
+
+        // Enter comes through as CR
+        pia.tick(16_668);
+        assert_eq!(pia.read(KBD), 0x8D);
+        pia.write(DSP, 0x8D);
+        assert_eq!(terminal.lock().unwrap().output(), Some(&b"A\n"[..]));
+    }
+}
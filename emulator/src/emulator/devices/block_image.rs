@@ -0,0 +1,135 @@
+/**
+ * A disk image made of 512 byte blocks, used by the storage devices.
+ *
+ * Images are either backed by a file on the host, so whatever the emulated
+ * machine writes survives between runs, or kept in memory (for tests).
+ */
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+pub const BLOCK_SIZE: usize = 512;
+
+enum Backing {
+    File(File),
+    Memory(Vec<u8>),
+}
+
+pub struct BlockImage {
+    backing: Backing,
+    blocks: u64,
+}
+
+impl BlockImage {
+    // Open an existing image file for reading and writing
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let blocks = file.metadata()?.len() / BLOCK_SIZE as u64;
+        Ok(Self {
+            backing: Backing::File(file),
+            blocks,
+        })
+    }
+
+    // A blank image of `blocks` blocks that only lives in memory
+    pub fn in_memory(blocks: u64) -> Self {
+        Self {
+            backing: Backing::Memory(vec![0; blocks as usize * BLOCK_SIZE]),
+            blocks,
+        }
+    }
+
+    pub fn from_bytes(mut data: Vec<u8>) -> Self {
+        // Round up to a whole number of blocks
+        let blocks = data.len().div_ceil(BLOCK_SIZE);
+        data.resize(blocks * BLOCK_SIZE, 0);
+        Self {
+            backing: Backing::Memory(data),
+            blocks: blocks as u64,
+        }
+    }
+
+    pub fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    pub fn read_block(&mut self, block: u64, buffer: &mut [u8; BLOCK_SIZE]) -> io::Result<()> {
+        self.check(block)?;
+        let offset = block * BLOCK_SIZE as u64;
+
+        match &mut self.backing {
+            Backing::File(file) => {
+                file.seek(SeekFrom::Start(offset))?;
+                file.read_exact(buffer)
+            }
+            Backing::Memory(data) => {
+                let offset = offset as usize;
+                buffer.copy_from_slice(&data[offset..offset + BLOCK_SIZE]);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn write_block(&mut self, block: u64, buffer: &[u8; BLOCK_SIZE]) -> io::Result<()> {
+        self.check(block)?;
+        let offset = block * BLOCK_SIZE as u64;
+
+        match &mut self.backing {
+            Backing::File(file) => {
+                file.seek(SeekFrom::Start(offset))?;
+                file.write_all(buffer)?;
+                file.flush()
+            }
+            Backing::Memory(data) => {
+                let offset = offset as usize;
+                data[offset..offset + BLOCK_SIZE].copy_from_slice(buffer);
+                Ok(())
+            }
+        }
+    }
+
+    fn check(&self, block: u64) -> io::Result<()> {
+        if block >= self.blocks {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("block {} is past the end of the image ({} blocks)", block, self.blocks),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_backed() {
+        let path =
+            std::env::temp_dir().join(format!("rusty502_block_image_{}.img", std::process::id()));
+        // A trailing partial block isn't part of the image
+        std::fs::write(&path, vec![0xE5; 2 * BLOCK_SIZE + 100]).unwrap();
+
+        {
+            let mut image = BlockImage::open(&path).unwrap();
+            assert_eq!(image.block_count(), 2);
+            image.write_block(1, &[0x42; BLOCK_SIZE]).unwrap();
+            assert!(image.write_block(2, &[0x00; BLOCK_SIZE]).is_err());
+        }
+
+        // The write went to the file and is there when it's opened again
+        let mut image = BlockImage::open(&path).unwrap();
+        let mut buffer = [0u8; BLOCK_SIZE];
+        image.read_block(0, &mut buffer).unwrap();
+        assert_eq!(buffer, [0xE5; BLOCK_SIZE]);
+        image.read_block(1, &mut buffer).unwrap();
+        assert_eq!(buffer, [0x42; BLOCK_SIZE]);
+        assert!(image.read_block(2, &mut buffer).is_err());
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(data.len(), 2 * BLOCK_SIZE + 100);
+        assert!(data[BLOCK_SIZE..2 * BLOCK_SIZE].iter().all(|byte| *byte == 0x42));
+        assert!(BlockImage::open(&path).is_err());
+    }
+}
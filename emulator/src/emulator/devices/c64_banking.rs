@@ -0,0 +1,260 @@
+/**
+ * The Commodore 64's memory banking: what the CPU sees at $A000-$FFFF,
+ * chosen by the LORAM, HIRAM and CHAREN lines of the 6510's I/O port.
+ *
+ *   $A000-$BFFF  BASIC ROM with LORAM and HIRAM high, RAM otherwise
+ *   $C000-$CFFF  RAM
+ *   $D000-$DFFF  RAM with LORAM and HIRAM both low; otherwise I/O with
+ *                CHAREN high, the character ROM with it low
+ *   $E000-$FFFF  KERNAL ROM with HIRAM high, RAM otherwise
+ *
+ * Writes to a ROM land in the RAM underneath, so programs can copy a ROM
+ * into RAM and bank it out to patch it. The cartridge port's GAME and
+ * EXROM lines are taken as high (no cartridge).
+ *
+ * The I/O area goes to one device, given offsets from $D000; without one it
+ * reads as $FF and ignores writes. The device is run through this one:
+ * its timing, interrupts and reset are passed on.
+ */
+use std::sync::{Arc, Mutex};
+
+use cpu::io_port::io_port::SharedIoPort;
+
+use super::ram::Ram;
+use super::{Device, SharedDevice};
+use crate::emulator::error::{Error, Result};
+
+// The banking lines on the 6510's port
+pub const LORAM: u8 = 0x01;
+pub const HIRAM: u8 = 0x02;
+pub const CHAREN: u8 = 0x04;
+
+pub const BASIC_START: u16 = 0xA000;
+pub const IO_START: u16 = 0xD000;
+pub const KERNAL_START: u16 = 0xE000;
+
+// The ROMs that can be banked in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rom {
+    Basic,
+    Kernal,
+    Character,
+}
+
+impl Rom {
+    pub fn size(self) -> usize {
+        match self {
+            Rom::Basic | Rom::Kernal => 0x2000,
+            Rom::Character => 0x1000,
+        }
+    }
+}
+
+// What answers at an address
+enum Bank {
+    Ram,
+    Rom(Rom),
+    Io,
+}
+
+pub struct C64Banking {
+    io_port: SharedIoPort,
+
+    // All 64 KiB of RAM, shared with whatever maps the rest of it
+    ram: Arc<Mutex<Ram>>,
+
+    basic: Vec<u8>,
+    kernal: Vec<u8>,
+    character: Vec<u8>,
+    io: Option<SharedDevice>,
+
+    last_cycle: u64,
+}
+
+impl C64Banking {
+    pub const START: u16 = 0xA000;
+    pub const END: u16 = 0xFFFF;
+
+    pub fn new(io_port: SharedIoPort, ram: Arc<Mutex<Ram>>) -> Self {
+        Self {
+            io_port,
+            ram,
+            basic: vec![0xFF; Rom::Basic.size()],
+            kernal: vec![0xFF; Rom::Kernal.size()],
+            character: vec![0xFF; Rom::Character.size()],
+            io: None,
+            last_cycle: 0,
+        }
+    }
+
+    // Burn an image into one of the ROMs; it has to be the ROM's size
+    pub fn load_rom(&mut self, rom: Rom, data: &[u8]) -> Result<()> {
+        if data.len() != rom.size() {
+            return Err(Error::BadImage(format!(
+                "the {:?} ROM is {} bytes, not {}",
+                rom,
+                data.len(),
+                rom.size()
+            )));
+        }
+        match rom {
+            Rom::Basic => self.basic.copy_from_slice(data),
+            Rom::Kernal => self.kernal.copy_from_slice(data),
+            Rom::Character => self.character.copy_from_slice(data),
+        }
+        Ok(())
+    }
+
+    // Put a device (the VIC-II, SID, CIAs and colour RAM, or a stand-in)
+    // behind the I/O area
+    pub fn attach_io(&mut self, device: SharedDevice) {
+        self.io = Some(device);
+    }
+
+    fn bank(&self, address: u16) -> Bank {
+        let pins = self.io_port.lock().unwrap().pins(self.last_cycle);
+        let loram = pins & LORAM != 0;
+        let hiram = pins & HIRAM != 0;
+        let charen = pins & CHAREN != 0;
+
+        match address {
+            0xA000..=0xBFFF if loram && hiram => Bank::Rom(Rom::Basic),
+            0xD000..=0xDFFF if (loram || hiram) && charen => Bank::Io,
+            0xD000..=0xDFFF if loram || hiram => Bank::Rom(Rom::Character),
+            0xE000..=0xFFFF if hiram => Bank::Rom(Rom::Kernal),
+            _ => Bank::Ram,
+        }
+    }
+
+    fn read_rom(&self, rom: Rom, address: u16) -> u8 {
+        let (data, start) = match rom {
+            Rom::Basic => (&self.basic, BASIC_START),
+            Rom::Kernal => (&self.kernal, KERNAL_START),
+            Rom::Character => (&self.character, IO_START),
+        };
+        data[(address - start) as usize]
+    }
+}
+
+impl Device for C64Banking {
+    fn name(&self) -> &str {
+        "c64 banking"
+    }
+
+    fn read(&mut self, offset: u16) -> u8 {
+        let address = Self::START + offset;
+        match self.bank(address) {
+            Bank::Io => match &self.io {
+                Some(io) => {
+                    let mut io = io.lock().unwrap();
+                    io.tick(self.last_cycle);
+                    io.read(address - IO_START)
+                }
+                None => 0xFF,
+            },
+            _ => self.peek(offset),
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        let address = Self::START + offset;
+        match self.bank(address) {
+            Bank::Ram => self.ram.lock().unwrap().peek(address),
+            Bank::Rom(rom) => self.read_rom(rom, address),
+            Bank::Io => self
+                .io
+                .as_ref()
+                .map_or(0xFF, |io| io.lock().unwrap().peek(address - IO_START)),
+        }
+    }
+
+    fn write(&mut self, offset: u16, data: u8) {
+        let address = Self::START + offset;
+        match self.bank(address) {
+            Bank::Io => {
+                if let Some(io) = &self.io {
+                    let mut io = io.lock().unwrap();
+                    io.tick(self.last_cycle);
+                    io.write(address - IO_START, data);
+                }
+            }
+            _ => self.ram.lock().unwrap().write(address, data),
+        }
+    }
+
+    fn tick(&mut self, now: u64) {
+        self.last_cycle = now;
+        if let Some(io) = &self.io {
+            io.lock().unwrap().tick(now);
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        self.io.as_ref()?.lock().unwrap().next_event()
+    }
+
+    fn clock_rate_changed(&mut self, hz: f64) {
+        if let Some(io) = &self.io {
+            io.lock().unwrap().clock_rate_changed(hz);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.last_cycle = 0;
+        if let Some(io) = &self.io {
+            io.lock().unwrap().reset();
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.io.as_ref().is_some_and(|io| io.lock().unwrap().irq())
+    }
+
+    fn nmi(&self) -> bool {
+        self.io.as_ref().is_some_and(|io| io.lock().unwrap().nmi())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::io_port::io_port::IoPort;
+
+    #[test]
+    fn test_banking() {
+        let port = IoPort::shared();
+        port.lock().unwrap().set_pull_ups(0x3F);
+        let ram = Arc::new(Mutex::new(Ram::new(0x10000)));
+        let mut banking = C64Banking::new(port.clone(), ram.clone());
+        banking.load_rom(Rom::Basic, &[0xBA; 0x2000]).unwrap();
+        banking.load_rom(Rom::Kernal, &[0xEE; 0x2000]).unwrap();
+        banking.load_rom(Rom::Character, &[0xCC; 0x1000]).unwrap();
+        banking.attach_io(Arc::new(Mutex::new(Ram::new(0x1000))));
+        assert!(banking.load_rom(Rom::Character, &[0; 0x800]).is_err());
+
+        // Writes go to the RAM under the ROMs; $D000 is I/O at power on
+        banking.write(0xA000 - C64Banking::START, 0x12);
+        banking.write(0xE000 - C64Banking::START, 0x34);
+        banking.write(0xD000 - C64Banking::START, 0x56);
+        assert_eq!(ram.lock().unwrap().data()[0xA000], 0x12);
+
+        // LORAM/HIRAM/CHAREN: what $A000, $D000 and $E000 read as
+        let cases = [
+            (0x07, [0xBA, 0x56, 0xEE]),
+            (0x06, [0x12, 0x56, 0xEE]),
+            (0x03, [0xBA, 0xCC, 0xEE]),
+            (0x05, [0x12, 0x56, 0x34]),
+            (0x04, [0x12, 0x00, 0x34]),
+        ];
+        for (lines, expected) in cases {
+            let mut port = port.lock().unwrap();
+            port.write(0x0000, 0x07, 0);
+            port.write(0x0001, lines, 0);
+            drop(port);
+
+            let read =
+                [0xA000, 0xD000, 0xE000].map(|address| banking.read(address - C64Banking::START));
+            assert_eq!(read, expected, "lines {:02X}", lines);
+        }
+    }
+}
@@ -0,0 +1,497 @@
+/**
+ * A CompactFlash card in 8-bit True IDE mode, backed by a disk image.
+ *
+ * The card is mapped as the eight task-file registers:
+ *
+ *   0  data
+ *   1  error (read) / features (write)
+ *   2  sector count
+ *   3  LBA bits 0-7
+ *   4  LBA bits 8-15
+ *   5  LBA bits 16-23
+ *   6  drive/head, LBA bits 24-27 in the low nibble
+ *   7  status (read) / command (write)
+ *
+ * Supported commands are IDENTIFY DEVICE, READ SECTOR(S), WRITE SECTOR(S)
+ * and SET FEATURES (8-bit mode is always on). After a command, and between
+ * sectors, the card reports BSY for a while before raising DRQ, so firmware
+ * that doesn't poll the status register properly fails here too.
+ */
+use super::block_image::{BlockImage, BLOCK_SIZE};
+use super::Device;
+
+// Status register bits
+const STATUS_BSY: u8 = 0x80;
+const STATUS_RDY: u8 = 0x40;
+const STATUS_DSC: u8 = 0x10;
+const STATUS_DRQ: u8 = 0x08;
+const STATUS_ERR: u8 = 0x01;
+
+// Error register bits
+const ERROR_IDNF: u8 = 0x10;
+const ERROR_ABRT: u8 = 0x04;
+
+// Commands
+const COMMAND_READ_SECTORS: u8 = 0x20;
+const COMMAND_READ_SECTORS_NO_RETRY: u8 = 0x21;
+const COMMAND_WRITE_SECTORS: u8 = 0x30;
+const COMMAND_WRITE_SECTORS_NO_RETRY: u8 = 0x31;
+const COMMAND_IDENTIFY: u8 = 0xEC;
+const COMMAND_SET_FEATURES: u8 = 0xEF;
+
+// How long the card stays busy
+const COMMAND_BUSY_SECONDS: f64 = 0.000_020;
+const SECTOR_BUSY_SECONDS: f64 = 0.000_200;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Transfer {
+    None,
+    Identify,
+    Read,
+    Write,
+}
+
+pub struct CompactFlash {
+    image: BlockImage,
+
+    features: u8,
+    error: u8,
+    sector_count: u8,
+    lba: [u8; 4],
+    status: u8,
+
+    transfer: Transfer,
+    // Sectors left in the current command
+    remaining: u16,
+    buffer: [u8; BLOCK_SIZE],
+    position: usize,
+
+    // The card drops BSY at this cycle
+    busy_until: Option<u64>,
+    last_cycle: u64,
+    clock_hz: f64,
+}
+
+impl CompactFlash {
+    pub fn new(image: BlockImage) -> Self {
+        Self {
+            image,
+            features: 0,
+            error: 0,
+            sector_count: 1,
+            lba: [1, 0, 0, 0xE0],
+            status: STATUS_RDY | STATUS_DSC,
+            transfer: Transfer::None,
+            remaining: 0,
+            buffer: [0; BLOCK_SIZE],
+            position: 0,
+            busy_until: None,
+            last_cycle: 0,
+            clock_hz: 1_000_000.0,
+        }
+    }
+
+    fn lba(&self) -> u64 {
+        u32::from_le_bytes([self.lba[0], self.lba[1], self.lba[2], self.lba[3] & 0x0F]) as u64
+    }
+
+    fn set_lba(&mut self, lba: u64) {
+        let bytes = (lba as u32).to_le_bytes();
+        self.lba[0] = bytes[0];
+        self.lba[1] = bytes[1];
+        self.lba[2] = bytes[2];
+        self.lba[3] = (self.lba[3] & 0xF0) | (bytes[3] & 0x0F);
+    }
+
+    fn go_busy(&mut self, seconds: f64) {
+        self.status = (self.status | STATUS_BSY) & !STATUS_DRQ;
+        self.busy_until = Some(self.last_cycle + (seconds * self.clock_hz).max(1.0) as u64);
+    }
+
+    // End the command, with an error if `error` isn't 0
+    fn finish(&mut self, error: u8) {
+        self.transfer = Transfer::None;
+        self.busy_until = None;
+        self.error = error;
+        self.status = STATUS_RDY | STATUS_DSC;
+        if error != 0 {
+            self.status |= STATUS_ERR;
+        }
+    }
+
+    fn execute(&mut self, command: u8) {
+        self.error = 0;
+        self.status &= !STATUS_ERR;
+        self.position = 0;
+        self.remaining = match self.sector_count {
+            0 => 256,
+            count => count as u16,
+        };
+
+        self.transfer = match command {
+            COMMAND_IDENTIFY => Transfer::Identify,
+            COMMAND_READ_SECTORS | COMMAND_READ_SECTORS_NO_RETRY => Transfer::Read,
+            COMMAND_WRITE_SECTORS | COMMAND_WRITE_SECTORS_NO_RETRY => Transfer::Write,
+            COMMAND_SET_FEATURES => {
+                // 01 enables 8-bit transfers, 81 disables them; the card only does 8-bit
+                let error = if self.features == 0x81 { ERROR_ABRT } else { 0 };
+                self.finish(error);
+                return;
+            }
+            _ => {
+                self.finish(ERROR_ABRT);
+                return;
+            }
+        };
+        self.go_busy(COMMAND_BUSY_SECONDS);
+    }
+
+    // BSY is over: get the next sector ready, or finish the command
+    fn ready(&mut self) {
+        self.busy_until = None;
+        self.status &= !STATUS_BSY;
+        self.position = 0;
+
+        match self.transfer {
+            Transfer::None => {}
+            Transfer::Identify => {
+                self.buffer = self.identify();
+                self.status |= STATUS_DRQ;
+            }
+            Transfer::Read => match self.image.read_block(self.lba(), &mut self.buffer) {
+                Ok(()) => self.status |= STATUS_DRQ,
+                Err(_) => self.finish(ERROR_IDNF),
+            },
+            Transfer::Write => {
+                if self.lba() >= self.image.block_count() {
+                    self.finish(ERROR_IDNF);
+                } else {
+                    self.status |= STATUS_DRQ;
+                }
+            }
+        }
+    }
+
+    // The host moved the last byte of a sector
+    fn sector_done(&mut self) {
+        self.status &= !STATUS_DRQ;
+
+        if self.transfer == Transfer::Write {
+            let buffer = self.buffer;
+            if self.image.write_block(self.lba(), &buffer).is_err() {
+                self.finish(ERROR_IDNF);
+                return;
+            }
+        }
+
+        self.remaining -= 1;
+        self.sector_count = self.remaining as u8;
+        if self.transfer != Transfer::Identify {
+            self.set_lba(self.lba() + 1);
+        }
+
+        match (self.transfer, self.remaining) {
+            (Transfer::Identify, _) | (Transfer::Read, 0) => self.finish(0),
+            (Transfer::Write, 0) => {
+                // Busy while the last sector is written
+                self.transfer = Transfer::None;
+                self.go_busy(SECTOR_BUSY_SECONDS);
+            }
+            _ => self.go_busy(SECTOR_BUSY_SECONDS),
+        }
+    }
+
+    // The 256 words returned by IDENTIFY DEVICE, low byte first
+    fn identify(&self) -> [u8; BLOCK_SIZE] {
+        let sectors = self.image.block_count().min(0x0FFF_FFFF) as u32;
+        let mut words = [0u16; BLOCK_SIZE / 2];
+
+        // A CompactFlash card, with a CHS geometry for whoever still wants one
+        let heads = 16;
+        let sectors_per_track = 63;
+        let cylinders = (sectors / (heads * sectors_per_track)).min(0xFFFF);
+        words[0] = 0x848A;
+        words[1] = cylinders as u16;
+        words[3] = heads as u16;
+        words[6] = sectors_per_track as u16;
+        words[7] = (sectors >> 16) as u16;
+        words[8] = sectors as u16;
+        set_string(&mut words[10..20], "RUSTY502");
+        set_string(&mut words[23..27], "1.0");
+        set_string(&mut words[27..47], "RUSTY502 EMULATED CF CARD");
+        words[47] = 0x0001;
+        words[49] = 0x0200; // LBA supported
+        words[53] = 0x0001;
+        words[54] = cylinders as u16;
+        words[55] = heads as u16;
+        words[56] = sectors_per_track as u16;
+        words[57] = sectors as u16;
+        words[58] = (sectors >> 16) as u16;
+        words[60] = sectors as u16;
+        words[61] = (sectors >> 16) as u16;
+
+        let mut data = [0u8; BLOCK_SIZE];
+        for (bytes, word) in data.chunks_exact_mut(2).zip(words) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        data
+    }
+}
+
+// ATA strings are space padded with the two bytes of each word swapped
+fn set_string(words: &mut [u16], text: &str) {
+    let mut bytes = text.bytes().chain(std::iter::repeat(b' '));
+    for word in words {
+        let high = bytes.next().unwrap_or(b' ');
+        let low = bytes.next().unwrap_or(b' ');
+        *word = u16::from_be_bytes([high, low]);
+    }
+}
+
+impl Device for CompactFlash {
+    fn name(&self) -> &str {
+        "compact_flash"
+    }
+
+    fn read(&mut self, offset: u16) -> u8 {
+        if offset & 0x07 == 0 && self.status & STATUS_DRQ != 0 && self.transfer != Transfer::Write {
+            let data = self.buffer[self.position];
+            self.position += 1;
+            if self.position == BLOCK_SIZE {
+                self.sector_done();
+            }
+            return data;
+        }
+        self.peek(offset)
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        // Only the status register can be read while the card is busy
+        if self.status & STATUS_BSY != 0 {
+            return self.status;
+        }
+
+        match offset & 0x07 {
+            0 => {
+                if self.status & STATUS_DRQ != 0 {
+                    self.buffer[self.position]
+                } else {
+                    0xFF
+                }
+            }
+            1 => self.error,
+            2 => self.sector_count,
+            3..=6 => self.lba[(offset & 0x07) as usize - 3],
+            _ => self.status,
+        }
+    }
+
+    fn write(&mut self, offset: u16, data: u8) {
+        // Writes are ignored while the card is busy
+        if self.status & STATUS_BSY != 0 {
+            return;
+        }
+
+        match offset & 0x07 {
+            0 => {
+                if self.status & STATUS_DRQ != 0 && self.transfer == Transfer::Write {
+                    self.buffer[self.position] = data;
+                    self.position += 1;
+                    if self.position == BLOCK_SIZE {
+                        self.sector_done();
+                    }
+                }
+            }
+            1 => self.features = data,
+            2 => self.sector_count = data,
+            3..=6 => self.lba[(offset & 0x07) as usize - 3] = data,
+            _ => self.execute(data),
+        }
+    }
+
+    fn tick(&mut self, now: u64) {
+        self.last_cycle = now;
+        if matches!(self.busy_until, Some(cycle) if now >= cycle) {
+            self.ready();
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        self.busy_until
+    }
+
+    fn clock_rate_changed(&mut self, hz: f64) {
+        self.clock_hz = hz;
+    }
+
+    fn reset(&mut self) {
+        self.features = 0;
+        self.error = 0;
+        self.sector_count = 1;
+        self.lba = [1, 0, 0, 0xE0];
+        self.status = STATUS_RDY | STATUS_DSC;
+        self.transfer = Transfer::None;
+        self.remaining = 0;
+        self.position = 0;
+        self.busy_until = None;
+        self.last_cycle = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::bus::Bus;
+    use std::sync::{Arc, Mutex};
+
+    const BASE: u16 = 0x7F00;
+
+    // Poll the status register the way firmware does, letting time pass
+    fn wait(bus: &mut Bus, mask: u8) -> u8 {
+        loop {
+            let next = bus.next_deadline().min(bus.cycle() + 10);
+            bus.sync(next);
+            let status = bus.read_byte(BASE + 7);
+            if status & STATUS_BSY == 0 && status & mask == mask {
+                return status;
+            }
+        }
+    }
+
+    fn command(bus: &mut Bus, lba: u32, count: u8, command: u8) {
+        wait(bus, STATUS_RDY);
+        bus.write_byte(BASE + 2, count);
+        for (i, byte) in lba.to_le_bytes().iter().enumerate() {
+            bus.write_byte(BASE + 3 + i as u16, if i == 3 { 0xE0 | byte } else { *byte });
+        }
+        bus.write_byte(BASE + 7, command);
+        assert!(bus.read_byte(BASE + 7) & STATUS_BSY != 0);
+    }
+
+    #[test]
+    fn test_identify_and_sectors() {
+        let mut bus = Bus::new();
+        let card = CompactFlash::new(BlockImage::in_memory(1000));
+        bus.add_device(BASE, BASE + 7, Arc::new(Mutex::new(card)));
+
+        command(&mut bus, 0, 1, COMMAND_IDENTIFY);
+        wait(&mut bus, STATUS_DRQ);
+        let identify: Vec<u8> = (0..BLOCK_SIZE).map(|_| bus.read_byte(BASE)).collect();
+        assert_eq!(&identify[0..2], &[0x8A, 0x84]);
+        assert_eq!(&identify[54..58], b"URTS");
+        assert_eq!(u16::from_le_bytes([identify[120], identify[121]]), 1000);
+        assert_eq!(wait(&mut bus, STATUS_RDY) & (STATUS_DRQ | STATUS_ERR), 0);
+
+        // Write two sectors at LBA 10
+        command(&mut bus, 10, 2, COMMAND_WRITE_SECTORS);
+        for sector in 0..2u8 {
+            wait(&mut bus, STATUS_DRQ);
+            for i in 0..BLOCK_SIZE {
+                bus.write_byte(BASE, sector ^ i as u8);
+            }
+        }
+        assert_eq!(wait(&mut bus, STATUS_RDY) & STATUS_ERR, 0);
+        assert_eq!(bus.read_byte(BASE + 3), 12);
+
+        // Read the second one back
+        command(&mut bus, 11, 1, COMMAND_READ_SECTORS);
+        wait(&mut bus, STATUS_DRQ);
+        assert!((0..BLOCK_SIZE).all(|i| bus.read_byte(BASE) == 1 ^ i as u8));
+
+        // Past the end of the card
+        command(&mut bus, 1000, 1, COMMAND_READ_SECTORS);
+        let status = wait(&mut bus, STATUS_RDY);
+        assert_eq!(status & STATUS_ERR, STATUS_ERR);
+        assert_eq!(bus.read_byte(BASE + 1), ERROR_IDNF);
+    }
+
+    #[test]
+    fn test_busy_and_drq() {
+        // At 1 MHz a command keeps the card busy for 20 cycles, a sector for 200
+        let mut card = CompactFlash::new(BlockImage::in_memory(8));
+        card.write(2, 2);
+        card.write(3, 5);
+        card.write(7, COMMAND_READ_SECTORS);
+
+        // While busy every register reads as the status, and writes are ignored
+        assert_eq!(card.read(7) & (STATUS_BSY | STATUS_DRQ), STATUS_BSY);
+        assert_eq!(card.read(2), card.read(7));
+        card.write(3, 0x77);
+        assert_eq!(card.next_event(), Some(20));
+        card.tick(19);
+        assert!(card.read(7) & STATUS_BSY != 0);
+        card.tick(20);
+        assert_eq!(card.read(7) & (STATUS_BSY | STATUS_DRQ), STATUS_DRQ);
+        assert_eq!(card.read(3), 5);
+
+        // BSY again between the sectors, DRQ once the next one is ready
+        for _ in 0..BLOCK_SIZE {
+            card.read(0);
+        }
+        assert_eq!(card.read(7) & (STATUS_BSY | STATUS_DRQ), STATUS_BSY);
+        assert_eq!(card.next_event(), Some(220));
+        card.tick(220);
+        assert_eq!(card.read(7) & (STATUS_BSY | STATUS_DRQ), STATUS_DRQ);
+        for _ in 0..BLOCK_SIZE {
+            card.read(0);
+        }
+        assert_eq!(card.read(7) & (STATUS_BSY | STATUS_DRQ | STATUS_ERR), 0);
+        assert_eq!(card.read(3), 7);
+        assert_eq!(card.next_event(), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut card = CompactFlash::new(BlockImage::in_memory(8));
+
+        // Unsupported commands and 16-bit mode are aborted straight away
+        card.write(7, 0x50);
+        assert_eq!(card.read(7) & (STATUS_BSY | STATUS_ERR), STATUS_ERR);
+        assert_eq!(card.read(1), ERROR_ABRT);
+        card.write(1, 0x81);
+        card.write(7, COMMAND_SET_FEATURES);
+        assert_eq!(card.read(1), ERROR_ABRT);
+        card.write(1, 0x01);
+        card.write(7, COMMAND_SET_FEATURES);
+        assert_eq!(card.read(7) & STATUS_ERR, 0);
+        assert_eq!(card.read(1), 0);
+
+        // Writing past the end of the card is refused once BSY drops
+        card.write(3, 8);
+        card.write(7, COMMAND_WRITE_SECTORS);
+        card.tick(20);
+        assert_eq!(card.read(7) & (STATUS_DRQ | STATUS_ERR), STATUS_ERR);
+        assert_eq!(card.read(1), ERROR_IDNF);
+
+        // Bits 24-27 of the LBA count too
+        card.write(3, 0);
+        card.write(6, 0xE1);
+        card.write(7, COMMAND_READ_SECTORS);
+        card.tick(40);
+        assert_eq!(card.read(7) & (STATUS_DRQ | STATUS_ERR), STATUS_ERR);
+        assert_eq!(card.read(1), ERROR_IDNF);
+    }
+
+    #[test]
+    fn test_write_back() {
+        let path =
+            std::env::temp_dir().join(format!("rusty502_compact_flash_{}.img", std::process::id()));
+        std::fs::write(&path, vec![0x00; 4 * BLOCK_SIZE]).unwrap();
+
+        let mut card = CompactFlash::new(BlockImage::open(&path).unwrap());
+        card.write(3, 2);
+        card.write(7, COMMAND_WRITE_SECTORS);
+        card.tick(20);
+        for i in 0..BLOCK_SIZE {
+            card.write(0, i as u8 ^ 0x5A);
+        }
+
+        // The sector is in the file before the card stops being busy
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(card.read(7) & STATUS_BSY != 0);
+        let sector = &data[2 * BLOCK_SIZE..3 * BLOCK_SIZE];
+        assert!(sector.iter().enumerate().all(|(i, byte)| *byte == i as u8 ^ 0x5A));
+        assert!(data[..2 * BLOCK_SIZE].iter().all(|byte| *byte == 0x00));
+    }
+}
@@ -0,0 +1,274 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::i2c::I2cDevice;
+
+const ADDRESS: u8 = 0x68;
+
+// Clock registers (00-06 are the time, 07 is control, 08-3F are RAM)
+const SECONDS: usize = 0;
+const MINUTES: usize = 1;
+const HOURS: usize = 2;
+const DAY: usize = 3;
+const DATE: usize = 4;
+const MONTH: usize = 5;
+const YEAR: usize = 6;
+const REGISTERS: usize = 64;
+
+// Clock halt bit in the seconds register
+const CLOCK_HALT: u8 = 0x80;
+
+/**
+ * Dallas DS1307 real-time clock on the I2C bus.
+ *
+ * Time runs off the emulated CPU clock, not the host's, so a program sees
+ * time pass at the speed it is emulated at. The clock starts either from
+ * the host's current time (UTC) or from a fixed time for reproducible tests.
+ */
+pub struct Ds1307 {
+    registers: [u8; REGISTERS],
+    pointer: usize,
+
+    // Whether the next write sets the register pointer
+    pointer_write: bool,
+
+    // The day of the week register counts on its own, this is its offset
+    // from the real weekday of the date
+    day_offset: i64,
+
+    // The time (Unix seconds) at `base_cycle`
+    base_time: i64,
+    base_cycle: u64,
+    clock_hz: f64,
+}
+
+impl Default for Ds1307 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ds1307 {
+    // A clock set to the host's current time
+    pub fn new() -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or(0);
+        Self::at(now)
+    }
+
+    // A clock set to a fixed time, in seconds since the Unix epoch
+    pub fn at(unix_time: i64) -> Self {
+        let mut rtc = Self {
+            registers: [0; REGISTERS],
+            pointer: 0,
+            pointer_write: false,
+            day_offset: 0,
+            base_time: unix_time,
+            base_cycle: 0,
+            clock_hz: 1_000_000.0,
+        };
+        rtc.latch_time(0);
+        rtc
+    }
+
+    fn halted(&self) -> bool {
+        self.registers[SECONDS] & CLOCK_HALT != 0
+    }
+
+    // The current time in Unix seconds
+    pub fn time(&self, now: u64) -> i64 {
+        if self.halted() {
+            return self.base_time;
+        }
+        let elapsed = now.saturating_sub(self.base_cycle) as f64 / self.clock_hz;
+        self.base_time + elapsed as i64
+    }
+
+    // Copy the running time into the time registers
+    fn latch_time(&mut self, now: u64) {
+        let time = self.time(now);
+        let days = time.div_euclid(86_400);
+        let seconds = time.rem_euclid(86_400);
+        let (year, month, date) = civil_from_days(days);
+
+        let halt = self.registers[SECONDS] & CLOCK_HALT;
+        self.registers[SECONDS] = halt | to_bcd((seconds % 60) as u8);
+        self.registers[MINUTES] = to_bcd((seconds / 60 % 60) as u8);
+        self.set_hours((seconds / 3600) as u8);
+        self.registers[DAY] = (weekday(days) + self.day_offset).rem_euclid(7) as u8 + 1;
+        self.registers[DATE] = to_bcd(date as u8);
+        self.registers[MONTH] = to_bcd(month as u8);
+        self.registers[YEAR] = to_bcd((year % 100) as u8);
+    }
+
+    // Store the hours, keeping whichever 12/24 hour mode is selected
+    fn set_hours(&mut self, hours: u8) {
+        if self.registers[HOURS] & 0x40 != 0 {
+            let pm = if hours >= 12 { 0x20 } else { 0x00 };
+            let hour = match hours % 12 {
+                0 => 12,
+                hour => hour,
+            };
+            self.registers[HOURS] = 0x40 | pm | to_bcd(hour);
+        } else {
+            self.registers[HOURS] = to_bcd(hours);
+        }
+    }
+
+    fn hours(&self) -> i64 {
+        let hours = self.registers[HOURS];
+        if hours & 0x40 != 0 {
+            let hour = from_bcd(hours & 0x1F) as i64 % 12;
+            if hours & 0x20 != 0 {
+                hour + 12
+            } else {
+                hour
+            }
+        } else {
+            from_bcd(hours & 0x3F) as i64
+        }
+    }
+
+    // Restart the running time from what was written to the time registers
+    fn load_time(&mut self, now: u64) {
+        let year = 2000 + from_bcd(self.registers[YEAR]) as i64;
+        let month = from_bcd(self.registers[MONTH] & 0x1F).max(1) as i64;
+        let date = from_bcd(self.registers[DATE] & 0x3F).max(1) as i64;
+        let days = days_from_civil(year, month, date);
+        self.day_offset = (self.registers[DAY] as i64 - 1 - weekday(days)).rem_euclid(7);
+
+        self.base_time = days * 86_400
+            + self.hours() * 3600
+            + from_bcd(self.registers[MINUTES] & 0x7F) as i64 * 60
+            + from_bcd(self.registers[SECONDS] & 0x7F) as i64;
+        self.base_cycle = now;
+    }
+}
+
+impl I2cDevice for Ds1307 {
+    fn name(&self) -> &str {
+        "ds1307"
+    }
+
+    fn address(&self) -> u8 {
+        ADDRESS
+    }
+
+    fn start(&mut self, read: bool, now: u64) -> bool {
+        // The time is copied to the registers at the start of each access
+        self.latch_time(now);
+        self.pointer_write = !read;
+        true
+    }
+
+    fn write(&mut self, data: u8, now: u64) -> bool {
+        if self.pointer_write {
+            self.pointer_write = false;
+            self.pointer = data as usize % REGISTERS;
+            return true;
+        }
+
+        let register = self.pointer;
+        let was_halted = self.halted();
+        self.registers[register] = data;
+        self.pointer = (self.pointer + 1) % REGISTERS;
+
+        if register <= YEAR {
+            // Writing the seconds also resets the sub-second divider
+            if was_halted && !self.halted() {
+                self.base_cycle = now;
+            }
+            self.load_time(now);
+        }
+        true
+    }
+
+    fn read(&mut self, _now: u64) -> u8 {
+        let data = self.registers[self.pointer];
+        self.pointer = (self.pointer + 1) % REGISTERS;
+        data
+    }
+
+    fn clock_rate_changed(&mut self, hz: f64) {
+        self.clock_hz = hz;
+    }
+
+    fn reset(&mut self) {
+        // The DS1307 is battery backed, only the bus interface resets
+        self.pointer_write = false;
+    }
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+// Day of the week, 0 = Sunday (1970-01-01 was a Thursday)
+fn weekday(days: i64) -> i64 {
+    (days + 4).rem_euclid(7)
+}
+
+// Days since 1970-01-01 for a proleptic Gregorian date (Howard Hinnant's algorithm)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// The inverse of days_from_civil: (year, month, day)
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-02-29 23:59:58 UTC, a Thursday
+    const LEAP_DAY: i64 = 1_709_251_198;
+
+    fn read_time(rtc: &mut Ds1307, now: u64) -> Vec<u8> {
+        rtc.start(false, now);
+        rtc.write(0x00, now);
+        rtc.start(true, now);
+        (0..7).map(|_| rtc.read(now)).collect()
+    }
+
+    #[test]
+    fn test_fixed_time() {
+        let mut rtc = Ds1307::at(LEAP_DAY);
+        assert_eq!(read_time(&mut rtc, 0), vec![0x58, 0x59, 0x23, 5, 0x29, 0x02, 0x24]);
+
+        // Two emulated seconds later (at 1 MHz) it is March
+        assert_eq!(read_time(&mut rtc, 2_000_000), vec![0x00, 0x00, 0x00, 6, 0x01, 0x03, 0x24]);
+    }
+
+    #[test]
+    fn test_set_time() {
+        let mut rtc = Ds1307::at(0);
+        rtc.start(false, 0);
+        for byte in [0x00, 0x30, 0x15, 0x08, 0x02, 0x01, 0x01, 0x99] {
+            rtc.write(byte, 0);
+        }
+        rtc.stop(0);
+        assert_eq!(read_time(&mut rtc, 1_000_000), vec![0x31, 0x15, 0x08, 0x02, 0x01, 0x01, 0x99]);
+    }
+}
@@ -0,0 +1,278 @@
+/**
+ * Microchip 24LC series serial EEPROM (24LC32 to 24LC512) on the I2C bus.
+ *
+ * The memory is addressed with two address bytes after the device address.
+ * Writes go into a page buffer that wraps within the page and are committed
+ * at the stop condition, after which the part is busy for its write cycle
+ * and NACKs its address, so firmware can use acknowledge polling. Reads are
+ * sequential and wrap at the end of the memory.
+ */
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::i2c::I2cDevice;
+
+// Base address, the low three bits come from the A2-A0 pins
+const BASE_ADDRESS: u8 = 0x50;
+
+// Worst case self-timed write cycle
+const WRITE_CYCLE_SECONDS: f64 = 0.005;
+
+pub struct Eeprom24 {
+    memory: Vec<u8>,
+    page_size: usize,
+    pins: u8,
+    file: Option<File>,
+
+    pointer: usize,
+    // Address bytes still expected in a write transaction
+    address_bytes: u8,
+    // Page bytes written since the last start, not committed yet
+    pending: Vec<(usize, u8)>,
+
+    busy_until: u64,
+    clock_hz: f64,
+}
+
+impl Eeprom24 {
+    // A blank (erased) EEPROM of `size` bytes that only lives in memory
+    pub fn new(size: usize, page_size: usize) -> Self {
+        Self {
+            memory: vec![0xFF; size],
+            page_size,
+            pins: 0,
+            file: None,
+            pointer: 0,
+            address_bytes: 0,
+            pending: Vec::new(),
+            busy_until: 0,
+            clock_hz: 1_000_000.0,
+        }
+    }
+
+    // A 24LC256: 32 KiB with 64 byte pages
+    pub fn lc256() -> Self {
+        Self::new(32 * 1024, 64)
+    }
+
+    // An EEPROM kept in a file on the host, created erased if missing
+    pub fn open<P: AsRef<Path>>(path: P, size: usize, page_size: usize) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let mut eeprom = Self::new(size, page_size);
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        contents.truncate(size);
+        eeprom.memory[..contents.len()].copy_from_slice(&contents);
+
+        // Pad short (or new) files out to the full size
+        if contents.len() < size {
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&eeprom.memory)?;
+            file.flush()?;
+        }
+
+        eeprom.file = Some(file);
+        Ok(eeprom)
+    }
+
+    // Set the levels of the A2-A0 pins, selecting one of eight addresses
+    pub fn set_address_pins(&mut self, pins: u8) {
+        self.pins = pins & 0x07;
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    fn busy(&self, now: u64) -> bool {
+        now < self.busy_until
+    }
+
+    // Write the page buffer to the memory (and the backing file)
+    fn commit(&mut self, now: u64) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        let page_start = self.pending[0].0 / self.page_size * self.page_size;
+        for (address, data) in self.pending.drain(..) {
+            self.memory[address] = data;
+        }
+
+        if let Some(file) = &mut self.file {
+            let page = &self.memory[page_start..page_start + self.page_size];
+            let written = file
+                .seek(SeekFrom::Start(page_start as u64))
+                .and_then(|_| file.write_all(page))
+                .and_then(|_| file.flush());
+            if let Err(error) = written {
+                eprintln!("eeprom: could not save page at {:04X}: {}", page_start, error);
+            }
+        }
+
+        self.busy_until = now + (WRITE_CYCLE_SECONDS * self.clock_hz) as u64;
+    }
+}
+
+impl I2cDevice for Eeprom24 {
+    fn name(&self) -> &str {
+        "eeprom_24lc"
+    }
+
+    fn address(&self) -> u8 {
+        BASE_ADDRESS | self.pins
+    }
+
+    fn start(&mut self, read: bool, now: u64) -> bool {
+        if self.busy(now) {
+            return false;
+        }
+        if !read {
+            self.address_bytes = 2;
+            self.pending.clear();
+        }
+        true
+    }
+
+    fn write(&mut self, data: u8, _now: u64) -> bool {
+        let size = self.memory.len();
+
+        if self.address_bytes > 0 {
+            self.address_bytes -= 1;
+            if self.address_bytes == 1 {
+                self.pointer = (data as usize) << 8;
+            } else {
+                self.pointer = (self.pointer | data as usize) % size;
+            }
+            return true;
+        }
+
+        // Writing past the end of the page wraps to its start
+        let page_start = self.pointer / self.page_size * self.page_size;
+        self.pending.retain(|(address, _)| *address != self.pointer);
+        self.pending.push((self.pointer, data));
+        self.pointer = page_start + (self.pointer + 1) % self.page_size;
+        true
+    }
+
+    fn read(&mut self, _now: u64) -> u8 {
+        let data = self.memory[self.pointer];
+        self.pointer = (self.pointer + 1) % self.memory.len();
+        data
+    }
+
+    fn stop(&mut self, now: u64) {
+        self.address_bytes = 0;
+        self.commit(now);
+    }
+
+    fn clock_rate_changed(&mut self, hz: f64) {
+        self.clock_hz = hz;
+    }
+
+    fn reset(&mut self) {
+        self.address_bytes = 0;
+        self.pending.clear();
+        self.busy_until = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::devices::i2c::tests::Master;
+    use crate::emulator::devices::i2c::I2cBus;
+    use crate::emulator::devices::port::{Pin, Port};
+
+    #[test]
+    fn test_page_write_and_read() {
+        let mut bus = I2cBus::new(Pin::new(Port::A, 0), Pin::new(Port::A, 1));
+        bus.attach(Box::new(Eeprom24::lc256()));
+        let mut master = Master::new(bus);
+
+        // Write four bytes, the last two wrap to the start of the page
+        master.start();
+        assert!(master.send(0xA0));
+        assert!(master.send(0x01));
+        assert!(master.send(0x3E));
+        for byte in [0x11, 0x22, 0x33, 0x44] {
+            assert!(master.send(byte));
+        }
+        master.stop();
+
+        // Busy during the write cycle
+        master.start();
+        assert!(!master.send(0xA0));
+        master.stop();
+        master.now += 5_000;
+
+        // Nothing answers at another address
+        master.start();
+        assert!(!master.send(0xA2));
+        master.stop();
+
+        // Random read from the start of the page
+        master.start();
+        assert!(master.send(0xA0));
+        assert!(master.send(0x01));
+        assert!(master.send(0x00));
+        master.start();
+        assert!(master.send(0xA1));
+        assert_eq!(master.receive(true), 0x33);
+        assert_eq!(master.receive(false), 0x44);
+        master.stop();
+
+        master.start();
+        assert!(master.send(0xA0));
+        assert!(master.send(0x01));
+        assert!(master.send(0x3E));
+        master.start();
+        assert!(master.send(0xA1));
+        assert_eq!(master.receive(true), 0x11);
+        assert_eq!(master.receive(true), 0x22);
+
+        // Reads run on into the next (erased) page
+        assert_eq!(master.receive(false), 0xFF);
+        master.stop();
+    }
+
+    #[test]
+    fn test_reopen_from_file() {
+        let path = std::env::temp_dir().join(format!("rusty502_eeprom_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // A new file is created erased at the full size
+        let mut eeprom = Eeprom24::open(&path, 1024, 16).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 1024);
+
+        eeprom.start(false, 0);
+        for byte in [0x02, 0x08, 0x12, 0x34] {
+            assert!(eeprom.write(byte, 0));
+        }
+        eeprom.stop(0);
+        drop(eeprom);
+
+        // The committed page is read back when the file is opened again
+        let mut eeprom = Eeprom24::open(&path, 1024, 16).unwrap();
+        assert_eq!(&eeprom.memory()[0x207..0x20B], &[0xFF, 0x12, 0x34, 0xFF]);
+
+        // Writes that never saw a stop are not saved
+        eeprom.start(false, 0);
+        for byte in [0x00, 0x00, 0x56] {
+            assert!(eeprom.write(byte, 0));
+        }
+        drop(eeprom);
+        let eeprom = Eeprom24::open(&path, 1024, 16).unwrap();
+        assert_eq!(eeprom.memory()[0], 0xFF);
+        assert_eq!(eeprom.memory()[0x208], 0x12);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
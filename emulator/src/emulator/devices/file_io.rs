@@ -0,0 +1,332 @@
+/**
+ * Semi-hosted file I/O: lets a program open, read, write, seek and close
+ * files on the host, like semihosting on ARM.
+ *
+ * The program fills in a parameter block in its own memory, writes the
+ * block's address to the device and then writes a command. The device
+ * takes over the bus to carry the command out, reading and writing the
+ * block and the data buffer directly, and leaves a status code behind.
+ * Only files inside the sandbox directory given on the host can be used.
+ *
+ * Registers:
+ *
+ *   $00-$01 (R/W) address of the parameter block
+ *   $02     (W)   command
+ *   $03     (R)   status of the last command
+ *
+ * Parameter block:
+ *
+ *   +0      handle (returned by open)
+ *   +1      open mode, or where a seek is from (0 start, 1 current, 2 end)
+ *   +2-+3   address of the data buffer, or of the file name (ends with a 0)
+ *   +4-+5   bytes to read or write; set to the number actually moved
+ *   +6-+9   seek offset (signed); set to the new position
+ */
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+use super::{Device, Dma};
+
+// Commands
+pub const COMMAND_OPEN: u8 = 0x01;
+pub const COMMAND_CLOSE: u8 = 0x02;
+pub const COMMAND_READ: u8 = 0x03;
+pub const COMMAND_WRITE: u8 = 0x04;
+pub const COMMAND_SEEK: u8 = 0x05;
+pub const COMMAND_DELETE: u8 = 0x06;
+
+// Open modes
+pub const MODE_READ: u8 = 0x00;
+pub const MODE_WRITE: u8 = 0x01; // Create or truncate
+pub const MODE_APPEND: u8 = 0x02;
+pub const MODE_READ_WRITE: u8 = 0x03;
+
+// Status codes
+pub const STATUS_OK: u8 = 0x00;
+pub const STATUS_NOT_FOUND: u8 = 0x01;
+pub const STATUS_DENIED: u8 = 0x02;
+pub const STATUS_BAD_HANDLE: u8 = 0x03;
+pub const STATUS_IO_ERROR: u8 = 0x04;
+pub const STATUS_TOO_MANY_FILES: u8 = 0x05;
+pub const STATUS_BAD_COMMAND: u8 = 0x06;
+
+const MAX_FILES: usize = 8;
+const MAX_NAME_LENGTH: u16 = 255;
+
+pub struct FileIo {
+    root: PathBuf,
+    files: Vec<Option<File>>,
+    block: u16,
+    command: Option<u8>,
+    status: u8,
+}
+
+impl FileIo {
+    // Files are opened relative to (and only inside) `root`
+    pub fn new<P: AsRef<Path>>(root: P) -> io::Result<Self> {
+        Ok(Self {
+            root: root.as_ref().canonicalize()?,
+            files: (0..MAX_FILES).map(|_| None).collect(),
+            block: 0,
+            command: None,
+            status: STATUS_OK,
+        })
+    }
+
+    // Map a name from the program to a path inside the sandbox
+    fn resolve(&self, name: &str) -> Result<PathBuf, u8> {
+        let relative = Path::new(name);
+        let plain = relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if name.is_empty() || !plain {
+            return Err(STATUS_DENIED);
+        }
+
+        // Don't let symbolic links lead out of the sandbox either
+        let path = self.root.join(relative);
+        let existing = if path.exists() {
+            path.canonicalize()
+        } else {
+            path.parent().unwrap_or(&self.root).canonicalize()
+        };
+        match existing {
+            Ok(real) if real.starts_with(&self.root) => Ok(path),
+            Ok(_) => Err(STATUS_DENIED),
+            Err(error) => Err(status_for(&error)),
+        }
+    }
+
+    fn file(&mut self, handle: u8) -> Result<&mut File, u8> {
+        (handle as usize)
+            .checked_sub(1)
+            .and_then(|index| self.files.get_mut(index))
+            .and_then(|file| file.as_mut())
+            .ok_or(STATUS_BAD_HANDLE)
+    }
+
+    fn execute(&mut self, command: u8, bus: &mut dyn Dma) -> Result<(), u8> {
+        let block = self.block;
+        let at = |offset: u16| block.wrapping_add(offset);
+        let handle = bus.read(at(0));
+        let mode = bus.read(at(1));
+        let buffer = read_word(bus, at(2));
+        let length = read_word(bus, at(4));
+
+        match command {
+            COMMAND_OPEN => {
+                let path = self.resolve(&read_name(bus, buffer))?;
+                let slot = self.files.iter().position(Option::is_none);
+                let slot = slot.ok_or(STATUS_TOO_MANY_FILES)?;
+
+                let mut options = OpenOptions::new();
+                match mode {
+                    MODE_READ => options.read(true),
+                    MODE_WRITE => options.write(true).create(true).truncate(true),
+                    MODE_APPEND => options.append(true).create(true),
+                    MODE_READ_WRITE => options.read(true).write(true).create(true),
+                    _ => return Err(STATUS_BAD_COMMAND),
+                };
+                let file = options.open(path).map_err(|error| status_for(&error))?;
+                self.files[slot] = Some(file);
+                bus.write(at(0), slot as u8 + 1);
+            }
+            COMMAND_CLOSE => {
+                self.file(handle)?;
+                self.files[handle as usize - 1] = None;
+            }
+            COMMAND_READ => {
+                let mut data = vec![0; length as usize];
+                let file = self.file(handle)?;
+                let mut count = 0;
+                while count < data.len() {
+                    match file.read(&mut data[count..]) {
+                        Ok(0) => break,
+                        Ok(read) => count += read,
+                        Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                        Err(error) => return Err(status_for(&error)),
+                    }
+                }
+                for (offset, byte) in data[..count].iter().enumerate() {
+                    bus.write(buffer.wrapping_add(offset as u16), *byte);
+                }
+                write_word(bus, at(4), count as u16);
+            }
+            COMMAND_WRITE => {
+                let data: Vec<u8> = (0..length).map(|offset| bus.read(buffer.wrapping_add(offset))).collect();
+                let file = self.file(handle)?;
+                file.write_all(&data).map_err(|error| status_for(&error))?;
+            }
+            COMMAND_SEEK => {
+                let offset = (0..4).fold(0u32, |value, byte| {
+                    value | (bus.read(at(6 + byte)) as u32) << (byte * 8)
+                }) as i32 as i64;
+                let from = match mode {
+                    0 => SeekFrom::Start(offset.max(0) as u64),
+                    1 => SeekFrom::Current(offset),
+                    2 => SeekFrom::End(offset),
+                    _ => return Err(STATUS_BAD_COMMAND),
+                };
+                let file = self.file(handle)?;
+                let position = file.seek(from).map_err(|error| status_for(&error))?;
+                for (byte, value) in (position as u32).to_le_bytes().iter().enumerate() {
+                    bus.write(at(6 + byte as u16), *value);
+                }
+            }
+            COMMAND_DELETE => {
+                let path = self.resolve(&read_name(bus, buffer))?;
+                fs::remove_file(path).map_err(|error| status_for(&error))?;
+            }
+            _ => return Err(STATUS_BAD_COMMAND),
+        }
+        Ok(())
+    }
+}
+
+fn status_for(error: &io::Error) -> u8 {
+    match error.kind() {
+        io::ErrorKind::NotFound => STATUS_NOT_FOUND,
+        io::ErrorKind::PermissionDenied => STATUS_DENIED,
+        _ => STATUS_IO_ERROR,
+    }
+}
+
+fn read_word(bus: &mut dyn Dma, address: u16) -> u16 {
+    bus.read(address) as u16 | (bus.read(address.wrapping_add(1)) as u16) << 8
+}
+
+fn write_word(bus: &mut dyn Dma, address: u16, value: u16) {
+    bus.write(address, value as u8);
+    bus.write(address.wrapping_add(1), (value >> 8) as u8);
+}
+
+// A zero terminated file name
+fn read_name(bus: &mut dyn Dma, address: u16) -> String {
+    let name: Vec<u8> = (0..MAX_NAME_LENGTH)
+        .map(|offset| bus.read(address.wrapping_add(offset)))
+        .take_while(|byte| *byte != 0)
+        .collect();
+    String::from_utf8_lossy(&name).into_owned()
+}
+
+impl Device for FileIo {
+    fn name(&self) -> &str {
+        "file_io"
+    }
+
+    fn read(&mut self, offset: u16) -> u8 {
+        self.peek(offset)
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        match offset {
+            0 => self.block as u8,
+            1 => (self.block >> 8) as u8,
+            3 => self.status,
+            _ => 0x00,
+        }
+    }
+
+    fn write(&mut self, offset: u16, data: u8) {
+        match offset {
+            0 => self.block = (self.block & 0xFF00) | data as u16,
+            1 => self.block = (self.block & 0x00FF) | (data as u16) << 8,
+            2 => self.command = Some(data),
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        for file in &mut self.files {
+            *file = None;
+        }
+        self.block = 0;
+        self.command = None;
+        self.status = STATUS_OK;
+    }
+
+    fn dma_pending(&self) -> bool {
+        self.command.is_some()
+    }
+
+    fn dma(&mut self, bus: &mut dyn Dma) -> u64 {
+        if let Some(command) = self.command.take() {
+            self.status = match self.execute(command, bus) {
+                Ok(()) => STATUS_OK,
+                Err(status) => status,
+            };
+        }
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::bus::Bus;
+    use std::sync::{Arc, Mutex};
+
+    const BASE: u16 = 0x7F20;
+    const BLOCK: u16 = 0x0300;
+    const NAME: u16 = 0x0310;
+    const BUFFER: u16 = 0x0400;
+
+    fn command(bus: &mut Bus, command: u8, mode: u8, pointer: u16, length: u16) -> u8 {
+        bus.write_byte(BLOCK + 1, mode);
+        bus.write_byte(BLOCK + 2, pointer as u8);
+        bus.write_byte(BLOCK + 3, (pointer >> 8) as u8);
+        bus.write_byte(BLOCK + 4, length as u8);
+        bus.write_byte(BLOCK + 5, (length >> 8) as u8);
+        bus.write_byte(BASE, BLOCK as u8);
+        bus.write_byte(BASE + 1, (BLOCK >> 8) as u8);
+        bus.write_byte(BASE + 2, command);
+        bus.read_byte(BASE + 3)
+    }
+
+    fn set_name(bus: &mut Bus, name: &str) {
+        bus.load_rom_at(name.as_bytes(), NAME).unwrap();
+        bus.write_byte(NAME + name.len() as u16, 0);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let root = std::env::temp_dir().join(format!("rusty502_file_io_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+
+        let mut bus = Bus::new();
+        bus.add_device(BASE, BASE + 0x0F, Arc::new(Mutex::new(FileIo::new(&root).unwrap())));
+
+        // Save
+        set_name(&mut bus, "PROGRAM.BAS");
+        assert_eq!(command(&mut bus, COMMAND_OPEN, MODE_WRITE, NAME, 0), STATUS_OK);
+        let handle = bus.read_byte(BLOCK);
+        assert_eq!(handle, 1);
+        bus.load_rom_at(b"10 PRINT \"HI\"\n", BUFFER).unwrap();
+        assert_eq!(command(&mut bus, COMMAND_WRITE, 0, BUFFER, 14), STATUS_OK);
+        assert_eq!(command(&mut bus, COMMAND_CLOSE, 0, 0, 0), STATUS_OK);
+        assert_eq!(fs::read(root.join("PROGRAM.BAS")).unwrap(), b"10 PRINT \"HI\"\n");
+
+        // Load, skipping the line number
+        assert_eq!(command(&mut bus, COMMAND_OPEN, MODE_READ, NAME, 0), STATUS_OK);
+        bus.load_rom_at(&[3, 0, 0, 0], BLOCK + 6).unwrap();
+        assert_eq!(command(&mut bus, COMMAND_SEEK, 0, 0, 0), STATUS_OK);
+        assert_eq!(command(&mut bus, COMMAND_READ, 0, BUFFER + 0x100, 64), STATUS_OK);
+        assert_eq!(bus.read_byte(BLOCK + 4), 11);
+        assert_eq!(bus.read_byte(BUFFER + 0x100), b'P');
+        assert_eq!(command(&mut bus, COMMAND_CLOSE, 0, 0, 0), STATUS_OK);
+        assert_eq!(command(&mut bus, COMMAND_CLOSE, 0, 0, 0), STATUS_BAD_HANDLE);
+
+        // Nothing outside the sandbox
+        set_name(&mut bus, "../escape");
+        assert_eq!(command(&mut bus, COMMAND_OPEN, MODE_WRITE, NAME, 0), STATUS_DENIED);
+        set_name(&mut bus, "/etc/passwd");
+        assert_eq!(command(&mut bus, COMMAND_OPEN, MODE_READ, NAME, 0), STATUS_DENIED);
+        set_name(&mut bus, "MISSING");
+        assert_eq!(command(&mut bus, COMMAND_OPEN, MODE_READ, NAME, 0), STATUS_NOT_FOUND);
+
+        set_name(&mut bus, "PROGRAM.BAS");
+        assert_eq!(command(&mut bus, COMMAND_DELETE, 0, NAME, 0), STATUS_OK);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
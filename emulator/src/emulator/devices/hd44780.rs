@@ -0,0 +1,467 @@
+/**
+ * An HD44780 character LCD (16x2), wired to the ports of a VIA the way Ben
+ * Eater's 6502 computer does it: the eight data lines on port B and the
+ * RS, RW and E control lines on PA5, PA6 and PA7.
+ *
+ * The controller latches a command (RS low) or a character (RS high) on
+ * the falling edge of E while RW is low, and drives the busy flag and
+ * address counter (RS low) or display RAM (RS high) onto the data lines
+ * while E is high and RW is high. Commands keep the busy flag set for as
+ * long as the datasheet says they take, so programs that skip waiting for
+ * it lose characters just as they would on the real thing.
+ *
+ * Both 8-bit and 4-bit interfaces work; in 4-bit mode only D4-D7 are used
+ * and every byte is sent as two nibbles, high first.
+ *
+ * The display is drawn to the terminal in a box, whenever what it shows
+ * changes and the program has turned it on.
+ */
+use std::io::Write;
+
+use super::port::{Pin, Port, PortDevice};
+
+pub const COLUMNS: usize = 16;
+pub const ROWS: usize = 2;
+
+// Commands, by their highest set bit
+const CLEAR: u8 = 0x01;
+const HOME: u8 = 0x02;
+const ENTRY_MODE: u8 = 0x04;
+const DISPLAY_CONTROL: u8 = 0x08;
+const SHIFT: u8 = 0x10;
+const FUNCTION_SET: u8 = 0x20;
+const SET_CGRAM: u8 = 0x40;
+const SET_DDRAM: u8 = 0x80;
+
+// Execution times, in microseconds
+const CLEAR_US: f64 = 1520.0;
+const COMMAND_US: f64 = 37.0;
+
+// Display RAM is 40 characters per line, the second line starting at $40
+const LINE_LENGTH: u8 = 40;
+const SECOND_LINE: u8 = 0x40;
+
+pub struct Hd44780 {
+    data: [Pin; 8],
+    rs: Pin,
+    rw: Pin,
+    e: Pin,
+    render: bool,
+
+    levels: [u8; 2], // Pin levels of both ports, as driven by the VIA
+    ddrs: [u8; 2],   // Which pins of each port the VIA drives
+    enable: bool,
+
+    ddram: [u8; 0x80],
+    cgram: [u8; 0x40],
+    address: u8,
+    cgram_selected: bool, // Whether the address counter points into CGRAM
+    increment: bool,
+    shift_display: bool,
+    display_on: bool,
+    shift: u8, // How far the display is scrolled
+    eight_bit: bool,
+    two_lines: bool,
+    pending_nibble: Option<u8>, // High nibble of a 4-bit write
+    read_latch: Option<u8>,     // Byte whose low nibble a 4-bit read gives next
+
+    hz: f64,
+    busy_until: u64,
+    last_drawn: Option<Vec<String>>,
+}
+
+impl Hd44780 {
+    pub fn new(data: [Pin; 8], rs: Pin, rw: Pin, e: Pin) -> Self {
+        Self {
+            data,
+            rs,
+            rw,
+            e,
+            render: true,
+            levels: [0xFF; 2],
+            ddrs: [0x00; 2],
+            enable: false,
+            ddram: [b' '; 0x80],
+            cgram: [0; 0x40],
+            address: 0,
+            cgram_selected: false,
+            increment: true,
+            shift_display: false,
+            display_on: false,
+            shift: 0,
+            eight_bit: true,
+            two_lines: false,
+            pending_nibble: None,
+            read_latch: None,
+            hz: 1_000_000.0,
+            busy_until: 0,
+            last_drawn: None,
+        }
+    }
+
+    // Data on port B, RS/RW/E on PA5/PA6/PA7, as in Ben Eater's schematic
+    pub fn ben_eater() -> Self {
+        let data = std::array::from_fn(|bit| Pin::new(Port::B, bit as u8));
+        Self::new(
+            data,
+            Pin::new(Port::A, 5),
+            Pin::new(Port::A, 6),
+            Pin::new(Port::A, 7),
+        )
+    }
+
+    // Turn drawing to the terminal on or off (tests run headless)
+    pub fn set_render(&mut self, render: bool) {
+        self.render = render;
+    }
+
+    pub fn is_display_on(&self) -> bool {
+        self.display_on
+    }
+
+    pub fn cursor(&self) -> u8 {
+        self.address
+    }
+
+    // The characters currently shown, one string per row
+    pub fn text(&self) -> Vec<String> {
+        let rows = if self.two_lines { ROWS } else { 1 };
+        (0..rows)
+            .map(|row| {
+                (0..COLUMNS)
+                    .map(|column| {
+                        let offset = (column as u8 + self.shift) % LINE_LENGTH;
+                        let address = row as u8 * SECOND_LINE + offset;
+                        glyph(self.ddram[address as usize])
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn level(&self, pin: Pin) -> bool {
+        pin.level(self.levels[pin.port.index()])
+    }
+
+    fn bus_value(&self) -> u8 {
+        (0..8).fold(0, |value, bit| {
+            value | (self.level(self.data[bit]) as u8) << bit
+        })
+    }
+
+    fn busy(&self, now: u64) -> bool {
+        now < self.busy_until
+    }
+
+    fn keep_busy(&mut self, now: u64, microseconds: f64) {
+        self.busy_until = now + (self.hz * microseconds / 1_000_000.0) as u64;
+    }
+
+    // Move the address counter on after a read or write
+    fn step_address(&mut self) {
+        if self.cgram_selected {
+            self.address = if self.increment {
+                self.address.wrapping_add(1)
+            } else {
+                self.address.wrapping_sub(1)
+            } & 0x3F;
+            return;
+        }
+
+        // Display RAM wraps from the end of one line to the start of the other
+        let (line, column) = (self.address & SECOND_LINE, self.address & 0x3F);
+        self.address = match (self.increment, self.two_lines) {
+            (true, true) if column + 1 >= LINE_LENGTH => line ^ SECOND_LINE,
+            (true, false) if self.address + 1 >= 2 * LINE_LENGTH => 0,
+            (true, _) => self.address + 1,
+            (false, true) if column == 0 => (line ^ SECOND_LINE) + LINE_LENGTH - 1,
+            (false, false) if self.address == 0 => 2 * LINE_LENGTH - 1,
+            (false, _) => self.address - 1,
+        };
+    }
+
+    fn write_data(&mut self, data: u8) {
+        if self.cgram_selected {
+            self.cgram[self.address as usize & 0x3F] = data;
+        } else {
+            self.ddram[self.address as usize & 0x7F] = data;
+        }
+        self.step_address();
+        if self.shift_display && !self.cgram_selected {
+            self.scroll(self.increment);
+        }
+    }
+
+    fn read_data(&mut self) -> u8 {
+        let data = if self.cgram_selected {
+            self.cgram[self.address as usize & 0x3F]
+        } else {
+            self.ddram[self.address as usize & 0x7F]
+        };
+        self.step_address();
+        data
+    }
+
+    fn scroll(&mut self, left: bool) {
+        self.shift = if left {
+            (self.shift + 1) % LINE_LENGTH
+        } else {
+            (self.shift + LINE_LENGTH - 1) % LINE_LENGTH
+        };
+    }
+
+    fn command(&mut self, command: u8, now: u64) {
+        self.keep_busy(now, COMMAND_US);
+        if command == 0 {
+            return;
+        }
+        match 0x80 >> command.leading_zeros().min(7) {
+            SET_DDRAM => {
+                self.address = command & 0x7F;
+                self.cgram_selected = false;
+            }
+            SET_CGRAM => {
+                self.address = command & 0x3F;
+                self.cgram_selected = true;
+            }
+            FUNCTION_SET => {
+                self.eight_bit = command & 0x10 != 0;
+                self.two_lines = command & 0x08 != 0;
+                self.pending_nibble = None;
+            }
+            SHIFT => {
+                let right = command & 0x04 != 0;
+                if command & 0x08 != 0 {
+                    self.scroll(!right);
+                } else {
+                    let increment = self.increment;
+                    self.increment = right;
+                    self.step_address();
+                    self.increment = increment;
+                }
+            }
+            // The cursor and blink bits aren't drawn
+            DISPLAY_CONTROL => self.display_on = command & 0x04 != 0,
+            ENTRY_MODE => {
+                self.increment = command & 0x02 != 0;
+                self.shift_display = command & 0x01 != 0;
+            }
+            HOME => {
+                self.address = 0;
+                self.cgram_selected = false;
+                self.shift = 0;
+                self.keep_busy(now, CLEAR_US);
+            }
+            CLEAR => {
+                self.ddram = [b' '; 0x80];
+                self.address = 0;
+                self.cgram_selected = false;
+                self.shift = 0;
+                self.increment = true;
+                self.keep_busy(now, CLEAR_US);
+            }
+            _ => {}
+        }
+    }
+
+    // E fell with RW low: take the byte (or nibble) on the data lines
+    fn latch(&mut self, now: u64) {
+        let value = self.bus_value();
+        let byte = if self.eight_bit {
+            value
+        } else {
+            match self.pending_nibble.take() {
+                Some(high) => high | value >> 4,
+                None => {
+                    self.pending_nibble = Some(value & 0xF0);
+                    return;
+                }
+            }
+        };
+
+        // While busy the controller ignores everything but its busy flag
+        if self.busy(now) {
+            return;
+        }
+        if self.level(self.rs) {
+            self.write_data(byte);
+            self.keep_busy(now, COMMAND_US);
+        } else {
+            self.command(byte, now);
+        }
+        self.draw();
+    }
+
+    // What the controller drives onto the data lines for a read at `now`
+    fn read_value(&mut self, now: u64) -> u8 {
+        if self.level(self.rs) {
+            if self.busy(now) {
+                return 0xFF;
+            }
+            self.read_data()
+        } else {
+            (self.busy(now) as u8) << 7 | self.address & 0x7F
+        }
+    }
+
+    fn draw(&mut self) {
+        if !self.render || !self.display_on {
+            return;
+        }
+        let text = self.text();
+        if self.last_drawn.as_ref() == Some(&text) {
+            return;
+        }
+
+        // Draw over the box drawn last time, leaving the cursor on the line below
+        let mut stdout = std::io::stdout();
+        if let Some(last) = &self.last_drawn {
+            let _ = write!(stdout, "\x1B[{}F", last.len() + 2);
+        }
+        let border = "─".repeat(COLUMNS);
+        let _ = write!(stdout, "\r\x1B[K┌{}┐\n", border);
+        for row in &text {
+            let _ = write!(stdout, "\r\x1B[K│{}│\n", row);
+        }
+        let _ = write!(stdout, "\r\x1B[K└{}┘\n", border);
+        let _ = stdout.flush();
+        self.last_drawn = Some(text);
+    }
+}
+
+// What a character code looks like in the A00 (Japanese) character ROM
+fn glyph(code: u8) -> char {
+    match code {
+        0x00..=0x0F => '▒', // Custom characters from CGRAM
+        0x5C => '¥',
+        0x7E => '→',
+        0x7F => '←',
+        0x20..=0x7D => code as char,
+        0xDF => '°',
+        0xFF => '█',
+        _ => '?',
+    }
+}
+
+impl PortDevice for Hd44780 {
+    fn name(&self) -> &str {
+        "hd44780"
+    }
+
+    fn pins_changed(&mut self, port: Port, pins: u8, ddr: u8, now: u64) {
+        self.levels[port.index()] = pins;
+        self.ddrs[port.index()] = ddr;
+
+        // E has no pull-up, so it's low until the VIA drives it
+        let enable = self.level(self.e) && self.e.level(self.ddrs[self.e.port.index()]);
+        let falling = self.enable && !enable;
+        self.enable = enable;
+        if falling && !self.level(self.rw) {
+            self.latch(now);
+        }
+    }
+
+    fn drive(&mut self, port: Port, now: u64) -> u8 {
+        let first_bit = if self.eight_bit { 0 } else { 4 };
+        let on_port = self.data[first_bit..].iter().any(|pin| pin.port == port);
+        if !self.enable || !self.level(self.rw) || !on_port {
+            return 0xFF;
+        }
+
+        // In 4-bit mode reads come a nibble at a time, high first
+        let value = match self.read_latch.take() {
+            Some(latched) => latched << 4,
+            None => {
+                let value = self.read_value(now);
+                if !self.eight_bit {
+                    self.read_latch = Some(value);
+                }
+                value
+            }
+        };
+
+        let pins = &self.data[first_bit..];
+        let mut level = 0xFF;
+        for (index, pin) in pins.iter().enumerate() {
+            if pin.port == port && value & 1 << (first_bit + index) == 0 {
+                level &= !pin.mask();
+            }
+        }
+        level
+    }
+
+    fn clock_rate_changed(&mut self, hz: f64) {
+        self.hz = hz;
+    }
+
+    fn reset(&mut self) {
+        // The LCD has no reset line; only the interface state starts over
+        self.levels = [0xFF; 2];
+        self.ddrs = [0x00; 2];
+        self.enable = false;
+        self.busy_until = 0;
+        self.pending_nibble = None;
+        self.read_latch = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::devices::{via::Via, Device};
+    use std::sync::{Arc, Mutex};
+
+    // Put `value` on port B and pulse E with RS and RW as given
+    fn send(via: &mut Via, now: &mut u64, rs: bool, value: u8) {
+        let control = if rs { 0x20 } else { 0x00 };
+        via.tick(*now);
+        via.write(0x0, value);
+        via.write(0x1, control);
+        via.write(0x1, control | 0x80);
+        via.write(0x1, control);
+        *now += 2000;
+    }
+
+    #[test]
+    fn test_hello_world() {
+        let lcd = Arc::new(Mutex::new(Hd44780::ben_eater()));
+        lcd.lock().unwrap().set_render(false);
+        let mut via = Via::new();
+        via.attach(lcd.clone());
+        via.write(0x2, 0xFF); // Port B all outputs
+        via.write(0x3, 0xE0); // PA5-PA7 outputs
+
+        let mut now = 0;
+        send(&mut via, &mut now, false, 0x38); // 8-bit, 2 lines, 5x8
+        send(&mut via, &mut now, false, 0x0E); // Display and cursor on
+        send(&mut via, &mut now, false, 0x06); // Increment, no shift
+        send(&mut via, &mut now, false, 0x01); // Clear
+        for byte in b"Hello, world!" {
+            send(&mut via, &mut now, true, *byte);
+        }
+        send(&mut via, &mut now, false, 0xC0); // Second line
+        send(&mut via, &mut now, true, 0xDF);
+
+        let text = lcd.lock().unwrap().text();
+        assert_eq!(text, ["Hello, world!   ", "°               "]);
+
+        // Read the busy flag and address: port B as inputs, RW high, E high
+        via.tick(now);
+        via.write(0x2, 0x00);
+        via.write(0x1, 0x40);
+        via.write(0x1, 0xC0);
+        assert_eq!(via.read(0x0), 0x41);
+        via.write(0x1, 0x40);
+
+        // A clear keeps it busy for 1.52 ms, and anything sent meanwhile is lost
+        via.write(0x2, 0xFF);
+        let mut during = now + 1000;
+        send(&mut via, &mut now, false, 0x01);
+        send(&mut via, &mut during, true, b'X');
+        via.write(0x2, 0x00);
+        via.write(0x1, 0x40);
+        via.write(0x1, 0xC0);
+        assert_eq!(via.read(0x0), 0x80);
+        assert_eq!(lcd.lock().unwrap().text()[0], " ".repeat(COLUMNS));
+    }
+}
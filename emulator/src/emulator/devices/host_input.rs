@@ -0,0 +1,71 @@
+/**
+ * Key presses coming from the host, either typed on the terminal or
+ * scripted ahead of time (for tests and demos).
+ *
+ * Terminal input is read on a background thread so the emulator never
+ * blocks waiting for a key. The terminal is left in its normal line mode,
+ * so typed keys only reach the emulator once Enter is pressed.
+ */
+use std::collections::VecDeque;
+use std::io::Read;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+pub struct HostInput {
+    receiver: Option<Receiver<u8>>,
+    script: VecDeque<u8>,
+}
+
+impl HostInput {
+    // Keys typed on the host terminal
+    pub fn stdin() -> Self {
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            let mut stdin = std::io::stdin();
+            let mut buffer = [0u8; 64];
+            while let Ok(count) = stdin.read(&mut buffer) {
+                if count == 0 {
+                    break;
+                }
+                for byte in &buffer[..count] {
+                    if sender.send(*byte).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        Self {
+            receiver: Some(receiver),
+            script: VecDeque::new(),
+        }
+    }
+
+    // A fixed sequence of keys
+    pub fn scripted(keys: &[u8]) -> Self {
+        Self {
+            receiver: None,
+            script: keys.iter().copied().collect(),
+        }
+    }
+
+    // Queue more scripted keys
+    pub fn push(&mut self, keys: &[u8]) {
+        self.script.extend(keys);
+    }
+
+    // The next key, if one is waiting
+    pub fn try_read(&mut self) -> Option<u8> {
+        if let Some(key) = self.script.pop_front() {
+            return Some(key);
+        }
+
+        self.receiver.as_ref()?.try_recv().ok()
+    }
+
+    // Whether no more keys can ever arrive
+    pub fn is_exhausted(&self) -> bool {
+        self.receiver.is_none() && self.script.is_empty()
+    }
+}
@@ -0,0 +1,178 @@
+/**
+ * A debug port that lets programs running in the emulator talk to the host.
+ *
+ * Meant for test programs run in CI: they print through the putchar
+ * register and report pass or fail by writing an exit status, which ends
+ * `Emulator::run` and becomes the emulator's process exit code.
+ *
+ * Registers:
+ *
+ *   $00     (W) putchar: write a byte to the host's stdout
+ *   $01     (W) exit: stop the emulator with this exit status
+ *   $02     (W) shutdown: stop the emulator with exit status 0
+ *   $03     (W) latch: latch the host time and the cycle counter
+ *   $04-$0B (R) host time in milliseconds since the Unix epoch (little endian)
+ *   $0C-$13 (R) CPU cycles since reset (little endian)
+ */
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::Device;
+
+pub const PUTCHAR: u16 = 0x00;
+pub const EXIT: u16 = 0x01;
+pub const SHUTDOWN: u16 = 0x02;
+pub const LATCH: u16 = 0x03;
+pub const TIME: u16 = 0x04;
+pub const CYCLES: u16 = 0x0C;
+
+enum Output {
+    Stdout,
+    Buffer(Vec<u8>),
+}
+
+pub struct HostInterface {
+    output: Output,
+    exit_status: Option<i32>,
+    time: u64,
+    cycles: u64,
+    last_cycle: u64,
+}
+
+impl Default for HostInterface {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HostInterface {
+    // Characters go to stdout
+    pub fn new() -> Self {
+        Self {
+            output: Output::Stdout,
+            exit_status: None,
+            time: 0,
+            cycles: 0,
+            last_cycle: 0,
+        }
+    }
+
+    // Characters are kept in memory, for tests
+    pub fn captured() -> Self {
+        Self {
+            output: Output::Buffer(Vec::new()),
+            ..Self::new()
+        }
+    }
+
+    // Everything written so far, if the output is captured
+    pub fn output(&self) -> Option<&[u8]> {
+        match &self.output {
+            Output::Stdout => None,
+            Output::Buffer(buffer) => Some(buffer),
+        }
+    }
+
+    fn putchar(&mut self, data: u8) {
+        match &mut self.output {
+            Output::Stdout => {
+                let mut stdout = io::stdout();
+                let _ = stdout.write_all(&[data]);
+                let _ = stdout.flush();
+            }
+            Output::Buffer(buffer) => buffer.push(data),
+        }
+    }
+
+    fn latch(&mut self) {
+        self.time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or(0);
+        self.cycles = self.last_cycle;
+    }
+}
+
+impl Device for HostInterface {
+    fn name(&self) -> &str {
+        "host_interface"
+    }
+
+    fn read(&mut self, offset: u16) -> u8 {
+        self.peek(offset)
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        match offset {
+            TIME..=0x0B => self.time.to_le_bytes()[(offset - TIME) as usize],
+            CYCLES..=0x13 => self.cycles.to_le_bytes()[(offset - CYCLES) as usize],
+            _ => 0x00,
+        }
+    }
+
+    fn write(&mut self, offset: u16, data: u8) {
+        match offset {
+            PUTCHAR => self.putchar(data),
+            EXIT => self.exit_status = Some(data as i32),
+            SHUTDOWN => self.exit_status = Some(0),
+            LATCH => self.latch(),
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, now: u64) {
+        self.last_cycle = now;
+    }
+
+    fn reset(&mut self) {
+        self.exit_status = None;
+        self.time = 0;
+        self.cycles = 0;
+        self.last_cycle = 0;
+    }
+
+    fn exit_status(&self) -> Option<i32> {
+        self.exit_status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Emulator;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_program_output_and_exit() {
+        let host = Arc::new(Mutex::new(HostInterface::captured()));
+        let mut emulator = Emulator::new();
+        emulator.init_bus();
+        emulator.add_device(0x7F00, 0x7F1F, host.clone());
+
+        // lda #'O' / sta $7F00 / lda #'K' / sta $7F00 / lda #3 / sta $7F01 / jmp *
+        let program = [
+            0xA9, b'O', 0x8D, 0x00, 0x7F, 0xA9, b'K', 0x8D, 0x00, 0x7F, 0xA9, 0x03, 0x8D, 0x01,
+            0x7F, 0x4C, 0x0F, 0xC0,
+        ];
+        {
+            let mut bus = emulator.bus.lock().unwrap();
+            bus.load_rom_at(&program, 0xC000).unwrap();
+            bus.load_rom_at(&[0x00, 0xC0], 0xFFFC).unwrap();
+        }
+        emulator.reset();
+
+        while emulator.exit_status().is_none() && emulator.cycles < 1000 {
+            emulator.clock();
+        }
+        assert_eq!(emulator.exit_status(), Some(3));
+        assert_eq!(host.lock().unwrap().output(), Some(&b"OK"[..]));
+
+        // The latch captures the cycle counter
+        let mut host = host.lock().unwrap();
+        host.tick(1234);
+        host.write(LATCH, 0);
+        assert_eq!(host.read(CYCLES), 0xD2);
+        assert_eq!(host.read(CYCLES + 1), 0x04);
+        assert!(host.read(TIME + 5) != 0);
+    }
+}
@@ -0,0 +1,464 @@
+use super::port::{Pin, Port, PortDevice};
+
+// A device on the I2C bus
+pub trait I2cDevice: Send {
+    fn name(&self) -> &str;
+
+    /// The 7 bit address the device answers to.
+    fn address(&self) -> u8;
+
+    /// The device was addressed after a (repeated) start. Returns false to
+    /// NACK the address, e.g. while busy.
+    fn start(&mut self, _read: bool, _now: u64) -> bool {
+        true
+    }
+
+    /// A byte written by the master. Returns whether it is acknowledged.
+    fn write(&mut self, data: u8, now: u64) -> bool;
+
+    /// The next byte to send to the master.
+    fn read(&mut self, now: u64) -> u8;
+
+    /// A stop condition ended the transaction.
+    fn stop(&mut self, _now: u64) {}
+
+    fn clock_rate_changed(&mut self, _hz: f64) {}
+
+    fn reset(&mut self) {}
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Idle,
+    Receive,  // Master is sending (address or data)
+    Transmit, // Addressed device is sending
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    Data,
+    Ack,
+}
+
+/**
+ * Decodes I2C bit-banged by the firmware on two open-drain port pins.
+ *
+ * The firmware pulls a line low by making its pin an output driving 0 and
+ * releases it by turning the pin back into an input, letting the pull-up
+ * take the line high. Devices pull SDA low the same way to acknowledge and
+ * to send data, so the level on each line is the wired-AND of everyone.
+ */
+pub struct I2cBus {
+    sda: Pin,
+    scl: Pin,
+    devices: Vec<Box<dyn I2cDevice>>,
+
+    // What the master drives on each port (1 = released)
+    master: [u8; 2],
+
+    // Line levels as of the last update
+    sda_level: bool,
+    scl_level: bool,
+
+    // What the devices drive on SDA (true = released)
+    sda_out: bool,
+
+    mode: Mode,
+    phase: Phase,
+    addressing: bool,
+    reading: bool,
+    target: Option<usize>,
+    bit: u8,
+    shift: u8,
+    master_ack: bool,
+
+    now: u64,
+}
+
+impl I2cBus {
+    pub fn new(sda: Pin, scl: Pin) -> Self {
+        Self {
+            sda,
+            scl,
+            devices: Vec::new(),
+            master: [0xFF; 2],
+            sda_level: true,
+            scl_level: true,
+            sda_out: true,
+            mode: Mode::Idle,
+            phase: Phase::Data,
+            addressing: false,
+            reading: false,
+            target: None,
+            bit: 0,
+            shift: 0,
+            master_ack: false,
+            now: 0,
+        }
+    }
+
+    pub fn attach(&mut self, device: Box<dyn I2cDevice>) {
+        self.devices.push(device);
+    }
+
+    fn master_level(&self, pin: Pin) -> bool {
+        pin.level(self.master[pin.port.index()])
+    }
+
+    fn update(&mut self) {
+        let scl = self.master_level(self.scl);
+        let sda = self.master_level(self.sda) && self.sda_out;
+
+        if scl && self.scl_level && sda != self.sda_level {
+            // SDA changing while SCL is high is a start or stop condition
+            self.sda_level = sda;
+            if sda {
+                self.stop();
+            } else {
+                self.start();
+            }
+            return;
+        }
+        self.sda_level = sda;
+
+        if scl != self.scl_level {
+            self.scl_level = scl;
+            if scl {
+                self.rising_edge();
+            } else {
+                self.falling_edge();
+            }
+        }
+    }
+
+    fn start(&mut self) {
+        self.mode = Mode::Receive;
+        self.phase = Phase::Data;
+        self.addressing = true;
+        self.bit = 0;
+        self.shift = 0;
+        self.sda_out = true;
+    }
+
+    fn stop(&mut self) {
+        if let Some(target) = self.target.take() {
+            self.devices[target].stop(self.now);
+        }
+        self.mode = Mode::Idle;
+        self.sda_out = true;
+    }
+
+    fn rising_edge(&mut self) {
+        match (self.mode, self.phase) {
+            (Mode::Receive, Phase::Data) => {
+                self.shift = self.shift << 1 | self.sda_level as u8;
+                self.bit += 1;
+            }
+            (Mode::Transmit, Phase::Data) => self.bit += 1,
+            (Mode::Transmit, Phase::Ack) => self.master_ack = !self.sda_level,
+            _ => {}
+        }
+    }
+
+    fn falling_edge(&mut self) {
+        match (self.mode, self.phase) {
+            (Mode::Receive, Phase::Data) if self.bit == 8 => {
+                let ack = self.receive_byte();
+                self.sda_out = !ack;
+                self.phase = Phase::Ack;
+            }
+            (Mode::Transmit, Phase::Data) => {
+                if self.bit == 8 {
+                    // Let the master acknowledge
+                    self.sda_out = true;
+                    self.phase = Phase::Ack;
+                } else {
+                    self.sda_out = self.shift & (0x80 >> self.bit) != 0;
+                }
+            }
+            (_, Phase::Ack) => self.end_ack(),
+            _ => {}
+        }
+    }
+
+    // Handle a byte from the master; returns whether it was acknowledged
+    fn receive_byte(&mut self) -> bool {
+        let byte = self.shift;
+
+        if self.addressing {
+            self.addressing = false;
+            self.reading = byte & 0x01 != 0;
+
+            let address = byte >> 1;
+            let now = self.now;
+            let reading = self.reading;
+            self.target = self
+                .devices
+                .iter_mut()
+                .position(|device| device.address() == address && device.start(reading, now));
+            return self.target.is_some();
+        }
+
+        match self.target {
+            Some(target) => self.devices[target].write(byte, self.now),
+            None => false,
+        }
+    }
+
+    // The ninth clock pulse is over
+    fn end_ack(&mut self) {
+        self.sda_out = true;
+        self.phase = Phase::Data;
+        self.bit = 0;
+        self.shift = 0;
+
+        let Some(target) = self.target else {
+            self.mode = Mode::Idle;
+            return;
+        };
+
+        let send_next = match self.mode {
+            Mode::Receive => self.reading,
+            Mode::Transmit => self.master_ack,
+            Mode::Idle => false,
+        };
+
+        if send_next {
+            self.mode = Mode::Transmit;
+            self.shift = self.devices[target].read(self.now);
+            self.sda_out = self.shift & 0x80 != 0;
+        } else if self.mode == Mode::Transmit {
+            // NACK from the master: it is done reading and will send a stop
+            self.mode = Mode::Idle;
+        }
+    }
+}
+
+impl PortDevice for I2cBus {
+    fn name(&self) -> &str {
+        "i2c_bus"
+    }
+
+    fn pins_changed(&mut self, port: Port, pins: u8, ddr: u8, now: u64) {
+        // A pin only pulls its line low when it is an output driving 0
+        self.master[port.index()] = !(ddr & !pins);
+        self.now = now;
+        self.update();
+    }
+
+    fn drive(&mut self, port: Port, _now: u64) -> u8 {
+        if port == self.sda.port && !self.sda_out {
+            !self.sda.mask()
+        } else {
+            0xFF
+        }
+    }
+
+    fn clock_rate_changed(&mut self, hz: f64) {
+        for device in &mut self.devices {
+            device.clock_rate_changed(hz);
+        }
+    }
+
+    fn reset(&mut self) {
+        for device in &mut self.devices {
+            device.reset();
+        }
+        self.master = [0xFF; 2];
+        self.sda_level = true;
+        self.scl_level = true;
+        self.sda_out = true;
+        self.mode = Mode::Idle;
+        self.target = None;
+        self.now = 0;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::emulator::devices::ds1307::Ds1307;
+    use crate::emulator::devices::eeprom_24lc::Eeprom24;
+
+    // Bit-bang I2C on port A: SDA = PA0, SCL = PA1. A line is pulled low by
+    // making its pin an output (the output register stays 0).
+    pub(crate) struct Master {
+        pub bus: I2cBus,
+        pub ddr: u8,
+        pub now: u64,
+
+        // Cycles spent waiting for a device to release SCL
+        pub stretched: u64,
+
+        // Cycles SCL is held in each half of a clock period
+        pub half_period: u64,
+    }
+
+    impl Master {
+        pub fn new(bus: I2cBus) -> Self {
+            Self {
+                bus,
+                ddr: 0,
+                now: 0,
+                stretched: 0,
+                half_period: 5,
+            }
+        }
+
+        pub fn set(&mut self, sda: bool, scl: bool) {
+            self.ddr = (!sda as u8) | (!scl as u8) << 1;
+            self.now += self.half_period;
+            self.bus.pins_changed(Port::A, 0x00, self.ddr, self.now);
+
+            // After releasing SCL, wait while a device stretches the clock
+            while scl && !self.line(0x02) {
+                assert!(self.stretched < 1_000_000, "SCL held low");
+                self.now += 1;
+                self.stretched += 1;
+            }
+        }
+
+        fn line(&mut self, mask: u8) -> bool {
+            let master = self.ddr & mask == 0;
+            master && self.bus.drive(Port::A, self.now) & mask != 0
+        }
+
+        pub fn sda(&mut self) -> bool {
+            self.line(0x01)
+        }
+
+        pub fn start(&mut self) {
+            self.set(true, true);
+            self.set(false, true);
+            self.set(false, false);
+        }
+
+        pub fn stop(&mut self) {
+            self.set(false, false);
+            self.set(false, true);
+            self.set(true, true);
+        }
+
+        // Send a byte, returning whether it was acknowledged
+        pub fn send(&mut self, byte: u8) -> bool {
+            for bit in (0..8).rev() {
+                let level = byte >> bit & 1 != 0;
+                self.set(level, false);
+                self.set(level, true);
+                self.set(level, false);
+            }
+            self.set(true, false);
+            self.set(true, true);
+            let ack = !self.sda();
+            self.set(true, false);
+            ack
+        }
+
+        pub fn receive(&mut self, ack: bool) -> u8 {
+            let mut byte = 0;
+            for _ in 0..8 {
+                self.set(true, false);
+                self.set(true, true);
+                byte = byte << 1 | self.sda() as u8;
+            }
+            self.set(!ack, false);
+            self.set(!ack, true);
+            self.set(!ack, false);
+            byte
+        }
+    }
+
+    fn master() -> Master {
+        let mut bus = I2cBus::new(Pin::new(Port::A, 0), Pin::new(Port::A, 1));
+        // 2024-02-29 23:59:58 UTC, a Thursday
+        bus.attach(Box::new(Ds1307::at(1_709_251_198)));
+        bus.attach(Box::new(Eeprom24::lc256()));
+        Master::new(bus)
+    }
+
+    #[test]
+    fn test_ds1307_read() {
+        let mut master = master();
+
+        // Set the register pointer, then read with a repeated start
+        master.start();
+        assert!(master.send(0xD0));
+        assert!(master.send(0x00));
+        master.start();
+        assert!(master.send(0xD1));
+        let mut time = Vec::new();
+        for _ in 0..6 {
+            time.push(master.receive(true));
+        }
+        time.push(master.receive(false));
+
+        // After the NACK the RTC lets go of SDA so the master can stop
+        assert!(master.sda());
+        master.stop();
+        assert_eq!(time, vec![0x58, 0x59, 0x23, 5, 0x29, 0x02, 0x24]);
+
+        // The DS1307 and the EEPROM never stretch the clock
+        assert_eq!(master.stretched, 0);
+    }
+
+    #[test]
+    fn test_eeprom_page_write() {
+        let mut master = master();
+
+        master.start();
+        assert!(master.send(0xA0));
+        assert!(master.send(0x12));
+        assert!(master.send(0x40));
+        for byte in [0xDE, 0xAD, 0xBE, 0xEF] {
+            assert!(master.send(byte));
+        }
+        master.stop();
+
+        // Acknowledge polling: NACKed until the write cycle is over
+        let written = master.now;
+        loop {
+            master.start();
+            if master.send(0xA0) {
+                break;
+            }
+            master.stop();
+        }
+        assert!(master.now - written >= 5_000);
+
+        // A slow clock makes no difference
+        master.half_period = 500;
+        assert!(master.send(0x12));
+        assert!(master.send(0x40));
+        master.start();
+        assert!(master.send(0xA1));
+        let data: Vec<u8> = (0..4).map(|i| master.receive(i < 3)).collect();
+        master.stop();
+        assert_eq!(data, vec![0xDE, 0xAD, 0xBE, 0xEF]);
+        assert_eq!(master.stretched, 0);
+    }
+
+    #[test]
+    fn test_start_and_stop() {
+        let mut master = master();
+
+        // Nothing answers at an unused address, and data after a NACKed
+        // address is ignored until the next start
+        master.start();
+        assert!(!master.send(0x42 << 1));
+        assert!(!master.send(0x00));
+        master.stop();
+
+        // Clocking bytes outside a transaction gets no acknowledge
+        assert!(!master.send(0xD0));
+
+        // A stop in the middle of a write leaves the EEPROM unchanged
+        master.start();
+        assert!(master.send(0xA0));
+        assert!(master.send(0x00));
+        master.stop();
+        master.start();
+        assert!(master.send(0xA1));
+        assert_eq!(master.receive(false), 0xFF);
+        master.stop();
+    }
+}
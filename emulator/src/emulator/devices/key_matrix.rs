@@ -0,0 +1,205 @@
+use std::collections::{HashMap, VecDeque};
+
+use super::host_input::HostInput;
+use super::port::{Port, PortDevice};
+
+/**
+ * An 8x8 key matrix scanned through two ports.
+ *
+ * The firmware drives one column low at a time on the column port and reads
+ * the row port: every pressed key in a driven column pulls its row low.
+ * Columns that aren't outputs, or are driven high, don't affect the rows.
+ */
+pub struct KeyMatrix {
+    column_port: Port,
+    row_port: Port,
+
+    // Bit r of pressed[c] is set while the key at column c, row r is down
+    pressed: [u8; 8],
+
+    // Column levels as last driven by the port
+    columns: u8,
+
+    // Host keys mapped to matrix positions, and how long each is held
+    input: Option<HostInput>,
+    keymap: HashMap<u8, (u8, u8)>,
+    hold_cycles: u64,
+    poll_cycles: u64,
+
+    // Scripted presses and releases: (cycle, column, row, pressed)
+    script: VecDeque<(u64, u8, u8, bool)>,
+
+    last_poll: u64,
+    last_cycle: u64,
+}
+
+impl KeyMatrix {
+    pub fn new(column_port: Port, row_port: Port) -> Self {
+        let mut matrix = Self {
+            column_port,
+            row_port,
+            pressed: [0; 8],
+            columns: 0xFF,
+            input: None,
+            keymap: HashMap::new(),
+            hold_cycles: 0,
+            poll_cycles: 0,
+            script: VecDeque::new(),
+            last_poll: 0,
+            last_cycle: 0,
+        };
+        matrix.clock_rate_changed(1_000_000.0);
+        matrix
+    }
+
+    pub fn press(&mut self, column: u8, row: u8) {
+        self.pressed[column as usize & 7] |= 1 << (row & 7);
+    }
+
+    pub fn release(&mut self, column: u8, row: u8) {
+        self.pressed[column as usize & 7] &= !(1 << (row & 7));
+    }
+
+    pub fn is_pressed(&self, column: u8, row: u8) -> bool {
+        self.pressed[column as usize & 7] & (1 << (row & 7)) != 0
+    }
+
+    // Press a key at `cycle` and release it again `hold` cycles later
+    pub fn schedule_press(&mut self, cycle: u64, hold: u64, column: u8, row: u8) {
+        self.script.push_back((cycle, column, row, true));
+        self.script.push_back((cycle + hold, column, row, false));
+        self.script.make_contiguous().sort_by_key(|event| event.0);
+    }
+
+    // Map host characters to matrix positions
+    pub fn set_keymap(&mut self, keymap: HashMap<u8, (u8, u8)>) {
+        self.keymap = keymap;
+    }
+
+    pub fn set_input(&mut self, input: HostInput) {
+        self.input = Some(input);
+    }
+
+    // The row levels seen with the current column drive
+    fn rows(&self) -> u8 {
+        let mut rows = 0xFF;
+        for column in 0..8 {
+            if self.columns & (1 << column) == 0 {
+                rows &= !self.pressed[column];
+            }
+        }
+        rows
+    }
+
+    fn poll_input(&mut self, now: u64) {
+        self.last_poll = now;
+        let mut at = now.max(self.script.back().map_or(0, |event| event.0));
+
+        while let Some(key) = self.input.as_mut().and_then(|input| input.try_read()) {
+            if let Some((column, row)) = self.keymap.get(&key).copied() {
+                // Type keys one after another, each held for a while
+                self.schedule_press(at, self.hold_cycles, column, row);
+                at += self.hold_cycles * 2;
+            }
+        }
+    }
+
+    fn run_script(&mut self, now: u64) {
+        while let Some((cycle, column, row, pressed)) = self.script.front().copied() {
+            if cycle > now {
+                break;
+            }
+            self.script.pop_front();
+
+            if pressed {
+                self.press(column, row);
+            } else {
+                self.release(column, row);
+            }
+        }
+    }
+}
+
+impl PortDevice for KeyMatrix {
+    fn name(&self) -> &str {
+        "key_matrix"
+    }
+
+    fn pins_changed(&mut self, port: Port, pins: u8, ddr: u8, _now: u64) {
+        if port == self.column_port {
+            // Only columns actively driven low select keys
+            self.columns = pins | !ddr;
+        }
+    }
+
+    fn drive(&mut self, port: Port, now: u64) -> u8 {
+        self.run_script(now);
+        if port == self.row_port {
+            self.rows()
+        } else {
+            0xFF
+        }
+    }
+
+    fn tick(&mut self, now: u64) {
+        if self.input.is_some() && now >= self.last_poll + self.poll_cycles {
+            self.poll_input(now);
+        }
+        self.last_cycle = now;
+        self.run_script(now);
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        let script = self.script.front().map(|event| event.0);
+        let poll = match &self.input {
+            Some(input) if !input.is_exhausted() => Some(self.last_poll + self.poll_cycles),
+            _ => None,
+        };
+
+        match (script, poll) {
+            (Some(script), Some(poll)) => Some(script.min(poll)),
+            (script, poll) => script.or(poll),
+        }
+    }
+
+    fn clock_rate_changed(&mut self, hz: f64) {
+        // Keys are held for 50 ms, and the host is polled every 10 ms
+        self.hold_cycles = ((hz / 20.0) as u64).max(1);
+        self.poll_cycles = ((hz / 100.0) as u64).max(1);
+    }
+
+    fn reset(&mut self) {
+        self.pressed = [0; 8];
+        self.columns = 0xFF;
+        self.script.clear();
+        self.last_poll = 0;
+        self.last_cycle = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::devices::{via::Via, Device};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_scan() {
+        let mut via = Via::new();
+        let matrix = Arc::new(Mutex::new(KeyMatrix::new(Port::A, Port::B)));
+        via.attach(matrix.clone());
+        matrix.lock().unwrap().press(2, 5);
+
+        // Columns on port A are outputs, rows on port B are inputs
+        via.write(0x3, 0xFF);
+        via.write(0x2, 0x00);
+
+        via.write(0x1, !0x01);
+        assert_eq!(via.read(0x0), 0xFF);
+        via.write(0x1, !0x04);
+        assert_eq!(via.read(0x0), !0x20);
+
+        matrix.lock().unwrap().release(2, 5);
+        assert_eq!(via.read(0x0), 0xFF);
+    }
+}
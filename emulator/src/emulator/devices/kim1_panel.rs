@@ -0,0 +1,342 @@
+/**
+ * The KIM-1's six-digit LED display and hex keypad, on the ports of its
+ * 6530-002 RRIOT.
+ *
+ * PB1-PB4 feed a 74145 decoder: outputs 4-9 light one digit each, whose
+ * segments (a-g) are driven high on PA0-PA6, and outputs 0-2 select a row
+ * of the keypad, whose keys pull PA0-PA6 low. The monitor lights the
+ * digits one after another faster than the eye can follow, so the display
+ * is rebuilt by sampling those writes: a digit shows the last pattern
+ * written to it, and goes dark if it isn't refreshed for a while.
+ *
+ * Keypad rows, from PA6 down to PA0:
+ *
+ *   row 0:  0  1  2  3  4  5  6
+ *   row 1:  7  8  9  A  B  C  D
+ *   row 2:  E  F  AD DA +  GO PC
+ *
+ * Host keys: 0-9 and A-F, M for AD (address), N for DA (data), + and G
+ * for GO, P for PC. ST and RS go to NMI and RESET rather than the RRIOT and
+ * aren't available.
+ */
+use std::collections::VecDeque;
+use std::io::Write;
+
+use super::host_input::HostInput;
+use super::port::{Port, PortDevice};
+
+pub const DIGITS: usize = 6;
+
+// The decoder output the first digit is on
+const FIRST_DIGIT: u8 = 4;
+
+// Segment patterns for 0-F, bit 0 = segment a
+const HEX_SEGMENTS: [u8; 16] = [
+    0x3F, 0x06, 0x5B, 0x4F, 0x66, 0x6D, 0x7D, 0x07, 0x7F, 0x6F, 0x77, 0x7C, 0x39, 0x5E, 0x79, 0x71,
+];
+
+pub struct Kim1Panel {
+    // Which decoder output PB1-PB4 select, and the segment lines
+    decoder: u8,
+    segments: u8,
+
+    // Last pattern written to each digit, and when
+    digits: [u8; DIGITS],
+    lit_at: [Option<u64>; DIGITS],
+
+    // Keys down, as (row, PA bit), and scripted presses: (cycle, row, bit, pressed)
+    pressed: Vec<(u8, u8)>,
+    script: VecDeque<(u64, u8, u8, bool)>,
+    input: Option<HostInput>,
+
+    render: bool,
+    last_drawn: Option<[u8; DIGITS]>,
+    next_frame: u64,
+
+    hold_cycles: u64,
+    poll_cycles: u64,
+    frame_cycles: u64,
+    persistence: u64,
+    last_poll: u64,
+    last_cycle: u64,
+}
+
+impl Default for Kim1Panel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Kim1Panel {
+    pub fn new() -> Self {
+        let mut panel = Self {
+            decoder: 0x0F,
+            segments: 0,
+            digits: [0; DIGITS],
+            lit_at: [None; DIGITS],
+            pressed: Vec::new(),
+            script: VecDeque::new(),
+            input: None,
+            render: true,
+            last_drawn: None,
+            next_frame: 0,
+            hold_cycles: 0,
+            poll_cycles: 0,
+            frame_cycles: 0,
+            persistence: 0,
+            last_poll: 0,
+            last_cycle: 0,
+        };
+        panel.clock_rate_changed(1_000_000.0);
+        panel
+    }
+
+    // Turn drawing the display in the terminal on or off
+    pub fn set_render(&mut self, render: bool) {
+        self.render = render;
+    }
+
+    pub fn set_input(&mut self, input: HostInput) {
+        self.input = Some(input);
+    }
+
+    // The segments showing on each digit at `now` (0 when dark)
+    pub fn segments(&self, now: u64) -> [u8; DIGITS] {
+        std::array::from_fn(|digit| {
+            let selected = self.selected_digit() == Some(digit) && self.segments != 0;
+            let recent = self.lit_at[digit].is_some_and(|lit_at| now < lit_at + self.persistence);
+            if selected || recent {
+                self.digits[digit]
+            } else {
+                0
+            }
+        })
+    }
+
+    // The display read as hex digits, with ' ' for dark and '?' for anything else
+    pub fn text(&self, now: u64) -> String {
+        self.segments(now)
+            .iter()
+            .map(|&pattern| match pattern {
+                0 => ' ',
+                pattern => HEX_SEGMENTS
+                    .iter()
+                    .position(|&hex| hex == pattern)
+                    .map_or('?', |digit| char::from_digit(digit as u32, 16).unwrap()),
+            })
+            .map(|digit| digit.to_ascii_uppercase())
+            .collect()
+    }
+
+    // Press a key at `cycle` and let go of it `hold` cycles later
+    pub fn schedule_press(&mut self, cycle: u64, hold: u64, key: u8) {
+        if let Some((row, bit)) = key_position(key) {
+            self.script.push_back((cycle, row, bit, true));
+            self.script.push_back((cycle + hold, row, bit, false));
+            self.script.make_contiguous().sort_by_key(|event| event.0);
+        }
+    }
+
+    fn poll_input(&mut self, now: u64) {
+        self.last_poll = now;
+        let mut at = now.max(self.script.back().map_or(0, |event| event.0));
+
+        while let Some(key) = self.input.as_mut().and_then(|input| input.try_read()) {
+            if key_position(key).is_some() {
+                // Type keys one after another, each held for a while
+                self.schedule_press(at, self.hold_cycles, key);
+                at += self.hold_cycles * 2;
+            }
+        }
+    }
+
+    fn run_script(&mut self, now: u64) {
+        while let Some((cycle, row, bit, pressed)) = self.script.front().copied() {
+            if cycle > now {
+                break;
+            }
+            self.script.pop_front();
+
+            self.pressed.retain(|&key| key != (row, bit));
+            if pressed {
+                self.pressed.push((row, bit));
+            }
+        }
+    }
+
+    fn selected_digit(&self) -> Option<usize> {
+        let digit = self.decoder.wrapping_sub(FIRST_DIGIT) as usize;
+        Some(digit).filter(|&digit| digit < DIGITS)
+    }
+
+    // Latch the segments into the selected digit
+    fn sample(&mut self, now: u64) {
+        if let Some(digit) = self.selected_digit().filter(|_| self.segments != 0) {
+            self.digits[digit] = self.segments;
+            self.lit_at[digit] = Some(now);
+        }
+    }
+
+    fn draw(&mut self, now: u64) {
+        let segments = self.segments(now);
+        if !self.render || self.last_drawn == Some(segments) {
+            return;
+        }
+
+        // Three rows of text per digit, over the ones drawn last time
+        let mut lines = [String::new(), String::new(), String::new()];
+        for pattern in segments {
+            let on = |segment: u8, glyph: char| {
+                if pattern & 1 << segment != 0 {
+                    glyph
+                } else {
+                    ' '
+                }
+            };
+            lines[0] += &format!(" {}  ", on(0, '_'));
+            lines[1] += &format!("{}{}{} ", on(5, '|'), on(6, '_'), on(1, '|'));
+            lines[2] += &format!("{}{}{} ", on(4, '|'), on(3, '_'), on(2, '|'));
+        }
+
+        let mut stdout = std::io::stdout();
+        if self.last_drawn.is_some() {
+            let _ = write!(stdout, "\x1B[3F");
+        }
+        for line in &lines {
+            let _ = write!(stdout, "\r\x1B[K\x1B[31m{}\x1B[0m\n", line);
+        }
+        let _ = stdout.flush();
+        self.last_drawn = Some(segments);
+    }
+}
+
+// Where a host key sits on the keypad: (row, PA bit)
+fn key_position(key: u8) -> Option<(u8, u8)> {
+    let index = match key.to_ascii_uppercase() {
+        key @ b'0'..=b'9' => key - b'0',
+        key @ b'A'..=b'F' => key - b'A' + 10,
+        b'M' => 16,
+        b'N' => 17,
+        b'+' => 18,
+        b'G' => 19,
+        b'P' => 20,
+        _ => return None,
+    };
+    Some((index / 7, 6 - index % 7))
+}
+
+impl PortDevice for Kim1Panel {
+    fn name(&self) -> &str {
+        "kim1_panel"
+    }
+
+    fn pins_changed(&mut self, port: Port, pins: u8, ddr: u8, now: u64) {
+        match port {
+            // Only driven segment lines light anything
+            Port::A => self.segments = pins & ddr & 0x7F,
+            Port::B => self.decoder = (pins | !ddr) >> 1 & 0x0F,
+        }
+        self.sample(now);
+    }
+
+    fn drive(&mut self, port: Port, now: u64) -> u8 {
+        self.run_script(now);
+        if port != Port::A {
+            return 0xFF;
+        }
+        let mut level = 0xFF;
+        for &(row, bit) in &self.pressed {
+            if row == self.decoder {
+                level &= !(1 << bit);
+            }
+        }
+        level
+    }
+
+    fn tick(&mut self, now: u64) {
+        if self.input.is_some() && now >= self.last_poll + self.poll_cycles {
+            self.poll_input(now);
+        }
+        self.run_script(now);
+        self.last_cycle = now;
+        if now >= self.next_frame {
+            self.draw(now);
+            self.next_frame = now + self.frame_cycles;
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        let mut next = self.script.front().map(|event| event.0);
+        if let Some(input) = &self.input {
+            if !input.is_exhausted() {
+                let poll = self.last_poll + self.poll_cycles;
+                next = Some(next.map_or(poll, |script| script.min(poll)));
+            }
+        }
+        if self.render {
+            next = Some(next.map_or(self.next_frame, |next| next.min(self.next_frame)));
+        }
+        next
+    }
+
+    fn clock_rate_changed(&mut self, hz: f64) {
+        // Keys are held for 100 ms and the host is polled every 10 ms; the
+        // display is redrawn 25 times a second, and a digit not lit for
+        // 50 ms has gone dark
+        self.hold_cycles = ((hz / 10.0) as u64).max(1);
+        self.poll_cycles = ((hz / 100.0) as u64).max(1);
+        self.frame_cycles = ((hz / 25.0) as u64).max(1);
+        self.persistence = ((hz / 20.0) as u64).max(1);
+    }
+
+    fn reset(&mut self) {
+        self.decoder = 0x0F;
+        self.segments = 0;
+        self.lit_at = [None; DIGITS];
+        self.pressed.clear();
+        self.script.clear();
+        self.next_frame = 0;
+        self.last_poll = 0;
+        self.last_cycle = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::devices::{rriot::Rriot, Device};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_scan() {
+        let mut panel = Kim1Panel::new();
+        panel.set_render(false);
+        let panel = Arc::new(Mutex::new(panel));
+        let mut rriot = Rriot::new();
+        rriot.attach(panel.clone());
+
+        // Light "1" on the first digit and "A" on the last the way the
+        // monitor does: blank, select the digit, then drive its segments
+        rriot.write(0x1, 0x7F);
+        rriot.write(0x3, 0x1E);
+        for (select, pattern) in [(0x09, 0x06), (0x13, 0x77)] {
+            rriot.write(0x0, 0x00);
+            rriot.write(0x2, select);
+            rriot.write(0x0, pattern);
+        }
+        assert_eq!(panel.lock().unwrap().text(0), "1    A");
+
+        // Digits fade once the scanning stops, except the one still lit
+        assert_eq!(panel.lock().unwrap().text(50_000), "     A");
+        rriot.write(0x0, 0x00);
+        assert_eq!(panel.lock().unwrap().text(50_000), "      ");
+
+        // "+" pulls PA2 low, but only with row 2 selected
+        panel.lock().unwrap().schedule_press(0, 100, b'+');
+        rriot.write(0x1, 0x00);
+        rriot.write(0x2, 0x05);
+        assert_eq!(rriot.read(0x0), !0x04);
+        rriot.write(0x2, 0x03);
+        assert_eq!(rriot.read(0x0), 0xFF);
+    }
+}
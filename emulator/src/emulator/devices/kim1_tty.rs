@@ -0,0 +1,317 @@
+/**
+ * The KIM-1's teletype interface: a serial line the monitor bit-bangs
+ * through pins of its 6530-002 RRIOT, bridged to the host terminal.
+ *
+ * The monitor sends by toggling PB0 and receives by timing PA7, both idle
+ * high (mark) with a low start bit, eight data bits LSB first and a stop
+ * bit. Bits sent on PB0 are sampled in the middle; host keys are shifted
+ * out on PA7, upper-cased and with Enter as CR, at the line's baud rate.
+ *
+ * Attaching the bridge also fits the TTY jumper, which ties PA0 to the
+ * keypad decoder's output 3; that's how the monitor knows to use the TTY
+ * instead of the keypad. The monitor then times the first character typed
+ * to find the baud rate, which has to be a RUBOUT, so one is sent 100 ms
+ * after every reset.
+ */
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+use super::host_input::HostInput;
+use super::port::{Port, PortDevice};
+
+pub const DEFAULT_BAUD: f64 = 1200.0;
+
+const RUBOUT: u8 = 0x7F;
+
+// How long after a reset the RUBOUT is typed, in seconds
+const RUBOUT_DELAY: f64 = 0.1;
+
+// The keypad decoder output the jumper connects to PA0
+const TTY_JUMPER: u8 = 3;
+
+enum Output {
+    Stdout,
+    Buffer(Vec<u8>),
+}
+
+pub struct Kim1Tty {
+    baud: f64,
+    hz: f64,
+
+    // Host to KIM: bytes waiting, and the one being shifted out since a cycle
+    input: Option<HostInput>,
+    pending: VecDeque<u8>,
+    sending: Option<(u64, u8)>,
+    quiet_until: u64,
+
+    // KIM to host: when the start bit fell, and the PB0 levels since then
+    output: Output,
+    receiving: Option<u64>,
+    transitions: Vec<(u64, bool)>,
+    line: bool,
+
+    decoder: u8,
+    last_cycle: u64,
+}
+
+impl Default for Kim1Tty {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Kim1Tty {
+    // Received characters are printed on stdout
+    pub fn new() -> Self {
+        Self {
+            baud: DEFAULT_BAUD,
+            hz: 1_000_000.0,
+            input: None,
+            pending: VecDeque::from([RUBOUT]),
+            sending: None,
+            quiet_until: 100_000,
+            output: Output::Stdout,
+            receiving: None,
+            transitions: Vec::new(),
+            line: true,
+            decoder: 0,
+            last_cycle: 0,
+        }
+    }
+
+    // Received characters are kept in memory, for tests
+    pub fn captured() -> Self {
+        Self {
+            output: Output::Buffer(Vec::new()),
+            ..Self::new()
+        }
+    }
+
+    pub fn set_baud(&mut self, baud: f64) {
+        self.baud = baud;
+    }
+
+    pub fn set_input(&mut self, input: HostInput) {
+        self.input = Some(input);
+    }
+
+    // Everything received so far, if the output is captured
+    pub fn output(&self) -> Option<&[u8]> {
+        match &self.output {
+            Output::Stdout => None,
+            Output::Buffer(buffer) => Some(buffer),
+        }
+    }
+
+    fn bit_cycles(&self) -> f64 {
+        self.hz / self.baud
+    }
+
+    // The cycle bit `bit` of a character started at `start` begins at
+    fn bit_start(&self, start: u64, bit: f64) -> u64 {
+        start + (bit * self.bit_cycles()) as u64
+    }
+
+    // The level on PA7 at `now`
+    fn transmit_level(&self, now: u64) -> bool {
+        let Some((start, byte)) = self.sending else {
+            return true;
+        };
+        let bit = (now.saturating_sub(start) as f64 / self.bit_cycles()) as u32;
+        match bit {
+            0 => false,
+            1..=8 => byte >> (bit - 1) & 1 != 0,
+            _ => true,
+        }
+    }
+
+    // Start shifting out the next byte once the last one's stop bits are done
+    fn transmit(&mut self, now: u64) {
+        if now < self.quiet_until {
+            return;
+        }
+        if let Some((start, _)) = self.sending {
+            if now < self.bit_start(start, 11.0) {
+                return;
+            }
+            self.sending = None;
+        }
+
+        if self.pending.is_empty() {
+            if let Some(key) = self.input.as_mut().and_then(|input| input.try_read()) {
+                let key = if key == b'\n' { b'\r' } else { key };
+                self.pending.push_back(key.to_ascii_uppercase());
+            }
+        }
+        if let Some(byte) = self.pending.pop_front() {
+            self.sending = Some((now, byte));
+        }
+    }
+
+    // The level PB0 had at `cycle`
+    fn level_at(&self, cycle: u64) -> bool {
+        self.transitions
+            .iter()
+            .take_while(|(at, _)| *at <= cycle)
+            .last()
+            .is_none_or(|(_, level)| *level)
+    }
+
+    // Decode a character once its last data bit has been seen
+    fn receive(&mut self, now: u64) {
+        let Some(start) = self.receiving else {
+            return;
+        };
+        if now < self.bit_start(start, 9.0) {
+            return;
+        }
+
+        let mut byte = 0;
+        for bit in 0..8 {
+            if self.level_at(self.bit_start(start, 1.5 + bit as f64)) {
+                byte |= 1 << bit;
+            }
+        }
+        self.receiving = None;
+        self.transitions.clear();
+
+        let byte = byte & 0x7F;
+        if byte == 0 || byte == RUBOUT {
+            return;
+        }
+        match &mut self.output {
+            Output::Stdout => {
+                let mut stdout = io::stdout();
+                let _ = stdout.write_all(&[byte]);
+                let _ = stdout.flush();
+            }
+            Output::Buffer(buffer) => buffer.push(byte),
+        }
+    }
+}
+
+impl PortDevice for Kim1Tty {
+    fn name(&self) -> &str {
+        "kim1_tty"
+    }
+
+    fn pins_changed(&mut self, port: Port, pins: u8, ddr: u8, now: u64) {
+        if port != Port::B {
+            return;
+        }
+        self.decoder = (pins | !ddr) >> 1 & 0x0F;
+
+        self.receive(now);
+        let line = pins & ddr & 0x01 != 0 || ddr & 0x01 == 0;
+        if line == self.line {
+            return;
+        }
+        self.line = line;
+        if self.receiving.is_some() {
+            self.transitions.push((now, line));
+        } else if !line {
+            // A start bit
+            self.receiving = Some(now);
+            self.transitions = vec![(now, false)];
+        }
+    }
+
+    fn drive(&mut self, port: Port, now: u64) -> u8 {
+        if port != Port::A {
+            return 0xFF;
+        }
+        self.transmit(now);
+        let mut level = 0x7F | (self.transmit_level(now) as u8) << 7;
+        if self.decoder == TTY_JUMPER {
+            level &= !0x01;
+        }
+        level
+    }
+
+    fn tick(&mut self, now: u64) {
+        self.last_cycle = now;
+        self.transmit(now);
+        self.receive(now);
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        let receive = self.receiving.map(|start| self.bit_start(start, 9.0));
+        let transmit = match self.sending {
+            Some((start, _)) => Some(self.bit_start(start, 11.0)),
+            None if !self.pending.is_empty() => Some(self.quiet_until.max(self.last_cycle + 1)),
+            None => match &self.input {
+                Some(input) if !input.is_exhausted() => {
+                    Some(self.last_cycle + self.bit_cycles() as u64 + 1)
+                }
+                _ => None,
+            },
+        };
+        match (receive, transmit) {
+            (Some(receive), Some(transmit)) => Some(receive.min(transmit)),
+            (receive, transmit) => receive.or(transmit),
+        }
+    }
+
+    fn clock_rate_changed(&mut self, hz: f64) {
+        self.hz = hz;
+        self.quiet_until = self.quiet_until.min((hz * RUBOUT_DELAY) as u64);
+    }
+
+    fn reset(&mut self) {
+        // The operator types RUBOUT so the monitor can measure the baud rate
+        self.pending = VecDeque::from([RUBOUT]);
+        self.sending = None;
+        self.quiet_until = (self.hz * RUBOUT_DELAY) as u64;
+        self.receiving = None;
+        self.transitions.clear();
+        self.line = true;
+        self.last_cycle = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::devices::{rriot::Rriot, Device};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_bit_bang() {
+        let mut tty = Kim1Tty::captured();
+        tty.set_input(HostInput::scripted(b"k"));
+        let tty = Arc::new(Mutex::new(tty));
+        let mut rriot = Rriot::new();
+        rriot.attach(tty.clone());
+
+        // Ten cycles a bit. PB0 is the line out, PB1-PB4 the decoder.
+        rriot.clock_rate_changed(12_000.0);
+        rriot.reset();
+        rriot.write(0x3, 0x1F);
+        rriot.write(0x2, 0x07);
+
+        // The jumper grounds PA0 with decoder output 3 selected, and the
+        // RUBOUT (after 100 ms) then the 'K' come in on PA7
+        let mut received = Vec::new();
+        for start in [1200, 1310] {
+            rriot.tick(start);
+            let mut byte = 0;
+            for bit in 0..8 {
+                rriot.tick(start + 15 + bit * 10);
+                let pins = rriot.read(0x0);
+                assert_eq!(pins & 0x01, 0);
+                byte |= (pins >> 7) << bit;
+            }
+            received.push(byte);
+        }
+        assert_eq!(received, [RUBOUT, b'K']);
+
+        // Send "H": start bit, 0x48 LSB first, stop bit
+        let bits = [0, 0, 0, 0, 1, 0, 0, 1, 0, 1];
+        for (index, bit) in bits.iter().enumerate() {
+            rriot.tick(1500 + index as u64 * 10);
+            rriot.write(0x2, 0x06 | bit);
+        }
+        rriot.tick(1600);
+        assert_eq!(tty.lock().unwrap().output(), Some(&b"H"[..]));
+    }
+}
@@ -0,0 +1,258 @@
+use std::io::Write;
+
+use super::port::{Pin, Port, PortDevice};
+
+// Glyphs from off to fully lit
+const SHADES: [char; 5] = [' ', '░', '▒', '▓', '█'];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LedColor {
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    White,
+}
+
+impl LedColor {
+    // ANSI escape sequence selecting the foreground colour
+    fn ansi(&self) -> &'static str {
+        match self {
+            Self::Red => "\x1B[31m",
+            Self::Green => "\x1B[32m",
+            Self::Yellow => "\x1B[33m",
+            Self::Blue => "\x1B[34m",
+            Self::White => "\x1B[37m",
+        }
+    }
+}
+
+/**
+ * A bar of LEDs wired to port pins, as in Ben Eater's blink demo.
+ *
+ * Instead of redrawing on every write, the bar integrates how long each
+ * LED is lit over a display interval and draws the LEDs with a brightness
+ * proportional to their duty cycle, so PWM dimming looks like dimming
+ * rather than flicker.
+ */
+pub struct LedBar {
+    // Pin driving each LED (LED i is lit while its pin is a high output)
+    pins: Vec<Pin>,
+    color: LedColor,
+    render: bool,
+
+    lit: Vec<bool>,
+
+    // Cycles each LED was lit during the current and the last display interval
+    on_cycles: Vec<u64>,
+    last_on_cycles: Vec<u64>,
+    total_on_cycles: Vec<u64>,
+
+    interval: u64,       // Length of a display interval, in cycles
+    interval_start: u64, // Cycle the current interval started at
+    last_interval: u64,  // Length of the last completed interval
+    last_cycle: u64,     // Cycle on-time was last integrated up to
+
+    last_drawn: Vec<usize>,
+}
+
+impl LedBar {
+    pub fn new(pins: Vec<Pin>) -> Self {
+        let count = pins.len();
+        Self {
+            pins,
+            color: LedColor::Red,
+            render: true,
+            lit: vec![false; count],
+            on_cycles: vec![0; count],
+            last_on_cycles: vec![0; count],
+            total_on_cycles: vec![0; count],
+            interval: 33_333,
+            interval_start: 0,
+            last_interval: 0,
+            last_cycle: 0,
+            last_drawn: Vec::new(),
+        }
+    }
+
+    // `count` LEDs on consecutive pins of a port, starting at bit 0
+    pub fn on_port(port: Port, count: u8) -> Self {
+        Self::new((0..count).map(|bit| Pin::new(port, bit)).collect())
+    }
+
+    pub fn set_color(&mut self, color: LedColor) {
+        self.color = color;
+    }
+
+    // Turn drawing to the terminal on or off (tests run headless)
+    pub fn set_render(&mut self, render: bool) {
+        self.render = render;
+    }
+
+    pub fn set_display_interval(&mut self, cycles: u64) {
+        self.interval = cycles.max(1);
+
+        // Start a fresh interval so the old one can't end in the past
+        self.interval_start = self.last_cycle;
+        self.on_cycles.iter_mut().for_each(|cycles| *cycles = 0);
+    }
+
+    pub fn len(&self) -> usize {
+        self.pins.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pins.is_empty()
+    }
+
+    pub fn is_lit(&self, led: usize) -> bool {
+        self.lit[led]
+    }
+
+    // Fraction of the last completed display interval that an LED was lit
+    pub fn duty_cycle(&self, led: usize) -> f64 {
+        if self.last_interval == 0 {
+            return 0.0;
+        }
+        self.last_on_cycles[led] as f64 / self.last_interval as f64
+    }
+
+    // Fraction of the time since reset that an LED was lit
+    pub fn average_duty_cycle(&self, led: usize) -> f64 {
+        if self.last_cycle == 0 {
+            return 0.0;
+        }
+        self.total_on_cycles[led] as f64 / self.last_cycle as f64
+    }
+
+    // Add the on-time of every lit LED up to `now`, closing display intervals on the way
+    fn integrate(&mut self, now: u64) {
+        while self.last_cycle < now {
+            let interval_end = self.interval_start + self.interval;
+            let until = now.min(interval_end);
+            let elapsed = until - self.last_cycle;
+
+            for (led, lit) in self.lit.iter().enumerate() {
+                if *lit {
+                    self.on_cycles[led] += elapsed;
+                    self.total_on_cycles[led] += elapsed;
+                }
+            }
+            self.last_cycle = until;
+
+            if until == interval_end {
+                self.end_interval();
+            }
+        }
+    }
+
+    fn end_interval(&mut self) {
+        self.last_interval = self.last_cycle - self.interval_start;
+        self.interval_start = self.last_cycle;
+        std::mem::swap(&mut self.on_cycles, &mut self.last_on_cycles);
+        self.on_cycles.iter_mut().for_each(|cycles| *cycles = 0);
+
+        if self.render {
+            self.draw();
+        }
+    }
+
+    fn draw(&mut self) {
+        let shades: Vec<usize> = (0..self.len())
+            .map(|led| (self.duty_cycle(led) * (SHADES.len() - 1) as f64).round() as usize)
+            .collect();
+
+        // Only redraw when something visibly changed
+        if shades == self.last_drawn {
+            return;
+        }
+
+        // Clear the line and print the LED bar
+        print!("\x1B[K");
+        print!("\rLED STRIP: {}", self.color.ansi());
+        for shade in &shades {
+            print!("{}", SHADES[*shade]);
+        }
+        print!("\x1B[0m");
+
+        // Flush stdout
+        std::io::stdout().flush().unwrap();
+        self.last_drawn = shades;
+    }
+}
+
+impl PortDevice for LedBar {
+    fn name(&self) -> &str {
+        "led_bar"
+    }
+
+    fn pins_changed(&mut self, port: Port, pins: u8, ddr: u8, now: u64) {
+        self.integrate(now);
+
+        // An LED is lit when its pin is an output and driven high
+        for (led, pin) in self.pins.iter().enumerate() {
+            if pin.port == port {
+                self.lit[led] = pin.level(pins & ddr);
+            }
+        }
+    }
+
+    fn tick(&mut self, now: u64) {
+        self.integrate(now);
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        // Wake up at the end of each interval to draw, unless nothing is drawn
+        if self.render {
+            Some(self.interval_start + self.interval)
+        } else {
+            None
+        }
+    }
+
+    fn clock_rate_changed(&mut self, hz: f64) {
+        // Refresh the display 30 times a second
+        self.set_display_interval((hz / 30.0) as u64);
+    }
+
+    fn reset(&mut self) {
+        self.lit.iter_mut().for_each(|lit| *lit = false);
+        self.on_cycles.iter_mut().for_each(|cycles| *cycles = 0);
+        self.last_on_cycles.iter_mut().for_each(|cycles| *cycles = 0);
+        self.total_on_cycles.iter_mut().for_each(|cycles| *cycles = 0);
+        self.interval_start = 0;
+        self.last_interval = 0;
+        self.last_cycle = 0;
+        self.last_drawn.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::devices::{via::Via, Device};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_pwm_duty_cycle() {
+        let mut via = Via::new();
+        let leds = Arc::new(Mutex::new(LedBar::on_port(Port::B, 8)));
+        leds.lock().unwrap().set_render(false);
+        leds.lock().unwrap().set_display_interval(1000);
+        via.attach(leds.clone());
+
+        // Toggle LED 3 every 10 cycles, keep LED 0 on, for two intervals
+        via.write(0x2, 0xFF);
+        for step in 0..200 {
+            via.tick(step * 10);
+            via.write(0x0, if step % 2 == 0 { 0x09 } else { 0x01 });
+        }
+        via.tick(2000);
+
+        let leds = leds.lock().unwrap();
+        assert!((leds.duty_cycle(3) - 0.5).abs() < 0.02);
+        assert!((leds.duty_cycle(0) - 1.0).abs() < 0.02);
+        assert_eq!(leds.duty_cycle(7), 0.0);
+        assert!((leds.average_duty_cycle(3) - 0.5).abs() < 0.02);
+    }
+}
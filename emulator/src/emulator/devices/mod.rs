@@ -1 +1,123 @@
-pub mod blink_led;
\ No newline at end of file
+/**
+ * Peripheral devices that can be attached to the system bus.
+ *
+ * Every peripheral implements the `Device` trait. The bus maps each device
+ * into a range of the address space and forwards accesses to it as offsets
+ * from the start of that range, so the same device can be mapped anywhere.
+ */
+use std::sync::{Arc, Mutex};
+
+pub mod acia;
+pub mod apple1_terminal;
+pub mod block_image;
+pub mod c64_banking;
+pub mod compact_flash;
+pub mod ds1307;
+pub mod eeprom_24lc;
+pub mod file_io;
+pub mod hd44780;
+pub mod host_input;
+pub mod host_interface;
+pub mod i2c;
+pub mod key_matrix;
+pub mod kim1_panel;
+pub mod kim1_tty;
+pub mod led_bar;
+pub mod nes;
+pub mod pia;
+pub mod port;
+pub mod ps2_keyboard;
+pub mod ram;
+pub mod rriot;
+pub mod sd_card;
+pub mod sid;
+pub mod spi;
+pub mod tms9918;
+pub mod vga;
+pub mod via;
+
+// A device shared between the bus and whoever created it
+pub type SharedDevice = Arc<Mutex<dyn Device>>;
+
+// Memory access for a device that has taken over the bus
+pub trait Dma {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, data: u8);
+}
+
+pub trait Device: Send {
+    /// Human readable name, used in logs and error messages.
+    fn name(&self) -> &str;
+
+    /// Reads the register at `offset`. Reads may have side effects, such as
+    /// clearing an interrupt flag.
+    fn read(&mut self, offset: u16) -> u8;
+
+    /// Returns what a read of `offset` would return, without any side effects.
+    fn peek(&self, offset: u16) -> u8;
+
+    /// Writes `data` to the register at `offset`.
+    fn write(&mut self, offset: u16, data: u8);
+
+    /// Advances the device up to CPU cycle `now`.
+    ///
+    /// `now` is an absolute cycle count, so a device produces the same
+    /// result whether it is ticked on every cycle or only once in a while.
+    fn tick(&mut self, _now: u64) {}
+
+    /// The next cycle at which the device needs to be ticked even if the
+    /// CPU doesn't touch it (e.g. a timer underflow), or `None` if it only
+    /// changes state when accessed. Asked again after every tick and access.
+    fn next_event(&self) -> Option<u64> {
+        None
+    }
+
+    /// The CPU clock rate changed. Devices that deal in real time (baud
+    /// rates, display refresh, audio) use it to convert time to cycles.
+    fn clock_rate_changed(&mut self, _hz: f64) {}
+
+    /// Puts the device back into its power-on state.
+    fn reset(&mut self) {}
+
+    /// Level of the device's IRQ output (true = asserted).
+    fn irq(&self) -> bool {
+        false
+    }
+
+    /// Level of the device's NMI output (true = asserted).
+    fn nmi(&self) -> bool {
+        false
+    }
+
+    /// Whether the device is holding the CPU off the bus (RDY pulled low),
+    /// e.g. a video circuit that shares RAM with the CPU.
+    fn halt(&self) -> bool {
+        false
+    }
+
+    /// Whether the device wants to take over the bus (see `dma`).
+    fn dma_pending(&self) -> bool {
+        false
+    }
+
+    /// Runs the device's bus transfer, reading and writing memory (and
+    /// other devices) through `bus`. Returns how many cycles the CPU is
+    /// held off the bus for.
+    fn dma(&mut self, _bus: &mut dyn Dma) -> u64 {
+        0
+    }
+
+    /// Set once the device has asked the emulator to stop, with the exit
+    /// status to report (e.g. a test program signalling pass or fail).
+    fn exit_status(&self) -> Option<i32> {
+        None
+    }
+
+    /// Serialises the device's internal state.
+    fn snapshot(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Restores state previously produced by `snapshot`.
+    fn restore(&mut self, _state: &[u8]) {}
+}
@@ -0,0 +1,915 @@
+/**
+ * The 2A03's audio processing unit (NTSC).
+ *
+ * Two pulse channels with envelopes and sweep units, a triangle channel, a
+ * noise channel and the delta modulation channel (DMC), driven by the frame
+ * counter in its 4-step or 5-step sequence. Everything is clocked once per
+ * CPU cycle and mixed through the chip's non-linear mixer; each output
+ * sample is the average of the mix over the cycles it covers, passed
+ * through the console's output filters. The samples go to a WAV file and/or
+ * are captured in memory, so a sound engine can be regression tested
+ * without an audio device.
+ *
+ * The DMC plays samples straight out of CPU memory. The APU can't reach the
+ * bus itself: when the DMC needs a byte it asks for one (`dmc_address`) and
+ * the I/O block fetches it with a DMA transfer, stalling the CPU.
+ *
+ * Registers (offsets from $4000):
+ *
+ *   $00-$03 pulse 1     $0C-$0F noise
+ *   $04-$07 pulse 2     $10-$13 DMC
+ *   $08-$0B triangle    $15     status / channel enable
+ *                       $17     frame counter
+ */
+use std::io;
+use std::path::Path;
+
+use crate::emulator::wav::{to_pcm, WavWriter};
+
+// NTSC CPU clock
+const DEFAULT_CLOCK_HZ: f64 = 1_789_773.0;
+
+// Keep the output flowing every 20 ms even if the CPU leaves the APU alone
+const OUTPUT_INTERVAL_SECONDS: f64 = 0.02;
+
+// CPU cycles the CPU loses to a DMC sample fetch
+pub const DMC_FETCH_CYCLES: u64 = 4;
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+const TRIANGLE_TABLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11,
+    12, 13, 14, 15,
+];
+
+// Timer periods in CPU cycles
+const NOISE_PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+const DMC_PERIODS: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+// Frame counter steps, in CPU cycles since the sequence started
+const STEP_1: u32 = 7457;
+const STEP_2: u32 = 14913;
+const STEP_3: u32 = 22371;
+const STEP_4: u32 = 29829;
+const STEP_5: u32 = 37281;
+const FOUR_STEP_LENGTH: u32 = 29830;
+const FIVE_STEP_LENGTH: u32 = 37282;
+
+// Status bits
+const STATUS_DMC_ACTIVE: u8 = 0x10;
+const STATUS_FRAME_IRQ: u8 = 0x40;
+const STATUS_DMC_IRQ: u8 = 0x80;
+
+#[derive(Clone, Copy, Default)]
+struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, data: u8) {
+        self.looping = data & 0x20 != 0;
+        self.constant = data & 0x10 != 0;
+        self.volume = data & 0x0F;
+    }
+
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Pulse {
+    // Pulse 1 negates with one's complement, pulse 2 with two's
+    ones_complement: bool,
+    enabled: bool,
+    duty: u8,
+    step: u8,
+    period: u16,
+    timer: u16,
+    length: u8,
+    envelope: Envelope,
+
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_reload: bool,
+    sweep_divider: u8,
+}
+
+impl Pulse {
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.duty = data >> 6;
+                self.envelope.write(data);
+            }
+            1 => {
+                self.sweep_enabled = data & 0x80 != 0;
+                self.sweep_period = (data >> 4) & 0x07;
+                self.sweep_negate = data & 0x08 != 0;
+                self.sweep_shift = data & 0x07;
+                self.sweep_reload = true;
+            }
+            2 => self.period = (self.period & 0x0700) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | (data as u16 & 0x07) << 8;
+                if self.enabled {
+                    self.length = LENGTH_TABLE[data as usize >> 3];
+                }
+                self.step = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    // Clocked every other CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) & 0x07;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let change = self.period >> self.sweep_shift;
+        if self.sweep_negate {
+            let change = change + self.ones_complement as u16;
+            self.period.saturating_sub(change)
+        } else {
+            self.period + change
+        }
+    }
+
+    fn muted(&self) -> bool {
+        self.period < 8 || self.sweep_target() > 0x07FF
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length > 0 && !self.envelope.looping {
+            self.length -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length == 0 || self.muted() || DUTY_TABLE[self.duty as usize][self.step as usize] == 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Triangle {
+    enabled: bool,
+    control: bool,
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+    period: u16,
+    timer: u16,
+    step: u8,
+    length: u8,
+}
+
+impl Triangle {
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.control = data & 0x80 != 0;
+                self.linear_reload_value = data & 0x7F;
+            }
+            1 => {}
+            2 => self.period = (self.period & 0x0700) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | (data as u16 & 0x07) << 8;
+                if self.enabled {
+                    self.length = LENGTH_TABLE[data as usize >> 3];
+                }
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    // Clocked every CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.length > 0 && self.linear_counter > 0 {
+                self.step = (self.step + 1) & 0x1F;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_linear(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length > 0 && !self.control {
+            self.length -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        TRIANGLE_TABLE[self.step as usize]
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Noise {
+    enabled: bool,
+    mode: bool,
+    period: u16,
+    timer: u16,
+    shift: u16,
+    length: u8,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            mode: false,
+            period: NOISE_PERIODS[0],
+            timer: 0,
+            shift: 1,
+            length: 0,
+            envelope: Envelope::default(),
+        }
+    }
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => self.envelope.write(data),
+            1 => {}
+            2 => {
+                self.mode = data & 0x80 != 0;
+                self.period = NOISE_PERIODS[data as usize & 0x0F];
+            }
+            _ => {
+                if self.enabled {
+                    self.length = LENGTH_TABLE[data as usize >> 3];
+                }
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    // Clocked every CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period - 1;
+            let tap = if self.mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 0x01;
+            self.shift = self.shift >> 1 | feedback << 14;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length > 0 && !self.envelope.looping {
+            self.length -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length == 0 || self.shift & 0x01 != 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    period: u16,
+    timer: u16,
+    level: u8,
+
+    sample_address: u16,
+    sample_length: u16,
+    address: u16,
+    bytes_remaining: u16,
+    buffer: Option<u8>,
+
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+    irq: bool,
+}
+
+impl Dmc {
+    fn new() -> Self {
+        Self {
+            irq_enabled: false,
+            looping: false,
+            period: DMC_PERIODS[0],
+            timer: 0,
+            level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            address: 0xC000,
+            bytes_remaining: 0,
+            buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+            irq: false,
+        }
+    }
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.irq_enabled = data & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.looping = data & 0x40 != 0;
+                self.period = DMC_PERIODS[data as usize & 0x0F];
+            }
+            1 => self.level = data & 0x7F,
+            2 => self.sample_address = 0xC000 | (data as u16) << 6,
+            _ => self.sample_length = (data as u16) << 4 | 1,
+        }
+    }
+
+    fn restart(&mut self) {
+        self.address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    // Clocked every CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period - 1;
+
+        if !self.silence {
+            if self.shift & 0x01 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(byte) => {
+                    self.silence = false;
+                    self.shift = byte;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    // The address of the next sample byte, if the buffer needs one
+    fn wanted_address(&self) -> Option<u16> {
+        if self.buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.address)
+        } else {
+            None
+        }
+    }
+
+    fn fill(&mut self, data: u8) {
+        self.buffer = Some(data);
+        self.address = self.address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    // CPU cycles until the buffer is emptied into the shift register
+    fn cycles_until_empty(&self) -> u64 {
+        self.timer as u64 + (self.bits_remaining as u64 - 1) * self.period as u64 + 1
+    }
+}
+
+// A first order filter, as on the console's audio output
+#[derive(Clone, Copy, Default)]
+struct Filter {
+    alpha: f32,
+    high_pass: bool,
+    previous_input: f32,
+    previous_output: f32,
+}
+
+impl Filter {
+    fn new(cutoff_hz: f32, sample_rate: u32, high_pass: bool) -> Self {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff_hz);
+        let dt = 1.0 / sample_rate as f32;
+        let alpha = if high_pass { rc / (rc + dt) } else { dt / (rc + dt) };
+        Self {
+            alpha,
+            high_pass,
+            ..Self::default()
+        }
+    }
+
+    fn apply(&mut self, input: f32) -> f32 {
+        let output = if self.high_pass {
+            self.alpha * (self.previous_output + input - self.previous_input)
+        } else {
+            self.previous_output + self.alpha * (input - self.previous_output)
+        };
+        self.previous_input = input;
+        self.previous_output = output;
+        output
+    }
+}
+
+pub struct Apu {
+    pulses: [Pulse; 2],
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+
+    five_step: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    frame_cycle: u32,
+    // Cycles until a $4017 write restarts the sequence
+    frame_reset_delay: u8,
+    cycle: u64,
+
+    // Mixer output summed over the current sample
+    mix_sum: f32,
+    sum_cycles: u32,
+
+    sample_rate: u32,
+    sample_phase: f64,
+    filters: [Filter; 3],
+    wav: Option<WavWriter>,
+    capture: Option<Vec<i16>>,
+    clock_hz: f64,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        let mut pulses = [Pulse::default(); 2];
+        pulses[0].ones_complement = true;
+        let mut apu = Self {
+            pulses,
+            triangle: Triangle::default(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            frame_reset_delay: 0,
+            cycle: 0,
+            mix_sum: 0.0,
+            sum_cycles: 0,
+            sample_rate: 44_100,
+            sample_phase: 0.0,
+            filters: [Filter::default(); 3],
+            wav: None,
+            capture: None,
+            clock_hz: DEFAULT_CLOCK_HZ,
+        };
+        apu.set_sample_rate(44_100);
+        apu
+    }
+
+    // Typically 44100 or 48000 Hz, set before the WAV output is opened
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate.max(1);
+        // Two high-pass filters and a low-pass one, like the console's output stage
+        self.filters = [
+            Filter::new(90.0, self.sample_rate, true),
+            Filter::new(440.0, self.sample_rate, true),
+            Filter::new(14_000.0, self.sample_rate, false),
+        ];
+    }
+
+    // Render the output into a WAV file
+    pub fn set_wav_output<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.wav = Some(WavWriter::create(path, self.sample_rate)?);
+        Ok(())
+    }
+
+    // Write out the samples still buffered for the WAV file
+    pub fn flush_wav(&mut self) -> io::Result<()> {
+        match &mut self.wav {
+            Some(wav) => wav.flush(),
+            None => Ok(()),
+        }
+    }
+
+    // Keep the output samples in memory, for tests
+    pub fn set_capture(&mut self, capture: bool) {
+        self.capture = if capture { Some(Vec::new()) } else { None };
+    }
+
+    // The samples captured since the last call
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        self.capture.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    pub fn set_clock_rate(&mut self, hz: f64) {
+        self.clock_hz = hz;
+    }
+
+    // Whether anything is listening to the output
+    fn has_output(&self) -> bool {
+        self.wav.is_some() || self.capture.is_some()
+    }
+
+    // Register writes, `register` counted from $4000
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0x00..=0x03 => self.pulses[0].write(register, data),
+            0x04..=0x07 => self.pulses[1].write(register - 0x04, data),
+            0x08..=0x0B => self.triangle.write(register - 0x08, data),
+            0x0C..=0x0F => self.noise.write(register - 0x0C, data),
+            0x10..=0x13 => self.dmc.write(register - 0x10, data),
+            0x15 => {
+                self.pulses[0].enabled = data & 0x01 != 0;
+                self.pulses[1].enabled = data & 0x02 != 0;
+                self.triangle.enabled = data & 0x04 != 0;
+                self.noise.enabled = data & 0x08 != 0;
+                for pulse in &mut self.pulses {
+                    if !pulse.enabled {
+                        pulse.length = 0;
+                    }
+                }
+                if !self.triangle.enabled {
+                    self.triangle.length = 0;
+                }
+                if !self.noise.enabled {
+                    self.noise.length = 0;
+                }
+
+                if data & STATUS_DMC_ACTIVE == 0 {
+                    self.dmc.bytes_remaining = 0;
+                } else if self.dmc.bytes_remaining == 0 {
+                    self.dmc.restart();
+                }
+                self.dmc.irq = false;
+            }
+            0x17 => {
+                self.five_step = data & 0x80 != 0;
+                self.irq_inhibit = data & 0x40 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+                // The sequence restarts 3 or 4 cycles later, depending on parity
+                self.frame_reset_delay = if self.cycle & 0x01 == 0 { 3 } else { 4 };
+            }
+            _ => {}
+        }
+    }
+
+    // $4015: channel length counters and interrupt flags. Reading
+    // acknowledges the frame interrupt.
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+        self.frame_irq = false;
+        status
+    }
+
+    pub fn peek_status(&self) -> u8 {
+        let mut status = 0;
+        for (bit, length) in [
+            self.pulses[0].length,
+            self.pulses[1].length,
+            self.triangle.length,
+            self.noise.length,
+        ]
+        .iter()
+        .enumerate()
+        {
+            if *length > 0 {
+                status |= 1 << bit;
+            }
+        }
+        if self.dmc.bytes_remaining > 0 {
+            status |= STATUS_DMC_ACTIVE;
+        }
+        if self.frame_irq {
+            status |= STATUS_FRAME_IRQ;
+        }
+        if self.dmc.irq {
+            status |= STATUS_DMC_IRQ;
+        }
+        status
+    }
+
+    pub fn irq(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    // Where the DMC wants its next sample byte from, if it needs one
+    pub fn dmc_address(&self) -> Option<u16> {
+        self.dmc.wanted_address()
+    }
+
+    // The byte fetched from `dmc_address`
+    pub fn dmc_fill(&mut self, data: u8) {
+        self.dmc.fill(data);
+    }
+
+    fn quarter_frame(&mut self) {
+        self.pulses[0].envelope.clock();
+        self.pulses[1].envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear();
+    }
+
+    fn half_frame(&mut self) {
+        for pulse in &mut self.pulses {
+            pulse.clock_length();
+            pulse.clock_sweep();
+        }
+        self.triangle.clock_length();
+        self.noise.clock_length();
+    }
+
+    fn clock_frame_counter(&mut self) {
+        if self.frame_reset_delay > 0 {
+            self.frame_reset_delay -= 1;
+            if self.frame_reset_delay == 0 {
+                self.frame_cycle = 0;
+                if self.five_step {
+                    self.quarter_frame();
+                    self.half_frame();
+                }
+                return;
+            }
+        }
+
+        self.frame_cycle += 1;
+        match self.frame_cycle {
+            STEP_1 | STEP_3 => self.quarter_frame(),
+            STEP_2 => {
+                self.quarter_frame();
+                self.half_frame();
+            }
+            STEP_4 if !self.five_step => {
+                self.quarter_frame();
+                self.half_frame();
+                if !self.irq_inhibit {
+                    self.frame_irq = true;
+                }
+            }
+            STEP_5 if self.five_step => {
+                self.quarter_frame();
+                self.half_frame();
+            }
+            _ => {}
+        }
+
+        let length = if self.five_step { FIVE_STEP_LENGTH } else { FOUR_STEP_LENGTH };
+        if self.frame_cycle >= length {
+            self.frame_cycle = 0;
+        }
+    }
+
+    // Run one CPU cycle
+    pub fn clock(&mut self) {
+        self.clock_frame_counter();
+
+        if self.cycle & 0x01 == 1 {
+            for pulse in &mut self.pulses {
+                pulse.clock_timer();
+            }
+        }
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        self.cycle += 1;
+
+        if !self.has_output() {
+            return;
+        }
+        self.mix_sum += self.mix();
+        self.sum_cycles += 1;
+
+        self.sample_phase += 1.0;
+        let cycles_per_sample = self.clock_hz / self.sample_rate as f64;
+        if self.sample_phase >= cycles_per_sample {
+            self.sample_phase -= cycles_per_sample;
+            self.emit_sample();
+        }
+    }
+
+    // The non-linear mixer, 0.0 to about 1.0
+    fn mix(&self) -> f32 {
+        let pulse = (self.pulses[0].output() + self.pulses[1].output()) as f32;
+        let pulse_out = if pulse == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulse + 100.0)
+        };
+
+        let tnd = self.triangle.output() as f32 / 8227.0
+            + self.noise.output() as f32 / 12241.0
+            + self.dmc.level as f32 / 22638.0;
+        let tnd_out = if tnd == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / tnd + 100.0)
+        };
+
+        pulse_out + tnd_out
+    }
+
+    fn emit_sample(&mut self) {
+        let mut sample = self.mix_sum / self.sum_cycles.max(1) as f32;
+        self.mix_sum = 0.0;
+        self.sum_cycles = 0;
+        for filter in &mut self.filters {
+            sample = filter.apply(sample);
+        }
+
+        if let Some(capture) = &mut self.capture {
+            capture.push(to_pcm(sample));
+        }
+        if let Some(wav) = &mut self.wav {
+            if let Err(error) = wav.write_sample(sample) {
+                eprintln!("apu: could not write audio: {}", error);
+                self.wav = None;
+            }
+        }
+    }
+
+    // CPU cycles from now until the APU next changes its IRQ output or
+    // needs a DMC byte, or the output wants flushing
+    pub fn cycles_until_event(&self) -> Option<u64> {
+        if self.dmc.wanted_address().is_some() {
+            return Some(0);
+        }
+
+        let mut next: Option<u64> = None;
+        let mut consider = |cycles: u64| next = Some(next.map_or(cycles, |next| next.min(cycles)));
+
+        if self.frame_reset_delay > 0 {
+            consider(self.frame_reset_delay as u64);
+        } else if !self.five_step && !self.irq_inhibit && !self.frame_irq {
+            consider(STEP_4.saturating_sub(self.frame_cycle).max(1) as u64);
+        }
+        if self.dmc.bytes_remaining > 0 {
+            consider(self.dmc.cycles_until_empty());
+        }
+        if self.has_output() {
+            consider(((self.clock_hz * OUTPUT_INTERVAL_SECONDS) as u64).max(1));
+        }
+        next
+    }
+
+    pub fn reset(&mut self) {
+        let (sample_rate, wav, capture, clock_hz) =
+            (self.sample_rate, self.wav.take(), self.capture.take(), self.clock_hz);
+        *self = Self::new();
+        self.set_sample_rate(sample_rate);
+        self.wav = wav;
+        self.capture = capture;
+        self.clock_hz = clock_hz;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pulse_output() {
+        let mut apu = Apu::new();
+        apu.set_capture(true);
+
+        // Pulse 1: 50% duty, constant volume 15, period 253 (about 440 Hz)
+        apu.write(0x15, 0x01);
+        apu.write(0x00, 0x9F);
+        apu.write(0x02, 0xFD);
+        apu.write(0x03, 0x10);
+        assert_eq!(apu.peek_status() & 0x01, 0x01);
+
+        for _ in 0..DEFAULT_CLOCK_HZ as u32 / 10 {
+            apu.clock();
+        }
+        let samples = apu.take_samples();
+        assert!((4409..=4411).contains(&samples.len()));
+
+        // A square wave: about 44 cycles of it in 100 ms
+        let rising = samples.windows(2).filter(|pair| pair[0] <= 0 && pair[1] > 0).count();
+        assert!((43..=45).contains(&rising), "{} rising edges", rising);
+
+        // The length counter (20 half frames) runs out after a sixth of a second
+        for _ in 0..DEFAULT_CLOCK_HZ as u32 / 10 {
+            apu.clock();
+        }
+        assert_eq!(apu.peek_status() & 0x01, 0x00);
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        let mut apu = Apu::new();
+        assert_eq!(apu.cycles_until_event(), Some(STEP_4 as u64));
+        for _ in 0..STEP_4 - 1 {
+            apu.clock();
+        }
+        assert!(!apu.irq());
+        apu.clock();
+        assert!(apu.irq());
+        assert_eq!(apu.read_status() & STATUS_FRAME_IRQ, STATUS_FRAME_IRQ);
+        assert!(!apu.irq());
+
+        // Inhibited in 5-step mode
+        apu.write(0x17, 0xC0);
+        for _ in 0..FIVE_STEP_LENGTH * 2 {
+            apu.clock();
+        }
+        assert!(!apu.irq());
+    }
+}
@@ -0,0 +1,482 @@
+/**
+ * NES cartridges loaded from iNES and NES 2.0 ROM images.
+ *
+ * An image is a 16 byte header, an optional 512 byte trainer, the PRG ROM
+ * and then the CHR ROM (boards without CHR ROM have 8 KiB of CHR RAM). The
+ * header gives the sizes, the mapper number, the nametable mirroring and
+ * whether the PRG RAM at $6000-$7FFF is battery backed. Battery backed RAM
+ * is loaded from and saved to a `.sav` file next to the ROM.
+ *
+ * The cartridge sits on both buses: the PPU reads the pattern tables
+ * through `ChrBus`, and the CPU sees it at $4020-$FFFF through a
+ * `CartridgeSlot`.
+ */
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use super::mappers::{self, Mapper};
+use super::ppu::{ChrBus, Mirroring, Ppu};
+use crate::emulator::devices::Device;
+use crate::emulator::error::{Error, Result};
+
+pub const HEADER_SIZE: usize = 16;
+pub const TRAINER_SIZE: usize = 512;
+pub const MAGIC: &[u8; 4] = b"NES\x1A";
+
+const PRG_ROM_UNIT: usize = 0x4000;
+const CHR_ROM_UNIT: usize = 0x2000;
+const DEFAULT_PRG_RAM_SIZE: usize = 0x2000;
+const DEFAULT_CHR_RAM_SIZE: usize = 0x2000;
+
+// Battery backed RAM is written out this long after the last change to it
+const SAVE_DELAY_SECONDS: f64 = 1.0;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub nes2: bool,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    pub chr_ram_size: usize,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub trainer: bool,
+}
+
+impl Header {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < HEADER_SIZE || &data[0..4] != MAGIC {
+            return Err(Error::BadHeader(String::from("not an iNES image")));
+        }
+
+        let flags6 = data[6];
+        let flags7 = data[7];
+        let nes2 = flags7 & 0x0C == 0x08;
+
+        let mirroring = if flags6 & 0x08 != 0 {
+            Mirroring::FourScreen
+        } else if flags6 & 0x01 != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+        let battery = flags6 & 0x02 != 0;
+        let trainer = flags6 & 0x04 != 0;
+
+        let header = if nes2 {
+            let mapper =
+                (flags6 >> 4) as u16 | (flags7 & 0xF0) as u16 | ((data[8] & 0x0F) as u16) << 8;
+            let ram_size = |shift: u8| if shift == 0 { 0 } else { 64 << shift };
+            Self {
+                nes2,
+                mapper,
+                submapper: data[8] >> 4,
+                prg_rom_size: nes2_rom_size(data[4], data[9] & 0x0F, PRG_ROM_UNIT),
+                chr_rom_size: nes2_rom_size(data[5], data[9] >> 4, CHR_ROM_UNIT),
+                prg_ram_size: ram_size(data[10] & 0x0F) + ram_size(data[10] >> 4),
+                chr_ram_size: ram_size(data[11] & 0x0F) + ram_size(data[11] >> 4),
+                mirroring,
+                battery,
+                trainer,
+            }
+        } else {
+            // Old dumps have junk like "DiskDude!" in bytes 7-15, so only
+            // trust byte 7 if the end of the header is clean
+            let clean = data[12..16].iter().all(|byte| *byte == 0);
+            let high = if clean { flags7 & 0xF0 } else { 0 };
+            let chr_rom_size = data[5] as usize * CHR_ROM_UNIT;
+            Self {
+                nes2,
+                mapper: (flags6 >> 4 | high) as u16,
+                submapper: 0,
+                prg_rom_size: data[4] as usize * PRG_ROM_UNIT,
+                chr_rom_size,
+                prg_ram_size: (data[8].max(1) as usize) * DEFAULT_PRG_RAM_SIZE,
+                chr_ram_size: if chr_rom_size == 0 {
+                    DEFAULT_CHR_RAM_SIZE
+                } else {
+                    0
+                },
+                mirroring,
+                battery,
+                trainer,
+            }
+        };
+
+        if header.prg_rom_size == 0 {
+            return Err(Error::BadHeader(String::from("image has no PRG ROM")));
+        }
+        Ok(header)
+    }
+}
+
+// A NES 2.0 ROM size: `lsb` units, plus `msb` * 256, or in exponent-multiplier
+// form if the MSB nibble is $F
+fn nes2_rom_size(lsb: u8, msb: u8, unit: usize) -> usize {
+    if msb == 0x0F {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0x03) as usize * 2 + 1;
+        (1usize << exponent.min(40)) * multiplier
+    } else {
+        ((msb as usize) << 8 | lsb as usize) * unit
+    }
+}
+
+pub struct Cartridge {
+    header: Header,
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_writable: bool,
+    prg_ram: Vec<u8>,
+    mapper: Box<dyn Mapper>,
+
+    save_path: Option<PathBuf>,
+    unsaved: bool,
+}
+
+impl Cartridge {
+    // Build a cartridge from a ROM image in memory
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let header = Header::parse(data)?;
+
+        let mut offset = HEADER_SIZE;
+        let trainer = if header.trainer {
+            let trainer = data.get(offset..offset + TRAINER_SIZE);
+            offset += TRAINER_SIZE;
+            trainer
+        } else {
+            None
+        };
+
+        let prg_end = offset + header.prg_rom_size;
+        let chr_end = prg_end + header.chr_rom_size;
+        if data.len() < chr_end {
+            return Err(Error::BadImage(format!(
+                "image is {} bytes, the header says {}",
+                data.len(),
+                chr_end
+            )));
+        }
+        let prg_rom = data[offset..prg_end].to_vec();
+
+        let (chr, chr_writable) = if header.chr_rom_size > 0 {
+            (data[prg_end..chr_end].to_vec(), false)
+        } else {
+            (vec![0; header.chr_ram_size.max(DEFAULT_CHR_RAM_SIZE)], true)
+        };
+
+        let mapper = mappers::create(header.mapper, prg_rom.len(), chr.len()).ok_or_else(|| {
+            Error::BadHeader(format!("mapper {} is not supported", header.mapper))
+        })?;
+
+        let mut prg_ram = vec![0; header.prg_ram_size.max(DEFAULT_PRG_RAM_SIZE)];
+        // The trainer is loaded at $7000
+        if let Some(trainer) = trainer {
+            prg_ram[0x1000..0x1000 + TRAINER_SIZE].copy_from_slice(trainer);
+        }
+
+        Ok(Self {
+            header,
+            prg_rom,
+            chr,
+            chr_writable,
+            prg_ram,
+            mapper,
+            save_path: None,
+            unsaved: false,
+        })
+    }
+
+    // Load a ROM image, and its battery backed RAM from a `.sav` file next to it
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let data = fs::read(path).map_err(|error| Error::io(path, error))?;
+        let mut cartridge = Self::from_bytes(&data)?;
+
+        if cartridge.header.battery {
+            let save_path = path.with_extension("sav");
+            match fs::read(&save_path) {
+                Ok(save) => {
+                    let length = save.len().min(cartridge.prg_ram.len());
+                    cartridge.prg_ram[..length].copy_from_slice(&save[..length]);
+                }
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+                Err(error) => return Err(Error::io(save_path, error)),
+            }
+            cartridge.save_path = Some(save_path);
+        }
+        Ok(cartridge)
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    // Where battery backed RAM is saved, if it is
+    pub fn save_path(&self) -> Option<&Path> {
+        self.save_path.as_deref()
+    }
+
+    // Write battery backed RAM to its `.sav` file, if it has changed
+    pub fn save(&mut self) -> io::Result<()> {
+        if let Some(path) = &self.save_path {
+            if self.unsaved {
+                fs::write(path, &self.prg_ram)?;
+                self.unsaved = false;
+            }
+        }
+        Ok(())
+    }
+
+    // A CPU read of $4020-$FFFF
+    pub fn read_prg(&self, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7FFF => self.prg_ram[(address as usize - 0x6000) % self.prg_ram.len()],
+            0x8000..=0xFFFF => self.prg_rom[self.mapper.prg_offset(address) % self.prg_rom.len()],
+            // Nothing on the board answers; the bus holds its last value
+            _ => (address >> 8) as u8,
+        }
+    }
+
+    // A CPU write to $4020-$FFFF
+    pub fn write_prg(&mut self, address: u16, data: u8) {
+        match address {
+            0x6000..=0x7FFF => {
+                let size = self.prg_ram.len();
+                self.prg_ram[(address as usize - 0x6000) % size] = data;
+                self.unsaved |= self.save_path.is_some();
+            }
+            0x8000..=0xFFFF => self.mapper.write(address, data),
+            _ => {}
+        }
+    }
+
+    pub fn irq(&self) -> bool {
+        self.mapper.irq()
+    }
+
+    pub fn counting_scanlines(&self) -> bool {
+        self.mapper.counting_scanlines()
+    }
+
+    pub fn reset(&mut self) {
+        self.mapper.reset();
+    }
+}
+
+impl ChrBus for Cartridge {
+    fn read_chr(&mut self, address: u16) -> u8 {
+        let offset = self.mapper.chr_offset(address) % self.chr.len();
+        self.chr[offset]
+    }
+
+    fn write_chr(&mut self, address: u16, data: u8) {
+        let offset = self.mapper.chr_offset(address) % self.chr.len();
+        if self.chr_writable {
+            self.chr[offset] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        // The four-screen wiring on the board wins over the mapper
+        match self.header.mirroring {
+            Mirroring::FourScreen => Mirroring::FourScreen,
+            mirroring => self.mapper.mirroring().unwrap_or(mirroring),
+        }
+    }
+}
+
+impl Drop for Cartridge {
+    fn drop(&mut self) {
+        if let Err(error) = self.save() {
+            eprintln!("cartridge: could not save battery RAM: {}", error);
+        }
+    }
+}
+
+/**
+ * The CPU side of the cartridge connector, mapped at $4020-$FFFF.
+ *
+ * A mapper's scanline IRQ is clocked by the PPU's pattern fetches, and the
+ * PPU only runs when something catches it up. While the mapper is counting,
+ * the slot catches the PPU up once per scanline so the IRQ arrives on time,
+ * and reports the mapper's IRQ line itself, so acknowledging it takes
+ * effect straight away.
+ */
+pub struct CartridgeSlot {
+    cartridge: Arc<Mutex<Cartridge>>,
+    ppu: Arc<Mutex<Ppu>>,
+    save_at: Option<u64>,
+    last_cycle: u64,
+    clock_hz: f64,
+}
+
+impl CartridgeSlot {
+    pub const START: u16 = 0x4020;
+    pub const END: u16 = 0xFFFF;
+
+    pub fn new(cartridge: Arc<Mutex<Cartridge>>, ppu: Arc<Mutex<Ppu>>) -> Self {
+        Self {
+            cartridge,
+            ppu,
+            save_at: None,
+            last_cycle: 0,
+            clock_hz: 1_789_773.0,
+        }
+    }
+}
+
+impl Device for CartridgeSlot {
+    fn name(&self) -> &str {
+        "cartridge"
+    }
+
+    fn read(&mut self, offset: u16) -> u8 {
+        self.peek(offset)
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        self.cartridge
+            .lock()
+            .unwrap()
+            .read_prg(Self::START + offset)
+    }
+
+    fn write(&mut self, offset: u16, data: u8) {
+        let address = Self::START + offset;
+        let mut cartridge = self.cartridge.lock().unwrap();
+        cartridge.write_prg(address, data);
+
+        if cartridge.save_path().is_some() && (0x6000..=0x7FFF).contains(&address) {
+            let delay = (self.clock_hz * SAVE_DELAY_SECONDS) as u64;
+            self.save_at = Some(self.last_cycle + delay);
+        }
+    }
+
+    fn tick(&mut self, now: u64) {
+        self.last_cycle = now;
+
+        if self.save_at.is_some_and(|save_at| now >= save_at) {
+            self.save_at = None;
+            if let Err(error) = self.cartridge.lock().unwrap().save() {
+                eprintln!("cartridge: could not save battery RAM: {}", error);
+            }
+        }
+
+        // The PPU locks the cartridge as it fetches, so it mustn't be held here
+        let counting = self.cartridge.lock().unwrap().counting_scanlines();
+        if counting {
+            self.ppu.lock().unwrap().tick(now);
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        let counting = self.cartridge.lock().unwrap().counting_scanlines();
+        // Just after the sprite fetches at the start of the next line's
+        // horizontal blank, where A12 rises
+        let scanline = counting.then(|| self.ppu.lock().unwrap().cycle_after_dot(261));
+        match (scanline, self.save_at) {
+            (Some(scanline), Some(save_at)) => Some(scanline.min(save_at)),
+            (scanline, save_at) => scanline.or(save_at),
+        }
+    }
+
+    fn clock_rate_changed(&mut self, hz: f64) {
+        self.clock_hz = hz;
+    }
+
+    fn reset(&mut self) {
+        self.cartridge.lock().unwrap().reset();
+        self.last_cycle = 0;
+    }
+
+    fn irq(&self) -> bool {
+        self.cartridge.lock().unwrap().irq()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An image with `prg_banks` 16 KiB banks filled with their bank number
+    fn image(flags6: u8, flags7: u8, prg_banks: u8, chr_banks: u8) -> Vec<u8> {
+        let mut data = vec![b'N', b'E', b'S', 0x1A, prg_banks, chr_banks, flags6, flags7];
+        data.resize(HEADER_SIZE, 0);
+        for bank in 0..prg_banks {
+            data.extend(std::iter::repeat_n(bank, PRG_ROM_UNIT));
+        }
+        data.extend(std::iter::repeat_n(0xC5, chr_banks as usize * CHR_ROM_UNIT));
+        data
+    }
+
+    #[test]
+    fn test_headers() {
+        // iNES: UxROM, vertical mirroring, battery, no CHR ROM
+        let header = Header::parse(&image(0x23, 0x00, 8, 0)).unwrap();
+        assert!(!header.nes2);
+        assert_eq!(header.mapper, 2);
+        assert_eq!(header.mirroring, Mirroring::Vertical);
+        assert!(header.battery);
+        assert_eq!(header.prg_rom_size, 128 * 1024);
+        assert_eq!(header.chr_ram_size, 8 * 1024);
+
+        // Junk at the end of an iNES header hides the mapper's high nibble
+        let mut data = image(0x10, 0x40, 1, 1);
+        data[12..16].copy_from_slice(b"Dude");
+        assert_eq!(Header::parse(&data).unwrap().mapper, 1);
+
+        // NES 2.0: mapper 4, 8 KiB of battery RAM, 32 KiB of CHR RAM
+        let mut data = image(0x42, 0x08, 2, 0);
+        data[10] = 0x70;
+        data[11] = 0x09;
+        let header = Header::parse(&data).unwrap();
+        assert!(header.nes2);
+        assert_eq!(header.mapper, 4);
+        assert_eq!(header.prg_ram_size, 8 * 1024);
+        assert_eq!(header.chr_ram_size, 32 * 1024);
+
+        assert!(matches!(Header::parse(b"NES"), Err(Error::BadHeader(_))));
+        let truncated = Cartridge::from_bytes(&image(0x00, 0x00, 2, 1)[..0x5000]);
+        assert!(matches!(truncated, Err(Error::BadImage(_))));
+        let mmc5 = Cartridge::from_bytes(&image(0x50, 0x00, 1, 1));
+        assert!(matches!(mmc5, Err(Error::BadHeader(_))));
+    }
+
+    #[test]
+    fn test_banks_and_battery() {
+        let directory =
+            std::env::temp_dir().join(format!("rusty502_cartridge_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("game.nes");
+        fs::write(&path, image(0x22, 0x00, 8, 0)).unwrap();
+
+        {
+            let mut cartridge = Cartridge::open(&path).unwrap();
+            // UxROM: switchable bank at $8000, last bank at $C000
+            assert_eq!(cartridge.read_prg(0x8000), 0);
+            assert_eq!(cartridge.read_prg(0xFFFF), 7);
+            cartridge.write_prg(0x8000, 3);
+            assert_eq!(cartridge.read_prg(0xBFFF), 3);
+
+            cartridge.write_prg(0x6000, 0x42);
+            cartridge.write_chr(0x0010, 0x99);
+            assert_eq!(cartridge.read_chr(0x0010), 0x99);
+        }
+
+        // The RAM comes back from game.sav
+        assert_eq!(fs::read(directory.join("game.sav")).unwrap()[0], 0x42);
+        let cartridge = Cartridge::open(&path).unwrap();
+        assert_eq!(cartridge.read_prg(0x6000), 0x42);
+        drop(cartridge);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
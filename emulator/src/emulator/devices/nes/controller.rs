@@ -0,0 +1,75 @@
+// Button masks, in shift order
+pub const BUTTON_A: u8 = 0x01;
+pub const BUTTON_B: u8 = 0x02;
+pub const BUTTON_SELECT: u8 = 0x04;
+pub const BUTTON_START: u8 = 0x08;
+pub const BUTTON_UP: u8 = 0x10;
+pub const BUTTON_DOWN: u8 = 0x20;
+pub const BUTTON_LEFT: u8 = 0x40;
+pub const BUTTON_RIGHT: u8 = 0x80;
+
+/**
+ * A standard NES controller: eight buttons behind a 4021 shift register.
+ *
+ * While the strobe is high the register keeps reloading from the buttons,
+ * so reads return the state of A. Once it goes low each read shifts out the
+ * next button, in the order A, B, Select, Start, Up, Down, Left, Right,
+ * after which an official controller returns 1s.
+ */
+#[derive(Clone, Copy, Default)]
+pub struct Controller {
+    buttons: u8,
+    shift: u8,
+    shifted: u8,
+    strobe: bool,
+}
+
+impl Controller {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn press(&mut self, buttons: u8) {
+        self.buttons |= buttons;
+    }
+
+    pub fn release(&mut self, buttons: u8) {
+        self.buttons &= !buttons;
+    }
+
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.buttons = buttons;
+    }
+
+    pub fn buttons(&self) -> u8 {
+        self.buttons
+    }
+
+    pub fn set_strobe(&mut self, strobe: bool) {
+        self.strobe = strobe;
+        if strobe {
+            self.shift = self.buttons;
+            self.shifted = 0;
+        }
+    }
+
+    // The next bit on the data line, shifting the register unless strobed
+    pub fn read(&mut self) -> u8 {
+        let bit = self.peek();
+        if !self.strobe && self.shifted < 8 {
+            self.shift >>= 1;
+            self.shifted += 1;
+        }
+        bit
+    }
+
+    pub fn peek(&self) -> u8 {
+        if self.strobe {
+            self.buttons & BUTTON_A
+        } else if self.shifted >= 8 {
+            1
+        } else {
+            self.shift & 0x01
+        }
+    }
+}
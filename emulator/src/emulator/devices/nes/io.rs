@@ -0,0 +1,178 @@
+/**
+ * The 2A03's register block at $4000-$401F: the APU, OAM DMA and the
+ * controller ports.
+ *
+ * The APU's registers are passed on to it (see `apu`), and when its DMC
+ * needs a sample byte this block fetches it, taking the bus away from the
+ * CPU for 4 cycles.
+ *
+ * Writing a page number to $4014 copies that page of CPU memory to the
+ * PPU's OAM through $2004, 256 reads and writes during which the CPU is
+ * held off the bus for 513 cycles, or 514 if the transfer starts on an odd
+ * cycle (it has to wait for a read cycle to line up with the APU clock).
+ *
+ * Registers:
+ *
+ *   $00-$13 (W) APU channels
+ *   $14     (W) OAM DMA page
+ *   $15     (R/W) APU status / channel enable
+ *   $16     (W) controller strobe (bit 0)
+ *   $16     (R) controller 1 data (bit 0)
+ *   $17     (W) APU frame counter
+ *   $17     (R) controller 2 data (bit 0)
+ *   $18-$1F     CPU test mode, disabled on retail units
+ */
+use super::apu::{Apu, DMC_FETCH_CYCLES};
+use super::controller::Controller;
+use crate::emulator::devices::{Device, Dma};
+
+pub const OAM_DMA: u16 = 0x14;
+pub const APU_STATUS: u16 = 0x15;
+pub const JOY1: u16 = 0x16;
+pub const JOY2: u16 = 0x17;
+
+// Where OAM DMA writes to
+pub const OAM_DATA_ADDRESS: u16 = 0x2004;
+
+// Cycles the CPU is stalled for by OAM DMA starting on an even cycle
+pub const OAM_DMA_CYCLES: u64 = 513;
+
+// The upper bits of a controller read are open bus, left over from the
+// high byte of the $4016/$4017 address
+const OPEN_BUS: u8 = 0x40;
+
+pub struct NesIo {
+    apu: Apu,
+    controllers: [Controller; 2],
+    oam_dma_page: Option<u8>,
+    last_cycle: u64,
+}
+
+impl Default for NesIo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NesIo {
+    pub fn new() -> Self {
+        Self {
+            apu: Apu::new(),
+            controllers: [Controller::new(); 2],
+            oam_dma_page: None,
+            last_cycle: 0,
+        }
+    }
+
+    // Controller 0 is plugged into $4016, controller 1 into $4017
+    pub fn controller(&self, port: usize) -> &Controller {
+        &self.controllers[port]
+    }
+
+    pub fn controller_mut(&mut self, port: usize) -> &mut Controller {
+        &mut self.controllers[port]
+    }
+
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+}
+
+impl Device for NesIo {
+    fn name(&self) -> &str {
+        "nes_io"
+    }
+
+    fn read(&mut self, offset: u16) -> u8 {
+        match offset {
+            JOY1 => OPEN_BUS | self.controllers[0].read(),
+            JOY2 => OPEN_BUS | self.controllers[1].read(),
+            APU_STATUS => self.apu.read_status(),
+            _ => self.peek(offset),
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        match offset {
+            JOY1 => OPEN_BUS | self.controllers[0].peek(),
+            JOY2 => OPEN_BUS | self.controllers[1].peek(),
+            APU_STATUS => self.apu.peek_status(),
+            _ => 0x00,
+        }
+    }
+
+    fn write(&mut self, offset: u16, data: u8) {
+        match offset {
+            OAM_DMA => self.oam_dma_page = Some(data),
+            JOY1 => {
+                // One strobe line goes to both ports
+                for controller in &mut self.controllers {
+                    controller.set_strobe(data & 0x01 != 0);
+                }
+            }
+            0x00..=0x17 => self.apu.write(offset, data),
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, now: u64) {
+        while self.last_cycle < now {
+            // Stop to let the bus fetch the DMC's next byte
+            if self.apu.dmc_address().is_some() {
+                return;
+            }
+            self.apu.clock();
+            self.last_cycle += 1;
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        self.apu
+            .cycles_until_event()
+            .map(|cycles| self.last_cycle + cycles)
+    }
+
+    fn clock_rate_changed(&mut self, hz: f64) {
+        self.apu.set_clock_rate(hz);
+    }
+
+    fn reset(&mut self) {
+        self.apu.reset();
+        for controller in &mut self.controllers {
+            controller.set_strobe(false);
+        }
+        self.oam_dma_page = None;
+        self.last_cycle = 0;
+    }
+
+    fn irq(&self) -> bool {
+        self.apu.irq()
+    }
+
+    fn dma_pending(&self) -> bool {
+        self.oam_dma_page.is_some() || self.apu.dmc_address().is_some()
+    }
+
+    fn dma(&mut self, bus: &mut dyn Dma) -> u64 {
+        let mut stall = 0;
+
+        if let Some(address) = self.apu.dmc_address() {
+            self.apu.dmc_fill(bus.read(address));
+            stall += DMC_FETCH_CYCLES;
+        }
+
+        if let Some(page) = self.oam_dma_page.take() {
+            let base = (page as u16) << 8;
+            for offset in 0..0x100 {
+                let data = bus.read(base | offset);
+                bus.write(OAM_DATA_ADDRESS, data);
+            }
+            stall += OAM_DMA_CYCLES + (self.last_cycle & 1);
+        }
+        stall
+    }
+}
@@ -0,0 +1,487 @@
+/**
+ * Cartridge mappers: the bank switching hardware on NES boards.
+ *
+ * A mapper only decides where things go. The cartridge owns the PRG ROM,
+ * CHR memory and PRG RAM, and asks its mapper which offset into them a CPU
+ * read of $8000-$FFFF or a PPU read of $0000-$1FFF lands on. Writes to
+ * $8000-$FFFF go to the mapper's registers.
+ *
+ * Supported: 0 (NROM), 1 (MMC1), 2 (UxROM), 3 (CNROM) and 4 (MMC3).
+ */
+use super::ppu::Mirroring;
+
+const PRG_BANK_8K: usize = 0x2000;
+const PRG_BANK_16K: usize = 0x4000;
+const CHR_BANK_1K: usize = 0x0400;
+const CHR_BANK_4K: usize = 0x1000;
+const CHR_BANK_8K: usize = 0x2000;
+
+pub trait Mapper: Send {
+    /// Handles a CPU write to $8000-$FFFF.
+    fn write(&mut self, address: u16, data: u8);
+
+    /// Offset into PRG ROM for a CPU read of $8000-$FFFF.
+    fn prg_offset(&self, address: u16) -> usize;
+
+    /// Offset into CHR memory for a PPU access to $0000-$1FFF. Called for
+    /// every pattern fetch, so mappers can watch the PPU address lines.
+    fn chr_offset(&mut self, address: u16) -> usize;
+
+    /// The nametable mirroring, if the mapper controls it.
+    fn mirroring(&self) -> Option<Mirroring> {
+        None
+    }
+
+    /// Level of the mapper's IRQ output.
+    fn irq(&self) -> bool {
+        false
+    }
+
+    /// Whether the mapper is counting scanlines towards an IRQ, i.e. needs
+    /// to be kept up to date with the PPU.
+    fn counting_scanlines(&self) -> bool {
+        false
+    }
+
+    /// Puts the registers back into their power-on state.
+    fn reset(&mut self);
+}
+
+// The mapper for iNES mapper number `number`, for `prg_size` bytes of PRG
+// ROM and `chr_size` bytes of CHR
+pub fn create(number: u16, prg_size: usize, chr_size: usize) -> Option<Box<dyn Mapper>> {
+    let mapper: Box<dyn Mapper> = match number {
+        0 => Box::new(Nrom::new(prg_size)),
+        1 => Box::new(Mmc1::new(prg_size, chr_size)),
+        2 => Box::new(Uxrom::new(prg_size)),
+        3 => Box::new(Cnrom::new(chr_size)),
+        4 => Box::new(Mmc3::new(prg_size, chr_size)),
+        _ => return None,
+    };
+    Some(mapper)
+}
+
+// Offset of `address` within a bank of `size` bytes, in bank `bank` of `count`
+fn banked(bank: usize, count: usize, size: usize, address: u16) -> usize {
+    (bank % count.max(1)) * size + (address as usize & (size - 1))
+}
+
+// Mapper 0: 16 or 32 KiB of PRG ROM and 8 KiB of CHR, no switching
+pub struct Nrom {
+    prg_size: usize,
+}
+
+impl Nrom {
+    pub fn new(prg_size: usize) -> Self {
+        Self { prg_size }
+    }
+}
+
+impl Mapper for Nrom {
+    fn write(&mut self, _address: u16, _data: u8) {}
+
+    fn prg_offset(&self, address: u16) -> usize {
+        // 16 KiB boards show the same bank at $8000 and $C000
+        (address as usize - 0x8000) % self.prg_size.max(1)
+    }
+
+    fn chr_offset(&mut self, address: u16) -> usize {
+        address as usize
+    }
+
+    fn reset(&mut self) {}
+}
+
+// Mapper 1: MMC1, written one bit at a time through a shift register
+pub struct Mmc1 {
+    prg_banks: usize,
+    chr_banks: usize,
+    shift: u8,
+    shift_count: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+}
+
+impl Mmc1 {
+    pub fn new(prg_size: usize, chr_size: usize) -> Self {
+        Self {
+            prg_banks: prg_size / PRG_BANK_16K,
+            chr_banks: chr_size / CHR_BANK_4K,
+            shift: 0,
+            shift_count: 0,
+            // PRG mode 3 at power-on: the last bank is fixed at $C000
+            control: 0x0C,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+        }
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn write(&mut self, address: u16, data: u8) {
+        if data & 0x80 != 0 {
+            self.shift = 0;
+            self.shift_count = 0;
+            self.control |= 0x0C;
+            return;
+        }
+
+        self.shift |= (data & 0x01) << self.shift_count;
+        self.shift_count += 1;
+        if self.shift_count < 5 {
+            return;
+        }
+
+        let value = self.shift;
+        self.shift = 0;
+        self.shift_count = 0;
+        match address & 0xE000 {
+            0x8000 => self.control = value,
+            0xA000 => self.chr_bank_0 = value,
+            0xC000 => self.chr_bank_1 = value,
+            _ => self.prg_bank = value & 0x0F,
+        }
+    }
+
+    fn prg_offset(&self, address: u16) -> usize {
+        let bank = self.prg_bank as usize;
+        let last = self.prg_banks.saturating_sub(1);
+        let bank = match ((self.control >> 2) & 0x03, address >= 0xC000) {
+            // 32 KiB at a time
+            (0 | 1, false) => bank & !1,
+            (0 | 1, true) => bank | 1,
+            // First bank fixed at $8000
+            (2, false) => 0,
+            (2, true) => bank,
+            // Last bank fixed at $C000
+            (_, false) => bank,
+            (_, true) => last,
+        };
+        banked(bank, self.prg_banks, PRG_BANK_16K, address)
+    }
+
+    fn chr_offset(&mut self, address: u16) -> usize {
+        let bank = if self.control & 0x10 == 0 {
+            // 8 KiB at a time
+            (self.chr_bank_0 & !1) as usize + (address >= 0x1000) as usize
+        } else if address < 0x1000 {
+            self.chr_bank_0 as usize
+        } else {
+            self.chr_bank_1 as usize
+        };
+        banked(bank, self.chr_banks, CHR_BANK_4K, address)
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(match self.control & 0x03 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        })
+    }
+
+    fn reset(&mut self) {
+        self.shift = 0;
+        self.shift_count = 0;
+        self.control = 0x0C;
+    }
+}
+
+// Mapper 2: UxROM, a switchable 16 KiB bank at $8000 and the last at $C000
+pub struct Uxrom {
+    prg_banks: usize,
+    bank: u8,
+}
+
+impl Uxrom {
+    pub fn new(prg_size: usize) -> Self {
+        Self {
+            prg_banks: prg_size / PRG_BANK_16K,
+            bank: 0,
+        }
+    }
+}
+
+impl Mapper for Uxrom {
+    fn write(&mut self, _address: u16, data: u8) {
+        self.bank = data;
+    }
+
+    fn prg_offset(&self, address: u16) -> usize {
+        let bank = if address >= 0xC000 {
+            self.prg_banks.saturating_sub(1)
+        } else {
+            self.bank as usize
+        };
+        banked(bank, self.prg_banks, PRG_BANK_16K, address)
+    }
+
+    fn chr_offset(&mut self, address: u16) -> usize {
+        address as usize
+    }
+
+    fn reset(&mut self) {
+        self.bank = 0;
+    }
+}
+
+// Mapper 3: CNROM, fixed PRG and a switchable 8 KiB CHR bank
+pub struct Cnrom {
+    chr_banks: usize,
+    bank: u8,
+}
+
+impl Cnrom {
+    pub fn new(chr_size: usize) -> Self {
+        Self {
+            chr_banks: chr_size / CHR_BANK_8K,
+            bank: 0,
+        }
+    }
+}
+
+impl Mapper for Cnrom {
+    fn write(&mut self, _address: u16, data: u8) {
+        self.bank = data;
+    }
+
+    fn prg_offset(&self, address: u16) -> usize {
+        // 16 or 32 KiB, like NROM
+        address as usize - 0x8000
+    }
+
+    fn chr_offset(&mut self, address: u16) -> usize {
+        banked(self.bank as usize, self.chr_banks, CHR_BANK_8K, address)
+    }
+
+    fn reset(&mut self) {
+        self.bank = 0;
+    }
+}
+
+// Mapper 4: MMC3, with 8 KiB PRG and 1-2 KiB CHR banks and a scanline
+// counter clocked by rising edges of PPU A12
+pub struct Mmc3 {
+    prg_banks: usize,
+    chr_banks: usize,
+    bank_select: u8,
+    registers: [u8; 8],
+    mirroring: Mirroring,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq: bool,
+
+    // A12 has to stay low for a while before a rise counts, so the short
+    // dips between sprite fetches don't clock the counter
+    a12_low_fetches: u8,
+}
+
+// Pattern fetches A12 has to be low for before a rise clocks the counter
+const A12_FILTER_FETCHES: u8 = 3;
+
+impl Mmc3 {
+    pub fn new(prg_size: usize, chr_size: usize) -> Self {
+        Self {
+            prg_banks: prg_size / PRG_BANK_8K,
+            chr_banks: chr_size / CHR_BANK_1K,
+            bank_select: 0,
+            registers: [0, 2, 4, 5, 6, 7, 0, 1],
+            mirroring: Mirroring::Vertical,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq: false,
+            a12_low_fetches: A12_FILTER_FETCHES,
+        }
+    }
+
+    fn clock_counter(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq = true;
+        }
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn write(&mut self, address: u16, data: u8) {
+        let odd = address & 0x01 != 0;
+        match (address & 0xE000, odd) {
+            (0x8000, false) => self.bank_select = data,
+            (0x8000, true) => self.registers[self.bank_select as usize & 0x07] = data,
+            (0xA000, false) => {
+                // Four-screen boards ignore this
+                if self.mirroring != Mirroring::FourScreen {
+                    self.mirroring = if data & 0x01 == 0 {
+                        Mirroring::Vertical
+                    } else {
+                        Mirroring::Horizontal
+                    };
+                }
+            }
+            // PRG RAM protection isn't emulated
+            (0xA000, true) => {}
+            (0xC000, false) => self.irq_latch = data,
+            (0xC000, true) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (_, false) => {
+                self.irq_enabled = false;
+                self.irq = false;
+            }
+            (_, true) => self.irq_enabled = true,
+        }
+    }
+
+    fn prg_offset(&self, address: u16) -> usize {
+        let second_last = self.prg_banks.saturating_sub(2);
+        let last = self.prg_banks.saturating_sub(1);
+        let swapped = self.bank_select & 0x40 != 0;
+        let bank = match (address >> 13) & 0x03 {
+            0 if swapped => second_last,
+            0 => self.registers[6] as usize & 0x3F,
+            1 => self.registers[7] as usize & 0x3F,
+            2 if swapped => self.registers[6] as usize & 0x3F,
+            2 => second_last,
+            _ => last,
+        };
+        banked(bank, self.prg_banks, PRG_BANK_8K, address)
+    }
+
+    fn chr_offset(&mut self, address: u16) -> usize {
+        if address & 0x1000 != 0 {
+            if self.a12_low_fetches >= A12_FILTER_FETCHES {
+                self.clock_counter();
+            }
+            self.a12_low_fetches = 0;
+        } else {
+            self.a12_low_fetches = self.a12_low_fetches.saturating_add(1);
+        }
+
+        // With CHR inversion the 2 KiB banks move to $1000
+        let address = if self.bank_select & 0x80 != 0 {
+            address ^ 0x1000
+        } else {
+            address
+        };
+        let bank = match address >> 10 {
+            0 => self.registers[0] as usize & !1,
+            1 => self.registers[0] as usize | 1,
+            2 => self.registers[1] as usize & !1,
+            3 => self.registers[1] as usize | 1,
+            slot => self.registers[slot as usize - 2] as usize,
+        };
+        banked(bank, self.chr_banks, CHR_BANK_1K, address)
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(self.mirroring)
+    }
+
+    fn irq(&self) -> bool {
+        self.irq
+    }
+
+    fn counting_scanlines(&self) -> bool {
+        self.irq_enabled
+    }
+
+    fn reset(&mut self) {
+        self.bank_select = 0;
+        self.irq_enabled = false;
+        self.irq = false;
+        self.irq_reload = false;
+        self.irq_counter = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Write a value to an MMC1 register one bit at a time
+    fn mmc1_write(mapper: &mut Mmc1, address: u16, value: u8) {
+        for bit in 0..5 {
+            mapper.write(address, (value >> bit) & 0x01);
+        }
+    }
+
+    #[test]
+    fn test_mmc1_banking() {
+        // 256 KiB PRG (16 banks), 128 KiB CHR (32 4 KiB banks)
+        let mut mapper = Mmc1::new(16 * PRG_BANK_16K, 32 * CHR_BANK_4K);
+        assert_eq!(mapper.prg_offset(0xC000), 15 * PRG_BANK_16K);
+
+        mmc1_write(&mut mapper, 0xE000, 5);
+        assert_eq!(mapper.prg_offset(0x8123), 5 * PRG_BANK_16K + 0x123);
+        assert_eq!(mapper.prg_offset(0xFFFC), 15 * PRG_BANK_16K + 0x3FFC);
+
+        // 32 KiB mode ignores the low bit; two 4 KiB CHR banks, vertical mirroring
+        mmc1_write(&mut mapper, 0x8000, 0x12);
+        assert_eq!(mapper.prg_offset(0x8000), 4 * PRG_BANK_16K);
+        assert_eq!(mapper.prg_offset(0xC000), 5 * PRG_BANK_16K);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::Vertical));
+        mmc1_write(&mut mapper, 0xA000, 3);
+        mmc1_write(&mut mapper, 0xC000, 9);
+        assert_eq!(mapper.chr_offset(0x0010), 3 * CHR_BANK_4K + 0x10);
+        assert_eq!(mapper.chr_offset(0x1010), 9 * CHR_BANK_4K + 0x10);
+
+        // A write with bit 7 set resets the shift register
+        mapper.write(0xE000, 0x01);
+        mapper.write(0xE000, 0x80);
+        mmc1_write(&mut mapper, 0xE000, 2);
+        assert_eq!(mapper.prg_offset(0x8000), 2 * PRG_BANK_16K);
+    }
+
+    #[test]
+    fn test_mmc3_banking_and_counter() {
+        // 128 KiB PRG (16 8 KiB banks), 128 KiB CHR (128 1 KiB banks)
+        let mut mapper = Mmc3::new(16 * PRG_BANK_8K, 128 * CHR_BANK_1K);
+        mapper.write(0x8000, 0x06);
+        mapper.write(0x8001, 3);
+        assert_eq!(mapper.prg_offset(0x8000), 3 * PRG_BANK_8K);
+        assert_eq!(mapper.prg_offset(0xC000), 14 * PRG_BANK_8K);
+        mapper.write(0x8000, 0x46);
+        assert_eq!(mapper.prg_offset(0x8000), 14 * PRG_BANK_8K);
+        assert_eq!(mapper.prg_offset(0xC000), 3 * PRG_BANK_8K);
+        assert_eq!(mapper.prg_offset(0xE000), 15 * PRG_BANK_8K);
+
+        mapper.write(0x8000, 0x80 | 0x02);
+        mapper.write(0x8001, 40);
+        assert_eq!(mapper.chr_offset(0x0005), 40 * CHR_BANK_1K + 5);
+
+        // IRQ after the counter is reloaded with 2 and counts down to 0
+        mapper.write(0xC000, 2);
+        mapper.write(0xC001, 0);
+        mapper.write(0xE001, 0);
+        let mut lines = 0;
+        while !mapper.irq() {
+            for _ in 0..4 {
+                mapper.chr_offset(0x0000);
+            }
+            mapper.chr_offset(0x1000);
+            // A quick dip doesn't count
+            mapper.chr_offset(0x0000);
+            mapper.chr_offset(0x1000);
+            lines += 1;
+        }
+        assert_eq!(lines, 3);
+
+        mapper.write(0xE000, 0);
+        assert!(!mapper.irq());
+    }
+}
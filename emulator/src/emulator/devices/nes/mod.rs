@@ -0,0 +1,9 @@
+/**
+ * The parts of the NES around its 2A03 CPU.
+ */
+pub mod apu;
+pub mod cartridge;
+pub mod controller;
+pub mod io;
+pub mod mappers;
+pub mod ppu;
@@ -0,0 +1,935 @@
+/**
+ * The Ricoh 2C02, the NES's picture processing unit (NTSC).
+ *
+ * The PPU runs three dots for every CPU cycle, 341 dots to a scanline and
+ * 262 scanlines to a frame: 240 visible lines, an idle line, 20 lines of
+ * vertical blank and a pre-render line. It is emulated dot by dot, with the
+ * same fetches, shift registers and scroll (v/t/x/w) updates as the chip,
+ * so mid-frame register writes and raster effects come out right. On odd
+ * frames with rendering enabled the pre-render line is one dot short.
+ *
+ * The pattern tables ($0000-$1FFF on the PPU bus) and the nametable
+ * mirroring come from the cartridge, through `ChrBus`. The 2 KiB of
+ * nametable RAM, the palette and OAM are inside the PPU.
+ *
+ * Registers (mirrored every 8 bytes):
+ *
+ *   $00 (W) PPUCTRL    $04 (R/W) OAMDATA
+ *   $01 (W) PPUMASK    $05 (W)   PPUSCROLL (x2)
+ *   $02 (R) PPUSTATUS  $06 (W)   PPUADDR (x2)
+ *   $03 (W) OAMADDR    $07 (R/W) PPUDATA
+ *
+ * Frames are drawn into a 256x240 framebuffer as the beam goes, and can be
+ * saved to PNG at the start of each vertical blank.
+ */
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::emulator::devices::Device;
+use crate::emulator::framebuffer::Framebuffer;
+
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 240;
+
+pub const DOTS_PER_LINE: u64 = 341;
+pub const LINES_PER_FRAME: u64 = 262;
+pub const DOTS_PER_CYCLE: u64 = 3;
+
+const VBLANK_LINE: u16 = 241;
+const PRE_RENDER_LINE: u16 = 261;
+
+// Registers
+pub const PPUCTRL: u16 = 0x00;
+pub const PPUMASK: u16 = 0x01;
+pub const PPUSTATUS: u16 = 0x02;
+pub const OAMADDR: u16 = 0x03;
+pub const OAMDATA: u16 = 0x04;
+pub const PPUSCROLL: u16 = 0x05;
+pub const PPUADDR: u16 = 0x06;
+pub const PPUDATA: u16 = 0x07;
+
+// PPUCTRL bits
+const CTRL_INCREMENT_32: u8 = 0x04;
+const CTRL_SPRITE_TABLE: u8 = 0x08;
+const CTRL_BACKGROUND_TABLE: u8 = 0x10;
+const CTRL_SPRITE_8X16: u8 = 0x20;
+const CTRL_NMI: u8 = 0x80;
+
+// PPUMASK bits
+const MASK_GREYSCALE: u8 = 0x01;
+const MASK_BACKGROUND_LEFT: u8 = 0x02;
+const MASK_SPRITES_LEFT: u8 = 0x04;
+const MASK_BACKGROUND: u8 = 0x08;
+const MASK_SPRITES: u8 = 0x10;
+const MASK_EMPHASIS: u8 = 0xE0;
+
+// PPUSTATUS bits
+const STATUS_OVERFLOW: u8 = 0x20;
+const STATUS_SPRITE_0_HIT: u8 = 0x40;
+const STATUS_VBLANK: u8 = 0x80;
+
+// The 2C02's 64 colours, as 0xRRGGBB
+pub const PALETTE: [u32; 64] = [
+    0x666666, 0x002A88, 0x1412A7, 0x3B00A4, 0x5C007E, 0x6E0040, 0x6C0600, 0x561D00,
+    0x333500, 0x0B4800, 0x005200, 0x004F08, 0x00404D, 0x000000, 0x000000, 0x000000,
+    0xADADAD, 0x155FD9, 0x4240FF, 0x7527FE, 0xA01ACC, 0xB71E7B, 0xB53120, 0x994E00,
+    0x6B6D00, 0x388700, 0x0C9300, 0x008F32, 0x007C8D, 0x000000, 0x000000, 0x000000,
+    0xFFFEFF, 0x64B0FF, 0x9290FF, 0xC676FF, 0xF36AFF, 0xFE6ECC, 0xFE8170, 0xEA9E22,
+    0xBCBE00, 0x88D800, 0x5CE430, 0x45E082, 0x48CDDE, 0x4F4F4F, 0x000000, 0x000000,
+    0xFFFEFF, 0xC0DFFF, 0xD3D2FF, 0xE8C8FF, 0xFBC2FF, 0xFEC4EA, 0xFECCC5, 0xF7D8A5,
+    0xE4E594, 0xCFEF96, 0xBDF4AB, 0xB3F3CC, 0xB5EBF2, 0xB8B8B8, 0x000000, 0x000000,
+];
+
+// How the four logical nametables map onto the PPU's 2 KiB of RAM
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    SingleScreenLower,
+    SingleScreenUpper,
+    FourScreen,
+}
+
+impl Mirroring {
+    // Offset into nametable RAM for a nametable address ($2000-$2FFF)
+    fn offset(self, address: u16) -> usize {
+        let table = (address >> 10) & 0x03;
+        let page = match self {
+            Mirroring::Horizontal => table >> 1,
+            Mirroring::Vertical => table & 0x01,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1,
+            Mirroring::FourScreen => table,
+        };
+        (page as usize) << 10 | (address & 0x03FF) as usize
+    }
+}
+
+// The cartridge side of the PPU bus
+pub trait ChrBus: Send {
+    /// Reads the pattern tables at `address` ($0000-$1FFF). Mappers that
+    /// watch the PPU address lines see every fetch here.
+    fn read_chr(&mut self, address: u16) -> u8;
+
+    /// Writes the pattern tables, if they are RAM.
+    fn write_chr(&mut self, address: u16, data: u8);
+
+    /// How the nametables are currently mirrored.
+    fn mirroring(&self) -> Mirroring;
+}
+
+pub type SharedChrBus = Arc<Mutex<dyn ChrBus>>;
+
+// Plain pattern table memory, for boards without a mapper
+pub struct ChrMemory {
+    data: Vec<u8>,
+    writable: bool,
+    mirroring: Mirroring,
+}
+
+impl ChrMemory {
+    // 8 KiB of CHR RAM
+    pub fn ram(mirroring: Mirroring) -> Self {
+        Self {
+            data: vec![0; 0x2000],
+            writable: true,
+            mirroring,
+        }
+    }
+
+    pub fn rom(data: Vec<u8>, mirroring: Mirroring) -> Self {
+        Self {
+            data: if data.is_empty() { vec![0; 0x2000] } else { data },
+            writable: false,
+            mirroring,
+        }
+    }
+}
+
+impl ChrBus for ChrMemory {
+    fn read_chr(&mut self, address: u16) -> u8 {
+        self.data[address as usize % self.data.len()]
+    }
+
+    fn write_chr(&mut self, address: u16, data: u8) {
+        if self.writable {
+            let size = self.data.len();
+            self.data[address as usize % size] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+// A sprite picked for the next scanline
+#[derive(Clone, Copy, Default)]
+struct LineSprite {
+    x: u8,
+    attributes: u8,
+    // Pattern bits, already flipped so bit 7 is the leftmost pixel
+    low: u8,
+    high: u8,
+}
+
+pub struct Ppu {
+    chr: SharedChrBus,
+    nametables: [u8; 0x1000],
+    palette: [u8; 32],
+    oam: [u8; 256],
+
+    ctrl: u8,
+    mask: u8,
+    status: u8,
+    oam_address: u8,
+    read_buffer: u8,
+    // The last value driven onto the register data bus
+    io_latch: u8,
+
+    // Loopy's scroll registers
+    v: u16,
+    t: u16,
+    x: u8,
+    w: bool,
+
+    // Beam position
+    scanline: u16,
+    dot: u16,
+    odd_frame: bool,
+    dots: u64,
+
+    // Background fetches and shift registers
+    next_tile: u8,
+    next_attribute: u8,
+    next_low: u8,
+    next_high: u8,
+    pattern_low: u16,
+    pattern_high: u16,
+    attribute_low: u16,
+    attribute_high: u16,
+
+    // Sprites found for the next line, and the ones being drawn on this one
+    secondary_oam: [u8; 32],
+    found_sprites: usize,
+    next_sprite_0: bool,
+    sprites: [LineSprite; 8],
+    sprite_count: usize,
+    sprite_0_on_line: bool,
+
+    frame: Framebuffer,
+    frame_count: u64,
+    png_output: Option<(PathBuf, u64)>,
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ppu {
+    // A PPU with 8 KiB of CHR RAM and horizontal mirroring, until a cartridge is attached
+    pub fn new() -> Self {
+        Self {
+            chr: Arc::new(Mutex::new(ChrMemory::ram(Mirroring::Horizontal))),
+            nametables: [0; 0x1000],
+            palette: [0; 32],
+            oam: [0; 256],
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            oam_address: 0,
+            read_buffer: 0,
+            io_latch: 0,
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+            scanline: 0,
+            dot: 0,
+            odd_frame: false,
+            dots: 0,
+            next_tile: 0,
+            next_attribute: 0,
+            next_low: 0,
+            next_high: 0,
+            pattern_low: 0,
+            pattern_high: 0,
+            attribute_low: 0,
+            attribute_high: 0,
+            secondary_oam: [0xFF; 32],
+            found_sprites: 0,
+            next_sprite_0: false,
+            sprites: [LineSprite::default(); 8],
+            sprite_count: 0,
+            sprite_0_on_line: false,
+            frame: Framebuffer::new(WIDTH, HEIGHT),
+            frame_count: 0,
+            png_output: None,
+        }
+    }
+
+    pub fn set_chr(&mut self, chr: SharedChrBus) {
+        self.chr = chr;
+    }
+
+    // The last complete frame (or the one being drawn)
+    pub fn frame(&self) -> &Framebuffer {
+        &self.frame
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    // Save every `interval`th frame to `path`, overwriting the previous one
+    pub fn set_png_output<P: Into<PathBuf>>(&mut self, path: P, interval: u64) {
+        self.png_output = Some((path.into(), interval.max(1)));
+    }
+
+    pub fn oam(&self) -> &[u8] {
+        &self.oam
+    }
+
+    // Beam position as (scanline, dot)
+    pub fn position(&self) -> (u16, u16) {
+        (self.scanline, self.dot)
+    }
+
+    // The first CPU cycle by which the beam is past `dot` of the current
+    // scanline, or of the next one if it already is
+    pub fn cycle_after_dot(&self, dot: u16) -> u64 {
+        let dots = if self.dot <= dot {
+            (dot - self.dot) as u64 + 1
+        } else {
+            DOTS_PER_LINE - self.dot as u64 + dot as u64 + 1
+        };
+        (self.dots + dots).div_ceil(DOTS_PER_CYCLE)
+    }
+
+    fn rendering(&self) -> bool {
+        self.mask & (MASK_BACKGROUND | MASK_SPRITES) != 0
+    }
+
+    fn increment(&self) -> u16 {
+        if self.ctrl & CTRL_INCREMENT_32 != 0 {
+            32
+        } else {
+            1
+        }
+    }
+
+    fn sprite_height(&self) -> u16 {
+        if self.ctrl & CTRL_SPRITE_8X16 != 0 {
+            16
+        } else {
+            8
+        }
+    }
+
+    fn palette_index(address: u16) -> usize {
+        let index = (address & 0x1F) as usize;
+        // The backdrop entries of the sprite palettes are the background's
+        if index >= 0x10 && index & 0x03 == 0 {
+            index - 0x10
+        } else {
+            index
+        }
+    }
+
+    fn read_memory(&mut self, address: u16) -> u8 {
+        let address = address & 0x3FFF;
+        match address {
+            0x0000..=0x1FFF => self.chr.lock().unwrap().read_chr(address),
+            0x2000..=0x3EFF => {
+                let mirroring = self.chr.lock().unwrap().mirroring();
+                self.nametables[mirroring.offset(address)]
+            }
+            _ => self.palette[Self::palette_index(address)],
+        }
+    }
+
+    fn write_memory(&mut self, address: u16, data: u8) {
+        let address = address & 0x3FFF;
+        match address {
+            0x0000..=0x1FFF => self.chr.lock().unwrap().write_chr(address, data),
+            0x2000..=0x3EFF => {
+                let mirroring = self.chr.lock().unwrap().mirroring();
+                self.nametables[mirroring.offset(address)] = data;
+            }
+            _ => self.palette[Self::palette_index(address)] = data & 0x3F,
+        }
+    }
+
+    // Coarse X, wrapping into the next horizontal nametable
+    fn increment_x(&mut self) {
+        if self.v & 0x001F == 31 {
+            self.v &= !0x001F;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    // Fine Y, then coarse Y, wrapping into the next vertical nametable
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let mut coarse_y = (self.v & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.v ^= 0x0800;
+        } else if coarse_y == 31 {
+            // Attribute rows wrap without switching nametables
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !0x03E0) | coarse_y << 5;
+    }
+
+    fn copy_x(&mut self) {
+        self.v = (self.v & !0x041F) | (self.t & 0x041F);
+    }
+
+    fn copy_y(&mut self) {
+        self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+    }
+
+    fn load_shifters(&mut self) {
+        self.pattern_low = (self.pattern_low & 0xFF00) | self.next_low as u16;
+        self.pattern_high = (self.pattern_high & 0xFF00) | self.next_high as u16;
+        let low = if self.next_attribute & 0x01 != 0 { 0xFF } else { 0x00 };
+        let high = if self.next_attribute & 0x02 != 0 { 0xFF } else { 0x00 };
+        self.attribute_low = (self.attribute_low & 0xFF00) | low;
+        self.attribute_high = (self.attribute_high & 0xFF00) | high;
+    }
+
+    fn shift(&mut self) {
+        self.pattern_low <<= 1;
+        self.pattern_high <<= 1;
+        self.attribute_low <<= 1;
+        self.attribute_high <<= 1;
+    }
+
+    // One of the eight dots of a background tile fetch
+    fn fetch_background(&mut self) {
+        let table = if self.ctrl & CTRL_BACKGROUND_TABLE != 0 { 0x1000 } else { 0x0000 };
+        let fine_y = (self.v >> 12) & 0x07;
+        match (self.dot - 1) % 8 {
+            0 => {
+                self.load_shifters();
+                self.next_tile = self.read_memory(0x2000 | (self.v & 0x0FFF));
+            }
+            2 => {
+                let address = 0x23C0
+                    | (self.v & 0x0C00)
+                    | ((self.v >> 4) & 0x38)
+                    | ((self.v >> 2) & 0x07);
+                let attribute = self.read_memory(address);
+                // Each attribute byte covers four 2x2 tile quadrants
+                let shift = ((self.v >> 4) & 0x04) | (self.v & 0x02);
+                self.next_attribute = (attribute >> shift) & 0x03;
+            }
+            4 => {
+                let address = table + self.next_tile as u16 * 16 + fine_y;
+                self.next_low = self.read_memory(address);
+            }
+            6 => {
+                let address = table + self.next_tile as u16 * 16 + fine_y + 8;
+                self.next_high = self.read_memory(address);
+            }
+            7 => self.increment_x(),
+            _ => {}
+        }
+    }
+
+    // Pick up to eight sprites on the current line, to draw on the next.
+    // Past eight, the chip's buggy search sets the overflow flag.
+    fn evaluate_sprites(&mut self) {
+        self.secondary_oam = [0xFF; 32];
+        self.found_sprites = 0;
+        self.next_sprite_0 = false;
+        if self.scanline == PRE_RENDER_LINE {
+            return;
+        }
+
+        let height = self.sprite_height();
+        let line = self.scanline;
+        let in_range = |y: u8| line >= y as u16 && line < y as u16 + height;
+
+        let mut n = 0;
+        while n < 64 && self.found_sprites < 8 {
+            if in_range(self.oam[n * 4]) {
+                let slot = self.found_sprites * 4;
+                self.secondary_oam[slot..slot + 4].copy_from_slice(&self.oam[n * 4..n * 4 + 4]);
+                self.next_sprite_0 |= n == 0;
+                self.found_sprites += 1;
+            }
+            n += 1;
+        }
+
+        let mut m = 0;
+        while n < 64 {
+            if in_range(self.oam[n * 4 + m]) {
+                self.status |= STATUS_OVERFLOW;
+                break;
+            }
+            n += 1;
+            m = (m + 1) & 0x03;
+        }
+    }
+
+    // The pattern fetches for sprite slot `slot`, during dots 257-320
+    fn fetch_sprite(&mut self, slot: usize, high: bool) {
+        let [y, tile, attributes, x] = self.secondary_oam[slot * 4..slot * 4 + 4]
+            .try_into()
+            .unwrap();
+        let height = self.sprite_height();
+        let empty = slot >= self.found_sprites;
+
+        let mut row = if empty {
+            0
+        } else {
+            self.scanline.wrapping_sub(y as u16) & (height - 1)
+        };
+        if attributes & 0x80 != 0 && !empty {
+            row = height - 1 - row;
+        }
+
+        let address = if height == 16 {
+            let table = (tile as u16 & 0x01) * 0x1000;
+            let tile = (tile & 0xFE) as u16 + (row >> 3);
+            table + tile * 16 + (row & 0x07)
+        } else {
+            let table = if self.ctrl & CTRL_SPRITE_TABLE != 0 { 0x1000 } else { 0x0000 };
+            table + tile as u16 * 16 + row
+        };
+        let mut data = self.read_memory(address + if high { 8 } else { 0 });
+
+        if empty {
+            return;
+        }
+        if attributes & 0x40 != 0 {
+            data = data.reverse_bits();
+        }
+        let sprite = &mut self.sprites[slot];
+        sprite.x = x;
+        sprite.attributes = attributes;
+        if high {
+            sprite.high = data;
+        } else {
+            sprite.low = data;
+        }
+    }
+
+    fn draw_pixel(&mut self) {
+        let x = (self.dot - 1) as usize;
+        let y = self.scanline as usize;
+
+        let mut background = 0;
+        let mut background_palette = 0;
+        if self.mask & MASK_BACKGROUND != 0 && (x >= 8 || self.mask & MASK_BACKGROUND_LEFT != 0) {
+            let bit = 0x8000 >> self.x;
+            background = (self.pattern_low & bit != 0) as u8 | ((self.pattern_high & bit != 0) as u8) << 1;
+            background_palette =
+                (self.attribute_low & bit != 0) as u8 | ((self.attribute_high & bit != 0) as u8) << 1;
+        }
+
+        let mut sprite = 0;
+        let mut sprite_palette = 0;
+        let mut behind = false;
+        if self.mask & MASK_SPRITES != 0 && (x >= 8 || self.mask & MASK_SPRITES_LEFT != 0) {
+            for (index, line_sprite) in self.sprites[..self.sprite_count].iter().enumerate() {
+                let offset = x.wrapping_sub(line_sprite.x as usize);
+                if offset >= 8 {
+                    continue;
+                }
+                let shift = 7 - offset;
+                let pixel = (line_sprite.low >> shift) & 0x01 | ((line_sprite.high >> shift) & 0x01) << 1;
+                if pixel == 0 {
+                    continue;
+                }
+
+                if index == 0 && self.sprite_0_on_line && background != 0 && x != 255 {
+                    self.status |= STATUS_SPRITE_0_HIT;
+                }
+                sprite = pixel;
+                sprite_palette = line_sprite.attributes & 0x03;
+                behind = line_sprite.attributes & 0x20 != 0;
+                break;
+            }
+        }
+
+        let address = match (background, sprite) {
+            (0, 0) => 0x00,
+            (0, _) => 0x10 | sprite_palette << 2 | sprite,
+            (_, 0) => background_palette << 2 | background,
+            _ if behind => background_palette << 2 | background,
+            _ => 0x10 | sprite_palette << 2 | sprite,
+        };
+        let color = self.color(self.palette[Self::palette_index(address as u16)]);
+        self.frame.set_pixel(x, y, color);
+    }
+
+    // RGB for a palette entry, with greyscale and colour emphasis applied
+    fn color(&self, entry: u8) -> u32 {
+        let entry = if self.mask & MASK_GREYSCALE != 0 { entry & 0x30 } else { entry & 0x3F };
+        let rgb = PALETTE[entry as usize];
+        let emphasis = self.mask & MASK_EMPHASIS;
+        if emphasis == 0 {
+            return rgb;
+        }
+
+        // Emphasising a colour darkens the other two
+        let mut channels = [(rgb >> 16) & 0xFF, (rgb >> 8) & 0xFF, rgb & 0xFF];
+        for (channel, bit) in channels.iter_mut().zip([0x20, 0x40, 0x80]) {
+            let darkened = emphasis & !bit != 0;
+            if darkened {
+                *channel = *channel * 3 / 4;
+            }
+        }
+        channels[0] << 16 | channels[1] << 8 | channels[2]
+    }
+
+    fn end_frame(&mut self) {
+        self.frame_count += 1;
+        if let Some((path, interval)) = &self.png_output {
+            if self.frame_count.is_multiple_of(*interval) {
+                if let Err(error) = self.frame.save_png(path) {
+                    eprintln!("ppu: could not save {}: {}", path.display(), error);
+                }
+            }
+        }
+    }
+
+    // Run one dot
+    fn step(&mut self) {
+        let visible = self.scanline < HEIGHT as u16;
+        let pre_render = self.scanline == PRE_RENDER_LINE;
+
+        if self.rendering() && (visible || pre_render) {
+            let dot = self.dot;
+            if (2..=257).contains(&dot) || (322..=337).contains(&dot) {
+                self.shift();
+            }
+            if (2..=257).contains(&dot) || (321..=337).contains(&dot) {
+                self.fetch_background();
+            }
+            match dot {
+                256 => self.increment_y(),
+                257 => {
+                    self.load_shifters();
+                    self.copy_x();
+                    self.evaluate_sprites();
+                }
+                280..=304 if pre_render => self.copy_y(),
+                _ => {}
+            }
+
+            // Sprite pattern fetches for the next line
+            if (257..=320).contains(&dot) {
+                let slot = (dot - 257) as usize / 8;
+                match (dot - 257) % 8 {
+                    4 => self.fetch_sprite(slot, false),
+                    6 => self.fetch_sprite(slot, true),
+                    _ => {}
+                }
+            }
+            if dot == 320 {
+                self.sprite_count = self.found_sprites;
+                self.sprite_0_on_line = self.next_sprite_0;
+            }
+        }
+
+        if visible && (1..=256).contains(&self.dot) {
+            if self.rendering() {
+                self.draw_pixel();
+            } else {
+                let color = self.color(self.palette[0]);
+                self.frame.set_pixel(self.dot as usize - 1, self.scanline as usize, color);
+            }
+        }
+
+        if self.dot == 1 {
+            if self.scanline == VBLANK_LINE {
+                self.status |= STATUS_VBLANK;
+                self.end_frame();
+            } else if pre_render {
+                self.status &= !(STATUS_VBLANK | STATUS_SPRITE_0_HIT | STATUS_OVERFLOW);
+            }
+        }
+
+        // Move the beam, skipping the last dot of odd pre-render lines
+        let skip = pre_render && self.dot == 339 && self.odd_frame && self.rendering();
+        self.dot += 1;
+        if self.dot as u64 == DOTS_PER_LINE || skip {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline as u64 == LINES_PER_FRAME {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+            }
+        }
+    }
+
+    // After a PPUDATA access. While rendering, the access bumps the scroll
+    // counters instead, like the fetches do.
+    fn advance_data_address(&mut self) {
+        let rendering_line = self.scanline < HEIGHT as u16 || self.scanline == PRE_RENDER_LINE;
+        if self.rendering() && rendering_line {
+            self.increment_x();
+            self.increment_y();
+        } else {
+            self.v = (self.v + self.increment()) & 0x7FFF;
+        }
+    }
+
+    // Dots from the current beam position until (scanline, dot)
+    fn dots_until(&self, scanline: u16, dot: u16) -> u64 {
+        let frame = DOTS_PER_LINE * LINES_PER_FRAME;
+        let now = self.scanline as u64 * DOTS_PER_LINE + self.dot as u64;
+        let then = scanline as u64 * DOTS_PER_LINE + dot as u64;
+        (then + frame - now - 1) % frame + 1
+    }
+}
+
+impl Device for Ppu {
+    fn name(&self) -> &str {
+        "ppu"
+    }
+
+    fn read(&mut self, offset: u16) -> u8 {
+        match offset & 0x07 {
+            PPUSTATUS => {
+                let status = self.status & 0xE0 | self.io_latch & 0x1F;
+                self.status &= !STATUS_VBLANK;
+                self.w = false;
+                self.io_latch = status;
+            }
+            OAMDATA => {
+                let mut data = self.oam[self.oam_address as usize];
+                // The unused attribute bits don't exist
+                if self.oam_address & 0x03 == 0x02 {
+                    data &= 0xE3;
+                }
+                self.io_latch = data;
+            }
+            PPUDATA => {
+                let address = self.v & 0x3FFF;
+                if address >= 0x3F00 {
+                    // Palette reads are immediate; the buffer gets the nametable underneath
+                    let entry = self.palette[Self::palette_index(address)];
+                    self.io_latch = self.io_latch & 0xC0 | entry;
+                    self.read_buffer = self.read_memory(address - 0x1000);
+                } else {
+                    self.io_latch = self.read_buffer;
+                    self.read_buffer = self.read_memory(address);
+                }
+                self.advance_data_address();
+            }
+            _ => {}
+        }
+        self.io_latch
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        match offset & 0x07 {
+            PPUSTATUS => self.status & 0xE0 | self.io_latch & 0x1F,
+            OAMDATA => self.oam[self.oam_address as usize],
+            PPUDATA => self.read_buffer,
+            _ => self.io_latch,
+        }
+    }
+
+    fn write(&mut self, offset: u16, data: u8) {
+        self.io_latch = data;
+        match offset & 0x07 {
+            PPUCTRL => {
+                self.ctrl = data;
+                self.t = (self.t & !0x0C00) | ((data as u16 & 0x03) << 10);
+            }
+            PPUMASK => self.mask = data,
+            OAMADDR => self.oam_address = data,
+            OAMDATA => {
+                self.oam[self.oam_address as usize] = data;
+                self.oam_address = self.oam_address.wrapping_add(1);
+            }
+            PPUSCROLL => {
+                if !self.w {
+                    self.t = (self.t & !0x001F) | (data as u16 >> 3);
+                    self.x = data & 0x07;
+                } else {
+                    self.t = (self.t & !0x73E0) | ((data as u16 & 0x07) << 12) | ((data as u16 & 0xF8) << 2);
+                }
+                self.w = !self.w;
+            }
+            PPUADDR => {
+                if !self.w {
+                    self.t = (self.t & 0x00FF) | ((data as u16 & 0x3F) << 8);
+                } else {
+                    self.t = (self.t & 0xFF00) | data as u16;
+                    self.v = self.t;
+                }
+                self.w = !self.w;
+            }
+            PPUDATA => {
+                self.write_memory(self.v, data);
+                self.advance_data_address();
+            }
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, now: u64) {
+        while self.dots < now * DOTS_PER_CYCLE {
+            self.step();
+            self.dots += 1;
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        // The vertical blank flag (and NMI) goes up and comes down again
+        let dots = self
+            .dots_until(VBLANK_LINE, 2)
+            .min(self.dots_until(PRE_RENDER_LINE, 2));
+        Some((self.dots + dots).div_ceil(DOTS_PER_CYCLE))
+    }
+
+    fn reset(&mut self) {
+        self.ctrl = 0;
+        self.mask = 0;
+        self.status = 0;
+        self.read_buffer = 0;
+        self.w = false;
+        self.x = 0;
+        self.t = 0;
+        self.scanline = 0;
+        self.dot = 0;
+        self.odd_frame = false;
+        self.dots = 0;
+        self.sprite_count = 0;
+        self.found_sprites = 0;
+    }
+
+    fn nmi(&self) -> bool {
+        self.ctrl & CTRL_NMI != 0 && self.status & STATUS_VBLANK != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME_CYCLES: u64 = DOTS_PER_LINE * LINES_PER_FRAME / DOTS_PER_CYCLE;
+
+    fn write_vram(ppu: &mut Ppu, address: u16, data: &[u8]) {
+        ppu.write(PPUADDR, (address >> 8) as u8);
+        ppu.write(PPUADDR, address as u8);
+        for byte in data {
+            ppu.write(PPUDATA, *byte);
+        }
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut ppu = Ppu::new();
+
+        // PPUDATA reads are buffered, except from the palette
+        write_vram(&mut ppu, 0x2400, &[0x11, 0x22]);
+        write_vram(&mut ppu, 0x3F00, &[0x0F, 0x30]);
+        write_vram(&mut ppu, 0x3F10, &[0x21]);
+        ppu.write(PPUADDR, 0x24);
+        ppu.write(PPUADDR, 0x00);
+        assert_eq!(ppu.read(PPUDATA), 0x00);
+        assert_eq!(ppu.read(PPUDATA), 0x11);
+        assert_eq!(ppu.read(PPUDATA), 0x22);
+        ppu.write(PPUADDR, 0x3F);
+        ppu.write(PPUADDR, 0x00);
+        assert_eq!(ppu.read(PPUDATA) & 0x3F, 0x21);
+        assert_eq!(ppu.read(PPUDATA) & 0x3F, 0x30);
+
+        // Horizontal mirroring: $2400 is $2000
+        write_vram(&mut ppu, 0x2000, &[]);
+        ppu.read(PPUDATA);
+        assert_eq!(ppu.read(PPUDATA), 0x11);
+
+        // Scroll writes land in t and fine x; PPUSTATUS resets the latch
+        ppu.write(PPUCTRL, 0x02);
+        ppu.write(PPUSCROLL, 0x7D);
+        ppu.read(PPUSTATUS);
+        ppu.write(PPUSCROLL, 0x7D);
+        ppu.write(PPUSCROLL, 0x5E);
+        assert_eq!(ppu.t, 0x6800 | 0x0160 | 0x0F);
+        assert_eq!(ppu.x, 0x05);
+    }
+
+    #[test]
+    fn test_vblank_and_odd_frames() {
+        let mut ppu = Ppu::new();
+        ppu.write(PPUCTRL, CTRL_NMI);
+
+        let vblank = (VBLANK_LINE as u64 * DOTS_PER_LINE + 2).div_ceil(DOTS_PER_CYCLE);
+        assert_eq!(ppu.next_event(), Some(vblank));
+        ppu.tick(vblank - 1);
+        assert!(!ppu.nmi());
+        ppu.tick(vblank);
+        assert!(ppu.nmi());
+        assert_eq!(ppu.frame_count(), 1);
+
+        // Reading PPUSTATUS acknowledges it
+        assert_eq!(ppu.read(PPUSTATUS) & STATUS_VBLANK, STATUS_VBLANK);
+        assert!(!ppu.nmi());
+
+        // With rendering on, every other frame is a dot short
+        ppu.write(PPUMASK, MASK_BACKGROUND);
+        ppu.tick(FRAME_CYCLES * 5);
+        let dots = FRAME_CYCLES * 5 * DOTS_PER_CYCLE;
+        let expected = dots - (DOTS_PER_LINE * LINES_PER_FRAME * 4 - 2);
+        let (scanline, dot) = ppu.position();
+        assert_eq!(scanline as u64 * DOTS_PER_LINE + dot as u64, expected);
+    }
+
+    #[test]
+    fn test_render_and_sprite_0_hit() {
+        let chr = Arc::new(Mutex::new(ChrMemory::ram(Mirroring::Vertical)));
+        let mut ppu = Ppu::new();
+        ppu.set_chr(chr.clone());
+
+        // Tile 1 is solid colour 1, tile 2 solid colour 3
+        write_vram(&mut ppu, 0x0010, &[0xFF; 8]);
+        write_vram(&mut ppu, 0x0020, &[0xFF; 16]);
+        write_vram(&mut ppu, 0x3F00, &[0x0F, 0x16, 0x00, 0x00]);
+        write_vram(&mut ppu, 0x3F10, &[0x0F, 0x00, 0x00, 0x2A]);
+
+        // A row of tile 1 across the top, then sprite 0 over it at (16, 4)
+        write_vram(&mut ppu, 0x2000, &[0x01; 32]);
+        ppu.write(OAMADDR, 0);
+        for byte in [3, 2, 0, 16] {
+            ppu.write(OAMDATA, byte);
+        }
+
+        ppu.write(PPUADDR, 0);
+        ppu.write(PPUADDR, 0);
+        ppu.write(PPUMASK, MASK_BACKGROUND | MASK_SPRITES | MASK_BACKGROUND_LEFT | MASK_SPRITES_LEFT);
+
+        // Render a frame with rendering on from the pre-render line
+        let vblank = (VBLANK_LINE as u64 * DOTS_PER_LINE + 2).div_ceil(DOTS_PER_CYCLE);
+        ppu.tick(FRAME_CYCLES);
+        assert!(ppu.peek(PPUSTATUS) & STATUS_SPRITE_0_HIT == 0);
+        ppu.tick(FRAME_CYCLES + vblank);
+        assert!(ppu.peek(PPUSTATUS) & STATUS_SPRITE_0_HIT != 0);
+        assert_eq!(ppu.frame_count(), 2);
+
+        let frame = ppu.frame();
+        assert_eq!(frame.pixel(0, 0), PALETTE[0x16]);
+        assert_eq!(frame.pixel(16, 4), PALETTE[0x2A]);
+        assert_eq!(frame.pixel(23, 11), PALETTE[0x2A]);
+        assert_eq!(frame.pixel(24, 4), PALETTE[0x16]);
+        assert_eq!(frame.pixel(0, 8), PALETTE[0x0F]);
+    }
+}
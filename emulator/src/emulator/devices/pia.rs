@@ -0,0 +1,246 @@
+/**
+ * Motorola 6821 Peripheral Interface Adapter, as used by the Apple-1 for
+ * its keyboard and display.
+ *
+ * Registers (selected by RS1/RS0):
+ *
+ *   $0  port A output register, or DDRA when CRA bit 2 is clear
+ *   $1  CRA: control register A
+ *   $2  port B output register, or DDRB when CRB bit 2 is clear
+ *   $3  CRB: control register B
+ *
+ * Control register bits:
+ *
+ *   7    IRQ1 flag: the selected edge was seen on CA1/CB1
+ *   6    IRQ2 flag (CA2/CB2 as inputs; nothing drives them here)
+ *   5-3  CA2/CB2 mode; 100 and 101 strobe the peripherals when the output
+ *        register is written
+ *   2    0 = the data register address selects the DDR
+ *   1    CA1/CB1 edge: 0 = falling, 1 = rising
+ *   0    CA1/CB1 interrupt enable
+ *
+ * Reading a port's data register clears both of its flags. Peripherals
+ * are attached the same way as to the 6522 VIA.
+ */
+use super::port::{Port, SharedPortDevice};
+use super::Device;
+
+// Register offsets
+const DATA_A: u16 = 0x0;
+const CRA: u16 = 0x1;
+const DATA_B: u16 = 0x2;
+const CRB: u16 = 0x3;
+
+// Control register bits
+pub const CR_IRQ1: u8 = 0x80;
+pub const CR_IRQ2: u8 = 0x40;
+const CR_STROBE_MODE: u8 = 0x30; // 10x: CA2/CB2 strobe on writes
+const CR_OUTPUT_REGISTER: u8 = 0x04;
+const CR_RISING_EDGE: u8 = 0x02;
+const CR_IRQ1_ENABLE: u8 = 0x01;
+
+pub struct Pia {
+    output: [u8; 2],
+    ddr: [u8; 2],
+    control: [u8; 2],
+
+    // Levels the peripherals drive onto the ports (pulled high when floating)
+    peripheral: [u8; 2],
+
+    // CA1 / CB1
+    c1: [bool; 2],
+
+    last_cycle: u64,
+
+    // Peripherals wired to the port pins and control lines
+    peripherals: Vec<SharedPortDevice>,
+}
+
+impl Default for Pia {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pia {
+    pub fn new() -> Self {
+        Self {
+            output: [0x00; 2],
+            ddr: [0x00; 2],
+            control: [0x00; 2],
+            peripheral: [0xFF; 2],
+            c1: [false; 2],
+            last_cycle: 0,
+            peripherals: Vec::new(),
+        }
+    }
+
+    // Wire a peripheral to the ports of this PIA
+    pub fn attach(&mut self, peripheral: SharedPortDevice) {
+        let mut device = peripheral.lock().unwrap();
+        for port in [Port::A, Port::B] {
+            let index = port.index();
+            device.pins_changed(port, self.port(port), self.ddr[index], self.last_cycle);
+        }
+        drop(device);
+
+        self.peripherals.push(peripheral);
+    }
+
+    // The levels currently on a port's pins
+    pub fn port(&self, port: Port) -> u8 {
+        let index = port.index();
+        (self.output[index] & self.ddr[index]) | (self.peripheral[index] & !self.ddr[index])
+    }
+
+    // Tell the peripherals about the current levels of a port
+    fn notify(&mut self, port: Port) {
+        let (pins, ddr) = (self.port(port), self.ddr[port.index()]);
+        for peripheral in &self.peripherals {
+            peripheral
+                .lock()
+                .unwrap()
+                .pins_changed(port, pins, ddr, self.last_cycle);
+        }
+    }
+
+    // Sample what the peripherals drive onto a port (wired-AND)
+    fn sample(&mut self, port: Port) {
+        let mut level = 0xFF;
+        for peripheral in &self.peripherals {
+            level &= peripheral.lock().unwrap().drive(port, self.last_cycle);
+        }
+        self.peripheral[port.index()] = level;
+    }
+
+    // Update CA1/CB1 from the peripherals driving them
+    fn sample_control_lines(&mut self) {
+        for port in [Port::A, Port::B] {
+            let mut level = None;
+            for peripheral in &self.peripherals {
+                if let Some(line) = peripheral.lock().unwrap().control(port, self.last_cycle) {
+                    level = Some(level.unwrap_or(true) && line);
+                }
+            }
+            if let Some(level) = level {
+                self.set_c1(port, level);
+            }
+        }
+    }
+
+    // Drive CA1/CB1; the flag is set on the edge selected in the control register
+    pub fn set_c1(&mut self, port: Port, level: bool) {
+        let index = port.index();
+        let rising = self.control[index] & CR_RISING_EDGE != 0;
+        if level != self.c1[index] && level == rising {
+            self.control[index] |= CR_IRQ1;
+        }
+        self.c1[index] = level;
+    }
+
+    fn port_for(offset: u16) -> Port {
+        if offset & 0x02 == 0 {
+            Port::A
+        } else {
+            Port::B
+        }
+    }
+}
+
+impl Device for Pia {
+    fn name(&self) -> &str {
+        "6821 PIA"
+    }
+
+    fn read(&mut self, offset: u16) -> u8 {
+        let port = Pia::port_for(offset);
+        let index = port.index();
+        let data_register = offset & 0x01 == 0 && self.control[index] & CR_OUTPUT_REGISTER != 0;
+        if !data_register {
+            return self.peek(offset);
+        }
+
+        // Reading the port acknowledges the peripheral, which may let go of C1
+        self.sample(port);
+        let data = self.peek(offset);
+        self.control[index] &= !(CR_IRQ1 | CR_IRQ2);
+        self.sample_control_lines();
+        data
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        let port = Pia::port_for(offset);
+        let index = port.index();
+        match offset & 0x03 {
+            CRA | CRB => self.control[index],
+            DATA_A | DATA_B if self.control[index] & CR_OUTPUT_REGISTER == 0 => self.ddr[index],
+            _ => self.port(port),
+        }
+    }
+
+    fn write(&mut self, offset: u16, data: u8) {
+        let port = Pia::port_for(offset);
+        let index = port.index();
+        match offset & 0x03 {
+            CRA | CRB => {
+                // The flags are read only
+                self.control[index] = (self.control[index] & 0xC0) | (data & 0x3F);
+            }
+            _ if self.control[index] & CR_OUTPUT_REGISTER == 0 => {
+                self.ddr[index] = data;
+                self.notify(port);
+            }
+            _ => {
+                self.output[index] = data;
+                self.notify(port);
+                if self.control[index] & CR_STROBE_MODE == 0x20 {
+                    for peripheral in &self.peripherals {
+                        peripheral.lock().unwrap().strobe(port, self.last_cycle);
+                    }
+                }
+            }
+        }
+    }
+
+    fn tick(&mut self, now: u64) {
+        self.last_cycle = self.last_cycle.max(now);
+        for peripheral in &self.peripherals {
+            peripheral.lock().unwrap().tick(now);
+        }
+        self.sample_control_lines();
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        self.peripherals
+            .iter()
+            .filter_map(|peripheral| peripheral.lock().unwrap().next_event())
+            .min()
+    }
+
+    fn clock_rate_changed(&mut self, hz: f64) {
+        for peripheral in &self.peripherals {
+            peripheral.lock().unwrap().clock_rate_changed(hz);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.output = [0x00; 2];
+        self.ddr = [0x00; 2];
+        self.control = [0x00; 2];
+        self.c1 = [false; 2];
+        self.last_cycle = 0;
+
+        for peripheral in &self.peripherals {
+            peripheral.lock().unwrap().reset();
+        }
+        self.notify(Port::A);
+        self.notify(Port::B);
+        self.sample_control_lines();
+    }
+
+    fn irq(&self) -> bool {
+        self.control
+            .iter()
+            .any(|control| control & (CR_IRQ1 | CR_IRQ1_ENABLE) == CR_IRQ1 | CR_IRQ1_ENABLE)
+    }
+}
@@ -0,0 +1,96 @@
+/**
+ * Peripherals wired to the pins of a parallel I/O port (such as the two
+ * ports of a 6522 VIA) rather than to the address bus.
+ *
+ * The chip owning the port tells each attached peripheral whenever the
+ * levels it drives change, and asks the peripherals what they drive back
+ * whenever the CPU reads the port. Pins are open-collector style: a pin
+ * nobody pulls low reads as 1.
+ */
+use std::sync::{Arc, Mutex};
+
+// A port peripheral shared between the port's chip and whoever created it
+pub type SharedPortDevice = Arc<Mutex<dyn PortDevice>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Port {
+    A,
+    B,
+}
+
+impl Port {
+    // Index for per-port arrays
+    pub fn index(&self) -> usize {
+        match self {
+            Self::A => 0,
+            Self::B => 1,
+        }
+    }
+}
+
+// A single pin of a port
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pin {
+    pub port: Port,
+    pub bit: u8,
+}
+
+impl Pin {
+    pub fn new(port: Port, bit: u8) -> Self {
+        Self { port, bit }
+    }
+
+    pub fn mask(&self) -> u8 {
+        1 << self.bit
+    }
+
+    // Level of this pin within a port value
+    pub fn level(&self, value: u8) -> bool {
+        value & self.mask() != 0
+    }
+}
+
+// A signal a peripheral can be wired to: a port pin, or a port's control
+// input (CA1 for port A, CB1 for port B)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Line {
+    Pin(Pin),
+    Control(Port),
+}
+
+pub trait PortDevice: Send {
+    fn name(&self) -> &str;
+
+    /// The levels on `port` changed. `pins` holds the level of every pin
+    /// (inputs read as pulled high) and `ddr` which of them the chip drives.
+    fn pins_changed(&mut self, _port: Port, _pins: u8, _ddr: u8, _now: u64) {}
+
+    /// The levels this peripheral drives onto `port`. Bits it leaves
+    /// floating must be 1.
+    fn drive(&mut self, _port: Port, _now: u64) -> u8 {
+        0xFF
+    }
+
+    /// The level this peripheral drives onto the port's control input
+    /// (CA1 for port A, CB1 for port B), if it is connected to it.
+    fn control(&mut self, _port: Port, _now: u64) -> Option<bool> {
+        None
+    }
+
+    /// The chip pulsed the port's data-ready strobe (CA2/CB2 in handshake
+    /// or pulse output mode) because the CPU wrote the port's output register.
+    fn strobe(&mut self, _port: Port, _now: u64) {}
+
+    /// Advances the peripheral up to CPU cycle `now`.
+    fn tick(&mut self, _now: u64) {}
+
+    /// The next cycle at which the peripheral needs to be ticked.
+    fn next_event(&self) -> Option<u64> {
+        None
+    }
+
+    /// The CPU clock rate changed (used to convert real time to cycles).
+    fn clock_rate_changed(&mut self, _hz: f64) {}
+
+    fn reset(&mut self) {}
+}
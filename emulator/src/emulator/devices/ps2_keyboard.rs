@@ -0,0 +1,336 @@
+use std::collections::VecDeque;
+
+use super::host_input::HostInput;
+use super::port::{Line, Pin, Port, PortDevice};
+
+// Scan code set 2 prefixes
+const BREAK: u8 = 0xF0;
+const LEFT_SHIFT: u8 = 0x12;
+
+// Bits in a frame: start, 8 data bits, odd parity, stop
+const FRAME_BITS: u64 = 11;
+
+/**
+ * A PS/2 keyboard bit-banging scan code set 2 frames onto two port lines.
+ *
+ * The keyboard drives both clock and data. Each bit is put on the data line
+ * while the clock is high, and the host samples it on the falling edge of
+ * the clock, which is usually wired to CA1 so every bit raises an interrupt.
+ */
+pub struct Ps2Keyboard {
+    clock_line: Line,
+    data_pin: Pin,
+    input: Option<HostInput>,
+
+    // Bytes waiting to be sent
+    queue: VecDeque<u8>,
+
+    // The frame being sent and the cycle it started at
+    frame: Option<(u16, u64)>,
+
+    clock: bool,
+    data: bool,
+
+    bit_cycles: u64,  // Length of one bit (a full clock period), in cycles
+    gap_cycles: u64,  // Idle time between two frames
+    poll_cycles: u64, // How often to look for new host keys
+    next_frame: u64,  // Earliest cycle the next frame may start at
+    last_poll: u64,
+    last_cycle: u64,
+}
+
+impl Ps2Keyboard {
+    pub fn new(clock_line: Line, data_pin: Pin) -> Self {
+        let mut keyboard = Self {
+            clock_line,
+            data_pin,
+            input: None,
+            queue: VecDeque::new(),
+            frame: None,
+            clock: true,
+            data: true,
+            bit_cycles: 0,
+            gap_cycles: 0,
+            poll_cycles: 0,
+            next_frame: 0,
+            last_poll: 0,
+            last_cycle: 0,
+        };
+        keyboard.clock_rate_changed(1_000_000.0);
+        keyboard
+    }
+
+    // Feed the keyboard from the host terminal or a script
+    pub fn set_input(&mut self, input: HostInput) {
+        self.input = Some(input);
+    }
+
+    // Queue the make and break codes for a key
+    pub fn press_key(&mut self, scancode: u8) {
+        self.queue.push_back(scancode);
+        self.queue.push_back(BREAK);
+        self.queue.push_back(scancode);
+    }
+
+    // Queue the key strokes needed to type a piece of text
+    pub fn type_text(&mut self, text: &str) {
+        for byte in text.bytes() {
+            self.type_char(byte);
+        }
+    }
+
+    pub fn type_char(&mut self, character: u8) {
+        let Some((scancode, shifted)) = scancode_for(character) else {
+            return;
+        };
+
+        if shifted {
+            self.queue.push_back(LEFT_SHIFT);
+        }
+        self.press_key(scancode);
+        if shifted {
+            self.queue.push_back(BREAK);
+            self.queue.push_back(LEFT_SHIFT);
+        }
+    }
+
+    // Whether everything queued has been sent
+    pub fn is_idle(&self) -> bool {
+        self.queue.is_empty() && self.frame.is_none()
+    }
+
+    // The 11 bit frame for a byte, LSB first
+    fn frame_bits(byte: u8) -> u16 {
+        let parity = (byte.count_ones() & 1 == 0) as u16;
+        (byte as u16) << 1 | parity << 9 | 1 << 10
+    }
+
+    fn poll_input(&mut self, now: u64) {
+        self.last_poll = now;
+        while let Some(key) = self.input.as_mut().and_then(|input| input.try_read()) {
+            // The terminal sends LF for Enter
+            self.type_char(if key == b'\n' { b'\r' } else { key });
+        }
+    }
+
+    // Work out the clock and data levels at `now`
+    fn advance(&mut self, now: u64) {
+        loop {
+            if let Some((bits, start)) = self.frame {
+                let elapsed = now.saturating_sub(start);
+                let bit = elapsed / self.bit_cycles;
+
+                if bit >= FRAME_BITS {
+                    // Frame done, idle with both lines high
+                    self.frame = None;
+                    self.clock = true;
+                    self.data = true;
+                    self.next_frame = start + FRAME_BITS * self.bit_cycles + self.gap_cycles;
+                    continue;
+                }
+
+                // Clock is high for the first half of each bit and low for the second
+                self.data = bits & (1 << bit) != 0;
+                self.clock = elapsed % self.bit_cycles < self.bit_cycles / 2;
+                return;
+            }
+
+            if self.queue.is_empty() || now < self.next_frame {
+                return;
+            }
+
+            let byte = self.queue.pop_front().unwrap();
+            self.frame = Some((Self::frame_bits(byte), self.next_frame.max(self.last_cycle)));
+        }
+    }
+}
+
+impl PortDevice for Ps2Keyboard {
+    fn name(&self) -> &str {
+        "ps2_keyboard"
+    }
+
+    fn drive(&mut self, port: Port, now: u64) -> u8 {
+        self.advance(now);
+
+        let mut level = 0xFF;
+        if self.data_pin.port == port && !self.data {
+            level &= !self.data_pin.mask();
+        }
+        if let Line::Pin(pin) = self.clock_line {
+            if pin.port == port && !self.clock {
+                level &= !pin.mask();
+            }
+        }
+        level
+    }
+
+    fn control(&mut self, port: Port, now: u64) -> Option<bool> {
+        self.advance(now);
+        match self.clock_line {
+            Line::Control(control) if control == port => Some(self.clock),
+            _ => None,
+        }
+    }
+
+    fn tick(&mut self, now: u64) {
+        if self.input.is_some() && now >= self.last_poll + self.poll_cycles {
+            self.poll_input(now);
+        }
+        self.last_cycle = now;
+        self.advance(now);
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        // Wake up on every clock edge while sending
+        if let Some((_, start)) = self.frame {
+            let half = self.bit_cycles / 2;
+            let elapsed = self.last_cycle.saturating_sub(start);
+            return Some(start + (elapsed / half + 1) * half);
+        }
+
+        if !self.queue.is_empty() {
+            return Some(self.next_frame.max(self.last_cycle + 1));
+        }
+
+        // Keep an eye on the host for more keys
+        match &self.input {
+            Some(input) if !input.is_exhausted() => Some(self.last_poll + self.poll_cycles),
+            _ => None,
+        }
+    }
+
+    fn clock_rate_changed(&mut self, hz: f64) {
+        // A 12.5 kHz clock, 1 ms between bytes, and keys polled every 10 ms
+        self.bit_cycles = ((hz / 12_500.0) as u64).max(2);
+        self.gap_cycles = (hz / 1_000.0) as u64;
+        self.poll_cycles = ((hz / 100.0) as u64).max(1);
+    }
+
+    fn reset(&mut self) {
+        // Keys typed before the reset aren't sent to the restarted firmware
+        self.queue.clear();
+        self.frame = None;
+        self.clock = true;
+        self.data = true;
+        self.next_frame = 0;
+        self.last_poll = 0;
+        self.last_cycle = 0;
+    }
+}
+
+// The set 2 make code for an ASCII character, and whether shift is needed
+pub fn scancode_for(character: u8) -> Option<(u8, bool)> {
+    const LETTERS: [u8; 26] = [
+        0x1C, 0x32, 0x21, 0x23, 0x24, 0x2B, 0x34, 0x33, 0x43, 0x3B, 0x42, 0x4B, 0x3A, 0x31,
+        0x44, 0x4D, 0x15, 0x2D, 0x1B, 0x2C, 0x3C, 0x2A, 0x1D, 0x22, 0x35, 0x1A,
+    ];
+    const DIGITS: [u8; 10] = [0x45, 0x16, 0x1E, 0x26, 0x25, 0x2E, 0x36, 0x3D, 0x3E, 0x46];
+
+    let code = match character {
+        b'a'..=b'z' => (LETTERS[(character - b'a') as usize], false),
+        b'A'..=b'Z' => (LETTERS[(character - b'A') as usize], true),
+        b'0'..=b'9' => (DIGITS[(character - b'0') as usize], false),
+        b')' => (DIGITS[0], true),
+        b'!' => (DIGITS[1], true),
+        b'@' => (DIGITS[2], true),
+        b'#' => (DIGITS[3], true),
+        b'$' => (DIGITS[4], true),
+        b'%' => (DIGITS[5], true),
+        b'^' => (DIGITS[6], true),
+        b'&' => (DIGITS[7], true),
+        b'*' => (DIGITS[8], true),
+        b'(' => (DIGITS[9], true),
+        b' ' => (0x29, false),
+        b'\r' => (0x5A, false),
+        b'\t' => (0x0D, false),
+        0x08 | 0x7F => (0x66, false),
+        0x1B => (0x76, false),
+        b'-' => (0x4E, false),
+        b'_' => (0x4E, true),
+        b'=' => (0x55, false),
+        b'+' => (0x55, true),
+        b'[' => (0x54, false),
+        b'{' => (0x54, true),
+        b']' => (0x5B, false),
+        b'}' => (0x5B, true),
+        b'\\' => (0x5D, false),
+        b'|' => (0x5D, true),
+        b';' => (0x4C, false),
+        b':' => (0x4C, true),
+        b'\'' => (0x52, false),
+        b'"' => (0x52, true),
+        b'`' => (0x0E, false),
+        b'~' => (0x0E, true),
+        b',' => (0x41, false),
+        b'<' => (0x41, true),
+        b'.' => (0x49, false),
+        b'>' => (0x49, true),
+        b'/' => (0x4A, false),
+        b'?' => (0x4A, true),
+        _ => return None,
+    };
+    Some(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sample the data line on every falling clock edge, like the host would
+    fn receive(keyboard: &mut Ps2Keyboard, cycles: u64) -> Vec<u16> {
+        let mut frames = Vec::new();
+        let mut bits = Vec::new();
+        let mut last_clock = true;
+
+        for now in 0..cycles {
+            keyboard.tick(now);
+            let clock = keyboard.control(Port::A, now).unwrap();
+            if last_clock && !clock {
+                bits.push(keyboard.drive(Port::A, now) & 0x01 != 0);
+                if bits.len() == 11 {
+                    let frame = bits.iter().enumerate().fold(0, |acc, (i, b)| acc | (*b as u16) << i);
+                    frames.push(frame);
+                    bits.clear();
+                }
+            }
+            last_clock = clock;
+        }
+        frames
+    }
+
+    #[test]
+    fn test_frames() {
+        let mut keyboard = Ps2Keyboard::new(Line::Control(Port::A), Pin::new(Port::A, 0));
+        keyboard.type_text("a");
+
+        let frames = receive(&mut keyboard, 10_000);
+        let bytes: Vec<u8> = frames.iter().map(|frame| (frame >> 1) as u8).collect();
+        assert_eq!(bytes, vec![0x1C, 0xF0, 0x1C]);
+
+        for frame in frames {
+            // Start bit low, stop bit high, odd parity over data + parity
+            assert_eq!(frame & 1, 0);
+            assert_eq!(frame >> 10, 1);
+            assert_eq!(((frame >> 1) & 0x1FF).count_ones() % 2, 1);
+        }
+        assert!(keyboard.is_idle());
+
+        // A reset drops what's still queued, even in the middle of a frame
+        keyboard.type_text("b");
+        keyboard.tick(100);
+        keyboard.reset();
+        assert!(keyboard.is_idle());
+        assert!(receive(&mut keyboard, 10_000).is_empty());
+    }
+
+    #[test]
+    fn test_shifted_characters() {
+        let mut keyboard = Ps2Keyboard::new(Line::Control(Port::A), Pin::new(Port::A, 0));
+        keyboard.type_text("A");
+        assert_eq!(
+            keyboard.queue.iter().copied().collect::<Vec<u8>>(),
+            vec![0x12, 0x1C, 0xF0, 0x1C, 0xF0, 0x12]
+        );
+    }
+}
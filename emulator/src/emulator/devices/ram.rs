@@ -0,0 +1,87 @@
+/**
+ * A block of RAM, mirrored over however much address space it is mapped
+ * into. Boards that don't decode every address line see the same chip
+ * repeated, e.g. the NES's 2 KiB of RAM filling $0000-$1FFF.
+ *
+ * The same chip doubles as a ROM: one made with `Ram::rom` ignores writes,
+ * so a program can't scribble over its own code.
+ */
+use super::Device;
+
+pub struct Ram {
+    data: Vec<u8>,
+    read_only: bool,
+}
+
+impl Ram {
+    pub fn new(size: usize) -> Self {
+        Self {
+            data: vec![0; size.max(1)],
+            read_only: false,
+        }
+    }
+
+    // A ROM holding `data`
+    pub fn rom(data: Vec<u8>) -> Self {
+        let mut rom = Self::new(data.len());
+        rom.data[..data.len()].copy_from_slice(&data);
+        rom.read_only = true;
+        rom
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl Device for Ram {
+    fn name(&self) -> &str {
+        if self.read_only {
+            "rom"
+        } else {
+            "ram"
+        }
+    }
+
+    fn read(&mut self, offset: u16) -> u8 {
+        self.peek(offset)
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        self.data[offset as usize % self.data.len()]
+    }
+
+    fn write(&mut self, offset: u16, data: u8) {
+        if self.read_only {
+            return;
+        }
+        let size = self.data.len();
+        self.data[offset as usize % size] = data;
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        if state.len() == self.data.len() {
+            self.data.copy_from_slice(state);
+        }
+    }
+}
@@ -0,0 +1,270 @@
+/**
+ * The I/O ports and interval timer of a MOS 6530 RRIOT (ROM-RAM-I/O-Timer),
+ * two of which run the KIM-1. The chip's 1 KiB of mask ROM and 64 bytes of
+ * RAM are mapped separately, as plain memory.
+ *
+ * Registers (they repeat through the chip's I/O window):
+ *
+ *   $0  port A data        $1  port A data direction
+ *   $2  port B data        $3  port B data direction
+ *   $4-$7 (W)  start the timer at the value written, counting every
+ *        1, 8, 64 or 1024 cycles; A3 set ($C-$F) enables its interrupt
+ *   $4/$6 (R)  the timer's count, which acknowledges the interrupt; A3
+ *        set enables the interrupt, clear disables it
+ *   $5/$7 (R)  bit 7 set once the timer has run out
+ *
+ * After running out the timer keeps counting down, once a cycle, from $FF
+ * so a program can tell how long ago that was. Like the VIA's timers it
+ * isn't decremented cycle by cycle: the count is worked out from the cycle
+ * it was started at.
+ */
+use super::port::{Port, SharedPortDevice};
+use super::Device;
+
+// Register offsets
+const PAD: u16 = 0x0;
+const PADD: u16 = 0x1;
+const PBD: u16 = 0x2;
+const PBDD: u16 = 0x3;
+
+const TIMER: u16 = 0x04;
+const TIMER_IRQ_ENABLE: u16 = 0x08;
+const TIMER_STATUS: u16 = 0x01;
+
+const DIVIDERS: [u64; 4] = [1, 8, 64, 1024];
+
+pub struct Rriot {
+    output: [u8; 2],
+    ddr: [u8; 2],
+
+    // Levels the peripherals drive onto the ports (pulled high when floating)
+    peripheral: [u8; 2],
+
+    // The timer was started at `timer_start` with `timer_value`
+    timer_start: u64,
+    timer_value: u8,
+    divider: u64,
+    acknowledged: bool, // The count was read since the timer ran out
+    irq_enabled: bool,
+
+    last_cycle: u64,
+
+    // Peripherals wired to the port pins
+    peripherals: Vec<SharedPortDevice>,
+}
+
+impl Default for Rriot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Rriot {
+    pub fn new() -> Self {
+        Self {
+            output: [0x00; 2],
+            ddr: [0x00; 2],
+            peripheral: [0xFF; 2],
+            timer_start: 0,
+            timer_value: 0xFF,
+            divider: 1024,
+            acknowledged: false,
+            irq_enabled: false,
+            last_cycle: 0,
+            peripherals: Vec::new(),
+        }
+    }
+
+    // Wire a peripheral to the ports of this RRIOT
+    pub fn attach(&mut self, peripheral: SharedPortDevice) {
+        let mut device = peripheral.lock().unwrap();
+        for port in [Port::A, Port::B] {
+            let index = port.index();
+            device.pins_changed(port, self.port(port), self.ddr[index], self.last_cycle);
+        }
+        drop(device);
+
+        self.peripherals.push(peripheral);
+    }
+
+    // The levels currently on a port's pins
+    pub fn port(&self, port: Port) -> u8 {
+        let index = port.index();
+        (self.output[index] & self.ddr[index]) | (self.peripheral[index] & !self.ddr[index])
+    }
+
+    // Tell the peripherals about the current levels of a port
+    fn notify(&mut self, port: Port) {
+        let (pins, ddr) = (self.port(port), self.ddr[port.index()]);
+        for peripheral in &self.peripherals {
+            peripheral
+                .lock()
+                .unwrap()
+                .pins_changed(port, pins, ddr, self.last_cycle);
+        }
+    }
+
+    // Sample what the peripherals drive onto a port (wired-AND)
+    fn sample(&mut self, port: Port) {
+        let mut level = 0xFF;
+        for peripheral in &self.peripherals {
+            level &= peripheral.lock().unwrap().drive(port, self.last_cycle);
+        }
+        self.peripheral[port.index()] = level;
+    }
+
+    // The cycle the timer runs out at
+    fn timer_expiry(&self) -> u64 {
+        self.timer_start + (self.timer_value as u64 + 1) * self.divider
+    }
+
+    fn timer_flag(&self, now: u64) -> bool {
+        now >= self.timer_expiry() && !self.acknowledged
+    }
+
+    fn timer_count(&self, now: u64) -> u8 {
+        let expiry = self.timer_expiry();
+        if now < expiry {
+            let elapsed = (now - self.timer_start) / self.divider;
+            self.timer_value - elapsed as u8
+        } else {
+            0xFF - ((now - expiry) & 0xFF) as u8
+        }
+    }
+
+    fn port_for(offset: u16) -> Port {
+        if offset & 0x02 == 0 {
+            Port::A
+        } else {
+            Port::B
+        }
+    }
+}
+
+impl Device for Rriot {
+    fn name(&self) -> &str {
+        "6530 RRIOT"
+    }
+
+    fn read(&mut self, offset: u16) -> u8 {
+        if offset & TIMER == 0 {
+            if offset & 0x01 == 0 {
+                self.sample(Rriot::port_for(offset));
+            }
+            return self.peek(offset);
+        }
+
+        let data = self.peek(offset);
+        if offset & TIMER_STATUS == 0 {
+            // Reading the count acknowledges the interrupt
+            self.irq_enabled = offset & TIMER_IRQ_ENABLE != 0;
+            if self.last_cycle >= self.timer_expiry() {
+                self.acknowledged = true;
+            }
+        }
+        data
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        match offset & 0x07 {
+            PAD => self.port(Port::A),
+            PADD => self.ddr[0],
+            PBD => self.port(Port::B),
+            PBDD => self.ddr[1],
+            offset if offset & TIMER_STATUS == 0 => self.timer_count(self.last_cycle),
+            _ => (self.timer_flag(self.last_cycle) as u8) << 7,
+        }
+    }
+
+    fn write(&mut self, offset: u16, data: u8) {
+        if offset & TIMER != 0 {
+            self.timer_start = self.last_cycle;
+            self.timer_value = data;
+            self.divider = DIVIDERS[(offset & 0x03) as usize];
+            self.acknowledged = false;
+            self.irq_enabled = offset & TIMER_IRQ_ENABLE != 0;
+            return;
+        }
+
+        let port = Rriot::port_for(offset);
+        let index = port.index();
+        if offset & 0x01 == 0 {
+            self.output[index] = data;
+        } else {
+            self.ddr[index] = data;
+        }
+        self.notify(port);
+    }
+
+    fn tick(&mut self, now: u64) {
+        self.last_cycle = self.last_cycle.max(now);
+        for peripheral in &self.peripherals {
+            peripheral.lock().unwrap().tick(now);
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        let mut next = None;
+        let expiry = self.timer_expiry();
+        if self.irq_enabled && !self.acknowledged && expiry > self.last_cycle {
+            next = Some(expiry);
+        }
+        for peripheral in &self.peripherals {
+            if let Some(event) = peripheral.lock().unwrap().next_event() {
+                next = Some(next.map_or(event, |current: u64| current.min(event)));
+            }
+        }
+        next
+    }
+
+    fn clock_rate_changed(&mut self, hz: f64) {
+        for peripheral in &self.peripherals {
+            peripheral.lock().unwrap().clock_rate_changed(hz);
+        }
+    }
+
+    fn reset(&mut self) {
+        // Reset clears the ports and the timer's interrupt enable
+        self.output = [0x00; 2];
+        self.ddr = [0x00; 2];
+        self.irq_enabled = false;
+        self.timer_start = 0;
+        self.acknowledged = false;
+        self.last_cycle = 0;
+
+        for peripheral in &self.peripherals {
+            peripheral.lock().unwrap().reset();
+        }
+        self.notify(Port::A);
+        self.notify(Port::B);
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_enabled && self.timer_flag(self.last_cycle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timer() {
+        let mut rriot = Rriot::new();
+
+        // 3 x 64 cycles with the interrupt enabled
+        rriot.write(0x0E, 3);
+        assert_eq!(rriot.next_event(), Some(256));
+        rriot.tick(100);
+        assert_eq!(rriot.read(0x0E), 2);
+        assert!(!rriot.irq());
+
+        rriot.tick(256);
+        assert!(rriot.irq());
+        assert_eq!(rriot.read(0x07), 0x80);
+        rriot.tick(260);
+        assert_eq!(rriot.read(0x0E), 0xFB);
+        assert!(!rriot.irq());
+        assert_eq!(rriot.read(0x07), 0x00);
+    }
+}
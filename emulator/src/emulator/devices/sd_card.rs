@@ -0,0 +1,329 @@
+use std::collections::VecDeque;
+
+use super::block_image::{BlockImage, BLOCK_SIZE};
+use super::spi::SpiDevice;
+
+// R1 response bits
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+const R1_ADDRESS_ERROR: u8 = 0x20;
+
+// Data tokens
+const TOKEN_START_BLOCK: u8 = 0xFE;
+const TOKEN_READ_ERROR: u8 = 0x08;
+const DATA_ACCEPTED: u8 = 0x05;
+const DATA_WRITE_ERROR: u8 = 0x0D;
+
+// OCR: powered up, card capacity status (block addressed), 2.7-3.6 V
+const OCR: u32 = 0xC0FF_8000;
+
+// Bytes of busy signalling after a block write
+const WRITE_BUSY_BYTES: usize = 8;
+
+enum State {
+    // Waiting for (or receiving) a command
+    Command,
+    // Got CMD24, waiting for the data token
+    WriteToken(u64),
+    // Receiving the data block and its CRC
+    WriteData(u64),
+}
+
+/**
+ * An SDHC card talking the SPI mode protocol, backed by a disk image.
+ *
+ * Supports the commands needed to bring a card up and move single blocks:
+ * CMD0, CMD8, CMD13, CMD16, CMD17, CMD24, CMD55, ACMD41 and CMD58.
+ * Blocks are always 512 bytes and addressed by block number.
+ */
+pub struct SdCard {
+    image: BlockImage,
+    state: State,
+    idle: bool,
+    app_command: bool,
+
+    command: Vec<u8>,
+    data: Vec<u8>,
+    response: VecDeque<u8>,
+}
+
+impl SdCard {
+    pub fn new(image: BlockImage) -> Self {
+        Self {
+            image,
+            state: State::Command,
+            idle: true,
+            app_command: false,
+            command: Vec::with_capacity(6),
+            data: Vec::with_capacity(BLOCK_SIZE + 2),
+            response: VecDeque::new(),
+        }
+    }
+
+    fn r1(&self, flags: u8) -> u8 {
+        if self.idle {
+            flags | R1_IDLE
+        } else {
+            flags
+        }
+    }
+
+    fn execute(&mut self) {
+        let index = self.command[0] & 0x3F;
+        let argument = u32::from_be_bytes([
+            self.command[1],
+            self.command[2],
+            self.command[3],
+            self.command[4],
+        ]);
+        self.command.clear();
+
+        let app_command = std::mem::replace(&mut self.app_command, false);
+
+        match (app_command, index) {
+            // GO_IDLE_STATE
+            (_, 0) => {
+                self.idle = true;
+                self.response.push_back(self.r1(0));
+            }
+            // SEND_IF_COND, echoing the voltage range and check pattern
+            (_, 8) => {
+                self.response.push_back(self.r1(0));
+                self.response.extend([0x00, 0x00]);
+                self.response.push_back((argument >> 8) as u8 & 0x0F);
+                self.response.push_back(argument as u8);
+            }
+            // SEND_STATUS
+            (_, 13) => {
+                self.response.push_back(self.r1(0));
+                self.response.push_back(0x00);
+            }
+            // SET_BLOCKLEN, only 512 byte blocks are supported
+            (_, 16) => {
+                let flags = if argument as usize == BLOCK_SIZE { 0 } else { R1_ADDRESS_ERROR };
+                self.response.push_back(self.r1(flags));
+            }
+            // READ_SINGLE_BLOCK
+            (_, 17) => self.read_block(argument as u64),
+            // WRITE_BLOCK
+            (_, 24) => {
+                if argument as u64 >= self.image.block_count() {
+                    self.response.push_back(self.r1(R1_ADDRESS_ERROR));
+                } else {
+                    self.response.push_back(self.r1(0));
+                    self.state = State::WriteToken(argument as u64);
+                }
+            }
+            // APP_CMD
+            (_, 55) => {
+                self.app_command = true;
+                self.response.push_back(self.r1(0));
+            }
+            // SD_SEND_OP_COND, the card is ready straight away
+            (true, 41) => {
+                self.idle = false;
+                self.response.push_back(self.r1(0));
+            }
+            // READ_OCR
+            (_, 58) => {
+                self.response.push_back(self.r1(0));
+                self.response.extend(OCR.to_be_bytes());
+            }
+            _ => self.response.push_back(self.r1(R1_ILLEGAL_COMMAND)),
+        }
+    }
+
+    fn read_block(&mut self, block: u64) {
+        if block >= self.image.block_count() {
+            self.response.push_back(self.r1(R1_ADDRESS_ERROR));
+            return;
+        }
+
+        self.response.push_back(self.r1(0));
+
+        // A byte of access time before the data token
+        self.response.push_back(0xFF);
+
+        let mut buffer = [0u8; BLOCK_SIZE];
+        match self.image.read_block(block, &mut buffer) {
+            Ok(()) => {
+                self.response.push_back(TOKEN_START_BLOCK);
+                self.response.extend(buffer);
+                self.response.extend(crc16(&buffer).to_be_bytes());
+            }
+            Err(_) => self.response.push_back(TOKEN_READ_ERROR),
+        }
+    }
+
+    fn write_block(&mut self, block: u64) {
+        let mut buffer = [0u8; BLOCK_SIZE];
+        buffer.copy_from_slice(&self.data[..BLOCK_SIZE]);
+        self.data.clear();
+
+        match self.image.write_block(block, &buffer) {
+            Ok(()) => {
+                self.response.push_back(DATA_ACCEPTED);
+                self.response.extend([0x00; WRITE_BUSY_BYTES]);
+            }
+            Err(_) => self.response.push_back(DATA_WRITE_ERROR),
+        }
+    }
+}
+
+impl SpiDevice for SdCard {
+    fn name(&self) -> &str {
+        "sd_card"
+    }
+
+    fn deselect(&mut self) {
+        // An unfinished command is abandoned
+        self.command.clear();
+    }
+
+    fn exchange(&mut self, mosi: u8) -> u8 {
+        match self.state {
+            State::Command => {
+                // Commands start with 01 in the top two bits
+                if !self.command.is_empty() || mosi & 0xC0 == 0x40 {
+                    self.command.push(mosi);
+                    if self.command.len() == 6 {
+                        self.response.clear();
+                        self.execute();
+                    }
+                }
+            }
+            State::WriteToken(block) => {
+                if mosi == TOKEN_START_BLOCK {
+                    self.data.clear();
+                    self.state = State::WriteData(block);
+                }
+            }
+            State::WriteData(block) => {
+                self.data.push(mosi);
+                if self.data.len() == BLOCK_SIZE + 2 {
+                    self.state = State::Command;
+                    self.write_block(block);
+                }
+            }
+        }
+
+        self.response.pop_front().unwrap_or(0xFF)
+    }
+
+    fn reset(&mut self) {
+        self.state = State::Command;
+        self.idle = true;
+        self.app_command = false;
+        self.command.clear();
+        self.data.clear();
+        self.response.clear();
+    }
+}
+
+// CRC-16/XMODEM, as used for SD data blocks
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::devices::port::{Pin, Port, PortDevice};
+    use crate::emulator::devices::spi::SpiBus;
+
+    // Bit-bang a byte in SPI mode 0 on port B: MOSI = PB0, MISO = PB1, SCK = PB2, CS = PB3
+    fn transfer(bus: &mut SpiBus, byte: u8) -> u8 {
+        let mut received = 0;
+        for bit in (0..8).rev() {
+            let mosi = (byte >> bit) & 1;
+            bus.pins_changed(Port::B, mosi, 0x0D, 0);
+            received = received << 1 | (bus.drive(Port::B, 0) >> 1) & 1;
+            bus.pins_changed(Port::B, mosi | 0x04, 0x0D, 0);
+        }
+        bus.pins_changed(Port::B, 0x00, 0x0D, 0);
+        received
+    }
+
+    fn command(bus: &mut SpiBus, index: u8, argument: u32) -> u8 {
+        transfer(bus, 0x40 | index);
+        for byte in argument.to_be_bytes() {
+            transfer(bus, byte);
+        }
+        transfer(bus, 0x95);
+
+        // Wait for the R1 response
+        (0..8).map(|_| transfer(bus, 0xFF)).find(|r1| *r1 != 0xFF).unwrap_or(0xFF)
+    }
+
+    #[test]
+    fn test_init_and_block_io() {
+        let mut bus = SpiBus::new(Pin::new(Port::B, 0), Pin::new(Port::B, 1), Pin::new(Port::B, 2));
+        bus.attach(Pin::new(Port::B, 3), Box::new(SdCard::new(BlockImage::in_memory(16))));
+        bus.pins_changed(Port::B, 0x00, 0x0D, 0);
+
+        assert_eq!(command(&mut bus, 0, 0), 0x01);
+        assert_eq!(command(&mut bus, 8, 0x1AA), 0x01);
+        let r7: Vec<u8> = (0..4).map(|_| transfer(&mut bus, 0xFF)).collect();
+        assert_eq!(r7, vec![0x00, 0x00, 0x01, 0xAA]);
+        assert_eq!(command(&mut bus, 55, 0), 0x01);
+        assert_eq!(command(&mut bus, 41, 0x4000_0000), 0x00);
+        assert_eq!(command(&mut bus, 58, 0), 0x00);
+        let ocr: Vec<u8> = (0..4).map(|_| transfer(&mut bus, 0xFF)).collect();
+        assert_eq!(ocr, OCR.to_be_bytes());
+
+        // Write block 3
+        assert_eq!(command(&mut bus, 24, 3), 0x00);
+        transfer(&mut bus, 0xFF);
+        transfer(&mut bus, TOKEN_START_BLOCK);
+        for i in 0..BLOCK_SIZE {
+            transfer(&mut bus, i as u8);
+        }
+        transfer(&mut bus, 0x00);
+        transfer(&mut bus, 0x00);
+        assert_eq!(transfer(&mut bus, 0xFF) & 0x1F, DATA_ACCEPTED);
+        while transfer(&mut bus, 0xFF) == 0x00 {}
+
+        // And read it back
+        assert_eq!(command(&mut bus, 17, 3), 0x00);
+        while transfer(&mut bus, 0xFF) != TOKEN_START_BLOCK {}
+        let block: Vec<u8> = (0..BLOCK_SIZE).map(|_| transfer(&mut bus, 0xFF)).collect();
+        assert!(block.iter().enumerate().all(|(i, byte)| *byte == i as u8));
+    }
+
+    #[test]
+    fn test_error_responses() {
+        let mut bus = SpiBus::new(Pin::new(Port::B, 0), Pin::new(Port::B, 1), Pin::new(Port::B, 2));
+        bus.attach(Pin::new(Port::B, 3), Box::new(SdCard::new(BlockImage::in_memory(16))));
+        bus.pins_changed(Port::B, 0x00, 0x0D, 0);
+
+        // While idle every R1 has the idle bit set
+        assert_eq!(command(&mut bus, 0, 0), R1_IDLE);
+        assert_eq!(command(&mut bus, 9, 0), R1_IDLE | R1_ILLEGAL_COMMAND);
+        assert_eq!(command(&mut bus, 41, 0x4000_0000), R1_IDLE | R1_ILLEGAL_COMMAND);
+        assert_eq!(command(&mut bus, 16, 1024), R1_IDLE | R1_ADDRESS_ERROR);
+        assert_eq!(command(&mut bus, 55, 0), R1_IDLE);
+        assert_eq!(command(&mut bus, 41, 0x4000_0000), 0x00);
+
+        // Blocks past the end of the card
+        assert_eq!(command(&mut bus, 17, 16), R1_ADDRESS_ERROR);
+        assert_eq!(command(&mut bus, 24, 16), R1_ADDRESS_ERROR);
+        assert_eq!(command(&mut bus, 16, BLOCK_SIZE as u32), 0x00);
+
+        // ACMD41 is only an application command; CMD55 applies to one command
+        assert_eq!(command(&mut bus, 55, 0), 0x00);
+        assert_eq!(command(&mut bus, 13, 0), 0x00);
+        transfer(&mut bus, 0xFF);
+        assert_eq!(command(&mut bus, 41, 0), R1_ILLEGAL_COMMAND);
+    }
+}
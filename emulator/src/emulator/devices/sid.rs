@@ -0,0 +1,549 @@
+/**
+ * MOS 6581/8580 SID sound chip.
+ *
+ * The three voices (oscillators with their waveforms, ring modulation and
+ * hard sync, and ADSR envelopes) run cycle by cycle off the CPU clock, as
+ * they do on hardware. The multimode filter is approximated with a state
+ * variable filter running at the output sample rate, with a rough cutoff
+ * curve for each model. The 6581's mixer DC offset is there too, so
+ * samples played through the volume register are audible.
+ *
+ * There is no sound card involved: the output is rendered offline into a
+ * WAV file and/or captured in memory, so a music driver can be tested
+ * headless and the result doesn't depend on how fast the host is.
+ */
+use std::io;
+use std::path::Path;
+
+use super::Device;
+use crate::emulator::wav::{to_pcm, WavWriter};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SidModel {
+    Mos6581,
+    Mos8580,
+}
+
+// Voice control register bits
+const CONTROL_GATE: u8 = 0x01;
+const CONTROL_SYNC: u8 = 0x02;
+const CONTROL_RING: u8 = 0x04;
+const CONTROL_TEST: u8 = 0x08;
+const CONTROL_TRIANGLE: u8 = 0x10;
+const CONTROL_SAWTOOTH: u8 = 0x20;
+const CONTROL_PULSE: u8 = 0x40;
+const CONTROL_NOISE: u8 = 0x80;
+
+// Mode/volume register bits
+const MODE_VOICE3_OFF: u8 = 0x80;
+const MODE_HIGH_PASS: u8 = 0x40;
+const MODE_BAND_PASS: u8 = 0x20;
+const MODE_LOW_PASS: u8 = 0x10;
+
+// Cycles per envelope step for each attack/decay/release setting
+const RATE_PERIODS: [u32; 16] = [
+    9, 32, 63, 95, 149, 220, 267, 313, 392, 977, 1954, 3126, 3907, 11720, 19532, 31251,
+];
+
+// Largest output of a voice: a 12 bit waveform times an 8 bit envelope
+const VOICE_RANGE: f32 = 2048.0 * 255.0;
+
+// How often the output is written while the CPU isn't touching the chip
+const OUTPUT_INTERVAL_SECONDS: f64 = 0.02;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Envelope {
+    Attack,
+    DecaySustain,
+    Release,
+}
+
+#[derive(Clone, Copy)]
+struct Voice {
+    frequency: u16,
+    pulse_width: u16,
+    control: u8,
+    attack_decay: u8,
+    sustain_release: u8,
+
+    accumulator: u32, // 24 bits
+    noise: u32,       // 23 bit shift register
+    msb_rising: bool, // The accumulator's top bit went high this cycle (for sync)
+
+    envelope: u8,
+    state: Envelope,
+    rate_counter: u32,
+    exponential_counter: u32,
+}
+
+impl Voice {
+    fn new() -> Self {
+        Self {
+            frequency: 0,
+            pulse_width: 0,
+            control: 0,
+            attack_decay: 0,
+            sustain_release: 0,
+            accumulator: 0,
+            noise: 0x7F_FFF8,
+            msb_rising: false,
+            envelope: 0,
+            state: Envelope::Release,
+            rate_counter: 0,
+            exponential_counter: 0,
+        }
+    }
+
+    fn set_control(&mut self, control: u8) {
+        let gate_on = control & CONTROL_GATE != 0;
+        if gate_on && self.control & CONTROL_GATE == 0 {
+            self.state = Envelope::Attack;
+        } else if !gate_on && self.control & CONTROL_GATE != 0 {
+            self.state = Envelope::Release;
+        }
+        self.control = control;
+    }
+
+    fn clock_oscillator(&mut self) {
+        if self.control & CONTROL_TEST != 0 {
+            // The test bit holds the oscillator and resets the noise generator
+            self.accumulator = 0;
+            self.noise = 0x7F_FFFF;
+            self.msb_rising = false;
+            return;
+        }
+
+        let previous = self.accumulator;
+        self.accumulator = (self.accumulator + self.frequency as u32) & 0xFF_FFFF;
+        self.msb_rising = previous & 0x80_0000 == 0 && self.accumulator & 0x80_0000 != 0;
+
+        // The noise generator is clocked by bit 19
+        if previous & 0x08_0000 == 0 && self.accumulator & 0x08_0000 != 0 {
+            let bit = (self.noise >> 22 ^ self.noise >> 17) & 1;
+            self.noise = (self.noise << 1 | bit) & 0x7F_FFFF;
+        }
+    }
+
+    fn clock_envelope(&mut self) {
+        let rate = match self.state {
+            Envelope::Attack => self.attack_decay >> 4,
+            Envelope::DecaySustain => self.attack_decay & 0x0F,
+            Envelope::Release => self.sustain_release & 0x0F,
+        };
+
+        self.rate_counter += 1;
+        if self.rate_counter < RATE_PERIODS[rate as usize] {
+            return;
+        }
+        self.rate_counter = 0;
+
+        if self.state == Envelope::Attack {
+            self.envelope = self.envelope.saturating_add(1);
+            if self.envelope == 0xFF {
+                self.state = Envelope::DecaySustain;
+            }
+            return;
+        }
+
+        // Decay and release slow down as the level drops, for an exponential curve
+        self.exponential_counter += 1;
+        let divider = match self.envelope {
+            0x5E..=0xFF => 1,
+            0x37..=0x5D => 2,
+            0x1B..=0x36 => 4,
+            0x0F..=0x1A => 8,
+            0x07..=0x0E => 16,
+            _ => 30,
+        };
+        if self.exponential_counter < divider {
+            return;
+        }
+        self.exponential_counter = 0;
+
+        let sustain = (self.sustain_release >> 4) * 0x11;
+        match self.state {
+            Envelope::DecaySustain if self.envelope > sustain => self.envelope -= 1,
+            Envelope::Release if self.envelope > 0 => self.envelope -= 1,
+            _ => {}
+        }
+    }
+
+    // The 12 bit waveform output; `ring_msb` is the top bit of the ring modulation source
+    fn waveform(&self, ring_msb: bool) -> u16 {
+        let accumulator = self.accumulator;
+        let mut output = 0x0FFF;
+        let mut selected = false;
+
+        if self.control & CONTROL_TRIANGLE != 0 {
+            let mut msb = accumulator & 0x80_0000 != 0;
+            if self.control & CONTROL_RING != 0 {
+                msb ^= ring_msb;
+            }
+            let folded = if msb { !accumulator } else { accumulator };
+            output &= (folded >> 11) as u16 & 0x0FFF;
+            selected = true;
+        }
+        if self.control & CONTROL_SAWTOOTH != 0 {
+            output &= (accumulator >> 12) as u16;
+            selected = true;
+        }
+        if self.control & CONTROL_PULSE != 0 {
+            let high = self.control & CONTROL_TEST != 0
+                || (accumulator >> 12) as u16 >= self.pulse_width & 0x0FFF;
+            output &= if high { 0x0FFF } else { 0x0000 };
+            selected = true;
+        }
+        if self.control & CONTROL_NOISE != 0 {
+            output &= self.noise_output();
+            selected = true;
+        }
+
+        // Combined waveforms come out as the AND of their parts
+        if selected {
+            output
+        } else {
+            0
+        }
+    }
+
+    fn noise_output(&self) -> u16 {
+        let noise = self.noise;
+        let bits = [22, 20, 16, 13, 11, 7, 4, 2];
+        let byte = bits
+            .iter()
+            .fold(0u16, |byte, bit| byte << 1 | (noise >> bit) as u16 & 1);
+        byte << 4
+    }
+
+    fn output(&self, ring_msb: bool) -> f32 {
+        (self.waveform(ring_msb) as i32 - 0x800) as f32 * self.envelope as f32
+    }
+}
+
+pub struct Sid {
+    model: SidModel,
+    voices: [Voice; 3],
+    filter_cutoff: u16,
+    resonance_filter: u8,
+    mode_volume: u8,
+    last_write: u8,
+
+    // Filter state
+    low: f32,
+    band: f32,
+
+    // Voice outputs summed over the current sample
+    direct_sum: f32,
+    filter_sum: f32,
+    sum_cycles: u32,
+
+    sample_rate: u32,
+    sample_phase: f64,
+    wav: Option<WavWriter>,
+    capture: Option<Vec<i16>>,
+
+    last_cycle: u64,
+    clock_hz: f64,
+}
+
+impl Sid {
+    pub fn new(model: SidModel) -> Self {
+        Self {
+            model,
+            voices: [Voice::new(); 3],
+            filter_cutoff: 0,
+            resonance_filter: 0,
+            mode_volume: 0,
+            last_write: 0,
+            low: 0.0,
+            band: 0.0,
+            direct_sum: 0.0,
+            filter_sum: 0.0,
+            sum_cycles: 0,
+            sample_rate: 44_100,
+            sample_phase: 0.0,
+            wav: None,
+            capture: None,
+            last_cycle: 0,
+            clock_hz: 1_000_000.0,
+        }
+    }
+
+    pub fn model(&self) -> SidModel {
+        self.model
+    }
+
+    // Typically 44100 or 48000 Hz, set before the WAV output is opened
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate.max(1);
+    }
+
+    // Render the output into a WAV file
+    pub fn set_wav_output<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.wav = Some(WavWriter::create(path, self.sample_rate)?);
+        Ok(())
+    }
+
+    // Write out the samples still buffered for the WAV file
+    pub fn flush_wav(&mut self) -> io::Result<()> {
+        match &mut self.wav {
+            Some(wav) => wav.flush(),
+            None => Ok(()),
+        }
+    }
+
+    // Keep the output samples in memory, for tests
+    pub fn set_capture(&mut self, capture: bool) {
+        self.capture = if capture { Some(Vec::new()) } else { None };
+    }
+
+    // The samples captured since the last call
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        self.capture.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    fn cycles_per_sample(&self) -> f64 {
+        self.clock_hz / self.sample_rate as f64
+    }
+
+    fn clock(&mut self) {
+        for voice in &mut self.voices {
+            voice.clock_oscillator();
+            voice.clock_envelope();
+        }
+
+        // Hard sync resets an oscillator when its source's top bit goes high
+        let rising = self.voices.map(|voice| voice.msb_rising);
+        for (index, voice) in self.voices.iter_mut().enumerate() {
+            if voice.control & CONTROL_SYNC != 0 && rising[(index + 2) % 3] {
+                voice.accumulator = 0;
+            }
+        }
+
+        for index in 0..3 {
+            let source = &self.voices[(index + 2) % 3];
+            let output = self.voices[index].output(source.accumulator & 0x80_0000 != 0);
+            if self.resonance_filter & (1 << index) != 0 {
+                self.filter_sum += output;
+            } else if index != 2 || self.mode_volume & MODE_VOICE3_OFF == 0 {
+                self.direct_sum += output;
+            }
+        }
+        self.sum_cycles += 1;
+
+        self.sample_phase += 1.0;
+        let cycles_per_sample = self.cycles_per_sample();
+        if self.sample_phase >= cycles_per_sample {
+            self.sample_phase -= cycles_per_sample;
+            self.emit_sample();
+        }
+    }
+
+    fn cutoff_hz(&self) -> f32 {
+        let cutoff = self.filter_cutoff as f32 / 2047.0;
+        match self.model {
+            // The 8580's curve is close to linear, the 6581's bends upwards
+            SidModel::Mos8580 => 30.0 + cutoff * 12_000.0,
+            SidModel::Mos6581 => 220.0 + cutoff * cutoff * 18_000.0,
+        }
+    }
+
+    // Run the filter (oversampled twice for stability) and mix a sample
+    fn emit_sample(&mut self) {
+        let cycles = self.sum_cycles.max(1) as f32;
+        let direct = self.direct_sum / cycles / VOICE_RANGE;
+        let input = self.filter_sum / cycles / VOICE_RANGE;
+        self.direct_sum = 0.0;
+        self.filter_sum = 0.0;
+        self.sum_cycles = 0;
+
+        let rate = self.sample_rate as f32 * 2.0;
+        let f = (2.0 * (std::f32::consts::PI * self.cutoff_hz() / rate).sin()).min(1.2);
+        let resonance = (self.resonance_filter >> 4) as f32 / 15.0;
+        let damping = 1.0 / (0.707 + resonance * 1.5);
+
+        let mut high = 0.0;
+        for _ in 0..2 {
+            self.low += f * self.band;
+            high = input - self.low - damping * self.band;
+            self.band += f * high;
+        }
+
+        let mut filtered = 0.0;
+        if self.mode_volume & MODE_LOW_PASS != 0 {
+            filtered += self.low;
+        }
+        if self.mode_volume & MODE_BAND_PASS != 0 {
+            filtered += self.band;
+        }
+        if self.mode_volume & MODE_HIGH_PASS != 0 {
+            filtered += high;
+        }
+
+        let offset = match self.model {
+            SidModel::Mos6581 => 0.3,
+            SidModel::Mos8580 => 0.0,
+        };
+        let volume = (self.mode_volume & 0x0F) as f32 / 15.0;
+        let sample = (direct + filtered + offset) * volume / 3.0;
+
+        if let Some(capture) = &mut self.capture {
+            capture.push(to_pcm(sample));
+        }
+        if let Some(wav) = &mut self.wav {
+            if let Err(error) = wav.write_sample(sample) {
+                eprintln!("sid: could not write audio: {}", error);
+                self.wav = None;
+            }
+        }
+    }
+}
+
+impl Device for Sid {
+    fn name(&self) -> &str {
+        "sid"
+    }
+
+    fn read(&mut self, offset: u16) -> u8 {
+        self.peek(offset)
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        match offset & 0x1F {
+            // No paddles connected
+            0x19 | 0x1A => 0xFF,
+            // Top 8 bits of voice 3's waveform, and its envelope
+            0x1B => {
+                let ring_msb = self.voices[1].accumulator & 0x80_0000 != 0;
+                (self.voices[2].waveform(ring_msb) >> 4) as u8
+            }
+            0x1C => self.voices[2].envelope,
+            // The write-only registers read back whatever was last on the bus
+            _ => self.last_write,
+        }
+    }
+
+    fn write(&mut self, offset: u16, data: u8) {
+        self.last_write = data;
+        let register = offset as usize & 0x1F;
+
+        if register < 0x15 {
+            let voice = &mut self.voices[register / 7];
+            match register % 7 {
+                0 => voice.frequency = (voice.frequency & 0xFF00) | data as u16,
+                1 => voice.frequency = (voice.frequency & 0x00FF) | (data as u16) << 8,
+                2 => voice.pulse_width = (voice.pulse_width & 0x0F00) | data as u16,
+                3 => voice.pulse_width = (voice.pulse_width & 0x00FF) | (data as u16 & 0x0F) << 8,
+                4 => voice.set_control(data),
+                5 => voice.attack_decay = data,
+                _ => voice.sustain_release = data,
+            }
+            return;
+        }
+
+        match register {
+            0x15 => self.filter_cutoff = (self.filter_cutoff & 0x7F8) | (data as u16 & 0x07),
+            0x16 => self.filter_cutoff = (self.filter_cutoff & 0x007) | (data as u16) << 3,
+            0x17 => self.resonance_filter = data,
+            0x18 => self.mode_volume = data,
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, now: u64) {
+        while self.last_cycle < now {
+            self.clock();
+            self.last_cycle += 1;
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        // Keep the output flowing even if the CPU leaves the chip alone
+        if self.wav.is_some() || self.capture.is_some() {
+            let interval = (self.clock_hz * OUTPUT_INTERVAL_SECONDS) as u64;
+            Some(self.last_cycle + interval.max(1))
+        } else {
+            None
+        }
+    }
+
+    fn clock_rate_changed(&mut self, hz: f64) {
+        self.clock_hz = hz;
+    }
+
+    fn reset(&mut self) {
+        self.voices = [Voice::new(); 3];
+        self.filter_cutoff = 0;
+        self.resonance_filter = 0;
+        self.mode_volume = 0;
+        self.last_write = 0;
+        self.low = 0.0;
+        self.band = 0.0;
+        self.direct_sum = 0.0;
+        self.filter_sum = 0.0;
+        self.sum_cycles = 0;
+        self.sample_phase = 0.0;
+        self.last_cycle = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_osc3_sawtooth() {
+        let mut sid = Sid::new(SidModel::Mos8580);
+        sid.write(0x0F, 0x10); // Voice 3 frequency $1000
+        sid.write(0x12, CONTROL_SAWTOOTH);
+
+        sid.tick(256);
+        assert_eq!(sid.read(0x1B), 0x10);
+        sid.tick(512);
+        assert_eq!(sid.read(0x1B), 0x20);
+    }
+
+    #[test]
+    fn test_env3_attack_and_release() {
+        let mut sid = Sid::new(SidModel::Mos8580);
+        sid.write(0x13, 0x00); // Fastest attack: a step every 9 cycles
+        sid.write(0x14, 0xF0); // Full sustain, fastest release
+        sid.write(0x12, CONTROL_GATE);
+
+        sid.tick(90);
+        assert_eq!(sid.read(0x1C), 10);
+        sid.tick(10_000);
+        assert_eq!(sid.read(0x1C), 0xFF);
+
+        // Released from full level, a step takes 9 cycles
+        sid.write(0x12, 0x00);
+        sid.tick(10_000 + 9 * 10);
+        assert_eq!(sid.read(0x1C), 0xF5);
+    }
+
+    #[test]
+    fn test_square_wave_output() {
+        let mut sid = Sid::new(SidModel::Mos8580);
+        sid.set_capture(true);
+        sid.write(0x18, 0x0F);
+
+        // 440 Hz at a 1 MHz clock, 50% pulse width
+        let frequency = (440.0 * 16_777_216.0 / 1_000_000.0) as u16;
+        sid.write(0x00, frequency as u8);
+        sid.write(0x01, (frequency >> 8) as u8);
+        sid.write(0x03, 0x08);
+        sid.write(0x06, 0xF0);
+        sid.write(0x04, CONTROL_PULSE | CONTROL_GATE);
+
+        sid.tick(1_000_000);
+        let samples = sid.take_samples();
+        assert!((samples.len() as i64 - 44_100).abs() <= 1);
+
+        // Two edges per period
+        let edges = samples
+            .windows(2)
+            .filter(|pair| (pair[0] < 0) != (pair[1] < 0))
+            .count();
+        assert!((878..=882).contains(&edges), "{} edges", edges);
+    }
+}
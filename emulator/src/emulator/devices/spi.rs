@@ -0,0 +1,258 @@
+use super::port::{Pin, Port, PortDevice};
+
+// A device on the SPI bus
+pub trait SpiDevice: Send {
+    fn name(&self) -> &str;
+
+    /// Chip select was asserted.
+    fn select(&mut self) {}
+
+    /// Chip select was released.
+    fn deselect(&mut self) {}
+
+    /// A whole byte arrived from the master. Returns the byte to shift out
+    /// during the next transfer.
+    fn exchange(&mut self, mosi: u8) -> u8;
+
+    fn reset(&mut self) {}
+}
+
+struct Slave {
+    cs: Pin,
+    device: Box<dyn SpiDevice>,
+    selected: bool,
+}
+
+/**
+ * Decodes SPI bit-banged by the firmware on port pins.
+ *
+ * The firmware is the master and drives MOSI, SCK and one chip select per
+ * slave; the selected slave drives MISO. Bytes are shifted MSB first using
+ * any of the four clock polarity/phase modes.
+ */
+pub struct SpiBus {
+    mosi: Pin,
+    miso: Pin,
+    sck: Pin,
+    cpol: bool, // Idle level of SCK
+    cpha: bool, // Sample on the trailing rather than the leading edge
+
+    slaves: Vec<Slave>,
+
+    // Last seen levels of both ports
+    levels: [u8; 2],
+
+    sck_level: bool,
+    bit_count: u8,
+    shift_in: u8,
+    shift_out: u8,
+    miso_level: bool,
+}
+
+impl SpiBus {
+    pub fn new(mosi: Pin, miso: Pin, sck: Pin) -> Self {
+        Self {
+            mosi,
+            miso,
+            sck,
+            cpol: false,
+            cpha: false,
+            slaves: Vec::new(),
+            levels: [0xFF; 2],
+            sck_level: false,
+            bit_count: 0,
+            shift_in: 0x00,
+            shift_out: 0xFF,
+            miso_level: true,
+        }
+    }
+
+    // Select one of SPI modes 0-3
+    pub fn set_mode(&mut self, mode: u8) {
+        self.cpol = mode & 0x02 != 0;
+        self.cpha = mode & 0x01 != 0;
+        self.sck_level = self.cpol;
+    }
+
+    // Attach a slave selected by pulling `cs` low
+    pub fn attach(&mut self, cs: Pin, device: Box<dyn SpiDevice>) {
+        self.slaves.push(Slave {
+            cs,
+            device,
+            selected: false,
+        });
+    }
+
+    fn level(&self, pin: Pin) -> bool {
+        pin.level(self.levels[pin.port.index()])
+    }
+
+    fn selected(&mut self) -> Option<&mut Slave> {
+        self.slaves.iter_mut().find(|slave| slave.selected)
+    }
+
+    // Put the next bit of the outgoing byte on MISO
+    fn shift(&mut self) {
+        self.miso_level = self.shift_out & (0x80 >> self.bit_count) != 0;
+    }
+
+    // Clock in a bit from MOSI; after eight of them swap bytes with the slave
+    fn sample(&mut self) {
+        let bit = self.level(self.mosi) as u8;
+        self.shift_in = self.shift_in << 1 | bit;
+        self.bit_count += 1;
+
+        if self.bit_count == 8 {
+            let received = self.shift_in;
+            self.bit_count = 0;
+            self.shift_out = match self.selected() {
+                Some(slave) => slave.device.exchange(received),
+                None => 0xFF,
+            };
+        }
+    }
+
+    fn update_chip_selects(&mut self) {
+        let levels = self.levels;
+        let mut changed = false;
+
+        for slave in &mut self.slaves {
+            let selected = !slave.cs.level(levels[slave.cs.port.index()]);
+            if selected == slave.selected {
+                continue;
+            }
+
+            slave.selected = selected;
+            changed = true;
+            if selected {
+                slave.device.select();
+            } else {
+                slave.device.deselect();
+            }
+        }
+
+        // A new transaction starts from a byte boundary
+        if changed {
+            self.bit_count = 0;
+            self.shift_in = 0x00;
+            self.shift_out = 0xFF;
+            if !self.cpha {
+                self.shift();
+            }
+        }
+    }
+
+    fn update_clock(&mut self) {
+        let sck = self.level(self.sck);
+        if sck == self.sck_level {
+            return;
+        }
+        self.sck_level = sck;
+
+        let leading = sck != self.cpol;
+        if leading != self.cpha {
+            self.sample();
+        } else {
+            self.shift();
+        }
+    }
+}
+
+impl PortDevice for SpiBus {
+    fn name(&self) -> &str {
+        "spi_bus"
+    }
+
+    fn pins_changed(&mut self, port: Port, pins: u8, _ddr: u8, _now: u64) {
+        self.levels[port.index()] = pins;
+        self.update_chip_selects();
+        self.update_clock();
+    }
+
+    fn drive(&mut self, port: Port, _now: u64) -> u8 {
+        // MISO floats high unless a slave is selected
+        let driving = self.slaves.iter().any(|slave| slave.selected);
+        if port == self.miso.port && driving && !self.miso_level {
+            !self.miso.mask()
+        } else {
+            0xFF
+        }
+    }
+
+    fn reset(&mut self) {
+        for slave in &mut self.slaves {
+            slave.selected = false;
+            slave.device.reset();
+        }
+        self.levels = [0xFF; 2];
+        self.sck_level = self.cpol;
+        self.bit_count = 0;
+        self.shift_out = 0xFF;
+        self.miso_level = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Answers each byte with its complement, one transfer later
+    struct Complement;
+
+    impl SpiDevice for Complement {
+        fn name(&self) -> &str {
+            "complement"
+        }
+
+        fn exchange(&mut self, mosi: u8) -> u8 {
+            !mosi
+        }
+    }
+
+    // Bit-bang a byte on port B in `mode`: MOSI = PB0, MISO = PB1, SCK = PB2, CS = PB3 (low).
+    // MOSI holds the wrong bit whenever the slave shouldn't be sampling it.
+    fn transfer(bus: &mut SpiBus, mode: u8, byte: u8) -> u8 {
+        let idle = if mode & 0x02 != 0 { 0x04 } else { 0x00 };
+        let active = idle ^ 0x04;
+        let mut received = 0;
+        for bit in (0..8).rev() {
+            let mosi = (byte >> bit) & 1;
+            let pins = if mode & 0x01 == 0 {
+                // Both sides sample on the leading edge
+                bus.pins_changed(Port::B, mosi | idle, 0x0D, 0);
+                let pins = bus.drive(Port::B, 0);
+                bus.pins_changed(Port::B, mosi | active, 0x0D, 0);
+                bus.pins_changed(Port::B, mosi | idle, 0x0D, 0);
+                bus.pins_changed(Port::B, (mosi ^ 1) | idle, 0x0D, 0);
+                pins
+            } else {
+                // Both sides sample on the trailing edge
+                bus.pins_changed(Port::B, (mosi ^ 1) | active, 0x0D, 0);
+                bus.pins_changed(Port::B, mosi | active, 0x0D, 0);
+                let pins = bus.drive(Port::B, 0);
+                bus.pins_changed(Port::B, mosi | idle, 0x0D, 0);
+                pins
+            };
+            received = received << 1 | (pins >> 1) & 1;
+        }
+        received
+    }
+
+    #[test]
+    fn test_modes() {
+        for mode in 0..4 {
+            let mut bus = SpiBus::new(Pin::new(Port::B, 0), Pin::new(Port::B, 1), Pin::new(Port::B, 2));
+            bus.set_mode(mode);
+            bus.attach(Pin::new(Port::B, 3), Box::new(Complement));
+
+            // Deselected, MISO floats high
+            let idle = if mode & 0x02 != 0 { 0x04 } else { 0x00 };
+            bus.pins_changed(Port::B, 0x08 | idle, 0x0D, 0);
+            assert_eq!(bus.drive(Port::B, 0), 0xFF);
+
+            bus.pins_changed(Port::B, idle, 0x0D, 0);
+            let received = [0xA5, 0x3C, 0xFF].map(|byte| transfer(&mut bus, mode, byte));
+            assert_eq!(received, [0xFF, 0x5A, 0xC3], "mode {}", mode);
+        }
+    }
+}
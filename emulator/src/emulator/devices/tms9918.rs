@@ -0,0 +1,591 @@
+/**
+ * TI TMS9918A video display processor.
+ *
+ * The VDP is mapped as two ports selected by address bit 0 (the MODE pin):
+ *
+ *   0  VRAM data
+ *   1  status (read) / address and register setup (write)
+ *
+ * It supports graphics I and II, text and multicolor modes, and sprites
+ * with the four-per-line limit and the fifth sprite and coincidence flags.
+ * Each frame is rendered in one go at the start of vertical blank, into a
+ * 256x192 framebuffer that can be saved as a PNG. That is when the frame
+ * flag is set and, if enabled, the interrupt raised.
+ */
+use std::path::PathBuf;
+
+use super::Device;
+use crate::emulator::framebuffer::Framebuffer;
+
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 192;
+
+const VRAM_SIZE: usize = 0x4000;
+const FRAME_RATE: f64 = 60.0;
+
+// Status register bits
+const STATUS_FRAME: u8 = 0x80;
+const STATUS_FIFTH_SPRITE: u8 = 0x40;
+const STATUS_COINCIDENCE: u8 = 0x20;
+
+// A sprite Y of 208 ends the sprite attribute table
+const SPRITE_TABLE_END: u8 = 0xD0;
+
+// RGB values of the 16 colors (0 is transparent)
+const PALETTE: [u32; 16] = [
+    0x000000, 0x000000, 0x21C842, 0x5EDC78, 0x5455ED, 0x7D76FC, 0xD4524D, 0x42EBF5, 0xFC5554,
+    0xFF7978, 0xD4C154, 0xE6CE80, 0x21B03B, 0xC95BBA, 0xCCCCCC, 0xFFFFFF,
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Mode {
+    Graphics1,
+    Graphics2,
+    Multicolor,
+    Text,
+}
+
+pub struct Tms9918 {
+    vram: Vec<u8>,
+    registers: [u8; 8],
+    status: u8,
+    address: u16,
+
+    // First byte of a two byte control port write
+    latch: Option<u8>,
+    // The read-ahead buffer behind the data port
+    read_buffer: u8,
+
+    frame: Framebuffer,
+    frame_count: u64,
+    png_output: Option<(PathBuf, u64)>,
+
+    // Frames are timed from `frame_base`, the cycle of the last clock change
+    frame_base: u64,
+    frames_since_base: u64,
+    last_cycle: u64,
+    clock_hz: f64,
+}
+
+impl Default for Tms9918 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tms9918 {
+    pub fn new() -> Self {
+        Self {
+            vram: vec![0; VRAM_SIZE],
+            registers: [0; 8],
+            status: 0,
+            address: 0,
+            latch: None,
+            read_buffer: 0,
+            frame: Framebuffer::new(WIDTH, HEIGHT),
+            frame_count: 0,
+            png_output: None,
+            frame_base: 0,
+            frames_since_base: 0,
+            last_cycle: 0,
+            clock_hz: 1_000_000.0,
+        }
+    }
+
+    // The last frame rendered
+    pub fn frame(&self) -> &Framebuffer {
+        &self.frame
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    // Save every `interval`th frame to `path`, overwriting the previous one
+    pub fn set_png_output<P: Into<PathBuf>>(&mut self, path: P, interval: u64) {
+        self.png_output = Some((path.into(), interval.max(1)));
+    }
+
+    pub fn vram(&self) -> &[u8] {
+        &self.vram
+    }
+
+    pub fn register(&self, index: usize) -> u8 {
+        self.registers[index & 0x07]
+    }
+
+    pub fn mode(&self) -> Mode {
+        if self.registers[1] & 0x10 != 0 {
+            Mode::Text
+        } else if self.registers[1] & 0x08 != 0 {
+            Mode::Multicolor
+        } else if self.registers[0] & 0x02 != 0 {
+            Mode::Graphics2
+        } else {
+            Mode::Graphics1
+        }
+    }
+
+    fn display_enabled(&self) -> bool {
+        self.registers[1] & 0x40 != 0
+    }
+
+    fn interrupt_enabled(&self) -> bool {
+        self.registers[1] & 0x20 != 0
+    }
+
+    fn backdrop(&self) -> u8 {
+        self.registers[7] & 0x0F
+    }
+
+    fn name_table(&self) -> usize {
+        (self.registers[2] as usize & 0x0F) << 10
+    }
+
+    fn color_table(&self) -> usize {
+        (self.registers[3] as usize) << 6
+    }
+
+    fn pattern_table(&self) -> usize {
+        (self.registers[4] as usize & 0x07) << 11
+    }
+
+    fn sprite_attribute_table(&self) -> usize {
+        (self.registers[5] as usize & 0x7F) << 7
+    }
+
+    fn sprite_pattern_table(&self) -> usize {
+        (self.registers[6] as usize & 0x07) << 11
+    }
+
+    fn vram_at(&self, address: usize) -> u8 {
+        self.vram[address & (VRAM_SIZE - 1)]
+    }
+
+    fn next_frame_cycle(&self) -> u64 {
+        let frames = (self.frames_since_base + 1) as f64;
+        self.frame_base + (frames * self.clock_hz / FRAME_RATE) as u64
+    }
+
+    fn end_frame(&mut self) {
+        self.render();
+        self.status |= STATUS_FRAME;
+        self.frame_count += 1;
+
+        if let Some((path, interval)) = &self.png_output {
+            if self.frame_count.is_multiple_of(*interval) {
+                if let Err(error) = self.frame.save_png(path) {
+                    eprintln!("tms9918: could not save {}: {}", path.display(), error);
+                }
+            }
+        }
+    }
+
+    fn render(&mut self) {
+        let mut line = [0u8; WIDTH];
+        for y in 0..HEIGHT {
+            if self.display_enabled() {
+                self.render_background(y, &mut line);
+                if self.mode() != Mode::Text {
+                    self.render_sprites(y, &mut line);
+                }
+            } else {
+                line.fill(0);
+            }
+
+            let backdrop = self.backdrop();
+            for (pixel, color) in self.frame.row_mut(y).iter_mut().zip(line) {
+                let color = if color == 0 { backdrop } else { color };
+                *pixel = PALETTE[color as usize];
+            }
+        }
+    }
+
+    fn render_background(&self, y: usize, line: &mut [u8; WIDTH]) {
+        let row = y / 8;
+        let names = self.name_table();
+
+        match self.mode() {
+            Mode::Graphics1 | Mode::Graphics2 => {
+                for column in 0..32 {
+                    let name = self.vram_at(names + row * 32 + column) as usize;
+                    let (pattern, color) = if self.mode() == Mode::Graphics1 {
+                        (
+                            self.vram_at(self.pattern_table() + name * 8 + y % 8),
+                            self.vram_at(self.color_table() + name / 8),
+                        )
+                    } else {
+                        self.graphics2_pattern(y, name)
+                    };
+                    draw_pattern(&mut line[column * 8..column * 8 + 8], pattern, color);
+                }
+            }
+            Mode::Multicolor => {
+                for column in 0..32 {
+                    let name = self.vram_at(names + row * 32 + column) as usize;
+                    let colors =
+                        self.vram_at(self.pattern_table() + name * 8 + (row & 3) * 2 + (y % 8) / 4);
+                    line[column * 8..column * 8 + 4].fill(colors >> 4);
+                    line[column * 8 + 4..column * 8 + 8].fill(colors & 0x0F);
+                }
+            }
+            Mode::Text => {
+                // 40 columns of 6 pixels, with an 8 pixel border each side
+                let color = self.registers[7];
+                line.fill(0);
+                for column in 0..40 {
+                    let name = self.vram_at(names + row * 40 + column) as usize;
+                    let pattern = self.vram_at(self.pattern_table() + name * 8 + y % 8);
+                    let start = 8 + column * 6;
+                    draw_pattern(&mut line[start..start + 6], pattern, color);
+                }
+            }
+        }
+    }
+
+    // In graphics II the screen is split in thirds with their own patterns
+    // and colors; the table registers mask which thirds are really used
+    fn graphics2_pattern(&self, y: usize, name: usize) -> (u8, u8) {
+        let index = ((y / 64) << 8 | name) << 3 | (y % 8);
+
+        let pattern_base = (self.registers[4] as usize & 0x04) << 11;
+        let pattern_mask = (self.registers[4] as usize & 0x03) << 11 | 0x07FF;
+        let color_base = (self.registers[3] as usize & 0x80) << 6;
+        let color_mask = (self.registers[3] as usize & 0x7F) << 6 | 0x003F;
+
+        (
+            self.vram_at(pattern_base | (index & pattern_mask)),
+            self.vram_at(color_base | (index & color_mask)),
+        )
+    }
+
+    fn render_sprites(&mut self, y: usize, line: &mut [u8; WIDTH]) {
+        let size = if self.registers[1] & 0x02 != 0 { 16 } else { 8 };
+        let magnify = if self.registers[1] & 0x01 != 0 { 2 } else { 1 };
+        let height = size * magnify;
+        let attributes = self.sprite_attribute_table();
+
+        // Find the (at most four) sprites on this line
+        let mut visible = Vec::with_capacity(4);
+        let mut last = 31;
+        for sprite in 0..32 {
+            let entry = attributes + sprite * 4;
+            let sprite_y = self.vram_at(entry);
+            if sprite_y == SPRITE_TABLE_END {
+                last = sprite;
+                break;
+            }
+
+            // Y is one less than the first line; values past 208 are above the screen
+            let top = if sprite_y > SPRITE_TABLE_END {
+                sprite_y as i32 - 255
+            } else {
+                sprite_y as i32 + 1
+            };
+            let row = y as i32 - top;
+            if row < 0 || row >= height as i32 {
+                continue;
+            }
+
+            if visible.len() == 4 {
+                if self.status & STATUS_FIFTH_SPRITE == 0 {
+                    self.status = (self.status & 0xE0) | STATUS_FIFTH_SPRITE | sprite as u8;
+                }
+                break;
+            }
+            visible.push((entry, row as usize / magnify));
+        }
+        if self.status & STATUS_FIFTH_SPRITE == 0 {
+            self.status = (self.status & 0xE0) | last as u8;
+        }
+
+        // Lower numbered sprites are in front
+        let mut covered = [false; WIDTH];
+        for (entry, row) in visible {
+            let mut x = self.vram_at(entry + 1) as i32;
+            let mut name = self.vram_at(entry + 2) as usize;
+            let color = self.vram_at(entry + 3);
+            if color & 0x80 != 0 {
+                x -= 32; // Early clock
+            }
+            if size == 16 {
+                name &= 0xFC;
+            }
+
+            let pattern = self.sprite_pattern_table() + name * 8 + row;
+            for dx in 0..size * magnify {
+                let screen_x = x + dx as i32;
+                if !(0..WIDTH as i32).contains(&screen_x) {
+                    continue;
+                }
+
+                let column = dx / magnify;
+                let bits = self.vram_at(pattern + (column / 8) * 16);
+                if bits & (0x80 >> (column % 8)) == 0 {
+                    continue;
+                }
+
+                let screen_x = screen_x as usize;
+                if covered[screen_x] {
+                    self.status |= STATUS_COINCIDENCE;
+                    continue;
+                }
+                covered[screen_x] = true;
+                if color & 0x0F != 0 {
+                    line[screen_x] = color & 0x0F;
+                }
+            }
+        }
+    }
+
+    fn write_control(&mut self, data: u8) {
+        let Some(low) = self.latch.take() else {
+            self.latch = Some(data);
+            return;
+        };
+
+        if data & 0x80 != 0 {
+            self.registers[data as usize & 0x07] = low;
+        } else {
+            self.address = ((data as u16 & 0x3F) << 8) | low as u16;
+            if data & 0x40 == 0 {
+                // Setting up a read fetches the first byte straight away
+                self.read_buffer = self.vram[self.address as usize];
+                self.increment_address();
+            }
+        }
+    }
+
+    fn increment_address(&mut self) {
+        self.address = (self.address + 1) & (VRAM_SIZE as u16 - 1);
+    }
+}
+
+// Draw a row of a pattern: set bits in the foreground (high nibble) color
+fn draw_pattern(pixels: &mut [u8], pattern: u8, color: u8) {
+    for (bit, pixel) in pixels.iter_mut().enumerate() {
+        *pixel = if pattern & (0x80 >> bit) != 0 {
+            color >> 4
+        } else {
+            color & 0x0F
+        };
+    }
+}
+
+impl Device for Tms9918 {
+    fn name(&self) -> &str {
+        "tms9918"
+    }
+
+    fn read(&mut self, offset: u16) -> u8 {
+        self.latch = None;
+        if offset & 0x01 == 0 {
+            let data = self.read_buffer;
+            self.read_buffer = self.vram[self.address as usize];
+            self.increment_address();
+            data
+        } else {
+            // Reading the status acknowledges the interrupt
+            let status = self.status;
+            self.status &= !(STATUS_FRAME | STATUS_FIFTH_SPRITE | STATUS_COINCIDENCE);
+            status
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        if offset & 0x01 == 0 {
+            self.read_buffer
+        } else {
+            self.status
+        }
+    }
+
+    fn write(&mut self, offset: u16, data: u8) {
+        if offset & 0x01 == 0 {
+            self.latch = None;
+            self.vram[self.address as usize] = data;
+            self.read_buffer = data;
+            self.increment_address();
+        } else {
+            self.write_control(data);
+        }
+    }
+
+    fn tick(&mut self, now: u64) {
+        self.last_cycle = now;
+        while now >= self.next_frame_cycle() {
+            self.frames_since_base += 1;
+            self.end_frame();
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        Some(self.next_frame_cycle())
+    }
+
+    fn clock_rate_changed(&mut self, hz: f64) {
+        self.clock_hz = hz;
+        self.frame_base = self.last_cycle;
+        self.frames_since_base = 0;
+    }
+
+    fn reset(&mut self) {
+        self.registers = [0; 8];
+        self.status = 0;
+        self.address = 0;
+        self.latch = None;
+        self.read_buffer = 0;
+        self.frame_base = 0;
+        self.frames_since_base = 0;
+        self.last_cycle = 0;
+    }
+
+    fn irq(&self) -> bool {
+        self.interrupt_enabled() && self.status & STATUS_FRAME != 0
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        let mut state = Vec::with_capacity(VRAM_SIZE + 40);
+        state.extend_from_slice(&self.registers);
+        state.push(self.status);
+        state.extend_from_slice(&self.address.to_le_bytes());
+        state.push(self.latch.is_some() as u8);
+        state.push(self.latch.unwrap_or(0));
+        state.push(self.read_buffer);
+        state.extend_from_slice(&self.frame_base.to_le_bytes());
+        state.extend_from_slice(&self.frames_since_base.to_le_bytes());
+        state.extend_from_slice(&self.last_cycle.to_le_bytes());
+        state.extend_from_slice(&self.vram);
+        state
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        if state.len() != 38 + VRAM_SIZE {
+            return;
+        }
+        self.registers.copy_from_slice(&state[0..8]);
+        self.status = state[8];
+        self.address = u16::from_le_bytes([state[9], state[10]]);
+        self.latch = if state[11] != 0 { Some(state[12]) } else { None };
+        self.read_buffer = state[13];
+        self.frame_base = u64::from_le_bytes(state[14..22].try_into().unwrap());
+        self.frames_since_base = u64::from_le_bytes(state[22..30].try_into().unwrap());
+        self.last_cycle = u64::from_le_bytes(state[30..38].try_into().unwrap());
+        self.vram.copy_from_slice(&state[38..]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_register(vdp: &mut Tms9918, register: u8, value: u8) {
+        vdp.write(1, value);
+        vdp.write(1, 0x80 | register);
+    }
+
+    fn write_vram(vdp: &mut Tms9918, address: u16, data: &[u8]) {
+        vdp.write(1, address as u8);
+        vdp.write(1, 0x40 | (address >> 8) as u8);
+        for byte in data {
+            vdp.write(0, *byte);
+        }
+    }
+
+    // Graphics I: names at $1800, colors at $2000, patterns at $0000,
+    // sprite attributes at $1B00 and sprite patterns at $3800
+    fn graphics1() -> Tms9918 {
+        let mut vdp = Tms9918::new();
+        for (register, value) in [0x00, 0xE0, 0x06, 0x80, 0x00, 0x36, 0x07, 0x04].iter().enumerate() {
+            set_register(&mut vdp, register as u8, *value);
+        }
+        write_vram(&mut vdp, 0x1B00, &[SPRITE_TABLE_END]);
+        vdp
+    }
+
+    #[test]
+    fn test_vram_ports() {
+        let mut vdp = Tms9918::new();
+        write_vram(&mut vdp, 0x1234, &[0xAA, 0xBB, 0xCC]);
+        assert_eq!(&vdp.vram()[0x1234..0x1237], &[0xAA, 0xBB, 0xCC]);
+
+        // Set up a read from $1235; the first byte is already waiting
+        vdp.write(1, 0x35);
+        vdp.write(1, 0x12);
+        assert_eq!(vdp.read(0), 0xBB);
+        assert_eq!(vdp.read(0), 0xCC);
+
+        set_register(&mut vdp, 7, 0xF4);
+        assert_eq!(vdp.register(7), 0xF4);
+    }
+
+    #[test]
+    fn test_graphics1_and_vblank() {
+        let mut vdp = graphics1();
+        vdp.clock_rate_changed(60_000.0);
+
+        // Character 1 is a checkerboard in white on dark red, in the top left corner
+        write_vram(&mut vdp, 0x0008, &[0xAA; 8]);
+        write_vram(&mut vdp, 0x2000, &[0xF6, 0x00]);
+        write_vram(&mut vdp, 0x1800, &[0x01, 0x00, 0x08]);
+
+        assert_eq!(vdp.next_event(), Some(1000));
+        vdp.tick(999);
+        assert!(!vdp.irq());
+        vdp.tick(1000);
+        assert!(vdp.irq());
+        assert_eq!(vdp.read(1) & STATUS_FRAME, STATUS_FRAME);
+        assert!(!vdp.irq());
+
+        let frame = vdp.frame();
+        assert_eq!(frame.pixel(0, 0), PALETTE[15]);
+        assert_eq!(frame.pixel(1, 0), PALETTE[6]);
+        // Character 0 is in the same color group, but blank
+        assert_eq!(frame.pixel(8, 0), PALETTE[6]);
+        // Character 8 has transparent colors, showing the backdrop
+        assert_eq!(frame.pixel(16, 0), PALETTE[4]);
+    }
+
+    #[test]
+    fn test_text_mode() {
+        let mut vdp = graphics1();
+        set_register(&mut vdp, 1, 0xD0);
+        set_register(&mut vdp, 7, 0xF1);
+        write_vram(&mut vdp, 0x0008, &[0xFC; 8]);
+        write_vram(&mut vdp, 0x1800, &[0x01]);
+        vdp.tick(1_000_000 / 60);
+
+        let frame = vdp.frame();
+        assert_eq!(frame.pixel(7, 0), PALETTE[1]);
+        assert!((8..14).all(|x| frame.pixel(x, 0) == PALETTE[15]));
+        assert_eq!(frame.pixel(14, 0), PALETTE[1]);
+    }
+
+    #[test]
+    fn test_sprite_limit_and_collision() {
+        let mut vdp = graphics1();
+        write_vram(&mut vdp, 0x3800, &[0xFF; 8]);
+
+        // Five sprites on line 10, the first two overlapping
+        let mut attributes = Vec::new();
+        for (sprite, x) in [0u8, 4, 40, 80, 120].iter().enumerate() {
+            attributes.extend_from_slice(&[9, *x, 0, 2 + sprite as u8]);
+        }
+        attributes.push(SPRITE_TABLE_END);
+        write_vram(&mut vdp, 0x1B00, &attributes);
+        vdp.tick(1_000_000 / 60);
+
+        let status = vdp.read(1);
+        assert_eq!(status & STATUS_FIFTH_SPRITE, STATUS_FIFTH_SPRITE);
+        assert_eq!(status & 0x1F, 4);
+        assert_eq!(status & STATUS_COINCIDENCE, STATUS_COINCIDENCE);
+
+        let frame = vdp.frame();
+        assert_eq!(frame.pixel(4, 9), PALETTE[4]);
+        assert_eq!(frame.pixel(4, 10), PALETTE[2]);
+        assert_eq!(frame.pixel(10, 10), PALETTE[3]);
+        assert_eq!(frame.pixel(80, 10), PALETTE[5]);
+        // The fifth sprite isn't drawn
+        assert_eq!(frame.pixel(120, 10), PALETTE[4]);
+    }
+}
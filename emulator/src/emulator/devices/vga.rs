@@ -0,0 +1,306 @@
+/**
+ * A Ben Eater style VGA video card that shares its RAM with the CPU.
+ *
+ * The card is mapped over the video RAM itself. While it is drawing the
+ * visible part of a line it needs the RAM, so it pulls RDY low and halts
+ * the CPU; the CPU only gets to run during the horizontal and vertical
+ * blanking intervals. The beam position follows real VGA timing, so how
+ * many cycles are stolen depends on the CPU clock just like on hardware.
+ *
+ * Each pixel is one byte of RAM in the form 00RRGGBB, and line `y` of the
+ * picture starts at offset `y * stride`. A frame is rendered into the
+ * framebuffer when the beam leaves the visible area.
+ */
+use std::path::PathBuf;
+
+use super::Device;
+use crate::emulator::framebuffer::Framebuffer;
+
+// Beam timing, counted in pixel clocks (dots) and lines
+#[derive(Clone, Copy, Debug)]
+pub struct VgaTiming {
+    pub pixel_clock_hz: u64,
+    pub h_visible: u64,
+    pub h_total: u64,
+    pub v_visible: u64,
+    pub v_total: u64,
+}
+
+impl VgaTiming {
+    // 800x600 at 60 Hz with the 40 MHz pixel clock divided down to 10 MHz
+    pub fn svga_800x600() -> Self {
+        Self {
+            pixel_clock_hz: 10_000_000,
+            h_visible: 200,
+            h_total: 264,
+            v_visible: 600,
+            v_total: 628,
+        }
+    }
+
+    fn frame_dots(&self) -> u64 {
+        self.h_total * self.v_total
+    }
+
+    // Whether the beam is in the visible area at `dot` (counted from a frame start)
+    fn visible(&self, dot: u64) -> bool {
+        let dot = dot % self.frame_dots();
+        dot / self.h_total < self.v_visible && dot % self.h_total < self.h_visible
+    }
+
+    // The next dot after `dot` at which the beam enters or leaves the visible area
+    fn next_change(&self, dot: u64) -> u64 {
+        let frame_start = dot - dot % self.frame_dots();
+        let line = dot % self.frame_dots() / self.h_total;
+        let line_start = frame_start + line * self.h_total;
+
+        if self.visible(dot) {
+            line_start + self.h_visible
+        } else if line + 1 < self.v_visible {
+            line_start + self.h_total
+        } else {
+            frame_start + self.frame_dots()
+        }
+    }
+
+    // The dot at which the beam leaves the visible area of the first frame
+    fn end_of_video(&self) -> u64 {
+        (self.v_visible - 1) * self.h_total + self.h_visible
+    }
+}
+
+pub struct Vga {
+    ram: Vec<u8>,
+    width: usize,
+    height: usize,
+    stride: usize,
+    timing: VgaTiming,
+
+    frame: Framebuffer,
+    frame_count: u64,
+    png_output: Option<(PathBuf, u64)>,
+
+    // The beam is at dot 0 of a frame at `frame_base`
+    frame_base: u64,
+    frames_since_base: u64,
+    halting: bool,
+    stolen_cycles: u64,
+    last_cycle: u64,
+    clock_hz: u64,
+}
+
+impl Vga {
+    pub fn new(width: usize, height: usize, stride: usize, timing: VgaTiming) -> Self {
+        Self {
+            ram: vec![0; stride * height],
+            width,
+            height,
+            stride,
+            timing,
+            frame: Framebuffer::new(width, height),
+            frame_count: 0,
+            png_output: None,
+            frame_base: 0,
+            frames_since_base: 0,
+            halting: true,
+            stolen_cycles: 0,
+            last_cycle: 0,
+            clock_hz: 1_000_000,
+        }
+    }
+
+    // The original card: 100x64 pixels with 128 byte lines, for 8 KiB of RAM
+    pub fn ben_eater() -> Self {
+        Self::new(100, 64, 128, VgaTiming::svga_800x600())
+    }
+
+    // Bytes of video RAM, i.e. how much of the address space to map the card over
+    pub fn ram_size(&self) -> usize {
+        self.ram.len()
+    }
+
+    // The last frame rendered
+    pub fn frame(&self) -> &Framebuffer {
+        &self.frame
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    // Save every `interval`th frame to `path`, overwriting the previous one
+    pub fn set_png_output<P: Into<PathBuf>>(&mut self, path: P, interval: u64) {
+        self.png_output = Some((path.into(), interval.max(1)));
+    }
+
+    // Cycles the card has held the CPU for since reset
+    pub fn stolen_cycles(&self) -> u64 {
+        self.stolen_cycles
+    }
+
+    // The share of cycles since reset the CPU has had to itself
+    pub fn cpu_share(&self) -> f64 {
+        if self.last_cycle == 0 {
+            return 1.0;
+        }
+        1.0 - self.stolen_cycles as f64 / self.last_cycle as f64
+    }
+
+    // Dots since `frame_base` at CPU cycle `cycle`
+    fn dot_at(&self, cycle: u64) -> u64 {
+        let cycles = (cycle - self.frame_base) as u128;
+        (cycles * self.timing.pixel_clock_hz as u128 / self.clock_hz as u128) as u64
+    }
+
+    // The first CPU cycle at or after `dot`
+    fn cycle_at(&self, dot: u64) -> u64 {
+        let cycles = (dot as u128 * self.clock_hz as u128).div_ceil(self.timing.pixel_clock_hz as u128);
+        self.frame_base + cycles as u64
+    }
+
+    fn render(&mut self) {
+        for y in 0..self.height {
+            let line = &self.ram[y * self.stride..y * self.stride + self.width];
+            for (pixel, byte) in self.frame.row_mut(y).iter_mut().zip(line) {
+                // Two bits per channel
+                let red = (*byte >> 4 & 0x03) as u32 * 0x55;
+                let green = (*byte >> 2 & 0x03) as u32 * 0x55;
+                let blue = (*byte & 0x03) as u32 * 0x55;
+                *pixel = red << 16 | green << 8 | blue;
+            }
+        }
+        self.frame_count += 1;
+
+        if let Some((path, interval)) = &self.png_output {
+            if self.frame_count.is_multiple_of(*interval) {
+                if let Err(error) = self.frame.save_png(path) {
+                    eprintln!("vga: could not save {}: {}", path.display(), error);
+                }
+            }
+        }
+    }
+}
+
+impl Device for Vga {
+    fn name(&self) -> &str {
+        "vga"
+    }
+
+    fn read(&mut self, offset: u16) -> u8 {
+        self.peek(offset)
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        self.ram.get(offset as usize).copied().unwrap_or(0xFF)
+    }
+
+    fn write(&mut self, offset: u16, data: u8) {
+        if let Some(byte) = self.ram.get_mut(offset as usize) {
+            *byte = data;
+        }
+    }
+
+    fn tick(&mut self, now: u64) {
+        if now <= self.last_cycle {
+            return;
+        }
+        if self.halting {
+            self.stolen_cycles += now - self.last_cycle;
+        }
+        self.last_cycle = now;
+
+        let dot = self.dot_at(now);
+        self.halting = self.timing.visible(dot);
+
+        // Render once the beam has left the visible area
+        let end = self.timing.end_of_video();
+        let frames = if dot >= end {
+            (dot - end) / self.timing.frame_dots() + 1
+        } else {
+            0
+        };
+        if frames > self.frames_since_base {
+            self.frames_since_base = frames;
+            self.render();
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        let dot = self.dot_at(self.last_cycle);
+        Some(self.cycle_at(self.timing.next_change(dot)))
+    }
+
+    fn clock_rate_changed(&mut self, hz: f64) {
+        // The beam restarts at the top of a frame
+        self.clock_hz = (hz.round() as u64).max(1);
+        self.frame_base = self.last_cycle;
+        self.frames_since_base = 0;
+        self.halting = true;
+    }
+
+    fn reset(&mut self) {
+        self.frame_base = 0;
+        self.frames_since_base = 0;
+        self.halting = true;
+        self.stolen_cycles = 0;
+        self.last_cycle = 0;
+    }
+
+    fn halt(&self) -> bool {
+        self.halting
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::bus::Bus;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_halt_schedule() {
+        let vga = Arc::new(Mutex::new(Vga::ben_eater()));
+        let mut bus = Bus::new();
+        bus.add_device(0x2000, 0x3FFF, vga.clone());
+        bus.set_clock_rate(1_000_000.0);
+
+        // At 1 MHz a line is 26.4 cycles, of which 20 are visible
+        assert!(bus.halted());
+        assert_eq!(bus.next_deadline(), 20);
+        bus.sync(20);
+        assert!(!bus.halted());
+        assert_eq!(bus.next_deadline(), 27);
+        bus.sync(27);
+        assert!(bus.halted());
+
+        // Over a whole frame the CPU gets (1 - 200/264 * 600/628) of the time
+        let frame = 26_400 * 628 / 1000;
+        while bus.cycle() < frame {
+            let next = bus.next_deadline();
+            bus.sync(next);
+        }
+        let vga = vga.lock().unwrap();
+        assert_eq!(vga.frame_count(), 1);
+        assert!((vga.cpu_share() - (1.0 - 200.0 / 264.0 * 600.0 / 628.0)).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_render() {
+        let vga = Arc::new(Mutex::new(Vga::ben_eater()));
+        let mut bus = Bus::new();
+        bus.add_device(0x2000, 0x3FFF, vga.clone());
+        bus.set_clock_rate(1_000_000.0);
+
+        bus.write_byte(0x2000, 0x30);
+        bus.write_byte(0x2000 + 128 + 99, 0x0F);
+        while vga.lock().unwrap().frame_count() == 0 {
+            let next = bus.next_deadline();
+            bus.sync(next);
+        }
+
+        let vga = vga.lock().unwrap();
+        assert_eq!(vga.frame().pixel(0, 0), 0xFF0000);
+        assert_eq!(vga.frame().pixel(99, 1), 0x00FFFF);
+        assert_eq!(vga.frame().pixel(1, 0), 0x000000);
+    }
+}
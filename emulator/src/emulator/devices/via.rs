@@ -0,0 +1,631 @@
+use super::port::{Port, SharedPortDevice};
+use super::Device;
+
+// Register offsets
+const ORB: u16 = 0x0;
+const ORA: u16 = 0x1;
+const DDRB: u16 = 0x2;
+const DDRA: u16 = 0x3;
+const T1C_L: u16 = 0x4;
+const T1C_H: u16 = 0x5;
+const T1L_L: u16 = 0x6;
+const T1L_H: u16 = 0x7;
+const T2C_L: u16 = 0x8;
+const T2C_H: u16 = 0x9;
+const SR: u16 = 0xA;
+const ACR: u16 = 0xB;
+const PCR: u16 = 0xC;
+const IFR: u16 = 0xD;
+const IER: u16 = 0xE;
+const ORA_NO_HANDSHAKE: u16 = 0xF;
+
+// Interrupt flag bits
+pub const IRQ_CA2: u8 = 0x01;
+pub const IRQ_CA1: u8 = 0x02;
+pub const IRQ_SR: u8 = 0x04;
+pub const IRQ_CB2: u8 = 0x08;
+pub const IRQ_CB1: u8 = 0x10;
+pub const IRQ_T2: u8 = 0x20;
+pub const IRQ_T1: u8 = 0x40;
+
+/**
+ * MOS 6522 Versatile Interface Adapter.
+ *
+ * The timers are not decremented every cycle. Each timer remembers the
+ * cycle it was last brought up to date and is advanced in one go when the
+ * VIA is ticked, so the scheduler only has to wake the VIA when a timer is
+ * about to raise an interrupt.
+ *
+ * Peripherals attached to the ports are told about every change of the
+ * output pins, and are asked for the input levels when a port is read.
+ */
+pub struct Via {
+    orb: u8,
+    ora: u8,
+    ddrb: u8,
+    ddra: u8,
+
+    // Levels driven onto the port pins from outside (pulled high when floating)
+    input_a: u8,
+    input_b: u8,
+    peripheral_a: u8,
+    peripheral_b: u8,
+
+    // Control lines
+    ca1: bool,
+    cb1: bool,
+
+    t1_counter: u16,
+    t1_latch: u16,
+    t1_armed: bool,  // One-shot mode fires once per load of T1C-H
+    t1_reload: bool, // Free-running mode reloads from the latch after an underflow
+    pb7: bool,       // Timer 1 square wave output
+
+    t2_counter: u16,
+    t2_latch_lo: u8,
+    t2_armed: bool,
+
+    sr: u8,
+    acr: u8,
+    pcr: u8,
+    ifr: u8,
+    ier: u8,
+
+    // The cycle the timers were last advanced to
+    last_cycle: u64,
+
+    // Peripherals wired to the port pins and control lines
+    peripherals: Vec<SharedPortDevice>,
+}
+
+impl Default for Via {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Via {
+    pub fn new() -> Self {
+        Self {
+            orb: 0x00,
+            ora: 0x00,
+            ddrb: 0x00,
+            ddra: 0x00,
+            input_a: 0xFF,
+            input_b: 0xFF,
+            peripheral_a: 0xFF,
+            peripheral_b: 0xFF,
+            ca1: true,
+            cb1: true,
+            t1_counter: 0xFFFF,
+            t1_latch: 0xFFFF,
+            t1_armed: false,
+            t1_reload: false,
+            pb7: false,
+            t2_counter: 0xFFFF,
+            t2_latch_lo: 0xFF,
+            t2_armed: false,
+            sr: 0x00,
+            acr: 0x00,
+            pcr: 0x00,
+            ifr: 0x00,
+            ier: 0x00,
+            last_cycle: 0,
+            peripherals: Vec::new(),
+        }
+    }
+
+    // Wire a peripheral to the ports of this VIA
+    pub fn attach(&mut self, peripheral: SharedPortDevice) {
+        let mut device = peripheral.lock().unwrap();
+        device.pins_changed(Port::A, self.port_a(), self.ddra, self.last_cycle);
+        device.pins_changed(Port::B, self.port_b(), self.ddrb, self.last_cycle);
+        drop(device);
+
+        self.peripherals.push(peripheral);
+    }
+
+    // The levels currently on the port A / port B pins
+    pub fn port_a(&self) -> u8 {
+        let input = self.input_a & self.peripheral_a;
+        (self.ora & self.ddra) | (input & !self.ddra)
+    }
+
+    pub fn port_b(&self) -> u8 {
+        let input = self.input_b & self.peripheral_b;
+        let mut value = (self.orb & self.ddrb) | (input & !self.ddrb);
+
+        // With PB7 output enabled, timer 1 drives PB7
+        if self.acr & 0x80 != 0 {
+            value = (value & 0x7F) | if self.pb7 { 0x80 } else { 0x00 };
+        }
+        value
+    }
+
+    pub fn ddr_a(&self) -> u8 {
+        self.ddra
+    }
+
+    pub fn ddr_b(&self) -> u8 {
+        self.ddrb
+    }
+
+    // Tell the peripherals about the current levels of a port
+    fn notify(&mut self, port: Port) {
+        let (pins, ddr) = match port {
+            Port::A => (self.port_a(), self.ddra),
+            Port::B => (self.port_b(), self.ddrb),
+        };
+
+        for peripheral in &self.peripherals {
+            peripheral
+                .lock()
+                .unwrap()
+                .pins_changed(port, pins, ddr, self.last_cycle);
+        }
+    }
+
+    // Tell the peripherals that new data was written to a port
+    fn strobe(&mut self, port: Port) {
+        for peripheral in &self.peripherals {
+            peripheral.lock().unwrap().strobe(port, self.last_cycle);
+        }
+    }
+
+    // Sample what the peripherals drive onto a port (wired-AND)
+    fn sample(&mut self, port: Port) {
+        let mut level = 0xFF;
+        for peripheral in &self.peripherals {
+            level &= peripheral.lock().unwrap().drive(port, self.last_cycle);
+        }
+
+        match port {
+            Port::A => self.peripheral_a = level,
+            Port::B => self.peripheral_b = level,
+        }
+    }
+
+    // Update CA1/CB1 from the peripherals driving them
+    fn sample_control_lines(&mut self) {
+        for port in [Port::A, Port::B] {
+            let mut level = None;
+            for peripheral in &self.peripherals {
+                if let Some(line) = peripheral.lock().unwrap().control(port, self.last_cycle) {
+                    level = Some(level.unwrap_or(true) && line);
+                }
+            }
+
+            match (port, level) {
+                (Port::A, Some(level)) => self.set_ca1(level),
+                (Port::B, Some(level)) => self.set_cb1(level),
+                _ => {}
+            }
+        }
+    }
+
+    // Set the levels external hardware drives onto the port pins
+    pub fn set_input_a(&mut self, value: u8) {
+        self.input_a = value;
+    }
+
+    pub fn set_input_b(&mut self, value: u8) {
+        self.input_b = value;
+    }
+
+    // Drive the CA1 input; an interrupt is flagged on the edge selected in the PCR
+    pub fn set_ca1(&mut self, level: bool) {
+        let rising = self.pcr & 0x01 != 0;
+        if level != self.ca1 && level == rising {
+            self.ifr |= IRQ_CA1;
+        }
+        self.ca1 = level;
+    }
+
+    pub fn set_cb1(&mut self, level: bool) {
+        let rising = self.pcr & 0x10 != 0;
+        if level != self.cb1 && level == rising {
+            self.ifr |= IRQ_CB1;
+        }
+        self.cb1 = level;
+    }
+
+    fn t1_free_running(&self) -> bool {
+        self.acr & 0x40 != 0
+    }
+
+    fn t2_counting_pulses(&self) -> bool {
+        self.acr & 0x20 != 0
+    }
+
+    // Advance timer 1 by a number of cycles, handling any underflows on the way
+    fn advance_t1(&mut self, mut cycles: u64) {
+        while cycles > 0 {
+            if self.t1_reload {
+                // Reloading from the latch takes one cycle
+                cycles -= 1;
+                self.t1_counter = self.t1_latch;
+                self.t1_reload = false;
+
+                // Skip whole periods in one go; only the parity matters for PB7
+                let period = self.t1_latch as u64 + 2;
+                let periods = cycles / period;
+                if periods > 0 && self.t1_armed {
+                    self.ifr |= IRQ_T1;
+                    if periods % 2 == 1 {
+                        self.pb7 = !self.pb7;
+                    }
+                }
+                cycles -= periods * period;
+                continue;
+            }
+
+            // The counter underflows (0000 -> FFFF) counter + 1 cycles from now
+            let to_underflow = self.t1_counter as u64 + 1;
+            if cycles < to_underflow {
+                self.t1_counter -= cycles as u16;
+                return;
+            }
+            cycles -= to_underflow;
+
+            if self.t1_armed {
+                self.ifr |= IRQ_T1;
+                self.pb7 = !self.pb7;
+            }
+
+            self.t1_counter = 0xFFFF;
+            if self.t1_free_running() {
+                self.t1_reload = true;
+            } else {
+                // In one-shot mode the counter keeps rolling without interrupting
+                self.t1_armed = false;
+            }
+        }
+    }
+
+    // Cycle at which timer 1 next underflows
+    fn t1_underflow_cycle(&self) -> u64 {
+        if self.t1_reload {
+            self.last_cycle + self.t1_latch as u64 + 2
+        } else {
+            self.last_cycle + self.t1_counter as u64 + 1
+        }
+    }
+
+    fn advance_t2(&mut self, cycles: u64) {
+        if self.t2_counting_pulses() {
+            return;
+        }
+
+        let to_underflow = self.t2_counter as u64 + 1;
+        if cycles >= to_underflow && self.t2_armed {
+            self.ifr |= IRQ_T2;
+            self.t2_armed = false;
+        }
+        self.t2_counter = self.t2_counter.wrapping_sub(cycles as u16);
+    }
+
+    fn irq_active(&self) -> bool {
+        self.ifr & self.ier & 0x7F != 0
+    }
+
+    fn read_ifr(&self) -> u8 {
+        if self.irq_active() {
+            self.ifr | 0x80
+        } else {
+            self.ifr
+        }
+    }
+
+    // Reading or writing port A clears the CA flags (CA2 unless it is independent)
+    fn clear_port_a_flags(&mut self) {
+        self.ifr &= !IRQ_CA1;
+        if self.pcr & 0x0A != 0x02 {
+            self.ifr &= !IRQ_CA2;
+        }
+    }
+
+    fn clear_port_b_flags(&mut self) {
+        self.ifr &= !IRQ_CB1;
+        if self.pcr & 0xA0 != 0x20 {
+            self.ifr &= !IRQ_CB2;
+        }
+    }
+}
+
+impl Device for Via {
+    fn name(&self) -> &str {
+        "6522 VIA"
+    }
+
+    fn read(&mut self, offset: u16) -> u8 {
+        match offset & 0x0F {
+            ORB => self.sample(Port::B),
+            ORA | ORA_NO_HANDSHAKE => self.sample(Port::A),
+            _ => {}
+        }
+
+        let data = self.peek(offset);
+
+        match offset & 0x0F {
+            ORB => self.clear_port_b_flags(),
+            ORA => self.clear_port_a_flags(),
+            T1C_L => self.ifr &= !IRQ_T1,
+            T2C_L => self.ifr &= !IRQ_T2,
+            SR => self.ifr &= !IRQ_SR,
+            _ => {}
+        }
+
+        data
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        match offset & 0x0F {
+            ORB => self.port_b(),
+            ORA | ORA_NO_HANDSHAKE => self.port_a(),
+            DDRB => self.ddrb,
+            DDRA => self.ddra,
+            T1C_L => self.t1_counter as u8,
+            T1C_H => (self.t1_counter >> 8) as u8,
+            T1L_L => self.t1_latch as u8,
+            T1L_H => (self.t1_latch >> 8) as u8,
+            T2C_L => self.t2_counter as u8,
+            T2C_H => (self.t2_counter >> 8) as u8,
+            SR => self.sr,
+            ACR => self.acr,
+            PCR => self.pcr,
+            IFR => self.read_ifr(),
+            IER => self.ier | 0x80,
+            _ => unreachable!(),
+        }
+    }
+
+    fn write(&mut self, offset: u16, data: u8) {
+        let pb7 = self.port_b() & 0x80;
+
+        match offset & 0x0F {
+            ORB => {
+                self.orb = data;
+                self.clear_port_b_flags();
+            }
+            ORA => {
+                self.ora = data;
+                self.clear_port_a_flags();
+            }
+            ORA_NO_HANDSHAKE => self.ora = data,
+            DDRB => self.ddrb = data,
+            DDRA => self.ddra = data,
+            T1C_L | T1L_L => self.t1_latch = (self.t1_latch & 0xFF00) | data as u16,
+            T1C_H => {
+                // Load the counter from the latch and start the timer
+                self.t1_latch = (self.t1_latch & 0x00FF) | (data as u16) << 8;
+                self.t1_counter = self.t1_latch;
+                self.t1_armed = true;
+                self.t1_reload = false;
+                self.pb7 = false;
+                self.ifr &= !IRQ_T1;
+            }
+            T1L_H => {
+                self.t1_latch = (self.t1_latch & 0x00FF) | (data as u16) << 8;
+                self.ifr &= !IRQ_T1;
+            }
+            T2C_L => self.t2_latch_lo = data,
+            T2C_H => {
+                self.t2_counter = (data as u16) << 8 | self.t2_latch_lo as u16;
+                self.t2_armed = true;
+                self.ifr &= !IRQ_T2;
+            }
+            SR => {
+                self.sr = data;
+                self.ifr &= !IRQ_SR;
+            }
+            ACR => self.acr = data,
+            PCR => self.pcr = data,
+            IFR => self.ifr &= !(data & 0x7F),
+            IER => {
+                if data & 0x80 != 0 {
+                    self.ier |= data & 0x7F;
+                } else {
+                    self.ier &= !(data & 0x7F);
+                }
+            }
+            _ => unreachable!(),
+        }
+
+        match offset & 0x0F {
+            ORA | ORA_NO_HANDSHAKE | DDRA => self.notify(Port::A),
+            ORB | DDRB => self.notify(Port::B),
+            _ if self.port_b() & 0x80 != pb7 => self.notify(Port::B),
+            _ => {}
+        }
+
+        // CA2/CB2 in handshake or pulse output mode signal the write
+        match offset & 0x0F {
+            ORA if self.pcr & 0x0C == 0x08 => self.strobe(Port::A),
+            ORB if self.pcr & 0xC0 == 0x80 => self.strobe(Port::B),
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, now: u64) {
+        if now > self.last_cycle {
+            let pb7 = self.port_b() & 0x80;
+
+            let elapsed = now - self.last_cycle;
+            self.last_cycle = now;
+            self.advance_t1(elapsed);
+            self.advance_t2(elapsed);
+
+            // Timer 1 toggling PB7
+            if self.port_b() & 0x80 != pb7 {
+                self.notify(Port::B);
+            }
+        }
+
+        for peripheral in &self.peripherals {
+            peripheral.lock().unwrap().tick(now);
+        }
+        self.sample_control_lines();
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        let mut next: Option<u64> = None;
+
+        // Only wake up for underflows that will raise an interrupt or toggle PB7
+        let t1_irq = self.ier & IRQ_T1 != 0 && self.ifr & IRQ_T1 == 0;
+        let t1_pb7 = self.acr & 0x80 != 0;
+        if self.t1_armed && (t1_irq || t1_pb7) {
+            next = Some(self.t1_underflow_cycle());
+        }
+
+        if self.t2_armed && !self.t2_counting_pulses() && self.ier & IRQ_T2 != 0 {
+            let t2 = self.last_cycle + self.t2_counter as u64 + 1;
+            next = Some(next.map_or(t2, |t1| t1.min(t2)));
+        }
+
+        // And whenever a peripheral needs to run
+        for peripheral in &self.peripherals {
+            if let Some(event) = peripheral.lock().unwrap().next_event() {
+                next = Some(next.map_or(event, |current| current.min(event)));
+            }
+        }
+
+        next
+    }
+
+    fn clock_rate_changed(&mut self, hz: f64) {
+        for peripheral in &self.peripherals {
+            peripheral.lock().unwrap().clock_rate_changed(hz);
+        }
+    }
+
+    fn reset(&mut self) {
+        // The 6522 reset clears every register except the timers, latches and SR
+        self.orb = 0x00;
+        self.ora = 0x00;
+        self.ddrb = 0x00;
+        self.ddra = 0x00;
+        self.acr = 0x00;
+        self.pcr = 0x00;
+        self.ifr = 0x00;
+        self.ier = 0x00;
+        self.t1_armed = false;
+        self.t1_reload = false;
+        self.t2_armed = false;
+        self.pb7 = false;
+        self.last_cycle = 0;
+
+        for peripheral in &self.peripherals {
+            peripheral.lock().unwrap().reset();
+        }
+        self.notify(Port::A);
+        self.notify(Port::B);
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_active()
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        let mut state = vec![
+            self.orb,
+            self.ora,
+            self.ddrb,
+            self.ddra,
+            self.sr,
+            self.acr,
+            self.pcr,
+            self.ifr,
+            self.ier,
+            self.t1_armed as u8,
+            self.t1_reload as u8,
+            self.pb7 as u8,
+            self.t2_armed as u8,
+            self.t2_latch_lo,
+        ];
+        state.extend_from_slice(&self.t1_counter.to_le_bytes());
+        state.extend_from_slice(&self.t1_latch.to_le_bytes());
+        state.extend_from_slice(&self.t2_counter.to_le_bytes());
+        state.extend_from_slice(&self.last_cycle.to_le_bytes());
+        state
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        if state.len() != 28 {
+            return;
+        }
+
+        self.orb = state[0];
+        self.ora = state[1];
+        self.ddrb = state[2];
+        self.ddra = state[3];
+        self.sr = state[4];
+        self.acr = state[5];
+        self.pcr = state[6];
+        self.ifr = state[7];
+        self.ier = state[8];
+        self.t1_armed = state[9] != 0;
+        self.t1_reload = state[10] != 0;
+        self.pb7 = state[11] != 0;
+        self.t2_armed = state[12] != 0;
+        self.t2_latch_lo = state[13];
+        self.t1_counter = u16::from_le_bytes([state[14], state[15]]);
+        self.t1_latch = u16::from_le_bytes([state[16], state[17]]);
+        self.t2_counter = u16::from_le_bytes([state[18], state[19]]);
+        self.last_cycle = u64::from_le_bytes(state[20..28].try_into().unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_t1_one_shot() {
+        let mut via = Via::new();
+        via.write(IER, 0x80 | IRQ_T1);
+        via.write(T1C_L, 0x10);
+        via.write(T1C_H, 0x00);
+
+        // Underflow happens 0x10 + 1 cycles after the load
+        assert_eq!(via.next_event(), Some(0x11));
+        via.tick(0x10);
+        assert!(!via.irq());
+        via.tick(0x11);
+        assert!(via.irq());
+        assert_eq!(via.next_event(), None);
+
+        // Reading T1C-L acknowledges the interrupt, and a one-shot doesn't fire again
+        via.read(T1C_L);
+        via.tick(0x20000);
+        assert!(!via.irq());
+    }
+
+    #[test]
+    fn test_t1_free_running() {
+        let mut via = Via::new();
+        via.write(ACR, 0x40);
+        via.write(IER, 0x80 | IRQ_T1);
+        via.write(T1C_L, 0x08);
+        via.write(T1C_H, 0x00);
+
+        via.tick(9);
+        assert!(via.irq());
+        via.write(IFR, IRQ_T1);
+
+        // Subsequent periods are latch + 2 cycles long
+        assert_eq!(via.next_event(), Some(9 + 10));
+        via.tick(18);
+        assert!(!via.irq());
+        via.tick(19);
+        assert!(via.irq());
+    }
+
+    #[test]
+    fn test_ports() {
+        let mut via = Via::new();
+        via.write(DDRB, 0x0F);
+        via.write(ORB, 0xAA);
+        via.set_input_b(0x50);
+        assert_eq!(via.read(ORB), 0x5A);
+    }
+}
@@ -0,0 +1,155 @@
+/**
+ * Errors from setting up a machine: reading the command line, its
+ * description and program images, placing them in memory and picking the
+ * CPU.
+ *
+ * Each kind has its own process exit status (see `exit_code`) so scripts
+ * driving the CLI can tell them apart. Errors a device runs into while the
+ * machine is running are reported by the device itself.
+ */
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum Error {
+    // Bad command line arguments
+    Usage(String),
+    // The file doesn't exist
+    MissingFile(PathBuf),
+    // Any other failure to read a file
+    Io { path: PathBuf, source: io::Error },
+    // `size` bytes placed at `address` run past `end`
+    ImageTooLarge { address: u32, size: usize, end: u32 },
+    // Two segments of an image both fill `address`
+    OverlappingSegments { address: u16 },
+    UnknownVariant(String),
+    // A file header (iNES, o65) that is malformed or asks for something unsupported
+    BadHeader(String),
+    // A malformed line of a text format (Intel HEX, S-records, ld65 debug info)
+    BadRecord { line: usize, message: String },
+    // A malformed or truncated binary image
+    BadImage(String),
+    // A machine description that is malformed or asks for something unsupported
+    BadMachine(String),
+    // Two parts of a machine description are both mapped at `address`
+    OverlappingRegions {
+        first: String,
+        second: String,
+        address: u16,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    // A failure reading `path`
+    pub fn io<P: Into<PathBuf>>(path: P, source: io::Error) -> Self {
+        let path = path.into();
+        if source.kind() == io::ErrorKind::NotFound {
+            Error::MissingFile(path)
+        } else {
+            Error::Io { path, source }
+        }
+    }
+
+    // The CLI's exit status for this kind of error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Usage(_) => 1,
+            Error::MissingFile(_) => 2,
+            Error::Io { .. } => 3,
+            Error::ImageTooLarge { .. } => 4,
+            Error::OverlappingSegments { .. } => 5,
+            Error::UnknownVariant(_) => 6,
+            Error::BadHeader(_) => 7,
+            Error::BadRecord { .. } => 8,
+            Error::BadImage(_) => 9,
+            Error::BadMachine(_) => 10,
+            Error::OverlappingRegions { .. } => 11,
+        }
+    }
+
+    // The file that couldn't be read, if that's what went wrong
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Error::MissingFile(path) | Error::Io { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Usage(message) => write!(f, "{}", message),
+            // Whoever reports these says which file they were loading
+            Error::MissingFile(_) => write!(f, "no such file"),
+            Error::Io { source, .. } => write!(f, "{}", source),
+            Error::ImageTooLarge { address, size, end } => write!(
+                f,
+                "{} bytes at ${:04X} run past ${:04X}",
+                size, address, end
+            ),
+            Error::OverlappingSegments { address } => {
+                write!(f, "segments overlap at ${:04X}", address)
+            }
+            // The CPU crate knows which variants there are
+            Error::UnknownVariant(name) => {
+                write!(f, "{}", cpu::cpu::Error::UnknownVariant(name.clone()))
+            }
+            Error::BadHeader(message) => write!(f, "bad header: {}", message),
+            Error::BadRecord { line, message } => write!(f, "line {}: {}", line, message),
+            Error::BadImage(message) => write!(f, "{}", message),
+            Error::BadMachine(message) => write!(f, "{}", message),
+            Error::OverlappingRegions {
+                first,
+                second,
+                address,
+            } => write!(f, "{} and {} overlap at ${:04X}", first, second, address),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<cpu::cpu::Error> for Error {
+    fn from(error: cpu::cpu::Error) -> Self {
+        match error {
+            cpu::cpu::Error::UnknownVariant(name) => Error::UnknownVariant(name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Emulator;
+
+    #[test]
+    fn test_load_errors() {
+        let mut emulator = Emulator::new();
+        let error = emulator
+            .load_rom_from_path("does/not/exist.bin", 0xC000)
+            .unwrap_err();
+        assert!(matches!(error, Error::MissingFile(_)));
+        assert_eq!(error.exit_code(), 2);
+
+        let error = emulator.bus.lock().unwrap().load_rom_at(&[0; 3], 0xFFFE);
+        let error = error.unwrap_err();
+        assert_eq!(error.to_string(), "3 bytes at $FFFE run past $10000");
+        assert_eq!(error.exit_code(), 4);
+
+        let error = emulator.change_variant(String::from("Z80")).unwrap_err();
+        assert!(matches!(error, Error::UnknownVariant(ref name) if name == "Z80"));
+        assert!(error.to_string().starts_with("unknown CPU variant \"Z80\""));
+        assert_eq!(error.exit_code(), 6);
+    }
+}
@@ -0,0 +1,180 @@
+/**
+ * An RGB framebuffer that video devices render into.
+ *
+ * The emulator runs headless, so frames are looked at by saving them as PNG
+ * files or by comparing them with known good frames in tests. The PNG
+ * encoder is deliberately simple: the image data is stored uncompressed
+ * inside the zlib stream, which every PNG reader accepts.
+ */
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    pixels: Vec<u32>, // 0xRRGGBB
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; width * height],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u32 {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, rgb: u32) {
+        self.pixels[y * self.width + x] = rgb;
+    }
+
+    pub fn row_mut(&mut self, y: usize) -> &mut [u32] {
+        &mut self.pixels[y * self.width..(y + 1) * self.width]
+    }
+
+    pub fn fill(&mut self, rgb: u32) {
+        self.pixels.fill(rgb);
+    }
+
+    // Number of pixels that differ from `other` (which must be the same size)
+    pub fn difference(&self, other: &Framebuffer) -> usize {
+        self.pixels.iter().zip(&other.pixels).filter(|(a, b)| a != b).count()
+    }
+
+    // CRC-32 of the pixels, a compact stand-in for a golden image in tests
+    pub fn checksum(&self) -> u32 {
+        let mut crc = Crc32::new();
+        for pixel in &self.pixels {
+            crc.update(&pixel.to_be_bytes()[1..]);
+        }
+        crc.finish()
+    }
+
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut png = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+        // 8 bit RGB, no interlacing
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&(self.width as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height as u32).to_be_bytes());
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+        write_chunk(&mut png, b"IHDR", &header);
+
+        // Each row starts with its filter type (none)
+        let mut raw = Vec::with_capacity(self.height * (self.width * 3 + 1));
+        for row in self.pixels.chunks(self.width) {
+            raw.push(0);
+            for pixel in row {
+                raw.extend_from_slice(&pixel.to_be_bytes()[1..]);
+            }
+        }
+        write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+        write_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_png())
+    }
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+
+    let mut crc = Crc32::new();
+    crc.update(kind);
+    crc.update(data);
+    png.extend_from_slice(&crc.finish().to_be_bytes());
+}
+
+// A zlib stream made of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        stream.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let length = block.len() as u16;
+        stream.push(last as u8);
+        stream.extend_from_slice(&length.to_le_bytes());
+        stream.extend_from_slice(&(!length).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+
+    // Adler-32 of the uncompressed data
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65_521;
+        b = (b + a) % 65_521;
+    }
+    stream.extend_from_slice(&(b << 16 | a).to_be_bytes());
+    stream
+}
+
+struct Crc32 {
+    crc: u32,
+}
+
+impl Crc32 {
+    fn new() -> Self {
+        Self { crc: 0xFFFF_FFFF }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.crc ^= *byte as u32;
+            for _ in 0..8 {
+                self.crc = if self.crc & 1 != 0 {
+                    self.crc >> 1 ^ 0xEDB8_8320
+                } else {
+                    self.crc >> 1
+                };
+            }
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.crc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_png_encoding() {
+        let mut frame = Framebuffer::new(2, 1);
+        frame.set_pixel(0, 0, 0xFF0000);
+        frame.set_pixel(1, 0, 0x00FF00);
+        let png = frame.to_png();
+
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        // IHDR is always the same for a 2x1 RGB image
+        assert_eq!(&png[8..33], b"\0\0\0\rIHDR\0\0\0\x02\0\0\0\x01\x08\x02\0\0\0\x7b\x40\xe8\xdd");
+        // Followed by the stored pixel data and an empty IEND
+        let idat = &png[33..png.len() - 12];
+        assert_eq!(&idat[4..8], b"IDAT");
+        assert_eq!(&idat[15..22], &[0, 0xFF, 0, 0, 0, 0xFF, 0]);
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xae\x42\x60\x82");
+    }
+}
@@ -0,0 +1,329 @@
+/**
+ * Debug information written by ld65's `--dbgfile` option.
+ *
+ * The file is text, one record per line: a record type, a tab, then
+ * comma separated `key=value` pairs. Values are decimal or `0x` hex
+ * numbers, quoted strings, or lists of ids joined with `+`. Records refer
+ * to each other by id:
+ *
+ *   file  id, name, size             a source file
+ *   seg   id, name, start, size      a segment, as placed by the linker
+ *   span  id, seg, start, size       bytes of a segment (start is relative)
+ *   line  id, file, line, span, type a source line and the spans it made
+ *   sym   id, name, val, seg, type   a symbol; type "lab" for labels
+ *
+ * Everything else (version, info, lib, mod, scope, csym, type) is skipped.
+ * The result answers the questions a trace or debugger asks: what symbol
+ * is at or before an address, and what source line produced it.
+ */
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::invalid;
+use crate::emulator::error::{Error, Result};
+
+// Line types: assembler source, C source and macro expansion
+const LINE_TYPE_MACRO: u32 = 2;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceFile {
+    pub name: String,
+    pub size: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DebugSegment {
+    pub name: String,
+    pub start: u32,
+    pub size: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u32,
+    pub segment: Option<usize>,
+    pub label: bool,
+}
+
+// A source line and the address ranges (start..end) it assembled to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLine {
+    pub file: usize,
+    pub line: u32,
+    pub macro_expansion: bool,
+    pub ranges: Vec<(u32, u32)>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    pub files: Vec<SourceFile>,
+    pub segments: Vec<DebugSegment>,
+    pub symbols: Vec<Symbol>,
+    pub lines: Vec<SourceLine>,
+}
+
+// The key=value pairs of one record
+struct Record<'a> {
+    line: usize,
+    fields: HashMap<&'a str, &'a str>,
+}
+
+impl Record<'_> {
+    fn raw(&self, key: &str) -> Result<&str> {
+        self.fields
+            .get(key)
+            .copied()
+            .ok_or_else(|| invalid(self.line, format!("record has no \"{}\"", key)))
+    }
+
+    fn number(&self, key: &str) -> Result<u32> {
+        parse_number(self.line, self.raw(key)?)
+    }
+
+    fn optional_number(&self, key: &str) -> Result<Option<u32>> {
+        match self.fields.get(key) {
+            Some(value) => parse_number(self.line, value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn string(&self, key: &str) -> Result<String> {
+        let value = self.raw(key)?;
+        Ok(value.trim_matches('"').to_string())
+    }
+
+    fn ids(&self, key: &str) -> Result<Vec<u32>> {
+        match self.fields.get(key) {
+            Some(value) => value
+                .split('+')
+                .map(|id| parse_number(self.line, id))
+                .collect(),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+fn parse_number(line: usize, value: &str) -> Result<u32> {
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|_| invalid(line, format!("bad number \"{}\"", value)))
+}
+
+// Split `key=value,key="a,b"` on the commas outside quotes
+fn split_fields(text: &str) -> Vec<&str> {
+    let mut fields = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (index, character) in text.char_indices() {
+        match character {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                fields.push(&text[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    fields.push(&text[start..]);
+    fields
+}
+
+// Ids are dense from 0 in ld65's output, but don't count on it
+fn index_of(ids: &HashMap<u32, usize>, line: usize, kind: &str, id: u32) -> Result<usize> {
+    ids.get(&id)
+        .copied()
+        .ok_or_else(|| invalid(line, format!("no {} with id {}", kind, id)))
+}
+
+impl DebugInfo {
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|error| Error::io(path, error))?;
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut records = Vec::new();
+        for (index, text) in text.lines().enumerate() {
+            let Some((kind, rest)) = text.split_once('\t') else {
+                continue;
+            };
+            let fields = split_fields(rest)
+                .into_iter()
+                .filter_map(|field| field.split_once('='))
+                .collect();
+            records.push((
+                kind,
+                Record {
+                    line: index + 1,
+                    fields,
+                },
+            ));
+        }
+
+        // Files, segments and spans first, as lines and symbols refer to them
+        let mut info = Self::default();
+        let mut file_ids = HashMap::new();
+        let mut segment_ids = HashMap::new();
+        let mut spans = HashMap::new();
+        for (kind, record) in &records {
+            match *kind {
+                "version" => {
+                    let major = record.number("major")?;
+                    if major != 2 {
+                        let message = format!("version {} is not supported", major);
+                        return Err(invalid(record.line, message));
+                    }
+                }
+                "file" => {
+                    file_ids.insert(record.number("id")?, info.files.len());
+                    info.files.push(SourceFile {
+                        name: record.string("name")?,
+                        size: record.optional_number("size")?.unwrap_or(0),
+                    });
+                }
+                "seg" => {
+                    segment_ids.insert(record.number("id")?, info.segments.len());
+                    info.segments.push(DebugSegment {
+                        name: record.string("name")?,
+                        start: record.number("start")?,
+                        size: record.number("size")?,
+                    });
+                }
+                "span" => {
+                    let segment = record.number("seg")?;
+                    let span = (segment, record.number("start")?, record.number("size")?);
+                    spans.insert(record.number("id")?, span);
+                }
+                _ => {}
+            }
+        }
+
+        for (kind, record) in &records {
+            let line = record.line;
+            match *kind {
+                "line" => {
+                    let mut ranges = Vec::new();
+                    for id in record.ids("span")? {
+                        let (segment, start, size) = *spans
+                            .get(&id)
+                            .ok_or_else(|| invalid(line, format!("no span with id {}", id)))?;
+                        let segment = index_of(&segment_ids, line, "segment", segment)?;
+                        let start = info.segments[segment].start + start;
+                        ranges.push((start, start + size));
+                    }
+                    info.lines.push(SourceLine {
+                        file: index_of(&file_ids, line, "file", record.number("file")?)?,
+                        line: record.number("line")?,
+                        macro_expansion: record.optional_number("type")? == Some(LINE_TYPE_MACRO),
+                        ranges,
+                    });
+                }
+                "sym" => {
+                    // Imports have no value of their own
+                    let Some(value) = record.optional_number("val")? else {
+                        continue;
+                    };
+                    let segment = match record.optional_number("seg")? {
+                        Some(id) => Some(index_of(&segment_ids, line, "segment", id)?),
+                        None => None,
+                    };
+                    info.symbols.push(Symbol {
+                        name: record.string("name")?,
+                        value,
+                        segment,
+                        label: record.raw("type").is_ok_and(|kind| kind == "lab"),
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        Ok(info)
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    // The label at `address`, or the closest one before it and how far past it `address` is
+    pub fn label_for(&self, address: u16) -> Option<(&Symbol, u16)> {
+        self.symbols
+            .iter()
+            .filter(|symbol| symbol.label && symbol.value <= address as u32)
+            .max_by_key(|symbol| symbol.value)
+            .map(|symbol| (symbol, (address as u32 - symbol.value) as u16))
+    }
+
+    // The source file and line that produced the byte at `address`.
+    // Where a macro was expanded, the line that invoked it is preferred.
+    pub fn line_for(&self, address: u16) -> Option<(&SourceFile, u32)> {
+        let address = address as u32;
+        let covers = |line: &&SourceLine| {
+            line.ranges
+                .iter()
+                .any(|(start, end)| (*start..*end).contains(&address))
+        };
+        self.lines
+            .iter()
+            .filter(covers)
+            .min_by_key(|line| line.macro_expansion)
+            .map(|line| (&self.files[line.file], line.line))
+    }
+
+    pub fn segment_for(&self, address: u16) -> Option<&DebugSegment> {
+        let address = address as u32;
+        self.segments
+            .iter()
+            .find(|segment| (segment.start..segment.start + segment.size).contains(&address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = "\
+version\tmajor=2,minor=0
+info\tcsym=0,file=2,lib=0,line=4,mod=1,scope=1,seg=2,span=4,sym=3,type=1
+file\tid=0,name=\"blink.s\",size=400,mtime=0x5F000000,mod=0
+file\tid=1,name=\"macros, etc.inc\",size=90,mtime=0x5F000000,mod=0
+seg\tid=0,name=\"CODE\",start=0x00C000,size=0x0010,addrsize=absolute,type=ro
+seg\tid=1,name=\"VECTORS\",start=0x00FFFA,size=0x0006,addrsize=absolute,type=ro
+span\tid=0,seg=0,start=0,size=2
+span\tid=1,seg=0,start=2,size=3
+span\tid=2,seg=1,start=0,size=6
+span\tid=3,seg=0,start=2,size=3
+line\tid=0,file=0,line=5,span=0
+line\tid=1,file=1,line=3,span=1,type=2,count=1
+line\tid=2,file=0,line=6,span=3
+line\tid=3,file=0,line=30,span=2
+sym\tid=0,name=\"reset\",addrsize=absolute,scope=0,def=0,ref=3,val=0xC000,seg=0,type=lab
+sym\tid=1,name=\"loop\",addrsize=absolute,scope=0,def=1,val=0xC002,seg=0,type=lab
+sym\tid=2,name=\"PORTB\",addrsize=absolute,scope=0,def=2,val=0x6000,type=equ
+";
+        let info = DebugInfo::parse(text).unwrap();
+        assert_eq!(info.files[1].name, "macros, etc.inc");
+        assert_eq!(info.symbol("PORTB").unwrap().value, 0x6000);
+        assert!(!info.symbol("PORTB").unwrap().label);
+
+        let (symbol, offset) = info.label_for(0xC004).unwrap();
+        assert_eq!((symbol.name.as_str(), offset), ("loop", 2));
+        assert!(info.label_for(0xBFFF).is_none());
+
+        // The macro's bytes belong to the line that invoked it
+        let (file, line) = info.line_for(0xC003).unwrap();
+        assert_eq!((file.name.as_str(), line), ("blink.s", 6));
+        assert_eq!(info.line_for(0xFFFC).unwrap().1, 30);
+        assert!(info.line_for(0xC005).is_none());
+        assert_eq!(info.segment_for(0xFFFF).unwrap().name, "VECTORS");
+
+        assert!(DebugInfo::parse("version\tmajor=3,minor=0\n").is_err());
+        assert!(DebugInfo::parse("line\tid=0,file=9,line=1\n").is_err());
+    }
+}
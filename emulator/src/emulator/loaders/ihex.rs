@@ -0,0 +1,156 @@
+/**
+ * Intel HEX images.
+ *
+ * Every line is a record: a colon, then hex digit pairs giving the byte
+ * count, a 16-bit address, the record type, the data and a checksum that
+ * makes all the bytes of the record add up to zero.
+ *
+ * Record types:
+ *
+ *   00  data
+ *   01  end of file
+ *   02  extended segment address (base = value * 16)
+ *   03  start segment address (CS:IP)
+ *   04  extended linear address (upper 16 bits of the address)
+ *   05  start linear address
+ *
+ * The 6502 only sees 64 KiB, so data placed above $FFFF is an error.
+ */
+use super::{decode_hex, invalid, Image};
+use crate::emulator::error::{Error, Result};
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+pub fn parse(text: &str) -> Result<Image> {
+    let mut image = Image::new();
+    let mut base = 0u32;
+
+    for (index, record) in text.lines().enumerate() {
+        let line = index + 1;
+        let record = record.trim();
+        if record.is_empty() {
+            continue;
+        }
+        let Some(digits) = record.strip_prefix(':') else {
+            return Err(invalid(line, String::from("record doesn't start with ':'")));
+        };
+
+        let bytes = decode_hex(line, digits)?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(invalid(
+                line,
+                String::from("record length doesn't match its byte count"),
+            ));
+        }
+        let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        if sum != 0 {
+            let checksum = bytes[bytes.len() - 1];
+            let expected = checksum.wrapping_sub(sum);
+            return Err(invalid(
+                line,
+                format!("checksum is ${:02X}, expected ${:02X}", checksum, expected),
+            ));
+        }
+
+        let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+        let record_type = bytes[3];
+        let data = &bytes[4..bytes.len() - 1];
+
+        // The value of an address record, which has exactly `length` bytes
+        let value = |length: usize| -> Result<u32> {
+            if data.len() != length {
+                return Err(invalid(
+                    line,
+                    format!("record type {:02X} has a bad length", record_type),
+                ));
+            }
+            Ok(data.iter().fold(0, |value, byte| value << 8 | *byte as u32))
+        };
+
+        match record_type {
+            DATA => {
+                let address = base
+                    .checked_add(offset)
+                    .filter(|address| *address <= 0xFFFF);
+                let Some(address) = address else {
+                    return Err(Error::ImageTooLarge {
+                        address: base.saturating_add(offset),
+                        size: data.len(),
+                        end: 0x10000,
+                    });
+                };
+                image.push(address as u16, data)?;
+            }
+            END_OF_FILE => return Ok(image),
+            EXTENDED_SEGMENT_ADDRESS => base = value(2)? << 4,
+            EXTENDED_LINEAR_ADDRESS => base = value(2)? << 16,
+            START_SEGMENT_ADDRESS => {
+                let value = value(4)?;
+                image.entry = Some(((value >> 16 << 4) + (value & 0xFFFF)) as u16);
+            }
+            START_LINEAR_ADDRESS => image.entry = Some(value(4)? as u16),
+            _ => {
+                return Err(invalid(
+                    line,
+                    format!("unknown record type {:02X}", record_type),
+                ))
+            }
+        }
+    }
+
+    Err(Error::BadImage(String::from("no end of file record")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = "\
+:03020000A9018FC2
+:03000500000000F7
+
+:02FFFC00000201
+:0400000500000200F5
+:00000001FF
+";
+        // The blank line is fine, and the second record has a bad checksum
+        let error = parse(text).unwrap_err();
+        assert_eq!(error.to_string(), "line 2: checksum is $F7, expected $F8");
+
+        let text = text.replace(":03000500000000F7", ":03000500000000F8");
+        let image = parse(&text).unwrap();
+        assert_eq!(image.segments.len(), 3);
+        assert_eq!(image.segments[0].address, 0x0200);
+        assert_eq!(image.segments[0].data, [0xA9, 0x01, 0x8F]);
+        assert_eq!(image.segments[2].address, 0xFFFC);
+        assert_eq!(image.entry, Some(0x0200));
+
+        assert!(matches!(
+            parse(":020000040001F9\n:0100000000FF\n:00000001FF\n"),
+            Err(Error::ImageTooLarge { .. })
+        ));
+        assert!(parse(":00000001FF\n").unwrap().segments.is_empty());
+
+        // Address records have a fixed length; a four byte segment address
+        // is refused rather than overflowing the base
+        let error = parse(":04000002FFFFFFFFFE\n:01001000EA05\n:00000001FF\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 1: record type 02 has a bad length");
+        assert!(parse(":020000050200F7\n:00000001FF\n").is_err());
+        assert!(matches!(
+            parse(":02000002F0000C\n:0100100000EF\n:00000001FF\n"),
+            Err(Error::ImageTooLarge {
+                address: 0xF0010,
+                ..
+            })
+        ));
+        assert!(parse(":0100000000FF\n").is_err());
+        assert!(parse("0100000000FF\n").is_err());
+    }
+}
@@ -0,0 +1,219 @@
+/**
+ * Program image formats the emulator can load besides a raw binary.
+ *
+ * Each loader turns a file into an `Image`: the segments of memory it fills
+ * and, if the file names one, the address execution should start at. The
+ * format is picked from the file's extension (see `Format::from_path`).
+ *
+ * `dbgfile` reads the debug information ld65 writes alongside a program.
+ */
+use std::path::Path;
+
+use super::bus::Bus;
+use super::error::{Error, Result};
+
+pub mod dbgfile;
+pub mod ihex;
+pub mod o65;
+pub mod srec;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Binary,
+    IntelHex,
+    SRecord,
+    Prg,
+    O65,
+}
+
+impl Format {
+    // .hex/.ihx/.ihex are Intel HEX, .s19/.s28/.s37/.srec/.mot are
+    // S-records, .prg and .o65 are cc65 output, anything else is a raw binary
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        let extension = path
+            .as_ref()
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("hex" | "ihx" | "ihex") => Format::IntelHex,
+            Some("s19" | "s28" | "s37" | "srec" | "mot") => Format::SRecord,
+            Some("prg") => Format::Prg,
+            Some("o65") => Format::O65,
+            _ => Format::Binary,
+        }
+    }
+}
+
+// A run of bytes to be placed at `address`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u16,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Image {
+    pub segments: Vec<Segment>,
+    pub entry: Option<u16>,
+}
+
+impl Image {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Add bytes at `address`, extending the last segment if they follow on
+    // from it. They mustn't run past $FFFF or land on bytes already placed.
+    pub fn push(&mut self, address: u16, data: &[u8]) -> Result<()> {
+        let start = address as usize;
+        let end = start + data.len();
+        if end > 0x10000 {
+            return Err(Error::ImageTooLarge {
+                address: address as u32,
+                size: data.len(),
+                end: 0x10000,
+            });
+        }
+        for segment in &self.segments {
+            let segment_start = segment.address as usize;
+            let segment_end = segment_start + segment.data.len();
+            if start < segment_end && segment_start < end {
+                let address = start.max(segment_start) as u16;
+                return Err(Error::OverlappingSegments { address });
+            }
+        }
+
+        if let Some(last) = self.segments.last_mut() {
+            if last.address as usize + last.data.len() == start {
+                last.data.extend_from_slice(data);
+                return Ok(());
+            }
+        }
+        self.segments.push(Segment {
+            address,
+            data: data.to_vec(),
+        });
+        Ok(())
+    }
+
+    // Read an image in the given format. A binary is placed at `address`,
+    // and an o65 object is relocated to it.
+    pub fn read<P: AsRef<Path>>(path: P, format: Format, address: u16) -> Result<Self> {
+        let path = path.as_ref();
+        let read = || std::fs::read(path).map_err(|error| Error::io(path, error));
+        let read_text = || std::fs::read_to_string(path).map_err(|error| Error::io(path, error));
+        match format {
+            Format::Binary => {
+                let mut image = Self::new();
+                image.push(address, &read()?)?;
+                Ok(image)
+            }
+            Format::IntelHex => ihex::parse(&read_text()?),
+            Format::SRecord => srec::parse(&read_text()?),
+            Format::Prg => parse_prg(&read()?),
+            Format::O65 => o65::parse(&read()?, address),
+        }
+    }
+
+    // Copy every segment into memory, and if asked point the reset vector
+    // at the entry address
+    pub fn load_into(&self, bus: &mut Bus, set_reset_vector: bool) -> Result<()> {
+        for segment in &self.segments {
+            bus.load_rom_at(&segment.data, segment.address)?;
+        }
+        if let (true, Some(entry)) = (set_reset_vector, self.entry) {
+            bus.load_rom_at(&entry.to_le_bytes(), 0xFFFC)?;
+        }
+        Ok(())
+    }
+}
+
+// A Commodore program file: the load address, low byte first, then the data
+pub fn parse_prg(data: &[u8]) -> Result<Image> {
+    let [low, high, data @ ..] = data else {
+        return Err(Error::BadImage(String::from(
+            "too short for a load address",
+        )));
+    };
+
+    let mut image = Image::new();
+    image.push(u16::from_le_bytes([*low, *high]), data)?;
+    Ok(image)
+}
+
+// The error for a malformed record on line `line` (counting from 1)
+pub(crate) fn invalid(line: usize, message: String) -> Error {
+    Error::BadRecord { line, message }
+}
+
+// Decode a record's hex digits into bytes
+pub(crate) fn decode_hex(line: usize, digits: &str) -> Result<Vec<u8>> {
+    if !digits.is_ascii() {
+        return Err(invalid(line, String::from("record isn't ASCII")));
+    }
+    if !digits.len().is_multiple_of(2) {
+        return Err(invalid(line, String::from("odd number of hex digits")));
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|index| {
+            let pair = &digits[index..index + 2];
+            u8::from_str_radix(pair, 16)
+                .map_err(|_| invalid(line, format!("bad hex digits \"{}\"", pair)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_into() {
+        let mut image = Image::new();
+        image.push(0x0200, &[0xA9, 0x01]).unwrap();
+        image.push(0x0202, &[0x00]).unwrap();
+        image.push(0xFFFA, &[0x00, 0x03]).unwrap();
+        image.entry = Some(0x0200);
+        assert_eq!(image.segments.len(), 2);
+        assert_eq!(image.segments[0].data, [0xA9, 0x01, 0x00]);
+
+        // Segments can't overlap or run off the end of memory
+        assert!(matches!(
+            image.push(0x01FF, &[0x00, 0x00]),
+            Err(Error::OverlappingSegments { address: 0x0200 })
+        ));
+        assert!(matches!(
+            image.push(0xFFFF, &[0x00, 0x00]),
+            Err(Error::ImageTooLarge { .. })
+        ));
+
+        let mut bus = Bus::new();
+        image.load_into(&mut bus, false).unwrap();
+        assert_eq!(bus.memory[0x0202], 0x00);
+        assert_eq!(bus.memory[0xFFFB], 0x03);
+        assert_eq!(bus.memory[0xFFFD], 0x00);
+        image.load_into(&mut bus, true).unwrap();
+        assert_eq!(&bus.memory[0xFFFC..0xFFFE], [0x00, 0x02]);
+
+        assert_eq!(Format::from_path("rom.HEX"), Format::IntelHex);
+        assert_eq!(Format::from_path("rom.s19"), Format::SRecord);
+        assert_eq!(Format::from_path("rom.bin"), Format::Binary);
+    }
+
+    #[test]
+    fn test_prg() {
+        let image = parse_prg(&[0x01, 0x08, 0x0B, 0x08]).unwrap();
+        assert_eq!(image.segments[0].address, 0x0801);
+        assert_eq!(image.segments[0].data, [0x0B, 0x08]);
+        assert_eq!(image.entry, None);
+
+        assert!(matches!(parse_prg(&[0x01]), Err(Error::BadImage(_))));
+        assert!(matches!(
+            parse_prg(&[0xFF, 0xFF, 0x00, 0x00]),
+            Err(Error::ImageTooLarge { .. })
+        ));
+        assert_eq!(Format::from_path("game.PRG"), Format::Prg);
+    }
+}
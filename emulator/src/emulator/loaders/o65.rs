@@ -0,0 +1,287 @@
+/**
+ * André Fachat's o65 relocatable object format, as written by ld65's
+ * `o65` target.
+ *
+ * The file is a header giving the address each segment was assembled for
+ * and its length, header options, the text and data segments, a list of
+ * undefined references, a relocation table for each of the two segments and
+ * the exported globals. Loading puts the text segment at the chosen base,
+ * the data segment straight after it and the bss after that, and patches
+ * every relocated byte and word to match. The zero page segment stays where
+ * it was assembled.
+ *
+ * Relocation entries are an offset from the previous entry (255 means "add
+ * 254 and keep going", 0 ends the table) followed by a type byte: the type
+ * in the top three bits and the segment the value points into in the
+ * bottom four.
+ */
+use super::Image;
+use crate::emulator::error::{Error, Result};
+
+pub const MARKER: [u8; 2] = [0x01, 0x00];
+pub const MAGIC: [u8; 3] = *b"o65";
+
+const MODE_65816: u16 = 0x8000;
+const MODE_PAGED: u16 = 0x4000;
+const MODE_LONG: u16 = 0x2000;
+
+const RELOC_WORD: u8 = 0x80;
+const RELOC_HIGH: u8 = 0x40;
+const RELOC_LOW: u8 = 0x20;
+
+const SEGMENT_UNDEFINED: u8 = 0;
+const SEGMENT_ABSOLUTE: u8 = 1;
+const SEGMENT_TEXT: u8 = 2;
+const SEGMENT_DATA: u8 = 3;
+const SEGMENT_BSS: u8 = 4;
+const SEGMENT_ZERO: u8 = 5;
+
+fn invalid(message: String) -> Error {
+    Error::BadImage(message)
+}
+
+// Reads through the file, failing cleanly if it's cut short
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+    long: bool,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, count: usize) -> Result<&[u8]> {
+        let end = self.position + count;
+        let bytes = self
+            .data
+            .get(self.position..end)
+            .ok_or_else(|| invalid(format!("file ends at ${:X}", self.data.len())))?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn word(&mut self) -> Result<u32> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]) as u32)
+    }
+
+    // A 16-bit value, or a 32-bit one if the header's size bit is set
+    fn size(&mut self) -> Result<u32> {
+        if self.long {
+            let bytes = self.bytes(4)?;
+            Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        } else {
+            self.word()
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let length = self.data[self.position..]
+            .iter()
+            .position(|byte| *byte == 0)
+            .ok_or_else(|| invalid(String::from("unterminated name")))?;
+        let name = String::from_utf8_lossy(self.bytes(length)?).into_owned();
+        self.position += 1;
+        Ok(name)
+    }
+}
+
+// The segments of an object, as assembled and as loaded
+struct Layout {
+    text: (u32, u32),
+    data: (u32, u32),
+    bss: (u32, u32),
+}
+
+impl Layout {
+    // How far a value pointing into `segment` moves
+    fn delta(&self, segment: u8) -> Result<u32> {
+        match segment {
+            SEGMENT_ABSOLUTE | SEGMENT_ZERO => Ok(0),
+            SEGMENT_TEXT => Ok(self.text.1.wrapping_sub(self.text.0)),
+            SEGMENT_DATA => Ok(self.data.1.wrapping_sub(self.data.0)),
+            SEGMENT_BSS => Ok(self.bss.1.wrapping_sub(self.bss.0)),
+            _ => Err(invalid(format!(
+                "relocation into unknown segment {}",
+                segment
+            ))),
+        }
+    }
+}
+
+// Patch `segment` using the relocation table at the reader's position
+fn relocate(
+    reader: &mut Reader,
+    segment: &mut [u8],
+    layout: &Layout,
+    paged: bool,
+    undefined: &[String],
+) -> Result<()> {
+    let mut offset = usize::MAX; // Offsets count from the byte before the segment
+    loop {
+        let mut step = reader.byte()? as usize;
+        if step == 0 {
+            return Ok(());
+        }
+        while step == 255 {
+            offset = offset.wrapping_add(254);
+            step = reader.byte()? as usize;
+        }
+        offset = offset.wrapping_add(step);
+
+        let type_byte = reader.byte()?;
+        let target = type_byte & 0x0F;
+        if target == SEGMENT_UNDEFINED {
+            let index = reader.word()? as usize;
+            let name = undefined.get(index).map_or("?", |name| name.as_str());
+            return Err(invalid(format!("undefined reference to \"{}\"", name)));
+        }
+        let delta = layout.delta(target)?;
+
+        let out_of_range = || {
+            invalid(format!(
+                "relocation at ${:04X} is outside its segment",
+                offset
+            ))
+        };
+        match type_byte & 0xE0 {
+            RELOC_WORD => {
+                let bytes = segment
+                    .get_mut(offset..offset + 2)
+                    .ok_or_else(out_of_range)?;
+                let value = u16::from_le_bytes([bytes[0], bytes[1]]).wrapping_add(delta as u16);
+                bytes.copy_from_slice(&value.to_le_bytes());
+            }
+            RELOC_HIGH => {
+                let low = if paged { 0 } else { reader.byte()? };
+                let byte = segment.get_mut(offset).ok_or_else(out_of_range)?;
+                let value = u16::from_be_bytes([*byte, low]).wrapping_add(delta as u16);
+                *byte = (value >> 8) as u8;
+            }
+            RELOC_LOW => {
+                let byte = segment.get_mut(offset).ok_or_else(out_of_range)?;
+                *byte = byte.wrapping_add(delta as u8);
+            }
+            kind => {
+                return Err(invalid(format!(
+                    "unsupported relocation type ${:02X}",
+                    kind
+                )))
+            }
+        }
+    }
+}
+
+// Load an object with its text segment at `base`
+pub fn parse(data: &[u8], base: u16) -> Result<Image> {
+    if data.len() < 8 || data[0..2] != MARKER || data[2..5] != MAGIC {
+        return Err(Error::BadHeader(String::from("not an o65 file")));
+    }
+    if data[5] != 0 {
+        let message = format!("o65 version {} is not supported", data[5]);
+        return Err(Error::BadHeader(message));
+    }
+    let mode = u16::from_le_bytes([data[6], data[7]]);
+    if mode & MODE_65816 != 0 {
+        let message = String::from("65816 objects are not supported");
+        return Err(Error::BadHeader(message));
+    }
+
+    let mut reader = Reader {
+        data,
+        position: 8,
+        long: mode & MODE_LONG != 0,
+    };
+    let mut header = [0; 9];
+    for value in &mut header {
+        *value = reader.size()?;
+    }
+    let [tbase, tlen, dbase, dlen, bbase, _blen, _zbase, _zlen, _stack] = header;
+
+    // Header options: a length (including itself), a type and data
+    loop {
+        let length = reader.byte()? as usize;
+        if length == 0 {
+            break;
+        }
+        reader.bytes(length.saturating_sub(1))?;
+    }
+
+    let mut text = reader.bytes(tlen as usize)?.to_vec();
+    let mut data_segment = reader.bytes(dlen as usize)?.to_vec();
+
+    let count = reader.size()?;
+    let undefined = (0..count)
+        .map(|_| reader.string())
+        .collect::<Result<Vec<_>>>()?;
+
+    let text_start = base as u32;
+    let data_start = text_start + tlen;
+    let layout = Layout {
+        text: (tbase, text_start),
+        data: (dbase, data_start),
+        bss: (bbase, data_start + dlen),
+    };
+    if data_start + dlen > 0x10000 {
+        return Err(Error::ImageTooLarge {
+            address: text_start,
+            size: (tlen + dlen) as usize,
+            end: 0x10000,
+        });
+    }
+
+    let paged = mode & MODE_PAGED != 0;
+    relocate(&mut reader, &mut text, &layout, paged, &undefined)?;
+    relocate(&mut reader, &mut data_segment, &layout, paged, &undefined)?;
+
+    let mut image = Image::new();
+    image.push(base, &text)?;
+    image.push(data_start as u16, &data_segment)?;
+    image.entry = Some(base);
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relocation() {
+        // Assembled for $1000: text is "LDA data / LDX #<data / LDY #>data / RTS",
+        // data is one byte at $1008
+        let mut file = vec![0x01, 0x00, b'o', b'6', b'5', 0x00, 0x00, 0x00];
+        for value in [0x1000u16, 8, 0x1008, 1, 0x1009, 0, 0x0000, 0, 0] {
+            file.extend(value.to_le_bytes());
+        }
+        file.extend([0x05, 0x00, b'h', b'i', 0x00, 0x00]); // One option, then the end
+        file.extend([0xAD, 0x08, 0x10, 0xA2, 0x08, 0xA0, 0x10, 0x60]);
+        file.extend([0x42]);
+        file.extend([0x00, 0x00]); // No undefined references
+
+        // Text: a word at 1, a low byte at 4, a high byte (low $08) at 6
+        file.extend([0x02, 0x83, 0x03, 0x23, 0x02, 0x43, 0x08, 0x00]);
+        file.extend([0x00]); // Nothing to relocate in data
+        file.extend([0x00, 0x00]); // No globals
+
+        let image = parse(&file, 0xC0F8).unwrap();
+        assert_eq!(image.segments.len(), 1);
+        assert_eq!(image.segments[0].address, 0xC0F8);
+        assert_eq!(
+            image.segments[0].data,
+            [0xAD, 0x00, 0xC1, 0xA2, 0x00, 0xA0, 0xC1, 0x60, 0x42]
+        );
+        assert_eq!(image.entry, Some(0xC0F8));
+
+        assert!(matches!(
+            parse(&file[..40], 0xC000),
+            Err(Error::BadImage(_))
+        ));
+        assert!(matches!(
+            parse(&file, 0xFFF8),
+            Err(Error::ImageTooLarge { .. })
+        ));
+        assert!(matches!(parse(b"o65", 0xC000), Err(Error::BadHeader(_))));
+    }
+}
@@ -0,0 +1,135 @@
+/**
+ * Motorola S-record images (S19, S28 and S37).
+ *
+ * Every line is a record: 'S', the record type, then hex digit pairs giving
+ * the count of bytes that follow, the address, the data and a checksum, the
+ * one's complement of the low byte of the sum of the count, address and
+ * data bytes.
+ *
+ * Record types:
+ *
+ *   S0      header (ignored)
+ *   S1/2/3  data at a 16, 24 or 32-bit address
+ *   S5/6    count of data records so far (16 or 24-bit)
+ *   S7/8/9  start address (32, 24 or 16-bit), ending the block
+ *
+ * The 6502 only sees 64 KiB, so data placed above $FFFF is an error.
+ */
+use super::{decode_hex, invalid, Image};
+use crate::emulator::error::{Error, Result};
+
+pub fn parse(text: &str) -> Result<Image> {
+    let mut image = Image::new();
+    let mut data_records = 0u32;
+
+    for (index, record) in text.lines().enumerate() {
+        let line = index + 1;
+        let record = record.trim();
+        if record.is_empty() {
+            continue;
+        }
+        let mut chars = record.chars();
+        let record_type = match (chars.next(), chars.next()) {
+            (Some('S'), Some(digit)) if digit.is_ascii_digit() && digit != '4' => digit,
+            _ => return Err(invalid(line, String::from("not an S-record"))),
+        };
+
+        let bytes = decode_hex(line, chars.as_str())?;
+        if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+            return Err(invalid(
+                line,
+                String::from("record length doesn't match its byte count"),
+            ));
+        }
+        let sum = bytes[..bytes.len() - 1]
+            .iter()
+            .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        let checksum = bytes[bytes.len() - 1];
+        if checksum != !sum {
+            return Err(invalid(
+                line,
+                format!("checksum is ${:02X}, expected ${:02X}", checksum, !sum),
+            ));
+        }
+
+        let address_size = match record_type {
+            '0' | '1' | '5' | '9' => 2,
+            '2' | '6' | '8' => 3,
+            _ => 4,
+        };
+        if bytes.len() < address_size + 2 {
+            return Err(invalid(
+                line,
+                String::from("record is too short for its address"),
+            ));
+        }
+        let address = bytes[1..=address_size]
+            .iter()
+            .fold(0u32, |address, byte| address << 8 | *byte as u32);
+        let data = &bytes[address_size + 1..bytes.len() - 1];
+
+        match record_type {
+            '0' => {}
+            '1' | '2' | '3' => {
+                if address > 0xFFFF {
+                    return Err(Error::ImageTooLarge {
+                        address,
+                        size: data.len(),
+                        end: 0x10000,
+                    });
+                }
+                image.push(address as u16, data)?;
+                data_records += 1;
+            }
+            '5' | '6' => {
+                if address != data_records {
+                    return Err(invalid(
+                        line,
+                        format!(
+                            "record count is {}, but {} were read",
+                            address, data_records
+                        ),
+                    ));
+                }
+            }
+            _ => {
+                image.entry = Some(address as u16);
+                return Ok(image);
+            }
+        }
+    }
+
+    // A start record is optional
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = "\
+S00600004844521B
+S1060200A9018FBE
+S1060005000000F4
+S105FFFC0002FD
+S5030003F9
+S9030200FA
+";
+        let image = parse(text).unwrap();
+        assert_eq!(image.segments.len(), 3);
+        assert_eq!(image.segments[0].address, 0x0200);
+        assert_eq!(image.segments[0].data, [0xA9, 0x01, 0x8F]);
+        assert_eq!(image.entry, Some(0x0200));
+
+        // A 24-bit address past 64 KiB, a bad checksum and a bad count
+        assert!(matches!(
+            parse("S2060100000000F8\n"),
+            Err(Error::ImageTooLarge { .. })
+        ));
+        let error = parse("S1060200A9018FBF\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 1: checksum is $BF, expected $BE");
+        assert!(parse("S1060200A9018FBE\nS5030002FA\n").is_err());
+    }
+}
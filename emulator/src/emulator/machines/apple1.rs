@@ -0,0 +1,141 @@
+/**
+ * The Apple-1, with the memory most of them ended up with.
+ *
+ * Memory map:
+ *
+ *   $0000-$7FFF  32 KiB RAM (the board came with 4 KiB, most were expanded)
+ *   $D010-$D013  6821 PIA: keyboard on port A, display on port B
+ *   $E000-$EFFF  4 KiB RAM where Integer BASIC was loaded from cassette
+ *   $FF00-$FFFF  256 byte PROM holding WozMon
+ *
+ * Neither ROM comes with the emulator: the user supplies WozMon (placed
+ * so it ends at $FFFF) and, if they want it, Integer BASIC for $E000.
+ * The PIA's IRQ output isn't connected, as on the real board.
+ */
+use std::sync::{Arc, Mutex};
+
+use cpu::cpu::Variant;
+
+use crate::emulator::bus::IrqWiring;
+use crate::emulator::devices::apple1_terminal::Apple1Terminal;
+use crate::emulator::devices::host_input::HostInput;
+use crate::emulator::devices::pia::Pia;
+use crate::emulator::devices::ram::Ram;
+use crate::emulator::error::Result;
+use crate::emulator::loaders::Image;
+use crate::emulator::Emulator;
+
+use super::{load_into_chips, Chip};
+
+// The 14.31818 MHz video crystal divided by 14
+pub const CLOCK_MHZ: f64 = 1.022727;
+
+pub const RAM_START: u16 = 0x0000;
+pub const RAM_END: u16 = 0x7FFF;
+pub const PIA_START: u16 = 0xD010;
+pub const PIA_END: u16 = 0xD013;
+pub const BASIC_START: u16 = 0xE000;
+pub const BASIC_END: u16 = 0xEFFF;
+pub const WOZMON_START: u16 = 0xFF00;
+pub const WOZMON_END: u16 = 0xFFFF;
+
+pub struct Apple1 {
+    pub chips: Vec<Chip>,
+    pub pia: Arc<Mutex<Pia>>,
+    pub terminal: Arc<Mutex<Apple1Terminal>>,
+}
+
+impl Apple1 {
+    // Replace the emulator's machine with an Apple-1
+    pub fn attach(emulator: &mut Emulator) -> Self {
+        Self::attach_with(emulator, Apple1Terminal::new())
+    }
+
+    // The same, with the terminal set up by the caller (for tests)
+    pub fn attach_with(emulator: &mut Emulator, terminal: Apple1Terminal) -> Self {
+        emulator.init_empty();
+        emulator.set_variant(Variant::NMOS);
+
+        let ram = Arc::new(Mutex::new(Ram::new(0x8000)));
+        emulator.add_device(RAM_START, RAM_END, ram.clone());
+
+        let terminal = Arc::new(Mutex::new(terminal));
+        let mut pia = Pia::new();
+        pia.attach(terminal.clone());
+        let pia = Arc::new(Mutex::new(pia));
+        emulator.add_device_wired(PIA_START, PIA_END, pia.clone(), IrqWiring::None);
+
+        let basic = Arc::new(Mutex::new(Ram::new(0x1000)));
+        emulator.add_device(BASIC_START, BASIC_END, basic.clone());
+
+        let wozmon = Arc::new(Mutex::new(Ram::rom(vec![0xFF; 0x100])));
+        emulator.add_device(WOZMON_START, WOZMON_END, wozmon.clone());
+
+        let chip = |start, end, memory| Chip { start, end, memory };
+        Self {
+            chips: vec![
+                chip(RAM_START, RAM_END, ram),
+                chip(BASIC_START, BASIC_END, basic),
+                chip(WOZMON_START, WOZMON_END, wozmon),
+            ],
+            pia,
+            terminal,
+        }
+    }
+
+    // Type on the host terminal
+    pub fn connect_keyboard(&self) {
+        self.terminal.lock().unwrap().set_input(HostInput::stdin());
+    }
+
+    // Load WozMon, Integer BASIC or a program into the PROM and RAM
+    pub fn load_image(
+        &self,
+        emulator: &mut Emulator,
+        image: &Image,
+        set_reset_vector: bool,
+    ) -> Result<()> {
+        load_into_chips(&self.chips, emulator, image, set_reset_vector)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_print_through_pia() {
+        let mut emulator = Emulator::new();
+        let board = Apple1::attach_with(&mut emulator, Apple1Terminal::captured());
+
+        // A stand-in for WozMon's reset code: set up the PIA and print "OK"
+        #[rustfmt::skip]
+        let program = [
+            0xA9, 0x7F, 0x8D, 0x12, 0xD0, // lda #$7f / sta DSP (DDRB)
+            0xA9, 0xA7, 0x8D, 0x11, 0xD0, // lda #$a7 / sta KBDCR
+            0x8D, 0x13, 0xD0,             // sta DSPCR
+            0xA9, 0xCF, 0x8D, 0x12, 0xD0, // lda #'O' / sta DSP
+            0xA9, 0xCB, 0x8D, 0x12, 0xD0, // lda #'K' / sta DSP
+            0x4C, 0x17, 0xFF,             // jmp *
+        ];
+        let mut image = Image::new();
+        image.push(WOZMON_START, &program).unwrap();
+        image.entry = Some(WOZMON_START);
+        board.load_image(&mut emulator, &image, true).unwrap();
+
+        // The second character comes too soon after the first at 1 MHz, but
+        // not at 100 Hz, where a frame is over in a cycle
+        emulator.reset();
+        while emulator.cycles < 100 {
+            emulator.clock();
+        }
+        assert_eq!(board.terminal.lock().unwrap().output(), Some(&b"O"[..]));
+
+        emulator.reset();
+        emulator.bus.lock().unwrap().set_clock_rate(100.0);
+        while emulator.cycles < 100 {
+            emulator.clock();
+        }
+        assert_eq!(board.terminal.lock().unwrap().output(), Some(&b"OOK"[..]));
+    }
+}
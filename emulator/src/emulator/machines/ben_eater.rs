@@ -0,0 +1,185 @@
+/**
+ * Ben Eater's 6502 breadboard computer, as built in his video series.
+ *
+ * Memory map, from the address decoding on his schematic:
+ *
+ *   $0000-$3FFF  16 KiB 62256 RAM (the top half of the chip isn't decoded)
+ *   $4000-$4FFF  nothing
+ *   $5000-$5FFF  6551 ACIA, its four registers repeated
+ *   $6000-$7FFF  6522 VIA, its sixteen registers repeated
+ *   $8000-$FFFF  32 KiB 28C256 EEPROM
+ *
+ * The VIA has a 16x2 HD44780 LCD on it (data on port B, RS/RW/E on
+ * PA5-PA7), and the LEDs from the first videos on port B. The ACIA talks
+ * to the host terminal.
+ *
+ * A ROM image given to `load_image` is burnt into the EEPROM, so blink.bin
+ * (assembled for $C000) lands in its top half as it would with the
+ * EEPROM programmer.
+ */
+use std::sync::{Arc, Mutex};
+
+use cpu::cpu::Variant;
+
+use crate::emulator::devices::acia::Acia;
+use crate::emulator::devices::hd44780::Hd44780;
+use crate::emulator::devices::host_input::HostInput;
+use crate::emulator::devices::led_bar::LedBar;
+use crate::emulator::devices::port::Port;
+use crate::emulator::devices::ram::Ram;
+use crate::emulator::devices::via::Via;
+use crate::emulator::error::Result;
+use crate::emulator::loaders::Image;
+use crate::emulator::Emulator;
+
+use super::{load_into_chips, Chip};
+
+// The 1 MHz can oscillator; the 555 clock module runs from about 1 Hz to a
+// few hundred, or a step at a time
+pub const CLOCK_HZ: f64 = 1_000_000.0;
+
+pub const RAM_START: u16 = 0x0000;
+pub const RAM_END: u16 = 0x3FFF;
+pub const ACIA_START: u16 = 0x5000;
+pub const ACIA_END: u16 = 0x5FFF;
+pub const VIA_START: u16 = 0x6000;
+pub const VIA_END: u16 = 0x7FFF;
+pub const ROM_START: u16 = 0x8000;
+pub const ROM_END: u16 = 0xFFFF;
+
+pub struct BenEater {
+    pub chips: Vec<Chip>,
+    pub via: Arc<Mutex<Via>>,
+    pub lcd: Arc<Mutex<Hd44780>>,
+    pub leds: Arc<Mutex<LedBar>>,
+    pub acia: Arc<Mutex<Acia>>,
+}
+
+impl BenEater {
+    // Replace the emulator's machine with Ben Eater's computer
+    pub fn attach(emulator: &mut Emulator) -> Self {
+        Self::attach_with(emulator, Acia::new(), Hd44780::ben_eater())
+    }
+
+    // The same, with the ACIA and LCD set up by the caller (for tests)
+    pub fn attach_with(emulator: &mut Emulator, acia: Acia, lcd: Hd44780) -> Self {
+        emulator.init_empty();
+        emulator.set_variant(Variant::CMOS);
+
+        let ram = Arc::new(Mutex::new(Ram::new(0x4000)));
+        emulator.add_device(RAM_START, RAM_END, ram.clone());
+
+        let acia = Arc::new(Mutex::new(acia));
+        emulator.add_device(ACIA_START, ACIA_END, acia.clone());
+
+        let lcd = Arc::new(Mutex::new(lcd));
+        let leds = Arc::new(Mutex::new(LedBar::on_port(Port::B, 8)));
+        let mut via = Via::new();
+        via.attach(lcd.clone());
+        via.attach(leds.clone());
+        let via = Arc::new(Mutex::new(via));
+        emulator.add_device(VIA_START, VIA_END, via.clone());
+
+        let rom = Arc::new(Mutex::new(Ram::rom(vec![0xFF; 0x8000])));
+        emulator.add_device(ROM_START, ROM_END, rom.clone());
+
+        let chip = |start, end, memory| Chip { start, end, memory };
+        Self {
+            chips: vec![chip(RAM_START, RAM_END, ram), chip(ROM_START, ROM_END, rom)],
+            via,
+            lcd,
+            leds,
+            acia,
+        }
+    }
+
+    // Wire the ACIA to the host terminal
+    pub fn connect_terminal(&self) {
+        self.acia.lock().unwrap().set_input(HostInput::stdin());
+    }
+
+    // Burn a program into the EEPROM (or load it into RAM)
+    pub fn load_image(
+        &self,
+        emulator: &mut Emulator,
+        image: &Image,
+        set_reset_vector: bool,
+    ) -> Result<()> {
+        load_into_chips(&self.chips, emulator, image, set_reset_vector)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::loaders::Format;
+
+    #[test]
+    fn test_blink_and_lcd() {
+        let mut emulator = Emulator::new();
+        let mut lcd = Hd44780::ben_eater();
+        lcd.set_render(false);
+        let board = BenEater::attach_with(&mut emulator, Acia::captured(), lcd);
+        board.leds.lock().unwrap().set_render(false);
+
+        // blink.bin runs from the top half of the EEPROM
+        let image = Image::read("../demos/blink.bin", Format::Binary, 0xC000).unwrap();
+        board.load_image(&mut emulator, &image, false).unwrap();
+        emulator.reset();
+        while emulator.cycles < 100 {
+            emulator.clock();
+        }
+        assert_eq!(emulator.bus.lock().unwrap().peek_byte(0x6002), 0xFF);
+        assert!((0..8).any(|led| board.leds.lock().unwrap().is_lit(led)));
+
+        // Print "Hi" on the LCD and "!" through the ACIA, a byte at a time
+        // with E pulsed by hand (this only needs LDA, STA and JMP)
+        let mut program = vec![
+            0xA9, 0xFF, 0x8D, 0x02, 0x60, // lda #$ff / sta DDRB
+            0xA9, 0xE0, 0x8D, 0x03, 0x60, // lda #$e0 / sta DDRA
+        ];
+        for (rs, value) in [(0x00, 0x38), (0x00, 0x0C), (0x20, b'H'), (0x20, b'i')] {
+            // lda #value / sta PORTB, then RS / RS+E / RS on PORTA
+            program.extend([0xA9, value, 0x8D, 0x00, 0x60]);
+            for control in [rs, rs | 0x80, rs] {
+                program.extend([0xA9, control, 0x8D, 0x01, 0x60]);
+            }
+        }
+        program.extend([0xA9, b'!', 0x8D, 0x00, 0x50]); // lda #'!' / sta ACIA_DATA
+        let end = 0x8000 + program.len() as u16;
+        program.extend([0x4C, end as u8, (end >> 8) as u8]); // jmp *
+
+        let mut image = Image::new();
+        image.push(0x8000, &program).unwrap();
+        image.entry = Some(0x8000);
+        board.load_image(&mut emulator, &image, true).unwrap();
+
+        // At 100 kHz every LCD command is done before the next one arrives,
+        // so the program can get away without polling the busy flag
+        emulator.reset();
+        emulator.bus.lock().unwrap().set_clock_rate(100_000.0);
+        while emulator.cycles < 2000 {
+            emulator.clock();
+        }
+        assert_eq!(board.lcd.lock().unwrap().text()[0], "Hi              ");
+        assert_eq!(board.acia.lock().unwrap().output(), Some(&b"!"[..]));
+    }
+
+    #[test]
+    fn test_single_step() {
+        let mut emulator = Emulator::new();
+        let mut lcd = Hd44780::ben_eater();
+        lcd.set_render(false);
+        let board = BenEater::attach_with(&mut emulator, Acia::captured(), lcd);
+        board.leds.lock().unwrap().set_render(false);
+        let image = Image::read("../demos/blink.bin", Format::Binary, 0xC000).unwrap();
+        board.load_image(&mut emulator, &image, false).unwrap();
+
+        // Two presses run the first two instructions, and "q" stops
+        let input = std::io::Cursor::new("\n\nq\n\n");
+        assert_eq!(emulator.run_single_step(input), None);
+        assert_eq!(emulator.cpu.registers.pc, 0xC005);
+        assert_eq!(emulator.cpu.registers.a, 0xFF);
+        assert_eq!(emulator.bus.lock().unwrap().peek_byte(0x6002), 0xFF);
+    }
+}
@@ -0,0 +1,1142 @@
+/**
+ * Custom boards, described in a TOML file rather than in code.
+ *
+ * A description gives the CPU, the RAM and ROM chips with the images to
+ * burn into them, and the devices on the bus with the peripherals wired to
+ * their ports. Addresses can be written in hex (`0x6000`), and image paths
+ * are relative to the description:
+ *
+ *   name = "Breadboard"
+ *
+ *   [cpu]
+ *   variant = "CMOS"         # NMOS, CMOS, NES or 6510 (default CMOS)
+ *   clock_mhz = 1.0          # used unless --speed is given
+ *
+ *   [[ram]]
+ *   start = 0x0000
+ *   end = 0x3FFF
+ *
+ *   [[rom]]
+ *   start = 0x8000
+ *   end = 0xFFFF
+ *   image = "rom.bin"        # any format the loaders know
+ *
+ *   [[device]]
+ *   type = "via"             # see `DeviceKind`
+ *   start = 0x6000           # `end` defaults to the device's register window
+ *   irq = "irq"              # irq, nmi or none (default irq)
+ *
+ *   [[device.peripheral]]
+ *   type = "led_bar"         # see `PeripheralKind`
+ *   port = "B"
+ *   count = 8
+ *
+ * Port peripherals name the pins they're wired to: `PA0`-`PA7`, `PB0`-`PB7`,
+ * and for the PS/2 clock also `CA1` or `CB1`. I2C and SPI buses list the
+ * chips on them as `[[device.peripheral.device]]` tables. An LCD takes its
+ * data lines from a whole port and names its control pins:
+ *
+ *   [[device.peripheral]]
+ *   type = "hd44780"
+ *   port = "B"
+ *   rs = "PA5"
+ *   rw = "PA6"
+ *   e = "PA7"
+ *
+ * A key matrix is scanned with its columns on one port and its rows on the
+ * other, and can map host keys to its positions (column, row):
+ *
+ *   [[device.peripheral]]
+ *   type = "key_matrix"
+ *   columns = "A"
+ *   rows = "B"
+ *   keys = { "1" = [0, 0], "2" = [1, 0] }
+ *
+ * A sid can write its output to a WAV file:
+ *
+ *   [[device]]
+ *   type = "sid"
+ *   start = 0x7000
+ *   wav = "sound.wav"
+ *   sample_rate = 44100
+ *
+ * A tms9918 or vga can save its frames to a PNG file, overwriting it every
+ * `png_interval` frames. A vga is mapped over its RAM, whose size follows
+ * from its resolution and the stride between lines:
+ *
+ *   [[device]]
+ *   type = "vga"
+ *   start = 0x2000
+ *   width = 100
+ *   height = 64
+ *   stride = 128
+ *   png = "screen.png"
+ *   png_interval = 60
+ *
+ * RAM, ROM and devices may not overlap; unmapped addresses fall through to
+ * the bus's plain memory.
+ */
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::Deserialize;
+
+use crate::emulator::bus::IrqWiring;
+use crate::emulator::devices::acia::Acia;
+use crate::emulator::devices::block_image::BlockImage;
+use crate::emulator::devices::compact_flash::CompactFlash;
+use crate::emulator::devices::ds1307::Ds1307;
+use crate::emulator::devices::eeprom_24lc::Eeprom24;
+use crate::emulator::devices::file_io::FileIo;
+use crate::emulator::devices::hd44780::Hd44780;
+use crate::emulator::devices::host_input::HostInput;
+use crate::emulator::devices::host_interface::HostInterface;
+use crate::emulator::devices::i2c::I2cBus;
+use crate::emulator::devices::key_matrix::KeyMatrix;
+use crate::emulator::devices::led_bar::LedBar;
+use crate::emulator::devices::pia::Pia;
+use crate::emulator::devices::port::{Line, Pin, Port, SharedPortDevice};
+use crate::emulator::devices::ps2_keyboard::Ps2Keyboard;
+use crate::emulator::devices::ram::Ram;
+use crate::emulator::devices::rriot::Rriot;
+use crate::emulator::devices::sd_card::SdCard;
+use crate::emulator::devices::sid::{Sid, SidModel};
+use crate::emulator::devices::spi::SpiBus;
+use crate::emulator::devices::tms9918::Tms9918;
+use crate::emulator::devices::vga::{Vga, VgaTiming};
+use crate::emulator::devices::via::Via;
+use crate::emulator::devices::SharedDevice;
+use crate::emulator::error::{Error, Result};
+use crate::emulator::loaders::{Format, Image};
+use crate::emulator::Emulator;
+
+use super::{load_into_chips, Chip};
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CpuDescription {
+    pub variant: Option<String>,
+    pub clock_mhz: Option<f64>,
+}
+
+// A RAM or ROM chip filling start..=end
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryDescription {
+    pub start: u16,
+    pub end: u16,
+    pub image: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceKind {
+    Via,
+    HostInterface,
+    FileIo,
+    Tms9918,
+    Sid,
+    CompactFlash,
+    Acia,
+    Pia,
+    Rriot,
+    Vga,
+}
+
+impl DeviceKind {
+    // The name used for it in descriptions
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceKind::Via => "via",
+            DeviceKind::HostInterface => "host_interface",
+            DeviceKind::FileIo => "file_io",
+            DeviceKind::Tms9918 => "tms9918",
+            DeviceKind::Sid => "sid",
+            DeviceKind::CompactFlash => "compact_flash",
+            DeviceKind::Acia => "acia",
+            DeviceKind::Pia => "pia",
+            DeviceKind::Rriot => "rriot",
+            DeviceKind::Vga => "vga",
+        }
+    }
+
+    // How many bytes of registers the device decodes
+    fn window(&self) -> u16 {
+        match self {
+            DeviceKind::Via => 0x10,
+            DeviceKind::HostInterface => 0x20,
+            DeviceKind::FileIo => 0x10,
+            DeviceKind::Tms9918 => 0x02,
+            DeviceKind::Sid => 0x20,
+            DeviceKind::CompactFlash => 0x08,
+            DeviceKind::Acia => 0x04,
+            DeviceKind::Pia => 0x04,
+            DeviceKind::Rriot => 0x40,
+            DeviceKind::Vga => 0, // Sized by its resolution instead
+        }
+    }
+
+    // Whether it has ports to wire peripherals to
+    fn has_ports(&self) -> bool {
+        matches!(self, DeviceKind::Via | DeviceKind::Pia | DeviceKind::Rriot)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Wiring {
+    #[default]
+    Irq,
+    Nmi,
+    None,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceDescription {
+    #[serde(rename = "type")]
+    pub kind: DeviceKind,
+    pub start: u16,
+    pub end: Option<u16>,
+    #[serde(default)]
+    pub irq: Wiring,
+    // The shared directory of a file_io device
+    pub root: Option<PathBuf>,
+    // The disk image of a compact_flash card
+    pub image: Option<PathBuf>,
+    // "6581" or "8580" for a SID
+    pub model: Option<String>,
+    // Where a sid writes its output, at `sample_rate` Hz (default 44100)
+    pub wav: Option<PathBuf>,
+    pub sample_rate: Option<u32>,
+    // Where a tms9918 or vga saves every `png_interval`th frame (default 1)
+    pub png: Option<PathBuf>,
+    pub png_interval: Option<u64>,
+    // A vga's resolution and the bytes from one line to the next in its RAM
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub stride: Option<usize>,
+    #[serde(default, rename = "peripheral")]
+    pub peripherals: Vec<PeripheralDescription>,
+}
+
+impl DeviceDescription {
+    pub fn name(&self) -> String {
+        format!("{} at ${:04X}", self.kind.name(), self.start)
+    }
+
+    pub fn end(&self) -> u16 {
+        let end = (self.start as usize).saturating_add(self.window().max(1) - 1);
+        self.end.unwrap_or(end.min(0xFFFF) as u16)
+    }
+
+    // How many bytes the device decodes; a vga is mapped over all its RAM
+    fn window(&self) -> usize {
+        match self.kind {
+            DeviceKind::Vga => {
+                let (_, height, stride) = self.resolution();
+                height.saturating_mul(stride)
+            }
+            kind => kind.window() as usize,
+        }
+    }
+
+    // A vga's width, height and stride, by default those of Ben Eater's card
+    fn resolution(&self) -> (usize, usize, usize) {
+        (
+            self.width.unwrap_or(100),
+            self.height.unwrap_or(64),
+            self.stride.unwrap_or(128),
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeripheralKind {
+    LedBar,
+    Ps2Keyboard,
+    I2c,
+    Spi,
+    Hd44780,
+    KeyMatrix,
+}
+
+// Something wired to a device's ports. Which pins it needs depends on its kind.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PeripheralDescription {
+    #[serde(rename = "type")]
+    pub kind: PeripheralKind,
+    pub port: Option<String>,
+    pub count: Option<u8>,
+    pub clock: Option<String>,
+    pub data: Option<String>,
+    pub sda: Option<String>,
+    pub scl: Option<String>,
+    pub mosi: Option<String>,
+    pub miso: Option<String>,
+    pub sck: Option<String>,
+    // An LCD's control pins; its data lines are the whole of `port`
+    pub rs: Option<String>,
+    pub rw: Option<String>,
+    pub e: Option<String>,
+    // A key matrix's column and row ports, and the host keys mapped onto it
+    pub columns: Option<String>,
+    pub rows: Option<String>,
+    pub keys: Option<BTreeMap<String, [u8; 2]>>,
+    #[serde(default, rename = "device")]
+    pub devices: Vec<BusDeviceDescription>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BusDeviceKind {
+    Ds1307,
+    #[serde(rename = "eeprom_24lc")]
+    Eeprom24lc,
+    SdCard,
+}
+
+// A chip on an I2C or SPI bus
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BusDeviceDescription {
+    #[serde(rename = "type")]
+    pub kind: BusDeviceKind,
+    // The SPI chip select pin
+    pub cs: Option<String>,
+    pub image: Option<PathBuf>,
+    // EEPROM size and page size in bytes, and the levels of its A0-A2 pins
+    pub size: Option<usize>,
+    pub page_size: Option<usize>,
+    pub address_pins: Option<u8>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BoardDescription {
+    pub name: Option<String>,
+    #[serde(default)]
+    pub cpu: CpuDescription,
+    #[serde(default)]
+    pub ram: Vec<MemoryDescription>,
+    #[serde(default)]
+    pub rom: Vec<MemoryDescription>,
+    #[serde(default, rename = "device")]
+    pub devices: Vec<DeviceDescription>,
+
+    // Where relative paths start from
+    #[serde(skip)]
+    pub directory: PathBuf,
+}
+
+fn invalid(message: String) -> Error {
+    Error::BadMachine(message)
+}
+
+// "A" or "B"
+fn parse_port(name: &str) -> Result<Port> {
+    match name.to_ascii_uppercase().as_str() {
+        "A" => Ok(Port::A),
+        "B" => Ok(Port::B),
+        _ => Err(invalid(format!("unknown port \"{}\"", name))),
+    }
+}
+
+// "PA0" to "PB7", or "CA1" / "CB1" for a port's control input
+fn parse_line(name: &str) -> Result<Line> {
+    let bad_pin = || invalid(format!("unknown pin \"{}\"", name));
+    let upper = name.to_ascii_uppercase();
+    match upper.as_bytes() {
+        [b'C', port, b'1'] => {
+            let port = parse_port(&(*port as char).to_string()).map_err(|_| bad_pin())?;
+            Ok(Line::Control(port))
+        }
+        [b'P', port, bit @ b'0'..=b'7'] => {
+            let port = parse_port(&(*port as char).to_string()).map_err(|_| bad_pin())?;
+            Ok(Line::Pin(Pin::new(port, bit - b'0')))
+        }
+        _ => Err(bad_pin()),
+    }
+}
+
+fn parse_pin(name: &str) -> Result<Pin> {
+    match parse_line(name)? {
+        Line::Pin(pin) => Ok(pin),
+        Line::Control(_) => Err(invalid(format!("\"{}\" isn't a port pin", name))),
+    }
+}
+
+// A pin a peripheral can't do without
+fn required<'a>(value: &'a Option<String>, kind: &str, what: &str) -> Result<&'a str> {
+    value
+        .as_deref()
+        .ok_or_else(|| invalid(format!("{} needs a {} pin", kind, what)))
+}
+
+impl BoardDescription {
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|error| Error::io(path, error))?;
+        let directory = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        Self::parse(&text, directory)
+    }
+
+    // Parse and check a description whose relative paths start at `directory`
+    pub fn parse<P: Into<PathBuf>>(text: &str, directory: P) -> Result<Self> {
+        let mut board: Self = toml::from_str(text).map_err(|error| {
+            let line = error
+                .span()
+                .map(|span| text[..span.start].lines().count().max(1));
+            match line {
+                Some(line) => invalid(format!("line {}: {}", line, error.message())),
+                None => invalid(error.message().to_string()),
+            }
+        })?;
+        board.directory = directory.into();
+        board.validate()?;
+        Ok(board)
+    }
+
+    // Every chip and device, by name and address range
+    pub fn regions(&self) -> Vec<(String, u16, u16)> {
+        let memories = |chips: &[MemoryDescription], kind: &str| {
+            chips
+                .iter()
+                .map(|chip| {
+                    let name = format!("{} at ${:04X}", kind, chip.start);
+                    (name, chip.start, chip.end)
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut regions = memories(&self.ram, "ram");
+        regions.extend(memories(&self.rom, "rom"));
+        for device in &self.devices {
+            regions.push((device.name(), device.start, device.end()));
+        }
+        regions
+    }
+
+    fn validate(&self) -> Result<()> {
+        if let Some(variant) = &self.cpu.variant {
+            cpu::cpu::Variant::from_string(variant.clone())?;
+        }
+        if let Some(clock) = self.cpu.clock_mhz {
+            if !(clock > 0.0 && clock.is_finite()) {
+                return Err(invalid(format!(
+                    "clock_mhz must be positive, not {}",
+                    clock
+                )));
+            }
+        }
+
+        for device in &self.devices {
+            let (width, height, stride) = device.resolution();
+            if device.kind == DeviceKind::Vga
+                && (width == 0 || height == 0 || stride < width || device.window() > 0x10000)
+            {
+                let message = format!(
+                    "{} can't show {}x{} pixels with {} byte lines",
+                    device.name(),
+                    width,
+                    height,
+                    stride
+                );
+                return Err(invalid(message));
+            }
+        }
+
+        let regions = self.regions();
+        for (name, start, end) in &regions {
+            if start > end {
+                return Err(invalid(format!("{} ends before it starts", name)));
+            }
+        }
+        for (index, (first, start, end)) in regions.iter().enumerate() {
+            for (second, other_start, other_end) in &regions[index + 1..] {
+                if start <= other_end && other_start <= end {
+                    return Err(Error::OverlappingRegions {
+                        first: first.clone(),
+                        second: second.clone(),
+                        address: *start.max(other_start),
+                    });
+                }
+            }
+        }
+
+        for device in &self.devices {
+            if !device.kind.has_ports() && !device.peripherals.is_empty() {
+                let message = format!("{} has no ports to wire peripherals to", device.name());
+                return Err(invalid(message));
+            }
+        }
+        Ok(())
+    }
+
+    fn path(&self, path: &Path) -> PathBuf {
+        self.directory.join(path)
+    }
+}
+
+// A board built from a description, with its memory chips at hand for
+// loading programs into
+pub struct Board {
+    pub name: String,
+    pub clock_mhz: Option<f64>,
+    pub chips: Vec<Chip>,
+    // SIDs, kept to finish their WAV files
+    pub sids: Vec<Arc<Mutex<Sid>>>,
+}
+
+impl Board {
+    // Replace the emulator's machine with the one described
+    pub fn attach(emulator: &mut Emulator, description: &BoardDescription) -> Result<Self> {
+        emulator.init_empty();
+        if let Some(variant) = &description.cpu.variant {
+            emulator.change_variant(variant.clone())?;
+        }
+
+        let mut board = Self {
+            name: description
+                .name
+                .clone()
+                .unwrap_or_else(|| String::from("board")),
+            clock_mhz: description.cpu.clock_mhz,
+            chips: Vec::new(),
+            sids: Vec::new(),
+        };
+
+        for (chips, read_only) in [(&description.ram, false), (&description.rom, true)] {
+            for chip in chips {
+                let size = chip.end as usize - chip.start as usize + 1;
+                let mut memory = if read_only {
+                    Ram::rom(vec![0xFF; size]) // Unprogrammed EEPROM reads as $FF
+                } else {
+                    Ram::new(size)
+                };
+                if let Some(path) = &chip.image {
+                    let path = description.path(path);
+                    let image = Image::read(&path, Format::from_path(&path), chip.start)?;
+                    for segment in &image.segments {
+                        let offset = segment.address.wrapping_sub(chip.start) as usize;
+                        if segment.address < chip.start || offset + segment.data.len() > size {
+                            return Err(Error::ImageTooLarge {
+                                address: segment.address as u32,
+                                size: segment.data.len(),
+                                end: chip.end as u32 + 1,
+                            });
+                        }
+                        memory.data_mut()[offset..offset + segment.data.len()]
+                            .copy_from_slice(&segment.data);
+                    }
+                }
+
+                let memory = Arc::new(Mutex::new(memory));
+                emulator.add_device(chip.start, chip.end, memory.clone());
+                board.chips.push(Chip {
+                    start: chip.start,
+                    end: chip.end,
+                    memory,
+                });
+            }
+        }
+
+        for device in &description.devices {
+            let shared = build_device(description, device, &mut board.sids)?;
+            let wiring = match device.irq {
+                Wiring::Irq => IrqWiring::Irq,
+                Wiring::Nmi => IrqWiring::Nmi,
+                Wiring::None => IrqWiring::None,
+            };
+            emulator.add_device_wired(device.start, device.end(), shared, wiring);
+        }
+
+        Ok(board)
+    }
+
+    // Place a program image, burning the parts that land on a ROM into it
+    pub fn load_image(
+        &self,
+        emulator: &mut Emulator,
+        image: &Image,
+        set_reset_vector: bool,
+    ) -> Result<()> {
+        load_into_chips(&self.chips, emulator, image, set_reset_vector)
+    }
+
+    // Write out what the SIDs still have buffered for their WAV files
+    pub fn flush_audio(&self) {
+        for sid in &self.sids {
+            if let Err(error) = sid.lock().unwrap().flush_wav() {
+                eprintln!("sid: could not write audio: {}", error);
+            }
+        }
+    }
+}
+
+fn build_device(
+    board: &BoardDescription,
+    device: &DeviceDescription,
+    sids: &mut Vec<Arc<Mutex<Sid>>>,
+) -> Result<SharedDevice> {
+    let open = |path: &Option<PathBuf>, what: &str| -> Result<PathBuf> {
+        let path = path
+            .as_ref()
+            .ok_or_else(|| invalid(format!("{} needs {}", device.name(), what)))?;
+        Ok(board.path(path))
+    };
+
+    let shared: SharedDevice = match device.kind {
+        DeviceKind::Via => {
+            let mut via = Via::new();
+            for peripheral in &device.peripherals {
+                via.attach(build_peripheral(board, peripheral)?);
+            }
+            Arc::new(Mutex::new(via))
+        }
+        DeviceKind::HostInterface => Arc::new(Mutex::new(HostInterface::new())),
+        DeviceKind::FileIo => {
+            let root = open(&device.root, "a root directory")?;
+            let file_io = FileIo::new(&root).map_err(|error| Error::io(&root, error))?;
+            Arc::new(Mutex::new(file_io))
+        }
+        DeviceKind::Tms9918 => {
+            let mut vdp = Tms9918::new();
+            if let Some(path) = &device.png {
+                vdp.set_png_output(board.path(path), device.png_interval.unwrap_or(1));
+            }
+            Arc::new(Mutex::new(vdp))
+        }
+        DeviceKind::Sid => {
+            let model = match device.model.as_deref() {
+                None | Some("6581") => SidModel::Mos6581,
+                Some("8580") => SidModel::Mos8580,
+                Some(model) => {
+                    return Err(invalid(format!(
+                        "unknown SID model \"{}\" (expected 6581 or 8580)",
+                        model
+                    )))
+                }
+            };
+            let mut sid = Sid::new(model);
+            if let Some(rate) = device.sample_rate {
+                sid.set_sample_rate(rate);
+            }
+            if let Some(path) = &device.wav {
+                let path = board.path(path);
+                sid.set_wav_output(&path)
+                    .map_err(|error| Error::io(&path, error))?;
+            }
+            let sid = Arc::new(Mutex::new(sid));
+            sids.push(sid.clone());
+            sid
+        }
+        DeviceKind::CompactFlash => {
+            let path = open(&device.image, "an image")?;
+            let image = BlockImage::open(&path).map_err(|error| Error::io(&path, error))?;
+            Arc::new(Mutex::new(CompactFlash::new(image)))
+        }
+        DeviceKind::Acia => {
+            // A serial terminal on the host's stdin and stdout
+            let mut acia = Acia::new();
+            acia.set_input(HostInput::stdin());
+            Arc::new(Mutex::new(acia))
+        }
+        DeviceKind::Pia => {
+            let mut pia = Pia::new();
+            for peripheral in &device.peripherals {
+                pia.attach(build_peripheral(board, peripheral)?);
+            }
+            Arc::new(Mutex::new(pia))
+        }
+        DeviceKind::Rriot => {
+            // The I/O ports and timer; its ROM and RAM go in as chips
+            let mut rriot = Rriot::new();
+            for peripheral in &device.peripherals {
+                rriot.attach(build_peripheral(board, peripheral)?);
+            }
+            Arc::new(Mutex::new(rriot))
+        }
+        DeviceKind::Vga => {
+            let (width, height, stride) = device.resolution();
+            let mut vga = Vga::new(width, height, stride, VgaTiming::svga_800x600());
+            if let Some(path) = &device.png {
+                vga.set_png_output(board.path(path), device.png_interval.unwrap_or(1));
+            }
+            Arc::new(Mutex::new(vga))
+        }
+    };
+    Ok(shared)
+}
+
+fn build_peripheral(
+    board: &BoardDescription,
+    peripheral: &PeripheralDescription,
+) -> Result<SharedPortDevice> {
+    let kind = match peripheral.kind {
+        PeripheralKind::LedBar => "led_bar",
+        PeripheralKind::Ps2Keyboard => "ps2_keyboard",
+        PeripheralKind::I2c => "i2c",
+        PeripheralKind::Spi => "spi",
+        PeripheralKind::Hd44780 => "hd44780",
+        PeripheralKind::KeyMatrix => "key_matrix",
+    };
+    let shared: SharedPortDevice = match peripheral.kind {
+        PeripheralKind::LedBar => {
+            let port = parse_port(peripheral.port.as_deref().unwrap_or("B"))?;
+            let count = peripheral.count.unwrap_or(8);
+            if count == 0 || count > 8 {
+                return Err(invalid(format!("a led_bar has 1 to 8 LEDs, not {}", count)));
+            }
+            Arc::new(Mutex::new(LedBar::on_port(port, count)))
+        }
+        PeripheralKind::Ps2Keyboard => {
+            let clock = parse_line(required(&peripheral.clock, kind, "clock")?)?;
+            let data = parse_pin(required(&peripheral.data, kind, "data")?)?;
+            let mut keyboard = Ps2Keyboard::new(clock, data);
+            keyboard.set_input(HostInput::stdin());
+            Arc::new(Mutex::new(keyboard))
+        }
+        PeripheralKind::I2c => {
+            let sda = parse_pin(required(&peripheral.sda, kind, "sda")?)?;
+            let scl = parse_pin(required(&peripheral.scl, kind, "scl")?)?;
+            let mut bus = I2cBus::new(sda, scl);
+            for chip in &peripheral.devices {
+                match chip.kind {
+                    BusDeviceKind::Ds1307 => bus.attach(Box::new(Ds1307::new())),
+                    BusDeviceKind::Eeprom24lc => {
+                        let size = chip.size.unwrap_or(0x8000);
+                        let page_size = chip.page_size.unwrap_or(64);
+                        let mut eeprom = match &chip.image {
+                            Some(path) => {
+                                let path = board.path(path);
+                                Eeprom24::open(&path, size, page_size)
+                                    .map_err(|error| Error::io(&path, error))?
+                            }
+                            None => Eeprom24::new(size, page_size),
+                        };
+                        eeprom.set_address_pins(chip.address_pins.unwrap_or(0));
+                        bus.attach(Box::new(eeprom));
+                    }
+                    BusDeviceKind::SdCard => {
+                        return Err(invalid(String::from("an sd_card goes on an spi bus")))
+                    }
+                }
+            }
+            Arc::new(Mutex::new(bus))
+        }
+        PeripheralKind::Spi => {
+            let mosi = parse_pin(required(&peripheral.mosi, kind, "mosi")?)?;
+            let miso = parse_pin(required(&peripheral.miso, kind, "miso")?)?;
+            let sck = parse_pin(required(&peripheral.sck, kind, "sck")?)?;
+            let mut bus = SpiBus::new(mosi, miso, sck);
+            for chip in &peripheral.devices {
+                if chip.kind != BusDeviceKind::SdCard {
+                    let message = String::from("ds1307 and eeprom_24lc chips go on an i2c bus");
+                    return Err(invalid(message));
+                }
+                let cs = parse_pin(required(&chip.cs, "sd_card", "cs")?)?;
+                let path = chip
+                    .image
+                    .as_ref()
+                    .ok_or_else(|| invalid(String::from("sd_card needs an image")))?;
+                let path = board.path(path);
+                let image = BlockImage::open(&path).map_err(|error| Error::io(&path, error))?;
+                bus.attach(cs, Box::new(SdCard::new(image)));
+            }
+            Arc::new(Mutex::new(bus))
+        }
+        PeripheralKind::Hd44780 => {
+            let port = parse_port(peripheral.port.as_deref().unwrap_or("B"))?;
+            let data = std::array::from_fn(|bit| Pin::new(port, bit as u8));
+            let rs = parse_pin(required(&peripheral.rs, kind, "rs")?)?;
+            let rw = parse_pin(required(&peripheral.rw, kind, "rw")?)?;
+            let e = parse_pin(required(&peripheral.e, kind, "e")?)?;
+            if [rs, rw, e].iter().any(|pin| pin.port == port) {
+                let message = String::from("an hd44780's control pins can't be on its data port");
+                return Err(invalid(message));
+            }
+            Arc::new(Mutex::new(Hd44780::new(data, rs, rw, e)))
+        }
+        PeripheralKind::KeyMatrix => {
+            let columns = parse_port(peripheral.columns.as_deref().unwrap_or("A"))?;
+            let rows = parse_port(peripheral.rows.as_deref().unwrap_or("B"))?;
+            if columns == rows {
+                return Err(invalid(String::from("a key_matrix needs two ports")));
+            }
+            let mut matrix = KeyMatrix::new(columns, rows);
+            if let Some(keys) = &peripheral.keys {
+                let mut keymap = HashMap::new();
+                for (key, &[column, row]) in keys {
+                    let &[byte] = key.as_bytes() else {
+                        return Err(invalid(format!("\"{}\" isn't a single key", key)));
+                    };
+                    if column > 7 || row > 7 {
+                        let message = format!("\"{}\" is off the 8x8 key_matrix", key);
+                        return Err(invalid(message));
+                    }
+                    keymap.insert(byte, (column, row));
+                }
+                matrix.set_keymap(keymap);
+                matrix.set_input(HostInput::stdin());
+            }
+            Arc::new(Mutex::new(matrix))
+        }
+    };
+    Ok(shared)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOARD: &str = r#"
+name = "Test board"
+
+[cpu]
+variant = "NMOS"
+clock_mhz = 2.0
+
+[[ram]]
+start = 0x0000
+end = 0x3FFF
+
+[[rom]]
+start = 0xC000
+end = 0xFFFF
+
+[[device]]
+type = "via"
+start = 0x6000
+irq = "nmi"
+
+[[device.peripheral]]
+type = "led_bar"
+port = "A"
+count = 4
+
+[[device]]
+type = "host_interface"
+start = 0x7F00
+"#;
+
+    #[test]
+    fn test_board() {
+        let description = BoardDescription::parse(BOARD, "").unwrap();
+        assert_eq!(description.devices[0].end(), 0x600F);
+
+        let mut emulator = Emulator::new();
+        let board = Board::attach(&mut emulator, &description).unwrap();
+        assert_eq!(board.name, "Test board");
+        assert_eq!(board.clock_mhz, Some(2.0));
+        assert!(emulator.cpu.variant == cpu::cpu::Variant::NMOS);
+
+        // The program lands in the chips, and the ROM ignores writes
+        let mut image = Image::new();
+        image.push(0xC000, &[0xEA, 0xEA]).unwrap();
+        image.push(0x0200, &[0x42]).unwrap();
+        image.entry = Some(0xC000);
+        board.load_image(&mut emulator, &image, true).unwrap();
+        {
+            let mut bus = emulator.bus.lock().unwrap();
+            assert_eq!(bus.peek_byte(0xC001), 0xEA);
+            assert_eq!(bus.peek_byte(0xC002), 0xFF);
+            assert_eq!(bus.peek_byte(0xFFFD), 0xC0);
+            assert_eq!(bus.peek_byte(0x0200), 0x42);
+            bus.write_byte(0xC000, 0x00);
+            assert_eq!(bus.peek_byte(0xC000), 0xEA);
+            assert_eq!(bus.devices()[2].irq_wiring, IrqWiring::Nmi);
+        }
+    }
+
+    #[test]
+    fn test_lcd_and_keys() {
+        // An LCD on port B of a VIA, and a key matrix on the ports of another
+        let text = r#"
+[[device]]
+type = "via"
+start = 0x6000
+
+[[device.peripheral]]
+type = "hd44780"
+port = "B"
+rs = "PA5"
+rw = "PA6"
+e = "PA7"
+
+[[device]]
+type = "via"
+start = 0x6010
+
+[[device.peripheral]]
+type = "key_matrix"
+"#;
+        let description = BoardDescription::parse(text, "").unwrap();
+        let mut emulator = Emulator::new();
+        Board::attach(&mut emulator, &description).unwrap();
+
+        // Function set, then read the busy flag and address while it runs
+        let mut bus = emulator.bus.lock().unwrap();
+        bus.write_byte(0x6003, 0xE0); // DDRA: RS, RW and E
+        bus.write_byte(0x6002, 0xFF); // DDRB: data
+        bus.write_byte(0x6000, 0x38);
+        bus.write_byte(0x6001, 0x80);
+        bus.write_byte(0x6001, 0x00);
+        bus.write_byte(0x6002, 0x00);
+        bus.write_byte(0x6001, 0x40);
+        bus.write_byte(0x6001, 0xC0);
+        assert_eq!(bus.read_byte(0x6000), 0x80);
+        drop(bus);
+
+        let mut attach = |text: &str| {
+            let description = BoardDescription::parse(text, "")?;
+            Board::attach(&mut emulator, &description).map(|_| ())
+        };
+        let on_data_port = text.replace("PA7", "PB7");
+        assert!(matches!(attach(&on_data_port), Err(Error::BadMachine(_))));
+        let one_port = format!("{}columns = \"B\"\n", text);
+        assert!(matches!(attach(&one_port), Err(Error::BadMachine(_))));
+        let bad_key = format!("{}keys = {{ \"ab\" = [0, 0] }}\n", text);
+        let error = attach(&bad_key).unwrap_err();
+        assert_eq!(error.to_string(), "\"ab\" isn't a single key");
+    }
+
+    #[test]
+    fn test_acia() {
+        let text = "[[device]]\ntype = \"acia\"\nstart = 0x5000\n";
+        let description = BoardDescription::parse(text, "").unwrap();
+        let mut emulator = Emulator::new();
+        Board::attach(&mut emulator, &description).unwrap();
+
+        let mut bus = emulator.bus.lock().unwrap();
+        assert_eq!(bus.devices()[0].end, 0x5003);
+        assert_eq!(bus.read_byte(0x5001), 0x10); // Transmitter empty
+        bus.write_byte(0x5002, 0x0B);
+        bus.write_byte(0x5003, 0x1F);
+        assert_eq!(bus.read_byte(0x5003), 0x1F);
+        bus.write_byte(0x5001, 0x00); // Programmed reset
+        assert_eq!(bus.read_byte(0x5002), 0x00);
+        drop(bus);
+
+        // It has no ports for peripherals
+        let wired = format!("{}[[device.peripheral]]\ntype = \"led_bar\"\n", text);
+        assert!(matches!(
+            BoardDescription::parse(&wired, ""),
+            Err(Error::BadMachine(_))
+        ));
+    }
+
+    #[test]
+    fn test_pia() {
+        // The LCD on a 6821 instead of a VIA
+        let text = r#"
+[[device]]
+type = "pia"
+start = 0xD010
+
+[[device.peripheral]]
+type = "hd44780"
+port = "B"
+rs = "PA5"
+rw = "PA6"
+e = "PA7"
+"#;
+        let description = BoardDescription::parse(text, "").unwrap();
+        let mut emulator = Emulator::new();
+        Board::attach(&mut emulator, &description).unwrap();
+
+        let mut bus = emulator.bus.lock().unwrap();
+        assert_eq!(bus.devices()[0].end, 0xD013);
+        for (control, ddr, direction) in [(0xD011, 0xD010, 0xE0), (0xD013, 0xD012, 0xFF)] {
+            bus.write_byte(control, 0x00);
+            bus.write_byte(ddr, direction);
+            bus.write_byte(control, 0x04);
+        }
+        bus.write_byte(0xD012, 0x38);
+        bus.write_byte(0xD010, 0x80);
+        bus.write_byte(0xD010, 0x00);
+
+        // Turn the data lines around and read the busy flag
+        bus.write_byte(0xD013, 0x00);
+        bus.write_byte(0xD012, 0x00);
+        bus.write_byte(0xD013, 0x04);
+        bus.write_byte(0xD010, 0x40);
+        bus.write_byte(0xD010, 0xC0);
+        assert_eq!(bus.read_byte(0xD012), 0x80);
+    }
+
+    #[test]
+    fn test_rriot() {
+        // A 6530's I/O and timer, with its RAM beside it as on the KIM-1
+        let text = r#"
+[[ram]]
+start = 0x1780
+end = 0x17BF
+
+[[device]]
+type = "rriot"
+start = 0x1740
+
+[[device.peripheral]]
+type = "hd44780"
+port = "A"
+rs = "PB0"
+rw = "PB1"
+e = "PB2"
+"#;
+        let description = BoardDescription::parse(text, "").unwrap();
+        let mut emulator = Emulator::new();
+        Board::attach(&mut emulator, &description).unwrap();
+
+        let mut bus = emulator.bus.lock().unwrap();
+        assert_eq!(bus.devices()[1].end, 0x177F);
+        bus.write_byte(0x1743, 0x07); // PBDD: RS, RW and E
+        bus.write_byte(0x1741, 0xFF); // PADD: data
+        bus.write_byte(0x1740, 0x38);
+        bus.write_byte(0x1742, 0x04);
+        bus.write_byte(0x1742, 0x00);
+
+        // Turn the data lines around and read the busy flag
+        bus.write_byte(0x1741, 0x00);
+        bus.write_byte(0x1742, 0x02);
+        bus.write_byte(0x1742, 0x06);
+        assert_eq!(bus.read_byte(0x1740), 0x80);
+    }
+
+    #[test]
+    fn test_tms9918_png() {
+        let directory = std::env::temp_dir();
+        let name = format!("rusty502_board_{}.png", std::process::id());
+        let text = format!(
+            "[[device]]\ntype = \"tms9918\"\nstart = 0x7000\npng = \"{}\"\npng_interval = 2\n",
+            name
+        );
+        let description = BoardDescription::parse(&text, &directory).unwrap();
+        let mut emulator = Emulator::new();
+        Board::attach(&mut emulator, &description).unwrap();
+
+        // Ten cycles a frame; only the second frame is saved
+        let path = directory.join(&name);
+        let mut bus = emulator.bus.lock().unwrap();
+        bus.set_clock_rate(600.0);
+        bus.sync(15);
+        assert!(!path.exists());
+        bus.sync(25);
+        assert!(std::fs::read(&path).unwrap().starts_with(b"\x89PNG"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_sid_wav() {
+        let directory = std::env::temp_dir();
+        let name = format!("rusty502_sid_{}.wav", std::process::id());
+        let text = format!(
+            "[[device]]\ntype = \"sid\"\nstart = 0xD400\nwav = \"{}\"\nsample_rate = 8000\n",
+            name
+        );
+        let description = BoardDescription::parse(&text, &directory).unwrap();
+        let mut emulator = Emulator::new();
+        let board = Board::attach(&mut emulator, &description).unwrap();
+
+        // A tenth of a second at 1 MHz is 800 samples, all in the file once
+        // it's flushed even though the SID (and its writer) lives on
+        {
+            let mut bus = emulator.bus.lock().unwrap();
+            bus.set_clock_rate(1_000_000.0);
+            bus.sync(100_000);
+        }
+        board.flush_audio();
+        let path = directory.join(&name);
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(u32::from_le_bytes(data[24..28].try_into().unwrap()), 8000);
+        assert_eq!(u32::from_le_bytes(data[40..44].try_into().unwrap()), 1600);
+        assert_eq!(data.len(), 44 + 1600);
+        drop(emulator);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_vga() {
+        let directory = std::env::temp_dir();
+        let name = format!("rusty502_vga_{}.png", std::process::id());
+        let text = format!(
+            "[[ram]]\nstart = 0x0000\nend = 0x1FFF\n\n\
+             [[rom]]\nstart = 0xE000\nend = 0xFFFF\n\n\
+             [[device]]\ntype = \"vga\"\nstart = 0x2000\npng = \"{}\"\n",
+            name
+        );
+        let description = BoardDescription::parse(&text, &directory).unwrap();
+        assert_eq!(description.devices[0].end(), 0x3FFF);
+        let mut emulator = Emulator::new();
+        let board = Board::attach(&mut emulator, &description).unwrap();
+
+        // LDA #$30, STA $2000, then spin
+        let mut image = Image::new();
+        image
+            .push(0xE000, &[0xA9, 0x30, 0x8D, 0x00, 0x20, 0x4C, 0x05, 0xE0])
+            .unwrap();
+        image.entry = Some(0xE000);
+        board.load_image(&mut emulator, &image, true).unwrap();
+
+        // Over a frame at 1 MHz the card holds the CPU for the 20 visible
+        // cycles of each of 600 lines, less what the CPU runs into a line
+        // finishing a JMP (it only stops between instructions)
+        emulator.set_throttled(false);
+        emulator.run(1.0, Some(26_400 * 628 / 1000), false);
+        assert!((600 * 18..=600 * 20).contains(&emulator.halted_cycles));
+        assert_eq!(emulator.bus.lock().unwrap().peek_byte(0x2000), 0x30);
+
+        let path = directory.join(&name);
+        assert!(std::fs::read(&path).unwrap().starts_with(b"\x89PNG"));
+        std::fs::remove_file(&path).unwrap();
+
+        // A bigger card takes more of the address space
+        let wide = text.replace(
+            "start = 0x2000",
+            "start = 0x2000\nwidth = 200\nstride = 256",
+        );
+        let description = BoardDescription::parse(&wide, &directory).unwrap();
+        assert_eq!(description.devices[0].end(), 0x5FFF);
+        let narrow = text.replace("start = 0x2000", "start = 0x2000\nstride = 64");
+        let error = BoardDescription::parse(&narrow, &directory).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "vga at $2000 can't show 100x64 pixels with 64 byte lines"
+        );
+    }
+
+    #[test]
+    fn test_bad_board() {
+        let overlapping = format!("{}\n[[device]]\ntype = \"sid\"\nstart = 0x3FF0\n", BOARD);
+        let error = BoardDescription::parse(&overlapping, "").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "ram at $0000 and sid at $3FF0 overlap at $3FF0"
+        );
+        assert_eq!(error.exit_code(), 11);
+
+        let error = BoardDescription::parse("[[device]]\ntype = \"z80\"\nstart = 0", "");
+        assert!(
+            matches!(error, Err(Error::BadMachine(ref message)) if message.starts_with("line 2"))
+        );
+        assert!(BoardDescription::parse("[[ram]]\nstart = 0x10000\nend = 0", "").is_err());
+        assert!(matches!(
+            BoardDescription::parse("[cpu]\nvariant = \"Z80\"", ""),
+            Err(Error::UnknownVariant(_))
+        ));
+
+        // Images must fit the chip they're burnt into
+        let mut emulator = Emulator::new();
+        let description = BoardDescription::parse(
+            "[[rom]]\nstart = 0xFF00\nend = 0xFFFF\nimage = \"demos/blink.bin\"",
+            "..",
+        )
+        .unwrap();
+        assert!(matches!(
+            Board::attach(&mut emulator, &description),
+            Err(Error::ImageTooLarge { .. })
+        ));
+    }
+}
@@ -0,0 +1,131 @@
+/**
+ * The memory side of a Commodore 64: a 6510 with 64 KiB of RAM and the
+ * BASIC, KERNAL and character ROMs banked in and out by its I/O port.
+ *
+ * Memory map (at power on):
+ *
+ *   $0000-$0001  the 6510's I/O port (data direction, data)
+ *   $0002-$9FFF  RAM
+ *   $A000-$BFFF  BASIC ROM
+ *   $C000-$CFFF  RAM
+ *   $D000-$DFFF  I/O
+ *   $E000-$FFFF  KERNAL ROM
+ *
+ * See `devices::c64_banking` for how the port's LORAM, HIRAM and CHAREN
+ * lines change that. The VIC-II, SID and CIAs aren't part of this; a device
+ * standing in for them can be put behind the I/O area. The ROMs don't come
+ * with the emulator.
+ */
+use std::sync::{Arc, Mutex};
+
+use cpu::cpu::Variant;
+use cpu::io_port::io_port::SharedIoPort;
+
+use crate::emulator::devices::c64_banking::{C64Banking, Rom};
+use crate::emulator::devices::ram::Ram;
+use crate::emulator::error::Result;
+use crate::emulator::loaders::Image;
+use crate::emulator::Emulator;
+
+use super::{load_into_chips, Chip};
+
+// PAL; an NTSC machine runs at 1.022727 MHz
+pub const CLOCK_MHZ: f64 = 0.985248;
+
+// Where BASIC programs start, and LOAD puts a file without an address
+pub const BASIC_PROGRAM_START: u16 = 0x0801;
+
+// The banking lines and the cassette lines are pulled high; bits 6 and 7
+// aren't connected, so they float
+pub const PORT_PULL_UPS: u8 = 0x3F;
+
+pub struct C64 {
+    pub ram: Arc<Mutex<Ram>>,
+    pub banking: Arc<Mutex<C64Banking>>,
+    pub io_port: SharedIoPort,
+}
+
+impl C64 {
+    // Replace the emulator's machine with a C64's memory map
+    pub fn attach(emulator: &mut Emulator) -> Self {
+        emulator.init_empty();
+        emulator.set_variant(Variant::MOS6510);
+
+        let io_port = emulator.cpu.io_port.clone();
+        io_port.lock().unwrap().set_pull_ups(PORT_PULL_UPS);
+
+        // One RAM chip for the whole space; the part under the ROMs and I/O
+        // is reached through the banking
+        let ram = Arc::new(Mutex::new(Ram::new(0x10000)));
+        emulator.add_device(0x0000, C64Banking::START - 1, ram.clone());
+
+        let banking = Arc::new(Mutex::new(C64Banking::new(io_port.clone(), ram.clone())));
+        emulator.add_device(C64Banking::START, C64Banking::END, banking.clone());
+
+        Self {
+            ram,
+            banking,
+            io_port,
+        }
+    }
+
+    // Burn BASIC, the KERNAL or the character set into its ROM
+    pub fn load_rom(&self, rom: Rom, data: &[u8]) -> Result<()> {
+        self.banking.lock().unwrap().load_rom(rom, data)
+    }
+
+    // Put a program into RAM, as LOAD would. The CPU starts from the
+    // KERNAL's reset vector, so there's no entry point to set.
+    pub fn load_image(&self, emulator: &mut Emulator, image: &Image) -> Result<()> {
+        let chips = [Chip {
+            start: 0x0000,
+            end: 0xFFFF,
+            memory: self.ram.clone(),
+        }];
+        load_into_chips(&chips, emulator, image, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bank_switching() {
+        let mut emulator = Emulator::new();
+        let c64 = C64::attach(&mut emulator);
+
+        // The KERNAL's reset vector points at a program in RAM
+        let mut kernal = vec![0xEA; 0x2000];
+        kernal[0x1FFC..].copy_from_slice(&[0x00, 0xC0, 0x00, 0xC0]);
+        c64.load_rom(Rom::Kernal, &kernal).unwrap();
+        c64.load_rom(Rom::Basic, &[0xBA; 0x2000]).unwrap();
+
+        #[rustfmt::skip]
+        let program = [
+            0xA9, 0x07, 0x8D, 0x00, 0x00, // lda #$07 / sta $0000 (LORAM/HIRAM/CHAREN out)
+            0xA9, 0x05, 0x8D, 0x01, 0x00, // lda #$05 / sta $0001 (HIRAM low)
+            0xAD, 0x00, 0xA0, 0x8D, 0x00, 0x04, // lda $a000 / sta $0400
+            0xAD, 0x00, 0xE0, 0x8D, 0x01, 0x04, // lda $e000 / sta $0401
+            0xA9, 0x07, 0x8D, 0x01, 0x00, // lda #$07 / sta $0001
+            0xAD, 0x00, 0xA0, 0x8D, 0x02, 0x04, // lda $a000 / sta $0402
+            0xAD, 0x01, 0x00, 0x8D, 0x03, 0x04, // lda $0001 / sta $0403
+            0x4C, 0x27, 0xC0,             // jmp *
+        ];
+        let mut image = Image::new();
+        image.push(0xC000, &program).unwrap();
+        image.push(0xA000, &[0x11]).unwrap();
+        image.push(0xE000, &[0x22]).unwrap();
+        c64.load_image(&mut emulator, &image).unwrap();
+
+        emulator.reset();
+        while emulator.cycles < 200 {
+            emulator.clock();
+        }
+
+        // RAM under BASIC and the KERNAL, then BASIC again, and the port
+        // with the pulled up inputs
+        let ram = c64.ram.lock().unwrap();
+        assert_eq!(ram.data()[0x0400..0x0404], [0x11, 0x22, 0xBA, 0x3F]);
+    }
+}
@@ -0,0 +1,180 @@
+/**
+ * The MOS KIM-1.
+ *
+ * Memory map:
+ *
+ *   $0000-$03FF  1 KiB RAM
+ *   $1700-$173F  6530-003 I/O and timer (free for the user's projects)
+ *   $1740-$177F  6530-002 I/O and timer: display, keypad and TTY
+ *   $1780-$17BF  6530-003 RAM
+ *   $17C0-$17FF  6530-002 RAM (the monitor's variables and vectors)
+ *   $1800-$1BFF  6530-003 ROM (cassette routines)
+ *   $1C00-$1FFF  6530-002 ROM (the monitor)
+ *
+ * The board only decodes A0-A12, so the 6530-002 ROM also answers at
+ * $FC00-$FFFF, where the CPU finds its vectors. The rest of the mirrors
+ * aren't mapped.
+ *
+ * The monitor ROMs don't come with the emulator; the user supplies a
+ * 2 KiB image for $1800. Either RRIOT's timer interrupt reaches IRQ, as
+ * with the usual jumper from PB7.
+ */
+use std::sync::{Arc, Mutex};
+
+use cpu::cpu::Variant;
+
+use crate::emulator::devices::host_input::HostInput;
+use crate::emulator::devices::kim1_panel::Kim1Panel;
+use crate::emulator::devices::kim1_tty::Kim1Tty;
+use crate::emulator::devices::ram::Ram;
+use crate::emulator::devices::rriot::Rriot;
+use crate::emulator::error::Result;
+use crate::emulator::loaders::Image;
+use crate::emulator::Emulator;
+
+use super::{load_into_chips, Chip};
+
+// The 1 MHz crystal
+pub const CLOCK_MHZ: f64 = 1.0;
+
+pub const RAM_START: u16 = 0x0000;
+pub const RAM_END: u16 = 0x03FF;
+pub const RRIOT_003_START: u16 = 0x1700;
+pub const RRIOT_003_END: u16 = 0x173F;
+pub const RRIOT_002_START: u16 = 0x1740;
+pub const RRIOT_002_END: u16 = 0x177F;
+pub const RRIOT_RAM_START: u16 = 0x1780;
+pub const RRIOT_RAM_END: u16 = 0x17FF;
+pub const ROM_003_START: u16 = 0x1800;
+pub const ROM_003_END: u16 = 0x1BFF;
+pub const ROM_002_START: u16 = 0x1C00;
+pub const ROM_002_END: u16 = 0x1FFF;
+pub const MONITOR_MIRROR_START: u16 = 0xFC00;
+pub const MONITOR_MIRROR_END: u16 = 0xFFFF;
+
+// What the user talks to the monitor through
+pub enum Console {
+    Keypad,
+    Teletype,
+}
+
+pub struct Kim1 {
+    pub chips: Vec<Chip>,
+    pub rriot_002: Arc<Mutex<Rriot>>,
+    pub rriot_003: Arc<Mutex<Rriot>>,
+    pub panel: Arc<Mutex<Kim1Panel>>,
+    pub tty: Option<Arc<Mutex<Kim1Tty>>>,
+}
+
+impl Kim1 {
+    // Replace the emulator's machine with a KIM-1
+    pub fn attach(emulator: &mut Emulator, console: Console) -> Self {
+        let tty = match console {
+            Console::Keypad => None,
+            Console::Teletype => Some(Kim1Tty::new()),
+        };
+        Self::attach_with(emulator, Kim1Panel::new(), tty)
+    }
+
+    // The same, with the panel and TTY set up by the caller (for tests)
+    pub fn attach_with(emulator: &mut Emulator, panel: Kim1Panel, tty: Option<Kim1Tty>) -> Self {
+        emulator.init_empty();
+        emulator.set_variant(Variant::NMOS);
+
+        let ram = Arc::new(Mutex::new(Ram::new(0x400)));
+        emulator.add_device(RAM_START, RAM_END, ram.clone());
+
+        let rriot_003 = Arc::new(Mutex::new(Rriot::new()));
+        emulator.add_device(RRIOT_003_START, RRIOT_003_END, rriot_003.clone());
+
+        let panel = Arc::new(Mutex::new(panel));
+        let tty = tty.map(|tty| Arc::new(Mutex::new(tty)));
+        let mut rriot_002 = Rriot::new();
+        rriot_002.attach(panel.clone());
+        if let Some(tty) = &tty {
+            rriot_002.attach(tty.clone());
+        }
+        let rriot_002 = Arc::new(Mutex::new(rriot_002));
+        emulator.add_device(RRIOT_002_START, RRIOT_002_END, rriot_002.clone());
+
+        let rriot_ram = Arc::new(Mutex::new(Ram::new(0x80)));
+        emulator.add_device(RRIOT_RAM_START, RRIOT_RAM_END, rriot_ram.clone());
+
+        let rom_003 = Arc::new(Mutex::new(Ram::rom(vec![0xFF; 0x400])));
+        emulator.add_device(ROM_003_START, ROM_003_END, rom_003.clone());
+
+        // The monitor's ROM, seen again at the top of memory
+        let rom_002 = Arc::new(Mutex::new(Ram::rom(vec![0xFF; 0x400])));
+        emulator.add_device(ROM_002_START, ROM_002_END, rom_002.clone());
+        emulator.add_device(MONITOR_MIRROR_START, MONITOR_MIRROR_END, rom_002.clone());
+
+        let chip = |start, end, memory| Chip { start, end, memory };
+        Self {
+            chips: vec![
+                chip(RAM_START, RAM_END, ram),
+                chip(RRIOT_RAM_START, RRIOT_RAM_END, rriot_ram),
+                chip(ROM_003_START, ROM_003_END, rom_003),
+                chip(ROM_002_START, ROM_002_END, rom_002.clone()),
+                chip(MONITOR_MIRROR_START, MONITOR_MIRROR_END, rom_002),
+            ],
+            rriot_002,
+            rriot_003,
+            panel,
+            tty,
+        }
+    }
+
+    // Type on the keypad, or the teletype if it's connected
+    pub fn connect_terminal(&self) {
+        match &self.tty {
+            Some(tty) => tty.lock().unwrap().set_input(HostInput::stdin()),
+            None => self.panel.lock().unwrap().set_input(HostInput::stdin()),
+        }
+    }
+
+    // Load the monitor ROMs or a program. A reset vector lands in the
+    // monitor's ROM, through its mirror.
+    pub fn load_image(
+        &self,
+        emulator: &mut Emulator,
+        image: &Image,
+        set_reset_vector: bool,
+    ) -> Result<()> {
+        load_into_chips(&self.chips, emulator, image, set_reset_vector)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let mut emulator = Emulator::new();
+        let mut panel = Kim1Panel::new();
+        panel.set_render(false);
+        let board = Kim1::attach_with(&mut emulator, panel, None);
+
+        // Light "7" on the third digit: segments on PA, digit select on PB
+        #[rustfmt::skip]
+        let program = [
+            0xA9, 0x7F, 0x8D, 0x41, 0x17, // lda #$7f / sta PADD
+            0xA9, 0x1E, 0x8D, 0x43, 0x17, // lda #$1e / sta PBDD
+            0xA9, 0x0D, 0x8D, 0x42, 0x17, // lda #$0d / sta PBD
+            0xA9, 0x07, 0x8D, 0x40, 0x17, // lda #$07 / sta PAD
+            0x4C, 0x14, 0x02,             // jmp *
+        ];
+        let mut image = Image::new();
+        image.push(0x0200, &program).unwrap();
+        image.entry = Some(0x0200);
+        board.load_image(&mut emulator, &image, true).unwrap();
+        assert_eq!(emulator.bus.lock().unwrap().peek_byte(0x1FFC), 0x00);
+
+        emulator.reset();
+        while emulator.cycles < 100 {
+            emulator.clock();
+        }
+        let panel = board.panel.lock().unwrap();
+        assert_eq!(panel.text(emulator.cycles), "  7   ");
+    }
+}
@@ -0,0 +1,70 @@
+/**
+ * Machine profiles: the memory maps and devices of particular computers,
+ * set up on an emulator in one go.
+ *
+ * `board` builds custom machines from a TOML description instead.
+ */
+use std::sync::{Arc, Mutex};
+
+use super::devices::ram::Ram;
+use super::error::{Error, Result};
+use super::loaders::Image;
+use super::Emulator;
+
+pub mod apple1;
+pub mod ben_eater;
+pub mod board;
+pub mod c64;
+pub mod kim1;
+pub mod nes;
+
+// A RAM or ROM chip on a machine's bus, kept so programs can be loaded into it
+#[derive(Clone)]
+pub struct Chip {
+    pub start: u16,
+    pub end: u16,
+    pub memory: Arc<Mutex<Ram>>,
+}
+
+// Place a program image in a machine's chips, burning the parts that land
+// on a ROM into it. Bytes outside every chip go to the bus's plain memory.
+pub fn load_into_chips(
+    chips: &[Chip],
+    emulator: &mut Emulator,
+    image: &Image,
+    set_reset_vector: bool,
+) -> Result<()> {
+    let mut bus = emulator.bus.lock().unwrap();
+    let mut store = |address: u16, data: &[u8]| -> Result<()> {
+        if address as usize + data.len() > 0x10000 {
+            return Err(Error::ImageTooLarge {
+                address: address as u32,
+                size: data.len(),
+                end: 0x10000,
+            });
+        }
+        for (index, byte) in data.iter().enumerate() {
+            let address = address + index as u16;
+            let chip = chips
+                .iter()
+                .find(|chip| (chip.start..=chip.end).contains(&address));
+            match chip {
+                Some(chip) => {
+                    let mut memory = chip.memory.lock().unwrap();
+                    let offset = (address - chip.start) as usize % memory.len();
+                    memory.data_mut()[offset] = *byte;
+                }
+                None => bus.memory[address as usize] = *byte,
+            }
+        }
+        Ok(())
+    };
+
+    for segment in &image.segments {
+        store(segment.address, &segment.data)?;
+    }
+    if let (true, Some(entry)) = (set_reset_vector, image.entry) {
+        store(0xFFFC, &entry.to_le_bytes())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,228 @@
+/**
+ * The Nintendo Entertainment System, as seen from its 2A03 CPU.
+ *
+ * Memory map:
+ *
+ *   $0000-$07FF  2 KiB of internal RAM, mirrored up to $1FFF
+ *   $2000-$2007  2C02 PPU registers, mirrored every 8 bytes up to $3FFF
+ *   $4000-$4017  APU and I/O registers (see `devices::nes::io`)
+ *   $4018-$401F  CPU test mode registers
+ *   $4020-$FFFF  cartridge space: a cartridge (see `devices::nes::cartridge`)
+ *                or, without one, plain memory for a ROM image
+ */
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use cpu::cpu::Variant;
+
+use crate::emulator::devices::nes::cartridge::{Cartridge, CartridgeSlot};
+use crate::emulator::devices::nes::io::NesIo;
+use crate::emulator::devices::nes::ppu::Ppu;
+use crate::emulator::devices::ram::Ram;
+use crate::emulator::error::Result;
+use crate::emulator::Emulator;
+
+// NTSC CPU clock: the 21.477272 MHz master clock divided by 12
+pub const CPU_CLOCK_HZ: f64 = 1_789_773.0;
+
+pub const RAM_SIZE: usize = 0x0800;
+pub const CARTRIDGE_START: u16 = 0x4020;
+
+pub struct Nes {
+    pub ram: Arc<Mutex<Ram>>,
+    pub ppu: Arc<Mutex<Ppu>>,
+    pub io: Arc<Mutex<NesIo>>,
+}
+
+impl Nes {
+    // Replace the emulator's machine with an NES
+    pub fn attach(emulator: &mut Emulator) -> Self {
+        emulator.init_empty();
+        emulator.set_variant(Variant::NES);
+
+        let ram = Arc::new(Mutex::new(Ram::new(RAM_SIZE)));
+        emulator.add_device(0x0000, 0x1FFF, ram.clone());
+
+        let ppu = Arc::new(Mutex::new(Ppu::new()));
+        emulator.add_device(0x2000, 0x3FFF, ppu.clone());
+
+        let io = Arc::new(Mutex::new(NesIo::new()));
+        emulator.add_device(0x4000, 0x401F, io.clone());
+
+        Self {
+            ram,
+            ppu,
+            io,
+        }
+    }
+
+    // Plug a cartridge into the slot: its PRG side into $4020-$FFFF and its
+    // CHR side into the PPU
+    pub fn insert_cartridge(
+        &self,
+        emulator: &mut Emulator,
+        cartridge: Cartridge,
+    ) -> Arc<Mutex<Cartridge>> {
+        let cartridge = Arc::new(Mutex::new(cartridge));
+        self.ppu.lock().unwrap().set_chr(cartridge.clone());
+
+        let slot = CartridgeSlot::new(cartridge.clone(), self.ppu.clone());
+        emulator.add_device(
+            CartridgeSlot::START,
+            CartridgeSlot::END,
+            Arc::new(Mutex::new(slot)),
+        );
+        cartridge
+    }
+
+    // Load an iNES / NES 2.0 image and insert it
+    pub fn load_cartridge<P: AsRef<Path>>(
+        &self,
+        emulator: &mut Emulator,
+        path: P,
+    ) -> Result<Arc<Mutex<Cartridge>>> {
+        let cartridge = Cartridge::open(path)?;
+        Ok(self.insert_cartridge(emulator, cartridge))
+    }
+
+    // Write out what the APU still has buffered for its WAV file
+    pub fn flush_audio(&self) {
+        if let Err(error) = self.io.lock().unwrap().apu_mut().flush_wav() {
+            eprintln!("apu: could not write audio: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::devices::nes::controller::{BUTTON_A, BUTTON_START};
+
+    #[test]
+    fn test_memory_map() {
+        let mut emulator = Emulator::new();
+        let nes = Nes::attach(&mut emulator);
+        let mut bus = emulator.bus.lock().unwrap();
+
+        // RAM and PPU registers repeat through their mirrors
+        bus.write_byte(0x0123, 0x42);
+        assert_eq!(bus.read_byte(0x0923), 0x42);
+        assert_eq!(bus.read_byte(0x1923), 0x42);
+        bus.write_byte(0x2006, 0x3F);
+        bus.write_byte(0x3FFE, 0x01);
+        bus.write_byte(0x3FF7, 0x17);
+        bus.write_byte(0x200E, 0x3F);
+        bus.write_byte(0x2006, 0x01);
+        assert_eq!(bus.read_byte(0x2FFF) & 0x3F, 0x17);
+
+        // Cartridge space is memory the ROM is loaded into
+        bus.load_rom_at(&[0x00, 0x80], 0xFFFC).unwrap();
+        assert_eq!(bus.read_byte(0xFFFD), 0x80);
+        assert_eq!(bus.memory[0x0123], 0x00);
+
+        // Controllers shift out A, B, Select, Start, ... then 1s
+        nes.io.lock().unwrap().controller_mut(0).press(BUTTON_A | BUTTON_START);
+        bus.write_byte(0x4016, 0x01);
+        assert_eq!(bus.read_byte(0x4016), 0x41);
+        assert_eq!(bus.read_byte(0x4016), 0x41);
+        bus.write_byte(0x4016, 0x00);
+        let bits: Vec<u8> = (0..10).map(|_| bus.read_byte(0x4016) & 0x01).collect();
+        assert_eq!(bits, [1, 0, 0, 1, 0, 0, 0, 0, 1, 1]);
+        assert_eq!(bus.read_byte(0x4017), 0x40);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        let mut emulator = Emulator::new();
+        let nes = Nes::attach(&mut emulator);
+        let mut bus = emulator.bus.lock().unwrap();
+        for offset in 0..0x100 {
+            bus.write_byte(0x0200 + offset, offset as u8);
+        }
+
+        // Started on an even cycle the CPU loses 513 cycles
+        bus.sync(1000);
+        bus.write_byte(0x4014, 0x02);
+        assert_eq!(nes.ppu.lock().unwrap().oam()[0xFF], 0xFF);
+        assert_eq!(nes.ppu.lock().unwrap().oam()[0x12], 0x12);
+        bus.sync(1512);
+        assert!(bus.halted());
+        bus.sync(1513);
+        assert!(!bus.halted());
+
+        // And 514 on an odd one
+        bus.sync(2001);
+        bus.write_byte(0x4014, 0x02);
+        bus.sync(2514);
+        assert!(bus.halted());
+        bus.sync(2515);
+        assert!(!bus.halted());
+    }
+
+    #[test]
+    fn test_dmc_fetches() {
+        let mut emulator = Emulator::new();
+        let nes = Nes::attach(&mut emulator);
+        let mut bus = emulator.bus.lock().unwrap();
+        bus.load_rom_at(&[0x55; 17], 0xC000).unwrap();
+
+        // 17 bytes from $C000 at the fastest rate, with an IRQ at the end
+        bus.write_byte(0x4010, 0x8F);
+        bus.write_byte(0x4012, 0x00);
+        bus.write_byte(0x4013, 0x01);
+        bus.write_byte(0x4015, 0x10);
+
+        // Every byte fetched costs the CPU 4 cycles
+        let mut halted = 0;
+        for cycle in 0..10_000 {
+            bus.sync(cycle);
+            halted += bus.halted() as u32;
+        }
+        assert_eq!(halted, 17 * 4);
+        assert!(bus.irq());
+        assert_eq!(bus.read_byte(0x4015) & 0x90, 0x80);
+
+        // Writing $4015 acknowledges the DMC interrupt
+        bus.write_byte(0x4015, 0x00);
+        assert!(!bus.irq());
+        assert!(!nes.io.lock().unwrap().apu().irq());
+    }
+
+    #[test]
+    fn test_mmc3_scanline_irq() {
+        // MMC3 with 32 KiB of PRG ROM and CHR RAM
+        let mut image = b"NES\x1A\x02\x00\x40\x00".to_vec();
+        image.resize(16 + 0x8000, 0);
+
+        let mut emulator = Emulator::new();
+        let nes = Nes::attach(&mut emulator);
+        nes.insert_cartridge(&mut emulator, Cartridge::from_bytes(&image).unwrap());
+        let mut bus = emulator.bus.lock().unwrap();
+
+        // Sprites from $1000 with rendering on, and no APU frame IRQ
+        bus.write_byte(0x4017, 0x40);
+        bus.write_byte(0x2000, 0x08);
+        bus.write_byte(0x2001, 0x18);
+
+        // In vertical blank, ask for an IRQ after 10 lines
+        let mut cycle = 0;
+        while nes.ppu.lock().unwrap().position().0 != 241 {
+            cycle += 1;
+            bus.sync(cycle);
+        }
+        bus.write_byte(0xC000, 10);
+        bus.write_byte(0xC001, 0);
+        bus.write_byte(0xE000, 0);
+        bus.write_byte(0xE001, 0);
+        assert!(!bus.irq());
+
+        // The pre-render line reloads the counter, and lines 0-9 count it down
+        while !bus.irq() {
+            cycle += 1;
+            bus.sync(cycle);
+        }
+        let (scanline, dot) = nes.ppu.lock().unwrap().position();
+        assert_eq!(scanline, 9);
+        assert!((261..=264).contains(&dot));
+    }
+}
@@ -0,0 +1,119 @@
+/**
+ * Cycle-timestamped event scheduler.
+ *
+ * Devices don't get ticked on every cycle. Instead each device tells the
+ * scheduler the next cycle at which something happens on its own (a timer
+ * underflowing, a scanline ending, ...) and is only ticked at that point,
+ * or earlier if the CPU touches one of its registers. Between events the
+ * CPU runs uninterrupted.
+ */
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Event {
+    cycle: u64,
+    device: usize,
+    generation: u64,
+}
+
+#[derive(Clone, Default)]
+pub struct Scheduler {
+    // Pending events, earliest first
+    queue: BinaryHeap<Reverse<Event>>,
+
+    // Bumped every time a device is rescheduled, so stale events can be dropped
+    generations: Vec<u64>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self {
+            queue: BinaryHeap::new(),
+            generations: Vec::new(),
+        }
+    }
+
+    // Replace the pending event (if any) of a device with a new one
+    pub fn schedule(&mut self, device: usize, cycle: Option<u64>) {
+        if device >= self.generations.len() {
+            self.generations.resize(device + 1, 0);
+        }
+        self.generations[device] += 1;
+
+        if let Some(cycle) = cycle {
+            self.queue.push(Reverse(Event {
+                cycle,
+                device,
+                generation: self.generations[device],
+            }));
+        }
+    }
+
+    pub fn cancel(&mut self, device: usize) {
+        self.schedule(device, None);
+    }
+
+    // Cycle of the earliest pending event, or u64::MAX if nothing is scheduled
+    pub fn next_deadline(&mut self) -> u64 {
+        self.drop_stale();
+        match self.queue.peek() {
+            Some(Reverse(event)) => event.cycle,
+            None => u64::MAX,
+        }
+    }
+
+    // Pop the next device whose event is due at or before `now`
+    pub fn pop_due(&mut self, now: u64) -> Option<usize> {
+        self.drop_stale();
+        match self.queue.peek() {
+            Some(Reverse(event)) if event.cycle <= now => {
+                let device = event.device;
+                self.queue.pop();
+                self.generations[device] += 1;
+                Some(device)
+            }
+            _ => None,
+        }
+    }
+
+    fn drop_stale(&mut self) {
+        while let Some(Reverse(event)) = self.queue.peek() {
+            if event.generation == self.generations[event.device] {
+                break;
+            }
+            self.queue.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_in_order() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(0, Some(300));
+        scheduler.schedule(1, Some(100));
+        scheduler.schedule(2, Some(200));
+
+        assert_eq!(scheduler.next_deadline(), 100);
+        assert_eq!(scheduler.pop_due(250), Some(1));
+        assert_eq!(scheduler.pop_due(250), Some(2));
+        assert_eq!(scheduler.pop_due(250), None);
+        assert_eq!(scheduler.next_deadline(), 300);
+    }
+
+    #[test]
+    fn test_reschedule_replaces_event() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(0, Some(100));
+        scheduler.schedule(0, Some(500));
+        assert_eq!(scheduler.next_deadline(), 500);
+
+        scheduler.cancel(0);
+        assert_eq!(scheduler.next_deadline(), u64::MAX);
+        assert_eq!(scheduler.pop_due(u64::MAX), None);
+    }
+}
@@ -0,0 +1,141 @@
+/**
+ * Keeps the emulated CPU in step with the wall clock.
+ *
+ * Sleeping after every cycle can't work: a sleep takes tens of microseconds
+ * at best, so anything past a few kHz falls behind, and every sleep's error
+ * adds to the last. Instead the CPU runs a slice of cycles (10 ms worth) as
+ * fast as it can, then sleeps until the wall clock reaches the time those
+ * cycles should have taken. That time is counted from the start of the run,
+ * so oversleeping once only makes the next sleep shorter.
+ *
+ * If the host can't keep up, or the process was stopped for a while, there
+ * is no racing ahead to catch up afterwards: more than 100 ms behind, the
+ * count starts again from now.
+ */
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How much emulated time runs between sleeps, in seconds
+const SLICE: f64 = 0.01;
+
+// How far behind the wall clock the CPU may fall before it gives up on
+// catching up, in seconds
+const MAX_LAG: f64 = 0.1;
+
+pub struct Throttle {
+    hz: f64,
+    enabled: bool,
+
+    // The wall clock time the count is kept from, and cycles since then
+    epoch: Instant,
+    epoch_cycles: u64,
+
+    // For the effective speed: when the run started and cycles since
+    start: Instant,
+    cycles: u64,
+}
+
+impl Throttle {
+    // Run at `hz`, or as fast as the host can if `enabled` is false
+    pub fn new(hz: f64, enabled: bool) -> Self {
+        let now = Instant::now();
+        Self {
+            hz,
+            enabled,
+            epoch: now,
+            epoch_cycles: 0,
+            start: now,
+            cycles: 0,
+        }
+    }
+
+    // How many cycles to run before calling `pace`
+    pub fn slice_cycles(&self) -> u64 {
+        ((self.hz * SLICE) as u64).max(1)
+    }
+
+    // Account for `cycles` just run, sleeping if they ran ahead of time
+    pub fn pace(&mut self, cycles: u64) {
+        if let Some(delay) = self.account(cycles, self.epoch.elapsed()) {
+            thread::sleep(delay);
+        }
+    }
+
+    // Count `cycles` run by `elapsed` after the epoch, and say how long to
+    // sleep for them to be due
+    fn account(&mut self, cycles: u64, elapsed: Duration) -> Option<Duration> {
+        self.cycles += cycles;
+        self.epoch_cycles += cycles;
+        if !self.enabled {
+            return None;
+        }
+
+        let due = self.epoch_cycles as f64 / self.hz;
+        let elapsed = elapsed.as_secs_f64();
+        if due > elapsed {
+            return Some(Duration::from_secs_f64(due - elapsed));
+        }
+        if elapsed - due > MAX_LAG {
+            self.epoch += Duration::from_secs_f64(elapsed);
+            self.epoch_cycles = 0;
+        }
+        None
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    // How fast the CPU actually ran, in Hz
+    pub fn effective_hz(&self) -> f64 {
+        rate(self.cycles, self.elapsed())
+    }
+}
+
+// Cycles per second, or 0 before any time has passed
+fn rate(cycles: u64, elapsed: Duration) -> f64 {
+    let elapsed = elapsed.as_secs_f64();
+    if elapsed > 0.0 {
+        cycles as f64 / elapsed
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pacing() {
+        let ms = Duration::from_millis;
+        let mut throttle = Throttle::new(10_000.0, true);
+        assert_eq!(throttle.slice_cycles(), 100);
+        assert_eq!(Throttle::new(50.0, true).slice_cycles(), 1);
+
+        // A 10 ms slice run in 4 ms sleeps for the rest
+        assert_eq!(throttle.account(100, ms(4)), Some(ms(6)));
+        assert_eq!(throttle.account(100, ms(20)), None);
+
+        // Oversleeping comes off the next sleep
+        assert_eq!(throttle.account(100, ms(32)), None);
+        assert_eq!(throttle.account(100, ms(35)), Some(ms(5)));
+
+        // More than 100 ms behind, the count starts again
+        assert_eq!(throttle.account(100, ms(200)), None);
+        assert_eq!(throttle.account(100, ms(1)), Some(ms(9)));
+        assert_eq!(throttle.cycles(), 600);
+
+        // Unthrottled, nothing sleeps
+        let mut throttle = Throttle::new(10_000.0, false);
+        assert_eq!(throttle.account(10_000, Duration::ZERO), None);
+        assert_eq!(throttle.cycles(), 10_000);
+
+        assert_eq!(rate(10_000, ms(2000)), 5_000.0);
+        assert_eq!(rate(10_000, Duration::ZERO), 0.0);
+    }
+}
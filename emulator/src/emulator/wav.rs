@@ -0,0 +1,119 @@
+/**
+ * Writes mono 16 bit PCM audio to a WAV file as it is produced.
+ *
+ * Sound devices render their output offline, timed by the emulated CPU
+ * clock, so this doesn't need to keep up with real time. The header is
+ * rewritten on every flush, so the file is valid even if the emulator is
+ * killed halfway through.
+ */
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const HEADER_SIZE: u32 = 44;
+
+// Samples are written out in chunks of this many bytes
+const BUFFER_SIZE: usize = 64 * 1024;
+
+pub struct WavWriter {
+    file: File,
+    sample_rate: u32,
+    buffer: Vec<u8>,
+    data_size: u32,
+}
+
+impl WavWriter {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32) -> io::Result<Self> {
+        let mut writer = Self {
+            file: File::create(path)?,
+            sample_rate,
+            buffer: Vec::with_capacity(BUFFER_SIZE),
+            data_size: 0,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Add a sample in the range -1.0..=1.0 (clipped if outside it)
+    pub fn write_sample(&mut self, sample: f32) -> io::Result<()> {
+        self.buffer.extend_from_slice(&to_pcm(sample).to_le_bytes());
+        if self.buffer.len() >= BUFFER_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&self.buffer)?;
+        self.data_size += self.buffer.len() as u32;
+        self.buffer.clear();
+        self.write_header()
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header(self.sample_rate, self.data_size))?;
+        self.file.flush()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if let Err(error) = self.flush() {
+            eprintln!("wav: could not write samples: {}", error);
+        }
+    }
+}
+
+// Convert a sample to 16 bit PCM
+pub fn to_pcm(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+// The 44 byte header of a mono 16 bit PCM file with `data_size` bytes of samples
+fn header(sample_rate: u32, data_size: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(HEADER_SIZE - 8 + data_size).to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    header.extend_from_slice(&1u16.to_le_bytes()); // Mono
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes()); // Bytes per frame
+    header.extend_from_slice(&16u16.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_size.to_le_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wav_file() {
+        let path = std::env::temp_dir().join(format!("rusty502_wav_{}.wav", std::process::id()));
+        {
+            let mut wav = WavWriter::create(&path, 44_100).unwrap();
+            for sample in [0.0, 1.0, -1.0, 2.0] {
+                wav.write_sample(sample).unwrap();
+            }
+        }
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(data.len(), 44 + 8);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(data[4..8].try_into().unwrap()), 44);
+        assert_eq!(u32::from_le_bytes(data[24..28].try_into().unwrap()), 44_100);
+        assert_eq!(u32::from_le_bytes(data[40..44].try_into().unwrap()), 8);
+        assert_eq!(&data[44..], &[0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80, 0xFF, 0x7F]);
+    }
+}
@@ -1,111 +1,626 @@
-use crate::emulator::Emulator;
+use emulator::emulator::devices::c64_banking::Rom;
+use emulator::emulator::devices::file_io::FileIo;
+use emulator::emulator::devices::nes::cartridge::{self, Cartridge};
+use emulator::emulator::error::{Error, Result};
+use emulator::emulator::loaders::{Format, Image};
+use emulator::emulator::machines::apple1::{self, Apple1};
+use emulator::emulator::machines::ben_eater::BenEater;
+use emulator::emulator::machines::board::{Board, BoardDescription};
+use emulator::emulator::machines::c64::{self, C64};
+use emulator::emulator::machines::kim1::{self, Console, Kim1};
+use emulator::emulator::machines::nes::Nes;
+use emulator::emulator::{Emulator, FILE_IO_ADDRESS};
 use std::env;
-
-mod emulator;
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
 
 /**
  * This is the main function for the emulator. It parses the command line arguments and
  * runs the emulator.
  *
  * The command line arguments are as follows:
- *  -r, --rom: The path to the ROM file to load
- *  -a, --address: The address to load the ROM at (default: 0xC000)
- *  -v, --variant: The variant of the CPU to use
+ *  -r, --rom: The path to the ROM file to load (default: demos/blink.bin,
+ *     or nothing on a board, whose description burns its own ROMs). Intel
+ *     HEX (.hex, .ihx), S-record (.s19, .s28, .s37, .srec) and Commodore .prg
+ *     files are placed at their own addresses, on a board into its chips
+ *  -a, --address: The address to load a binary ROM at, or to relocate an
+ *     .o65 object to (default: 0xC000, $FF00 for WozMon on the Apple-1,
+ *     $1800 for the monitor ROMs on the KIM-1, or $0801 for a program on
+ *     the C64)
+ *  -e, --entry: Point the reset vector at the start address of a HEX/S-record
+ *     file, or the start of an .o65 object's code
+ *  -v, --variant: The variant of the CPU to use (default: the machine's)
  *     - NMOS: The NMOS 6502 CPU
  *     - CMOS: The CMOS 65C02 CPU (default)
  *     - NES: The NES CPU (Ricoh 2A03)
- *  -s, --speed: The speed of the CPU in MHz (default: 0.000100 (100 Hz))
+ *     - 6510: The Commodore 64's CPU, with its I/O port at $0000/$0001
+ *  -m, --machine: The machine to emulate
+ *     - default: A VIA with LEDs at $6000 and the host interface at $7F00
+ *     - apple1: An Apple-1 with 32 KiB RAM, the keyboard and display on a
+ *       6821 PIA at $D010, and WozMon (given with -r) at $FF00
+ *     - c64: The Commodore 64's memory map: a 6510 with 64 KiB RAM and the
+ *       BASIC, KERNAL and character ROMs (given with --basic, --kernal and
+ *       --chargen) banked by its I/O port. The VIC-II, SID and CIAs aren't
+ *       there, so the I/O area reads $FF. A program given with -r goes into
+ *       RAM
+ *     - ben_eater: Ben Eater's 6502 computer: 16 KiB RAM at $0000, a 6551
+ *       ACIA at $5000 on the terminal, a VIA at $6000 with the LEDs and a
+ *       16x2 LCD, and a 32 KiB EEPROM at $8000 (blink.bin lands at $C000)
+ *     - kim1: A KIM-1 with its two 6530 RRIOTs, the six-digit display and
+ *       hex keypad, and the monitor ROMs (given with -r) at $1800
+ *     - nes: The NES memory map. An iNES / NES 2.0 image is plugged in as a
+ *       cartridge (mappers 0-4), anything else is loaded into cartridge space
+ *     - A path to a .toml file describing a custom board (see
+ *       `machines::board` for the format)
+ *  -s, --speed: The speed of the CPU in MHz (default: the board's clock, or
+ *     0.000100 (100 Hz)), "step" to run an instruction each time Enter is
+ *     pressed, or "max" to run as fast as the host can. Ben Eater's
+ *     computer runs at 1 with its crystal
+ *  --basic: An Integer BASIC image to load at $E000 on the Apple-1, or the
+ *     C64's 8 KiB BASIC ROM
+ *  --kernal: The C64's 8 KiB KERNAL ROM
+ *  --chargen: The C64's 4 KiB character ROM
+ *  --tty: Talk to the KIM-1's monitor over its teletype interface instead
+ *     of the keypad and display
  *  -b, --benchmark: Runs demos/blink.bin for 1000000 cycles and prints the results"
+ *  -f, --files: A host directory programs can load and save files in, through
+ *     the file I/O device at $7F20. Only on the default machine, as the others
+ *     have memory there; a board can have a file_io device of its own
+ *  --png: Save the NES's frames to a PNG file as `path[,interval]`, overwriting
+ *     it every `interval` frames (default 1). Boards give their video devices
+ *     a `png` path instead
+ *  --wav: Write the NES's sound to a WAV file as `path[,sample_rate]`
+ *     (default 44100 Hz). Boards give a SID a `wav` path instead
  *  -h, --help: Prints the help message
+ *
+ * Bad arguments print the help message and exit with 1. If the ROM can't
+ * be loaded the emulator exits with a status saying why:
+ * 2 file not found, 3 read error, 4 too large for memory, 5 overlapping
+ * segments, 6 unknown CPU variant, 7 bad header, 8 bad record, 9 bad image.
+ * A board description that can't be used exits with 10 if it's malformed or
+ * 11 if two of its parts overlap.
+ *
+ * Programs can print and stop the emulator through the host interface at $7F00,
+ * in which case the emulator exits with the status the program asked for.
+ * The speed the CPU actually ran at is printed on stderr when it stops,
+ * including when it's stopped with Ctrl-C (exit status 130).
  */
-
 fn main() {
     // Parse the command line arguments
     let args: Vec<String> = env::args().collect();
-    let (rom_path, address, variant, speed, benchmark_mode) = parse_args(args);
+    let options = match parse_args(args) {
+        Ok(options) => options,
+        Err(error) => {
+            println!("{}", error);
+            print_help();
+            std::process::exit(error.exit_code());
+        }
+    };
 
     // Create the emulator
     let mut emulator = Emulator::new();
-    emulator.init();
+    let mut nes = None;
+    let mut board = None;
+    let mut ben_eater = None;
+    let mut apple1 = None;
+    let mut kim1 = None;
+    let mut c64 = None;
+    match options.machine.to_lowercase().as_str() {
+        "default" => emulator.init(),
+        "nes" => nes = Some(Nes::attach(&mut emulator)),
+        "apple1" => apple1 = Some(Apple1::attach(&mut emulator)),
+        "ben_eater" => ben_eater = Some(BenEater::attach(&mut emulator)),
+        "kim1" => {
+            let console = if options.tty {
+                Console::Teletype
+            } else {
+                Console::Keypad
+            };
+            kim1 = Some(Kim1::attach(&mut emulator, console));
+        }
+        "c64" => c64 = Some(C64::attach(&mut emulator)),
+        machine if machine.ends_with(".toml") => {
+            let attached = BoardDescription::read(&options.machine)
+                .and_then(|description| Board::attach(&mut emulator, &description));
+            match attached {
+                Ok(attached) => board = Some(attached),
+                Err(error) => exit_with(&error, &options.machine),
+            }
+        }
+        machine => {
+            println!("Unknown machine: {}", machine);
+            print_help();
+            std::process::exit(1);
+        }
+    }
+
+    // Share a directory with the program. The other machines have memory at
+    // $7F20 (a board says where its file_io device goes itself).
+    if let Some(files) = &options.files {
+        if options.machine.to_lowercase() != "default" {
+            println!("--files needs the default machine (boards have a file_io device)");
+            std::process::exit(1);
+        }
+        match FileIo::new(files) {
+            Ok(file_io) => emulator.add_device(
+                FILE_IO_ADDRESS,
+                FILE_IO_ADDRESS + 0x0F,
+                Arc::new(Mutex::new(file_io)),
+            ),
+            Err(error) => {
+                eprintln!("file_io: could not open {}: {}", files, error);
+                std::process::exit(1);
+            }
+        }
+    }
 
     // If benchmark mode is enabled, run the benchmark
-    if benchmark_mode {
-        emulator.benchmark();
+    if options.benchmark_mode {
+        if let Err(error) = emulator.benchmark() {
+            eprintln!("emulator: could not load demos/blink.bin: {}", error);
+            std::process::exit(error.exit_code());
+        }
         std::process::exit(0);
     }
 
-    // Load the ROM file
-    emulator.load_rom_from_path(&rom_path, address);
+    // Load the ROM file, unless the board brings its own
+    let rom_path = match (&options.rom_path, &board) {
+        (Some(path), _) => Some(path.as_str()),
+        (None, Some(_)) => None,
+        (None, None) if apple1.is_some() => {
+            println!("The Apple-1 needs a WozMon image: -r wozmon.bin");
+            std::process::exit(1);
+        }
+        (None, None) if kim1.is_some() => {
+            println!("The KIM-1 needs its monitor ROMs: -r kim1.bin");
+            std::process::exit(1);
+        }
+        (None, None) if c64.is_some() => None,
+        (None, None) => Some("demos/blink.bin"),
+    };
+    let mut cartridge = None;
+    if let Some(rom_path) = rom_path {
+        let machine = Machine {
+            nes: nes.as_ref(),
+            board: board.as_ref(),
+            ben_eater: ben_eater.as_ref(),
+            apple1: apple1.as_ref(),
+            kim1: kim1.as_ref(),
+            c64: c64.as_ref(),
+        };
+        match load_rom(&mut emulator, &machine, rom_path, &options) {
+            Ok(loaded) => cartridge = loaded,
+            Err(error) => exit_with(&error, rom_path),
+        }
+    }
+
+    // The C64's ROMs are burnt in whole; it can't start without the KERNAL
+    if let Some(c64) = &c64 {
+        let Some(kernal_path) = &options.kernal_path else {
+            println!("The C64 needs its KERNAL ROM: --kernal kernal.bin");
+            std::process::exit(1);
+        };
+        let roms = [
+            (Rom::Kernal, Some(kernal_path)),
+            (Rom::Basic, options.basic_path.as_ref()),
+            (Rom::Character, options.chargen_path.as_ref()),
+        ];
+        for (rom, path) in roms {
+            let Some(path) = path else {
+                continue;
+            };
+            let loaded = std::fs::read(path)
+                .map_err(|error| Error::io(path, error))
+                .and_then(|data| c64.load_rom(rom, &data));
+            if let Err(error) = loaded {
+                exit_with(&error, path);
+            }
+        }
+    } else if options.kernal_path.is_some() || options.chargen_path.is_some() {
+        println!("--kernal and --chargen need the C64 (-m c64)");
+        std::process::exit(1);
+    }
+
+    // Integer BASIC goes where it was loaded from cassette
+    if let Some(basic_path) = options.basic_path.as_ref().filter(|_| c64.is_none()) {
+        let Some(apple1) = &apple1 else {
+            println!("--basic needs the Apple-1 (-m apple1) or the C64 (-m c64)");
+            std::process::exit(1);
+        };
+        let loaded = Image::read(
+            basic_path,
+            Format::from_path(basic_path),
+            apple1::BASIC_START,
+        )
+        .and_then(|image| apple1.load_image(&mut emulator, &image, false));
+        if let Err(error) = loaded {
+            exit_with(&error, basic_path);
+        }
+    }
+
+    // Save the NES's frames as the PPU draws them
+    if let Some((path, interval)) = &options.png {
+        let Some(nes) = &nes else {
+            println!("--png needs the NES (-m nes)");
+            std::process::exit(1);
+        };
+        nes.ppu.lock().unwrap().set_png_output(path, *interval);
+    }
+
+    // Write the NES's sound out as the APU makes it
+    if let Some((path, sample_rate)) = &options.wav {
+        let Some(nes) = &nes else {
+            println!("--wav needs the NES (-m nes)");
+            std::process::exit(1);
+        };
+        let mut io = nes.io.lock().unwrap();
+        io.apu_mut().set_sample_rate(*sample_rate);
+        if let Err(error) = io.apu_mut().set_wav_output(path) {
+            eprintln!("apu: could not create {}: {}", path, error);
+            std::process::exit(1);
+        }
+    }
 
-    // Change the variant of the CPU
-    emulator.change_variant(variant);
+    // Change the variant of the CPU, if the machine's isn't wanted
+    if let Some(variant) = options.variant {
+        if let Err(error) = emulator.change_variant(variant) {
+            eprintln!("emulator: {}", error);
+            std::process::exit(error.exit_code());
+        }
+    }
 
-    // Run the emulator
-    emulator.run(speed, None, false);
+    // Run the emulator, until the program stops it through the host interface
+    let status = if options.single_step {
+        emulator.run_single_step(io::stdin().lock())
+    } else {
+        // The terminal is left alone while stepping, which reads it for Enter
+        if let Some(ben_eater) = &ben_eater {
+            ben_eater.connect_terminal();
+        }
+        if let Some(apple1) = &apple1 {
+            apple1.connect_keyboard();
+        }
+        if let Some(kim1) = &kim1 {
+            kim1.connect_terminal();
+        }
+        let speed = options
+            .speed
+            .or(board.as_ref().and_then(|board| board.clock_mhz))
+            .or(apple1.as_ref().map(|_| apple1::CLOCK_MHZ))
+            .or(kim1.as_ref().map(|_| kim1::CLOCK_MHZ))
+            .or(c64.as_ref().map(|_| c64::CLOCK_MHZ))
+            .unwrap_or(0.000100); // 100 Hz
+        emulator.set_throttled(!options.unthrottled);
+
+        // Ctrl-C ends the run at the next slice, so the speed still gets
+        // reported; a second one doesn't wait
+        let interrupt = emulator.interrupt_flag();
+        let handler = ctrlc::set_handler(move || {
+            if interrupt.swap(true, Ordering::SeqCst) {
+                std::process::exit(130);
+            }
+        });
+        if let Err(error) = handler {
+            eprintln!("emulator: could not catch Ctrl-C: {}", error);
+        }
+        let status = emulator.run(speed, None, false);
+        if let Some(hz) = emulator.effective_speed_hz() {
+            eprintln!("emulator: ran at {:.6} MHz", hz / 1_000_000.0);
+        }
+        status
+    };
+
+    // Exiting skips destructors, so write battery backed RAM and the end
+    // of any WAV files out here
+    if let Some(cartridge) = cartridge {
+        if let Err(error) = cartridge.lock().unwrap().save() {
+            eprintln!("cartridge: could not save battery RAM: {}", error);
+        }
+    }
+    if let Some(board) = &board {
+        board.flush_audio();
+    }
+    if let Some(nes) = &nes {
+        nes.flush_audio();
+    }
+
+    if let Some(status) = status {
+        std::process::exit(status);
+    }
+    if emulator.interrupted() {
+        std::process::exit(130);
+    }
 
     println!();
 }
 
-fn parse_args(args: Vec<String>) -> (String, u16, String, f64, bool) {
+// Report why `path` couldn't be loaded (or the file it needed) and exit
+fn exit_with(error: &Error, path: &str) -> ! {
+    let path = error
+        .path()
+        .map_or(path.into(), |path| path.display().to_string());
+    eprintln!("emulator: could not load {}: {}", path, error);
+    std::process::exit(error.exit_code());
+}
+
+// The machine profile chosen with -m, if it isn't the default
+struct Machine<'a> {
+    nes: Option<&'a Nes>,
+    board: Option<&'a Board>,
+    ben_eater: Option<&'a BenEater>,
+    apple1: Option<&'a Apple1>,
+    kim1: Option<&'a Kim1>,
+    c64: Option<&'a C64>,
+}
+
+// Load the ROM file, as a cartridge if the NES is given an iNES image, or
+// into the chips of a board
+fn load_rom(
+    emulator: &mut Emulator,
+    machine: &Machine,
+    path: &str,
+    options: &Options,
+) -> Result<Option<Arc<Mutex<Cartridge>>>> {
+    if let Some(nes) = machine.nes.filter(|_| is_ines(path)) {
+        return Ok(Some(nes.load_cartridge(emulator, path)?));
+    }
+
+    let address = match options.address {
+        Some(address) => address,
+        None if machine.apple1.is_some() => apple1::WOZMON_START,
+        None if machine.kim1.is_some() => kim1::ROM_003_START,
+        None if machine.c64.is_some() => c64::BASIC_PROGRAM_START,
+        None => 0xC000,
+    };
+    let image = Image::read(path, Format::from_path(path), address)?;
+    let entry = options.use_entry;
+    if let Some(board) = machine.board {
+        board.load_image(emulator, &image, entry)?;
+    } else if let Some(ben_eater) = machine.ben_eater {
+        ben_eater.load_image(emulator, &image, entry)?;
+    } else if let Some(apple1) = machine.apple1 {
+        apple1.load_image(emulator, &image, entry)?;
+    } else if let Some(kim1) = machine.kim1 {
+        kim1.load_image(emulator, &image, entry)?;
+    } else if let Some(c64) = machine.c64 {
+        c64.load_image(emulator, &image)?;
+    } else {
+        emulator.load_image(&image, entry)?;
+    }
+    Ok(None)
+}
+
+// Whether the file at `path` starts with an iNES header
+fn is_ines(path: &str) -> bool {
+    let mut magic = [0; 4];
+    std::fs::File::open(path)
+        .and_then(|mut file| std::io::Read::read_exact(&mut file, &mut magic))
+        .is_ok_and(|_| &magic == cartridge::MAGIC)
+}
+
+struct Options {
+    rom_path: Option<String>,
+    address: Option<u16>,
+    use_entry: bool,
+    variant: Option<String>,
+    machine: String,
+    speed: Option<f64>,
+    single_step: bool,
+    unthrottled: bool,
+    benchmark_mode: bool,
+    files: Option<String>,
+    basic_path: Option<String>,
+    kernal_path: Option<String>,
+    chargen_path: Option<String>,
+    tty: bool,
+    png: Option<(String, u64)>,
+    wav: Option<(String, u32)>,
+}
+
+// The value given after `args[i]`
+fn value(args: &[String], i: usize) -> Result<&str> {
+    match args.get(i + 1) {
+        Some(value) => Ok(value),
+        None => Err(Error::Usage(format!("{} needs a value", args[i]))),
+    }
+}
+
+fn parse_args(args: Vec<String>) -> Result<Options> {
     // Set the default values
-    let mut rom_path = String::from("demos/blink.bin");
-    let mut address = 0xC000;
-    let mut variant = String::from("CMOS");
-    let mut speed: f64 = 0.000100; // 100 Hz
-    let mut benchmark_mode = false;
+    let mut options = Options {
+        rom_path: None,
+        address: None,
+        use_entry: false,
+        variant: None,
+        machine: String::from("default"),
+        speed: None,
+        single_step: false,
+        unthrottled: false,
+        benchmark_mode: false,
+        files: None,
+        basic_path: None,
+        kernal_path: None,
+        chargen_path: None,
+        tty: false,
+        png: None,
+        wav: None,
+    };
 
     // Parse the arguments
     let mut i = 1;
     while i < args.len() {
         match args[i].as_str() {
             "-r" | "--rom" => {
-                rom_path = args[i + 1].clone();
+                options.rom_path = Some(value(&args, i)?.to_string());
                 i += 1;
             }
             "-a" | "--address" => {
-                address = u16::from_str_radix(&args[i + 1], 16).unwrap();
+                let address = value(&args, i)?;
+                match u16::from_str_radix(address, 16) {
+                    Ok(address) => options.address = Some(address),
+                    Err(_) => {
+                        return Err(Error::Usage(format!(
+                            "Invalid address: {} (expected hex, up to FFFF)",
+                            address
+                        )))
+                    }
+                }
                 i += 1;
             }
+            "-e" | "--entry" => {
+                options.use_entry = true;
+            }
             "-v" | "--variant" => {
-                variant = args[i + 1].clone();
+                options.variant = Some(value(&args, i)?.to_string());
+                i += 1;
+            }
+            "-m" | "--machine" => {
+                options.machine = value(&args, i)?.to_string();
                 i += 1;
             }
             "-s" | "--speed" => {
-                speed = args[i + 1].parse::<f64>().unwrap();
+                match value(&args, i)? {
+                    "step" => options.single_step = true,
+                    "max" => options.unthrottled = true,
+                    speed => match speed.parse::<f64>() {
+                        Ok(speed) if speed > 0.0 && speed.is_finite() => {
+                            options.speed = Some(speed)
+                        }
+                        _ => {
+                            return Err(Error::Usage(format!(
+                                "Invalid speed: {} (expected MHz, \"step\" or \"max\")",
+                                speed
+                            )))
+                        }
+                    },
+                }
                 i += 1;
             }
+            "--basic" => {
+                options.basic_path = Some(value(&args, i)?.to_string());
+                i += 1;
+            }
+            "--kernal" => {
+                options.kernal_path = Some(value(&args, i)?.to_string());
+                i += 1;
+            }
+            "--chargen" => {
+                options.chargen_path = Some(value(&args, i)?.to_string());
+                i += 1;
+            }
+            "--tty" => {
+                options.tty = true;
+            }
             "-b" | "--benchmark" => {
-                benchmark_mode = true;
+                options.benchmark_mode = true;
+            }
+            "-f" | "--files" => {
+                options.files = Some(value(&args, i)?.to_string());
+                i += 1;
+            }
+            "--png" => {
+                let png = value(&args, i)?;
+                options.png = match png.rsplit_once(',') {
+                    None => Some((png.to_string(), 1)),
+                    Some((path, interval)) => match interval.parse::<u64>() {
+                        Ok(interval) if interval > 0 => Some((path.to_string(), interval)),
+                        _ => {
+                            return Err(Error::Usage(format!(
+                                "Invalid PNG interval: {} (expected a number of frames)",
+                                interval
+                            )))
+                        }
+                    },
+                };
+                i += 1;
+            }
+            "--wav" => {
+                let wav = value(&args, i)?;
+                options.wav = match wav.rsplit_once(',') {
+                    None => Some((wav.to_string(), 44_100)),
+                    Some((path, rate)) => match rate.parse::<u32>() {
+                        Ok(rate) if rate > 0 => Some((path.to_string(), rate)),
+                        _ => {
+                            return Err(Error::Usage(format!(
+                                "Invalid sample rate: {} (expected Hz)",
+                                rate
+                            )))
+                        }
+                    },
+                };
+                i += 1;
             }
             "-h" | "--help" => {
                 print_help();
                 std::process::exit(0);
             }
-            _ => {
-                println!("Invalid argument: {}", args[i]);
-                print_help();
-                std::process::exit(1);
-            }
+            _ => return Err(Error::Usage(format!("Invalid argument: {}", args[i]))),
         }
         i += 1;
     }
 
-    // Return the parsed arguments
-    (rom_path, address, variant, speed, benchmark_mode)
+    Ok(options)
 }
 
 fn print_help() {
     println!("Usage: emulator [OPTIONS]");
     println!("Options:");
     println!("  -r, --rom: The path to the ROM file to load");
-    println!("  -a, --address: The address to load the ROM at (default: 0xC000)");
-    println!("  -v, --variant: The variant of the CPU to use");
+    println!("  -a, --address: The address to load a binary ROM at (default: 0xC000,");
+    println!("     $FF00 for WozMon on the Apple-1, $1800 for the KIM-1's monitor ROMs,");
+    println!("     $0801 for a program in the C64's RAM)");
+    println!("     Intel HEX (.hex, .ihx), S-record (.s19, .s28, .s37, .srec) and .prg");
+    println!("     files are placed at the addresses they give; .o65 objects are");
+    println!("     relocated to the address");
+    println!("  -e, --entry: Point the reset vector at the file's start address");
+    println!("  -v, --variant: The variant of the CPU to use (default: the machine's)");
     println!("     - NMOS: The MMOS 6502 CPU");
     println!("     - CMOS: The CMOS 65C02 CPU (default)");
     println!("     - NES: The NES CPU (Ricoh 2A03)");
-    println!("  -s, --speed: The speed of the CPU in MHz (default: 0.000100 (100 Hz))");
+    println!("     - 6510: The Commodore 64's CPU, with its I/O port at $0000/$0001");
+    println!("  -m, --machine: The machine to emulate");
+    println!("     - default: A VIA with LEDs at $6000 and the host interface at $7F00");
+    println!("     - apple1: An Apple-1: 32 KiB RAM, keyboard and 40-column display on a");
+    println!("       6821 PIA at $D010, WozMon (your own image, with -r) at $FF00");
+    println!("     - c64: The C64's memory map: a 6510, 64 KiB RAM and the ROMs given with");
+    println!("       --basic, --kernal and --chargen (no VIC-II, SID or CIAs; -r loads RAM)");
+    println!("     - ben_eater: Ben Eater's 6502 computer: 16 KiB RAM, 6551 ACIA at $5000 on");
+    println!("       the terminal, VIA at $6000 with LEDs and a 16x2 LCD, 32 KiB EEPROM at $8000");
+    println!("     - kim1: A KIM-1: two 6530 RRIOTs, six-digit display and hex keypad,");
+    println!("       the monitor ROMs (your own image, with -r) at $1800");
+    println!("     - nes: The NES memory map, with an iNES / NES 2.0 ROM as a cartridge");
+    println!("       (mappers 0-4, battery RAM saved to a .sav file next to the ROM)");
+    println!("     - board.toml: A custom board described in a TOML file: CPU, RAM and");
+    println!("       ROM chips with their images, and devices with their IRQ wiring and");
+    println!("       port peripherals");
+    println!("  -s, --speed: The speed of the CPU in MHz (default: the board's clock, or");
+    println!("     0.000100 (100 Hz)), \"step\" to run one instruction per Enter press, or");
+    println!("     \"max\" to run as fast as the host can (1 runs Ben Eater's computer at its");
+    println!("     crystal's speed)");
+    println!("  --basic: An Integer BASIC image to load at $E000 on the Apple-1, or the");
+    println!("     C64's BASIC ROM (8 KiB)");
+    println!("  --kernal: The C64's KERNAL ROM (8 KiB)");
+    println!("  --chargen: The C64's character ROM (4 KiB)");
+    println!("  --tty: Use the KIM-1's teletype interface instead of its keypad and display");
     println!(
         "  -b, --benchmark: Runs demos/blink.bin for 200,000,000 cycles and prints the results"
     );
+    println!("  -f, --files: A directory programs can load and save files in (file I/O at");
+    println!("     $7F20 on the default machine; a board can have a file_io device)");
+    println!("  --png: Save the NES's frames to a PNG file, as path[,interval] to overwrite");
+    println!("     it every interval frames (default 1)");
+    println!("  --wav: Write the NES's sound to a WAV file, as path[,sample_rate] (default");
+    println!("     44100 Hz)");
     println!("  -h, --help: Prints the help message");
+    println!();
+    println!("Programs can print to stdout and exit with a status through the host");
+    println!("interface at $7F00: $7F00 putchar, $7F01 exit, $7F02 shutdown.");
+    println!();
+    println!("Exit statuses when the ROM can't be loaded:");
+    println!("  2 file not found, 3 read error, 4 too large for memory,");
+    println!("  5 overlapping segments, 6 unknown CPU variant, 7 bad header,");
+    println!("  8 bad record, 9 bad image");
+    println!("and when a board description can't be used:");
+    println!("  10 bad description, 11 overlapping regions");
 }